@@ -0,0 +1,15 @@
+#![no_main]
+
+use std::io::Cursor;
+
+use lf_watermark::cmyk::CmykImage;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(image) = CmykImage::read_tiff(Cursor::new(data)) {
+        assert_eq!(
+            image.data.len(),
+            image.width as usize * image.height as usize * 4
+        );
+    }
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use lf_watermark::dicom::{mark_dicom, read_dicom_mark, restore_dicom};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(marked) = mark_dicom(data, "{patient_id}") {
+        assert!(read_dicom_mark(&marked).is_ok());
+        assert_eq!(restore_dicom(&marked).unwrap(), data);
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use lf_watermark::dng::{mark_dng, read_dng_mark, DngOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(marked) = mark_dng(data, "Hello", &DngOptions::default()) {
+        assert_eq!(read_dng_mark(&marked.bytes).as_deref(), Some("Hello"));
+    }
+});
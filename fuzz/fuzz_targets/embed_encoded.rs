@@ -0,0 +1,39 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use lf_watermark::{Band, OrientationPolicy, PalettePolicy, WatermarkConfig};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Debug, Arbitrary)]
+struct Input<'a> {
+    watermark: &'a str,
+    band: u8,
+    restore_orientation: bool,
+    refuse_palette: bool,
+    data: &'a [u8],
+}
+
+fuzz_target!(|input: Input| {
+    let config = WatermarkConfig {
+        band: match input.band % 4 {
+            0 => Band::Dc,
+            1 => Band::Low,
+            2 => Band::Mid,
+            _ => Band::High,
+        },
+        orientation: if input.restore_orientation {
+            OrientationPolicy::Restore
+        } else {
+            OrientationPolicy::Normalize
+        },
+        palette: if input.refuse_palette {
+            PalettePolicy::Refuse
+        } else {
+            PalettePolicy::default()
+        },
+        ..Default::default()
+    };
+
+    // Errors are expected on garbage; panics and hangs are the bugs.
+    let _ = lf_watermark::embed_watermark_encoded(input.data, input.watermark, &config);
+});
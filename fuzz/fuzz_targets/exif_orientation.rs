@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Some(orientation) = lf_watermark::orientation::read_orientation(data) {
+        assert!((1..=8).contains(&orientation));
+    }
+    let _ = lf_watermark::exif::ExifFields::read(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|words: &str| {
+    if let Ok(value) = lf_watermark::get_watermark_from_str(words) {
+        assert!(value >= 0.0);
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use lf_watermark::pdf::{mark_pdf, PdfOptions};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(marked) = mark_pdf(data, "Hello", &PdfOptions::default()) {
+        assert!(marked.bytes.starts_with(data));
+    }
+});
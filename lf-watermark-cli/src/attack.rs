@@ -0,0 +1,441 @@
+//! Attack simulations for checking settings from the command line.
+//!
+//! Each attack is what an image typically goes through after release: re-encoding, resizing,
+//! cropping, blurring, noise and brightness edits. A scaled image is resized back to the marked
+//! size, as a verifier that knows the original size would do; a cropped one is not, since a
+//! verifier cannot know what was cut away.
+//!
+//! Pipelines of one's own, such as a CDN's exact transcode chain, join the built-in attacks
+//! through the [`Attack`] trait and a [`Registry`]. A registered external command gets the marked
+//! image as PNG on standard input and writes the attacked image to standard output, so any tool
+//! chain can be wrapped in a script.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{Cursor, Write};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+use serde::Serialize;
+
+use crate::key::KeyFile;
+use crate::{mark, Result};
+
+/// Something an image goes through after release.
+pub trait Attack: Send + Sync {
+    /// Name in the survival table, the attack's spec on the command line.
+    fn name(&self) -> String;
+
+    /// The attacked copy of `image`.
+    fn apply(&self, image: &DynamicImage) -> Result<DynamicImage>;
+}
+
+/// Names of the built-in attacks, which plugins cannot take.
+pub const BUILTIN: [&str; 6] = ["jpeg", "scale", "crop", "blur", "noise", "brightness"];
+
+/// One built-in attack, written `name:parameter` on the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Builtin {
+    /// `jpeg:75`: JPEG re-encoding at a quality of 1 to 100.
+    Jpeg(u8),
+    /// `scale:0.5`: resizing by a factor, then back to the marked size.
+    Scale(f32),
+    /// `crop:0.8`: keeping this share of the width and height around the center.
+    Crop(f32),
+    /// `blur:1.5`: Gaussian blur with this sigma in pixels.
+    Blur(f32),
+    /// `noise:4`: uniform noise of up to this many levels per channel.
+    Noise(u8),
+    /// `brightness:10`: a uniform level offset.
+    Brightness(i32),
+}
+
+impl FromStr for Builtin {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, String> {
+        let (name, value) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("Attack {} is not name:parameter", spec))?;
+        let invalid = || format!("Invalid parameter in attack {}", spec);
+        let fraction = |value: &str| {
+            value
+                .parse::<f32>()
+                .ok()
+                .filter(|f| *f > 0.0 && *f <= 1.0)
+                .ok_or_else(invalid)
+        };
+
+        Ok(match name {
+            "jpeg" => Builtin::Jpeg(
+                value
+                    .parse()
+                    .ok()
+                    .filter(|q| (1..=100).contains(q))
+                    .ok_or_else(invalid)?,
+            ),
+            "scale" => Builtin::Scale(
+                value
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|f| *f > 0.0)
+                    .ok_or_else(invalid)?,
+            ),
+            "crop" => Builtin::Crop(fraction(value)?),
+            "blur" => Builtin::Blur(
+                value
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|s| *s > 0.0)
+                    .ok_or_else(invalid)?,
+            ),
+            "noise" => Builtin::Noise(value.parse().map_err(|_| invalid())?),
+            "brightness" => Builtin::Brightness(value.parse().map_err(|_| invalid())?),
+            _ => return Err(format!("Unknown attack {}", name)),
+        })
+    }
+}
+
+impl fmt::Display for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Builtin::Jpeg(quality) => write!(f, "jpeg:{}", quality),
+            Builtin::Scale(factor) => write!(f, "scale:{}", factor),
+            Builtin::Crop(fraction) => write!(f, "crop:{}", fraction),
+            Builtin::Blur(sigma) => write!(f, "blur:{}", sigma),
+            Builtin::Noise(amplitude) => write!(f, "noise:{}", amplitude),
+            Builtin::Brightness(offset) => write!(f, "brightness:{}", offset),
+        }
+    }
+}
+
+impl Attack for Builtin {
+    fn name(&self) -> String {
+        self.to_string()
+    }
+
+    fn apply(&self, image: &DynamicImage) -> Result<DynamicImage> {
+        let (width, height) = image.dimensions();
+        Ok(match *self {
+            Builtin::Jpeg(quality) => {
+                let mut encoded = Vec::new();
+                JpegEncoder::new_with_quality(&mut encoded, quality).encode_image(image)?;
+                image::load(Cursor::new(encoded), image::ImageFormat::Jpeg)?
+            }
+            Builtin::Scale(factor) => {
+                let scaled = |v: u32| ((v as f32 * factor).round() as u32).max(1);
+                image
+                    .resize_exact(scaled(width), scaled(height), FilterType::Triangle)
+                    .resize_exact(width, height, FilterType::Triangle)
+            }
+            Builtin::Crop(fraction) => {
+                let kept = |v: u32| ((v as f32 * fraction).round() as u32).max(1);
+                let (w, h) = (kept(width), kept(height));
+                image.crop_imm((width - w) / 2, (height - h) / 2, w, h)
+            }
+            Builtin::Blur(sigma) => image.blur(sigma),
+            Builtin::Noise(amplitude) => {
+                let source = image.to_rgb8();
+                let span = 2 * amplitude as u32 + 1;
+                DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+                    let p = source.get_pixel(x, y);
+                    Rgb(std::array::from_fn(|c| {
+                        let noise = hash(x, y, c as u32) % span;
+                        (p[c] as i32 + noise as i32 - amplitude as i32).clamp(0, 255) as u8
+                    }))
+                }))
+            }
+            Builtin::Brightness(offset) => image.brighten(offset),
+        })
+    }
+}
+
+/// Makes an attack from the parameter of its spec, empty when the spec has none.
+pub type Factory = Box<dyn Fn(&str) -> Result<Box<dyn Attack>> + Send + Sync>;
+
+/// The attacks specs can name: the built-in ones and the registered plugins.
+#[derive(Default)]
+pub struct Registry {
+    plugins: BTreeMap<String, Factory>,
+}
+
+impl Registry {
+    /// Makes the attacks named by `name` with `factory`. Built-in names and names given before are
+    /// taken.
+    pub fn register(&mut self, name: &str, factory: Factory) -> Result<()> {
+        if name.is_empty() || name.contains([':', ',', '=']) {
+            return Err(format!("Invalid attack name {}", name).into());
+        }
+        if BUILTIN.contains(&name) || self.plugins.contains_key(name) {
+            return Err(format!("Attack {} is already registered", name).into());
+        }
+
+        self.plugins.insert(name.to_string(), factory);
+        Ok(())
+    }
+
+    /// Registers `name` as the external command `command`, split at whitespace; see
+    /// [`External`].
+    pub fn register_command(&mut self, name: &str, command: &str) -> Result<()> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        let program = words
+            .next()
+            .ok_or_else(|| format!("Plugin {} has no command", name))?;
+        let args: Vec<String> = words.collect();
+        let name_owned = name.to_string();
+
+        self.register(
+            name,
+            Box::new(move |parameter| {
+                Ok(Box::new(External {
+                    name: name_owned.clone(),
+                    program: program.clone(),
+                    args: args.clone(),
+                    parameter: parameter.to_string(),
+                }))
+            }),
+        )
+    }
+
+    /// The attack of `spec`: `name:parameter` for a built-in attack, and `name` or
+    /// `name:parameter` for a plugin.
+    pub fn parse(&self, spec: &str) -> Result<Box<dyn Attack>> {
+        let (name, parameter) = spec.split_once(':').unwrap_or((spec, ""));
+        match self.plugins.get(name) {
+            Some(factory) => factory(parameter),
+            None => Ok(Box::new(spec.parse::<Builtin>()?)),
+        }
+    }
+}
+
+/// An attack run by an external command, which reads the image as PNG on standard input and
+/// writes the attacked image in any format `image` reads to standard output. A parameter in the
+/// spec is passed as the last argument.
+#[derive(Debug, Clone, PartialEq)]
+pub struct External {
+    pub name: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub parameter: String,
+}
+
+impl Attack for External {
+    fn name(&self) -> String {
+        if self.parameter.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}:{}", self.name, self.parameter)
+        }
+    }
+
+    fn apply(&self, image: &DynamicImage) -> Result<DynamicImage> {
+        let mut input = Vec::new();
+        image.write_to(&mut Cursor::new(&mut input), image::ImageFormat::Png)?;
+
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+        if !self.parameter.is_empty() {
+            command.arg(&self.parameter);
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| format!("Cannot run {}; {}", self.program, e))?;
+        // Written from another thread, so a command that streams its output cannot deadlock.
+        let mut stdin = child.stdin.take().expect("piped");
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output()?;
+        // A command may stop reading once it has what it needs.
+        let _ = writer.join();
+
+        if !output.status.success() {
+            return Err(format!("Attack {} failed with {}", self.name(), output.status).into());
+        }
+        image::load_from_memory(&output.stdout)
+            .map_err(|e| format!("Attack {} wrote no image; {}", self.name(), e).into())
+    }
+}
+
+/// Outcome of one attack on the marked image.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Survival {
+    pub attack: String,
+    pub detected: bool,
+    /// Whether the decoded payload is the embedded one.
+    pub payload_intact: bool,
+    pub ber: f64,
+    pub confidence: f64,
+}
+
+/// Marks `image` with `payload` under `key` and verifies the mark after each of `attacks`. The
+/// first row is the unattacked marked image.
+pub fn run(
+    image: &DynamicImage,
+    payload: &str,
+    key: &KeyFile,
+    attacks: &[Box<dyn Attack>],
+) -> Result<Vec<Survival>> {
+    let marked = DynamicImage::ImageRgb8(mark::embed(image, payload, key)?);
+    let mut rows = vec![survival("none", &marked, payload, key)?];
+    for attack in attacks {
+        let attacked = attack.apply(&marked)?;
+        rows.push(survival(&attack.name(), &attacked, payload, key)?);
+    }
+
+    Ok(rows)
+}
+
+fn survival(attack: &str, image: &DynamicImage, payload: &str, key: &KeyFile) -> Result<Survival> {
+    let (detected, payload_intact, ber, confidence) = match mark::verify(image, key, Some(payload))
+    {
+        Ok(found) => (
+            found.detected,
+            found.detected && found.payload == payload,
+            found.ber,
+            found.confidence,
+        ),
+        // Too small to carry the payload any more, as after a strong crop.
+        Err(_) => (false, false, 1.0, 0.0),
+    };
+
+    Ok(Survival {
+        attack: attack.to_string(),
+        detected,
+        payload_intact,
+        ber,
+        confidence,
+    })
+}
+
+/// Hashed noise source, so an attack run is reproducible.
+fn hash(x: u32, y: u32, c: u32) -> u32 {
+    let mut h =
+        x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663) ^ c.wrapping_mul(83_492_791);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^ h >> 15
+}
+
+#[cfg(test)]
+mod tests {
+    use lf_watermark::test_utils::grainy;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let attacks: Vec<Builtin> = "jpeg:75,scale:0.5,crop:0.8,blur:1.5,noise:4,brightness:-10"
+            .split(',')
+            .map(|spec| spec.parse().unwrap())
+            .collect();
+        assert_eq!(
+            attacks,
+            vec![
+                Builtin::Jpeg(75),
+                Builtin::Scale(0.5),
+                Builtin::Crop(0.8),
+                Builtin::Blur(1.5),
+                Builtin::Noise(4),
+                Builtin::Brightness(-10),
+            ]
+        );
+        assert_eq!(attacks[1].to_string(), "scale:0.5");
+        for spec in ["jpeg", "jpeg:0", "crop:1.5", "scale:-1", "rotate:90"] {
+            assert!(spec.parse::<Builtin>().is_err(), "{}", spec);
+        }
+    }
+
+    struct Invert;
+
+    impl Attack for Invert {
+        fn name(&self) -> String {
+            "invert".into()
+        }
+
+        fn apply(&self, image: &DynamicImage) -> Result<DynamicImage> {
+            let mut inverted = image.clone();
+            inverted.invert();
+            Ok(inverted)
+        }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = Registry::default();
+        registry.register_command("identity", "cat").unwrap();
+        registry
+            .register_command("fails", "false --always")
+            .unwrap();
+        assert!(registry.register_command("jpeg", "cat").is_err());
+        assert!(registry.register_command("identity", "cat").is_err());
+        assert!(registry.register_command("a:b", "cat").is_err());
+        assert!(registry.register_command("empty", " ").is_err());
+
+        assert_eq!(registry.parse("jpeg:75").unwrap().name(), "jpeg:75");
+        assert_eq!(registry.parse("identity:3").unwrap().name(), "identity:3");
+        assert!(registry.parse("rotate:90").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_external() {
+        let mut registry = Registry::default();
+        registry.register_command("identity", "cat").unwrap();
+        registry
+            .register_command("fails", "false --always")
+            .unwrap();
+
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 8, |x, y| {
+            Rgb([x as u8 * 16, y as u8 * 32, 7])
+        }));
+        let passed = registry.parse("identity").unwrap().apply(&image).unwrap();
+        assert_eq!(passed.to_rgb8(), image.to_rgb8());
+        assert!(registry.parse("fails").unwrap().apply(&image).is_err());
+    }
+
+    #[test]
+    fn test_run() {
+        let image = DynamicImage::ImageRgb8(grainy(320, 240, 41));
+        let key = KeyFile {
+            id: "test".into(),
+            secret: "0123456789abcdef".into(),
+            payload_len: 8,
+            compress: false,
+        };
+
+        let mut registry = Registry::default();
+        registry
+            .register(
+                "invert",
+                Box::new(|_| Ok(Box::new(Invert) as Box<dyn Attack>)),
+            )
+            .unwrap();
+        let attacks: Vec<Box<dyn Attack>> = ["noise:1", "crop:0.5", "jpeg:90", "invert"]
+            .iter()
+            .map(|spec| registry.parse(spec).unwrap())
+            .collect();
+        let rows = run(&image, "Press", &key, &attacks).unwrap();
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0].attack, "none");
+        assert!(
+            rows[0].payload_intact && rows[0].ber == 0.0,
+            "{:?}",
+            rows[0]
+        );
+        assert!(rows[1].payload_intact, "{:?}", rows[1]);
+        assert!(!rows[2].detected, "{:?}", rows[2]);
+
+        assert_eq!(rows[4].attack, "invert");
+        assert!(!rows[4].payload_intact, "{:?}", rows[4]);
+
+        let cropped = Builtin::Crop(0.5).apply(&image).unwrap();
+        assert_eq!(cropped.dimensions(), (160, 120));
+        let scaled = Builtin::Scale(0.5).apply(&image).unwrap();
+        assert_eq!(scaled.dimensions(), (320, 240));
+    }
+}
@@ -0,0 +1,213 @@
+//! Batch verification with corpus statistics, for periodic audits of a CDN or marketplace.
+//!
+//! Every file is verified under every key of the key ring and attributed to the key whose check
+//! matches. The statistics count detections per key and sort the best confidence of each file
+//! into a histogram, which shows at a glance whether a corpus holds clean marks, damaged ones or
+//! none.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use serde::Serialize;
+
+use crate::key::KeyFile;
+use crate::{mark, Result};
+
+/// Number of confidence histogram bins, each a tenth wide.
+pub const HISTOGRAM_BINS: usize = 10;
+
+/// Outcome of one file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileResult {
+    pub path: PathBuf,
+    /// Key whose mark was detected, if any.
+    pub key_id: Option<String>,
+    pub payload: Option<String>,
+    /// Confidence under the detected key, or the best over the key ring without a detection.
+    pub confidence: f64,
+    /// Why the file could not be verified.
+    pub error: Option<String>,
+}
+
+/// Aggregate over all files.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Statistics {
+    pub files: usize,
+    pub detected: usize,
+    pub errors: usize,
+    /// Detected files over files that could be verified.
+    pub detection_rate: f64,
+    /// Detected files per key id.
+    pub per_key: BTreeMap<String, usize>,
+    /// Files by confidence, in bins of a tenth from 0 to 1. Files with errors are left out.
+    pub histogram: [usize; HISTOGRAM_BINS],
+}
+
+/// Per-file results, in input order, and their statistics.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Report {
+    pub files: Vec<FileResult>,
+    pub statistics: Statistics,
+}
+
+/// Verifies each of `paths` under each key of `keyring` on `jobs` threads.
+pub fn verify_batch(paths: &[PathBuf], keyring: &[KeyFile], jobs: usize) -> Result<Report> {
+    if keyring.is_empty() {
+        return Err("The key ring is empty".into());
+    }
+
+    let files: Vec<FileResult> =
+        ThreadPoolBuilder::new()
+            .num_threads(jobs)
+            .build()?
+            .install(|| {
+                paths
+                    .par_iter()
+                    .map(|path| verify_file(path, keyring))
+                    .collect()
+            });
+    let statistics = statistics(&files);
+
+    Ok(Report { files, statistics })
+}
+
+fn verify_file(path: &Path, keyring: &[KeyFile]) -> FileResult {
+    let mut result = FileResult {
+        path: path.to_path_buf(),
+        key_id: None,
+        payload: None,
+        confidence: 0.0,
+        error: None,
+    };
+    let image = match image::open(path) {
+        Ok(image) => image,
+        Err(e) => {
+            result.error = Some(e.to_string());
+            return result;
+        }
+    };
+
+    for key in keyring {
+        match mark::verify(&image, key, None) {
+            Ok(found) if found.detected => {
+                result.key_id = Some(found.key_id);
+                result.payload = Some(found.payload);
+                result.confidence = found.confidence;
+                return result;
+            }
+            Ok(found) => result.confidence = result.confidence.max(found.confidence),
+            Err(e) => {
+                result.error = Some(e.to_string());
+                return result;
+            }
+        }
+    }
+
+    result
+}
+
+fn statistics(files: &[FileResult]) -> Statistics {
+    let mut per_key = BTreeMap::new();
+    let mut histogram = [0; HISTOGRAM_BINS];
+    let mut errors = 0;
+    for file in files {
+        if file.error.is_some() {
+            errors += 1;
+            continue;
+        }
+        if let Some(key_id) = &file.key_id {
+            *per_key.entry(key_id.clone()).or_insert(0) += 1;
+        }
+        let bin = (file.confidence * HISTOGRAM_BINS as f64) as usize;
+        histogram[bin.min(HISTOGRAM_BINS - 1)] += 1;
+    }
+
+    let detected = per_key.values().sum();
+    let verified = files.len() - errors;
+    Statistics {
+        files: files.len(),
+        detected,
+        errors,
+        detection_rate: if verified == 0 {
+            0.0
+        } else {
+            detected as f64 / verified as f64
+        },
+        per_key,
+        histogram,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use lf_watermark::test_utils::grainy;
+
+    use super::*;
+
+    fn key(id: &str, secret: &str) -> KeyFile {
+        KeyFile {
+            id: id.into(),
+            secret: secret.into(),
+            payload_len: 8,
+            compress: false,
+        }
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let root = std::env::temp_dir().join(format!("lf-watermark-audit-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let image = image::DynamicImage::ImageRgb8(grainy(320, 240, 41));
+        let keyring = [key("a", "0123456789abcdef"), key("b", "fedcba9876543210")];
+
+        let paths: Vec<PathBuf> = ["a.png", "b.png", "plain.png", "broken.png"]
+            .iter()
+            .map(|name| root.join(name))
+            .collect();
+        mark::embed(&image, "One", &keyring[0])
+            .unwrap()
+            .save(&paths[0])
+            .unwrap();
+        mark::embed(&image, "Two", &keyring[1])
+            .unwrap()
+            .save(&paths[1])
+            .unwrap();
+        image.save(&paths[2]).unwrap();
+        fs::write(&paths[3], b"not an image").unwrap();
+
+        let report = verify_batch(&paths, &keyring, 2);
+        fs::remove_dir_all(&root).unwrap();
+        let report = report.unwrap();
+
+        let found: Vec<(Option<&str>, Option<&str>)> = report
+            .files
+            .iter()
+            .map(|file| (file.key_id.as_deref(), file.payload.as_deref()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (Some("a"), Some("One")),
+                (Some("b"), Some("Two")),
+                (None, None),
+                (None, None)
+            ]
+        );
+        assert!(report.files[3].error.is_some());
+
+        let statistics = &report.statistics;
+        assert_eq!(
+            (statistics.files, statistics.detected, statistics.errors),
+            (4, 2, 1)
+        );
+        assert!((statistics.detection_rate - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!(statistics.per_key.get("a"), Some(&1));
+        assert_eq!(statistics.histogram.iter().sum::<usize>(), 3);
+        assert_eq!(statistics.histogram[HISTOGRAM_BINS - 1], 2);
+        assert!(verify_batch(&paths, &[], 1).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! Batch marking of many files on a rayon thread pool.
+//!
+//! Each input is marked into the output directory under its own file name. Inputs whose output
+//! already exists are skipped, so an interrupted run of thousands of files can be restarted, and
+//! a failing file does not stop the others: every file ends up in the [`Summary`] with its reason.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use image::ImageFormat;
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use crate::key::KeyFile;
+use crate::{mark, Result};
+
+/// Settings of a batch run.
+pub struct Options {
+    pub output: PathBuf,
+    pub payload: String,
+    pub key: KeyFile,
+    /// Number of worker threads.
+    pub jobs: usize,
+    /// Whether to replace existing outputs instead of skipping their inputs.
+    pub overwrite: bool,
+}
+
+/// Tally of a batch run, growing while it runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub succeeded: usize,
+    /// Inputs that could not be marked, with the error.
+    pub failed: Vec<(PathBuf, String)>,
+    /// Inputs that were left alone, with the reason.
+    pub skipped: Vec<(PathBuf, String)>,
+}
+
+impl Summary {
+    /// Number of inputs handled so far.
+    pub fn done(&self) -> usize {
+        self.succeeded + self.failed.len() + self.skipped.len()
+    }
+
+    /// One line of progress towards `total` inputs, with a bar `width` characters wide.
+    pub fn progress(&self, total: usize, width: usize) -> String {
+        let filled = (self.done() * width).checked_div(total).unwrap_or(width);
+        format!(
+            "[{}{}] {}/{} succeeded {}, failed {}, skipped {}",
+            "#".repeat(filled),
+            " ".repeat(width - filled),
+            self.done(),
+            total,
+            self.succeeded,
+            self.failed.len(),
+            self.skipped.len()
+        )
+    }
+}
+
+/// Lists the files of `inputs`, replacing each directory by the files directly in it.
+pub fn files(inputs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for input in inputs {
+        if input.is_dir() {
+            let mut entries = Vec::new();
+            for entry in fs::read_dir(input)
+                .map_err(|e| format!("Cannot list {}; {}", input.display(), e))?
+            {
+                let path = entry?.path();
+                if path.is_file() {
+                    entries.push(path);
+                }
+            }
+            entries.sort();
+            files.extend(entries);
+        } else {
+            files.push(input.clone());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Marks every file of `files` as `options` say, calling `progress` after each one.
+pub fn run(
+    files: &[PathBuf],
+    options: &Options,
+    progress: impl Fn(&Summary) + Sync,
+) -> Result<Summary> {
+    fs::create_dir_all(&options.output)
+        .map_err(|e| format!("Cannot create {}; {}", options.output.display(), e))?;
+
+    let summary = Mutex::new(Summary::default());
+    let mut claimed = HashSet::new();
+    let mut work = Vec::new();
+    for file in files {
+        let name = file.file_name().map(|name| name.to_os_string());
+        match name {
+            Some(name) if !claimed.insert(name.clone()) => skip(
+                &summary,
+                file,
+                "another input has the same name".into(),
+                &progress,
+            ),
+            Some(name) => work.push((file, options.output.join(name))),
+            None => skip(&summary, file, "not a file".into(), &progress),
+        }
+    }
+
+    ThreadPoolBuilder::new()
+        .num_threads(options.jobs)
+        .build()?
+        .install(|| {
+            work.par_iter().for_each(|(file, target)| {
+                let status = process(file, target, options);
+                let mut summary = summary.lock().unwrap();
+                match status {
+                    Status::Marked => summary.succeeded += 1,
+                    Status::Failed(reason) => summary.failed.push((file.to_path_buf(), reason)),
+                    Status::Skipped(reason) => summary.skipped.push((file.to_path_buf(), reason)),
+                }
+                progress(&summary);
+            })
+        });
+
+    let mut summary = summary.into_inner().unwrap();
+    summary.failed.sort();
+    summary.skipped.sort();
+    Ok(summary)
+}
+
+enum Status {
+    Marked,
+    Failed(String),
+    Skipped(String),
+}
+
+fn process(file: &Path, target: &Path, options: &Options) -> Status {
+    if !options.overwrite && target.exists() {
+        return Status::Skipped(format!("{} exists", target.display()));
+    }
+    if ImageFormat::from_path(file).is_err() {
+        return Status::Skipped("not an image file name".into());
+    }
+
+    let result = image::open(file)
+        .map_err(|e| e.into())
+        .and_then(|image| mark::embed(&image, &options.payload, &options.key))
+        .and_then(|marked| Ok(marked.save(target)?));
+    match result {
+        Ok(()) => Status::Marked,
+        Err(e) => Status::Failed(e.to_string()),
+    }
+}
+
+fn skip(summary: &Mutex<Summary>, file: &Path, reason: String, progress: &impl Fn(&Summary)) {
+    let mut summary = summary.lock().unwrap();
+    summary.skipped.push((file.to_path_buf(), reason));
+    progress(&summary);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let root = std::env::temp_dir().join(format!("lf-watermark-batch-{}", std::process::id()));
+        let (input, output) = (root.join("in"), root.join("out"));
+        fs::create_dir_all(input.join("nested")).unwrap();
+        fs::create_dir_all(&output).unwrap();
+
+        let image = RgbImage::from_fn(320, 240, |x, y| Rgb([x as u8, y as u8, 96]));
+        for name in ["a.png", "b.png", "done.png"] {
+            image.save(input.join(name)).unwrap();
+        }
+        fs::write(input.join("broken.png"), b"not an image").unwrap();
+        fs::write(input.join("notes.txt"), b"not an image either").unwrap();
+        fs::copy(input.join("done.png"), output.join("done.png")).unwrap();
+
+        let options = Options {
+            output: output.clone(),
+            payload: "Press".into(),
+            key: KeyFile {
+                id: "test".into(),
+                secret: "0123456789abcdef".into(),
+                payload_len: 8,
+                compress: false,
+            },
+            jobs: 2,
+            overwrite: false,
+        };
+        let inputs = [input.clone(), input.join("a.png"), input.join("nested")];
+        let files = files(&inputs).unwrap();
+        let calls = AtomicUsize::new(0);
+        let summary = run(&files, &options, |_| {
+            calls.fetch_add(1, Ordering::Relaxed);
+        })
+        .unwrap();
+        let marked = image::open(output.join("b.png")).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(files.len(), 6);
+        assert_eq!(calls.into_inner(), 6);
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, input.join("broken.png"));
+        let skipped: Vec<&Path> = summary.skipped.iter().map(|(p, _)| p.as_path()).collect();
+        assert_eq!(
+            skipped,
+            [
+                input.join("a.png"),
+                input.join("done.png"),
+                input.join("notes.txt")
+            ]
+        );
+        assert!(mark::verify(&marked, &options.key, None).unwrap().detected);
+        assert_eq!(
+            summary.progress(12, 4),
+            "[##  ] 6/12 succeeded 2, failed 1, skipped 3"
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! Quality comparison of two images, usually an original and its marked copy.
+
+use image::DynamicImage;
+use lf_watermark::metrics::{perceptual_distance, psnr, ssim_map};
+use serde::Serialize;
+
+use crate::Result;
+
+/// Side of the blocks SSIM is computed over, the window size commonly used for it.
+const SSIM_BLOCK: u32 = 8;
+
+/// Metrics of [`compare`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Comparison {
+    /// Peak signal-to-noise ratio in dB; infinite for identical images, `null` in JSON.
+    pub psnr: f64,
+    /// Mean SSIM of the luma plane over 8x8 blocks, 1 for identical luma.
+    pub ssim: f64,
+    /// Perceptual distance in just-noticeable differences; around 1 is at the edge of visibility.
+    pub perceptual_distance: f64,
+}
+
+/// Compares `a` against `b`, which must have the same dimensions.
+pub fn compare(a: &DynamicImage, b: &DynamicImage) -> Result<Comparison> {
+    Ok(Comparison {
+        psnr: psnr(a, b)?,
+        ssim: ssim_map(a, b, SSIM_BLOCK)?.mean(),
+        perceptual_distance: perceptual_distance(a, b)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let a = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(x * 4) as u8, (y * 5) as u8, 96])
+        }));
+        let b = a.brighten(2);
+
+        let same = compare(&a, &a).unwrap();
+        assert!(same.psnr.is_infinite());
+        assert!((same.ssim - 1.0).abs() < 1e-9);
+        assert_eq!(same.perceptual_distance, 0.0);
+
+        let near = compare(&a, &b).unwrap();
+        assert!(near.psnr > 40.0 && near.psnr.is_finite(), "{:?}", near);
+        assert!(near.ssim < 1.0 && near.ssim > 0.9, "{:?}", near);
+        assert!(near.perceptual_distance > 0.0, "{:?}", near);
+
+        assert!(compare(&a, &a.crop_imm(0, 0, 32, 32)).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+//! Signed forensic reports for takedown submissions.
+//!
+//! A report bundles the suspect image itself, its digest, the verification result and the
+//! parameters it was obtained with, and with an original at hand the quality metrics and an SSIM
+//! heatmap of where the two differ. It is one JSON object:
+//!
+//! ```json
+//! { "report": { "format": 1, "tool": "...", "suspect": { ... }, ... },
+//!   "signature": { "algorithm": "HMAC-SHA256", "key_id": "press-2026", "value": "..." } }
+//! ```
+//!
+//! The signature is an HMAC over the exact bytes of the `report` value, keyed with the key's
+//! secret, so anyone holding the key file can confirm with [`check`] that the report was made with
+//! that key and not edited since.
+
+use std::io::Cursor;
+
+use image::{DynamicImage, GenericImageView, ImageFormat};
+use lf_watermark::metrics::ssim_map;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::{Digest, Sha256};
+
+use crate::compare::{compare, Comparison};
+use crate::key::KeyFile;
+use crate::mark::{self, Verification};
+use crate::Result;
+
+/// Version of the report layout.
+pub const REPORT_FORMAT: u32 = 1;
+/// Block size of the SSIM heatmap.
+const HEATMAP_BLOCK: u32 = 16;
+
+/// An image as it was submitted.
+#[derive(Debug, Clone, Serialize)]
+pub struct Evidence {
+    pub name: String,
+    pub sha256: String,
+    pub width: u32,
+    pub height: u32,
+    pub format: String,
+    /// The file's bytes in base64.
+    pub data: String,
+}
+
+/// How the mark was read, so the verification can be repeated.
+#[derive(Debug, Clone, Serialize)]
+pub struct Parameters {
+    pub scheme: &'static str,
+    pub step: f32,
+    pub alpha: f32,
+    pub repetition: usize,
+    pub band: String,
+    pub payload_len: usize,
+    pub check_len: usize,
+}
+
+/// Comparison against the original.
+#[derive(Debug, Clone, Serialize)]
+pub struct OriginalComparison {
+    pub sha256: String,
+    pub metrics: Comparison,
+    /// PNG of the SSIM per 16x16 block in base64, blue where untouched and red where changed most.
+    pub heatmap: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Body {
+    format: u32,
+    tool: String,
+    /// Seconds since the Unix epoch.
+    created: u64,
+    suspect: Evidence,
+    verification: Verification,
+    parameters: Parameters,
+    original: Option<OriginalComparison>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Signature {
+    algorithm: String,
+    key_id: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Signed<'a> {
+    #[serde(borrow)]
+    report: &'a RawValue,
+    signature: Signature,
+}
+
+/// Verifies the suspect image file `suspect` named `name` under `key` and returns the signed
+/// report, created at `created` seconds since the Unix epoch. `expected` is the payload to
+/// measure the bit error rate against, and `original` the bytes of the original image.
+pub fn report(
+    suspect: &[u8],
+    name: &str,
+    key: &KeyFile,
+    expected: Option<&str>,
+    original: Option<&[u8]>,
+    created: u64,
+) -> Result<String> {
+    let format = image::guess_format(suspect)?;
+    let image = image::load_from_memory_with_format(suspect, format)?;
+    let (width, height) = image.dimensions();
+    let config = mark::scs_config();
+
+    let original = match original {
+        Some(bytes) => Some(compare_original(&image, bytes)?),
+        None => None,
+    };
+    let body = Body {
+        format: REPORT_FORMAT,
+        tool: format!("lf-watermark-cli {}", env!("CARGO_PKG_VERSION")),
+        created,
+        suspect: Evidence {
+            name: name.to_string(),
+            sha256: hex(&Sha256::digest(suspect)),
+            width,
+            height,
+            format: format!("{:?}", format),
+            data: base64(suspect),
+        },
+        verification: mark::verify(&image, key, expected)?,
+        parameters: Parameters {
+            scheme: "scs-lattice",
+            step: config.step,
+            alpha: config.alpha,
+            repetition: config.repetition,
+            band: format!("{:?}", config.band),
+            payload_len: key.payload_len,
+            check_len: mark::CHECK_LEN,
+        },
+        original,
+    };
+
+    let body = serde_json::to_string(&body)?;
+    let signature = Signature {
+        algorithm: "HMAC-SHA256".into(),
+        key_id: key.id.clone(),
+        value: hex(&hmac_sha256(&key.secret_bytes()?, body.as_bytes())),
+    };
+
+    Ok(format!(
+        "{{\"report\":{},\"signature\":{}}}",
+        body,
+        serde_json::to_string(&signature)?
+    ))
+}
+
+/// Whether `report` carries a valid signature under `key`.
+pub fn check(report: &str, key: &KeyFile) -> Result<bool> {
+    let signed: Signed = serde_json::from_str(report)?;
+    if signed.signature.algorithm != "HMAC-SHA256" {
+        return Err(format!("Unknown signature algorithm {}", signed.signature.algorithm).into());
+    }
+    if signed.signature.key_id != key.id {
+        return Err(format!(
+            "The report is signed with key {}, not {}",
+            signed.signature.key_id, key.id
+        )
+        .into());
+    }
+
+    let expected = hex(&hmac_sha256(
+        &key.secret_bytes()?,
+        signed.report.get().as_bytes(),
+    ));
+    Ok(expected == signed.signature.value.to_ascii_lowercase())
+}
+
+fn compare_original(suspect: &DynamicImage, bytes: &[u8]) -> Result<OriginalComparison> {
+    let original = image::load_from_memory(bytes)?;
+    let mut heatmap = Cursor::new(Vec::new());
+    ssim_map(&original, suspect, HEATMAP_BLOCK)?
+        .heatmap()
+        .write_to(&mut heatmap, ImageFormat::Png)?;
+
+    Ok(OriginalComparison {
+        sha256: hex(&Sha256::digest(bytes)),
+        metrics: compare(&original, suspect)?,
+        heatmap: base64(heatmap.get_ref()),
+    })
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    const BLOCK: usize = 64;
+
+    let mut block = [0; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.map(|b| b ^ byte);
+
+    let inner = Sha256::new()
+        .chain_update(pad(0x36))
+        .chain_update(message)
+        .finalize();
+    Sha256::new()
+        .chain_update(pad(0x5c))
+        .chain_update(inner)
+        .finalize()
+        .into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use lf_watermark::test_utils::grainy;
+
+    use super::*;
+
+    #[test]
+    fn test_primitives() {
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+        assert_eq!(base64(b""), "");
+        // RFC 4231, test case 2.
+        assert_eq!(
+            hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        let key = KeyFile {
+            id: "test".into(),
+            secret: "0123456789abcdef".into(),
+            payload_len: 8,
+            compress: false,
+        };
+        let image = DynamicImage::ImageRgb8(grainy(320, 240, 41));
+        let png = |image: &DynamicImage| {
+            let mut bytes = Cursor::new(Vec::new());
+            image.write_to(&mut bytes, ImageFormat::Png).unwrap();
+            bytes.into_inner()
+        };
+        let original = png(&image);
+        let suspect = png(&DynamicImage::ImageRgb8(
+            mark::embed(&image, "Press", &key).unwrap(),
+        ));
+
+        let report = report(
+            &suspect,
+            "leak.png",
+            &key,
+            None,
+            Some(&original),
+            1_700_000_000,
+        )
+        .unwrap();
+        assert!(check(&report, &key).unwrap());
+
+        let value: serde_json::Value = serde_json::from_str(&report).unwrap();
+        let body = &value["report"];
+        assert_eq!(body["verification"]["detected"], true);
+        assert_eq!(body["verification"]["payload"], "Press");
+        assert_eq!(body["suspect"]["data"], base64(&suspect));
+        assert_eq!(body["suspect"]["format"], "Png");
+        assert_eq!(body["parameters"]["repetition"], 32);
+        assert!(body["original"]["metrics"]["psnr"].as_f64().unwrap() > 30.0);
+        assert_eq!(value["signature"]["key_id"], "test");
+
+        let edited = report.replace("\"Press\"", "\"Other\"");
+        assert!(!check(&edited, &key).unwrap());
+        let other = KeyFile {
+            secret: "fedcba9876543210".into(),
+            ..key.clone()
+        };
+        assert!(!check(&report, &other).unwrap());
+        assert!(check("{}", &key).is_err());
+    }
+}
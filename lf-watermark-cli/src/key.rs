@@ -0,0 +1,214 @@
+//! Key files.
+//!
+//! A key file is a JSON object naming the key, holding its secret in hex and fixing the payload
+//! length in bytes, which the verifier needs to know how many bits to read:
+//!
+//! ```json
+//! { "id": "press-2026", "secret": "9f86d081884c7d65...", "payload_len": 16 }
+//! ```
+//!
+//! With `"compress": true` payloads are compressed before framing, see
+//! [`lf_watermark::compress`], so `payload_len` fits longer copyright lines, URLs and UUIDs. The
+//! field is left out when false, and key files without it read as uncompressed.
+//!
+//! `lf-watermark key generate` makes one with a random secret. Since anyone holding the secret can
+//! forge marks, key files are written readable by their owner only.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Length of generated secrets in bytes.
+pub const SECRET_LEN: usize = 32;
+/// Longest payload a key may fix, in bytes. Already more than the low band of a large photo can
+/// carry at the CLI's repetition, so the limit only keeps bit counts in range.
+pub const MAX_PAYLOAD_LEN: usize = 1 << 16;
+
+/// A key and the payload length marks made with it carry.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyFile {
+    /// Name of the key, reported with every verification.
+    pub id: String,
+    /// Secret bytes, hex encoded.
+    pub secret: String,
+    /// Payload length in bytes; shorter payloads are padded with zeros.
+    pub payload_len: usize,
+    /// Whether payloads are compressed, in which case `payload_len` bounds the compressed size.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compress: bool,
+}
+
+impl KeyFile {
+    /// A new key named `id` with a random secret of [`SECRET_LEN`] bytes.
+    pub fn generate(id: &str, payload_len: usize, compress: bool) -> Result<Self> {
+        if id.is_empty() {
+            return Err("The key id must not be empty".into());
+        }
+        check_payload_len(payload_len)?;
+        let mut secret = [0; SECRET_LEN];
+        getrandom::fill(&mut secret).map_err(|e| format!("Cannot generate a secret; {}", e))?;
+
+        Ok(KeyFile {
+            id: id.to_string(),
+            secret: secret.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            payload_len,
+            compress,
+        })
+    }
+
+    /// Reads and validates the key file at `path`.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read key file {}; {}", path.display(), e))?;
+        Self::parse(&text).map_err(|e| format!("Invalid key file {}; {}", path.display(), e).into())
+    }
+
+    /// Parses and validates the JSON of a key file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let key: KeyFile = serde_json::from_str(text)?;
+        key.secret_bytes()?;
+        check_payload_len(key.payload_len)?;
+
+        Ok(key)
+    }
+
+    /// Writes the key to a new file at `path`, readable by its owner only where the platform
+    /// supports it. An existing file is never replaced.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options
+            .open(path)
+            .map_err(|e| format!("Cannot create key file {}; {}", path.display(), e))?;
+        writeln!(file, "{}", self.to_json()?)?;
+        Ok(())
+    }
+
+    /// The key file's JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// The decoded secret.
+    pub fn secret_bytes(&self) -> Result<Vec<u8>> {
+        let secret = self.secret.as_bytes();
+        if secret.is_empty() || !secret.len().is_multiple_of(2) {
+            return Err("The key secret must be a non-empty, even-length hex string".into());
+        }
+
+        secret
+            .chunks_exact(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(|| "The key secret is not hex".into())
+            })
+            .collect()
+    }
+}
+
+fn check_payload_len(payload_len: usize) -> Result<()> {
+    if payload_len == 0 {
+        return Err("payload_len is 0".into());
+    }
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(format!(
+            "payload_len {} is over the limit of {} bytes",
+            payload_len, MAX_PAYLOAD_LEN
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_bytes() {
+        let key = KeyFile {
+            id: "k".into(),
+            secret: "00ff7A".into(),
+            payload_len: 8,
+            compress: false,
+        };
+        assert_eq!(key.secret_bytes().unwrap(), vec![0x00, 0xff, 0x7a]);
+
+        for secret in ["", "abc", "zz"] {
+            let key = KeyFile {
+                secret: secret.into(),
+                ..key.clone()
+            };
+            assert!(key.secret_bytes().is_err());
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let path =
+            std::env::temp_dir().join(format!("lf-watermark-key-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"id": "k", "secret": "0102", "payload_len": 4}"#).unwrap();
+        let key = KeyFile::load(&path);
+        std::fs::write(&path, r#"{"id": "k", "secret": "0102", "payload_len": 0}"#).unwrap();
+        let empty = KeyFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let key = key.unwrap();
+        assert_eq!((key.payload_len, key.compress), (4, false));
+        assert!(!key.to_json().unwrap().contains("compress"));
+        assert!(empty.is_err());
+        assert!(KeyFile::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_huge_payload_len() {
+        let json =
+            |len: usize| format!(r#"{{"id": "k", "secret": "0102", "payload_len": {}}}"#, len);
+        assert!(KeyFile::parse(&json(MAX_PAYLOAD_LEN)).is_ok());
+        for len in [MAX_PAYLOAD_LEN + 1, usize::MAX / 8, usize::MAX] {
+            assert!(KeyFile::parse(&json(len)).is_err(), "{}", len);
+        }
+        assert!(KeyFile::generate("k", usize::MAX, false).is_err());
+    }
+
+    #[test]
+    fn test_generate() {
+        let key = KeyFile::generate("press", 16, false).unwrap();
+        assert_eq!(key.secret_bytes().unwrap().len(), SECRET_LEN);
+        assert_ne!(
+            key.secret,
+            KeyFile::generate("press", 16, false).unwrap().secret
+        );
+        assert!(KeyFile::generate("", 16, false).is_err());
+        assert!(KeyFile::generate("press", 0, false).is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("lf-watermark-new-key-{}.json", std::process::id()));
+        key.save(&path).unwrap();
+        let loaded = KeyFile::load(&path);
+        let again = key.save(&path);
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(&path.metadata().unwrap().permissions());
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.unwrap(), key);
+        let compressed = KeyFile::generate("press", 16, true).unwrap();
+        assert_eq!(
+            KeyFile::parse(&compressed.to_json().unwrap()).unwrap(),
+            compressed
+        );
+        assert!(again.is_err());
+        #[cfg(unix)]
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(KeyFile::parse(&key.to_json().unwrap()).unwrap(), key);
+    }
+}
@@ -0,0 +1,911 @@
+//! Command-line interface of lf-watermark.
+//!
+//! `lf-watermark embed` marks an image with a payload under a key file, and `lf-watermark verify`
+//! reads the mark back. The exit status of `verify` reflects detection, and `--json` prints the
+//! result as one JSON object, so both fit into shell pipelines and takedown tooling. Images can
+//! be piped through standard input and output as `-`.
+//! `lf-watermark watch` marks every image that appears in a hot folder, and
+//! `lf-watermark attack-test` shows which attacks a mark survives, and `lf-watermark compare`
+//! measures how visible it is. `lf-watermark batch` marks many files in parallel,
+//! `lf-watermark key` creates and moves key files, and `lf-watermark remark` migrates a mark to a
+//! new payload or key. `lf-watermark audit` verifies a corpus under a key ring and prints its
+//! statistics, and `lf-watermark report` writes signed forensic reports.
+
+use std::error::Error;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+
+use crate::attack::Registry;
+use crate::key::KeyFile;
+
+mod attack;
+mod audit;
+mod batch;
+mod compare;
+mod forensics;
+mod key;
+mod mark;
+mod pipe;
+mod watch;
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+/// Exit status of `verify` when the image carries no mark of the key, and of `attack-test` when
+/// an attack breaks the mark.
+const EXIT_NOT_DETECTED: u8 = 1;
+/// Secret of the key `attack-test` uses when none is given. Only for trials.
+const TRIAL_SECRET: &str = "6c662d77617465726d61726b2d747269616c";
+/// Attacks `attack-test` runs when none are given.
+const DEFAULT_ATTACKS: &str = "jpeg:90,jpeg:75,scale:0.5,crop:0.8,blur:1,noise:4,brightness:10";
+
+/// Exit status of a failure, the same as clap uses for usage errors.
+const EXIT_ERROR: u8 = 2;
+
+fn cli() -> Command {
+    let key_file = Arg::new("key-file")
+        .long("key-file")
+        .value_name("FILE")
+        .value_parser(value_parser!(PathBuf))
+        .required(true)
+        .help("JSON key file with the id, hex secret and payload length");
+
+    Command::new("lf-watermark")
+        .about("Embeds and verifies low frequency watermarks")
+        .version(env!("CARGO_PKG_VERSION"))
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("embed")
+                .about("Marks an image with a payload")
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Input image, - for standard input"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value(pipe::STDIO)
+                        .help("Output image, - for standard output; the format follows the extension"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(pipe::parse_format)
+                        .help("Output format, such as png or jpg [default: the extension, or the input's on standard output]"),
+                )
+                .arg(
+                    Arg::new("payload")
+                        .long("payload")
+                        .required(true)
+                        .help("Text to embed, at most the key's payload length in bytes"),
+                )
+                .arg(key_file.clone()),
+        )
+        .subcommand(
+            Command::new("remark")
+                .about("Replaces the mark of an old key by a new payload or key")
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Marked image, - for standard input"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .value_parser(value_parser!(PathBuf))
+                        .default_value(pipe::STDIO)
+                        .help("Output image, - for standard output; the format follows the extension"),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_parser(pipe::parse_format)
+                        .help("Output format, such as png or jpg [default: the extension, or the input's on standard output]"),
+                )
+                .arg(
+                    Arg::new("payload")
+                        .long("payload")
+                        .required(true)
+                        .help("New text to embed"),
+                )
+                .arg(
+                    key_file
+                        .clone()
+                        .id("old-key-file")
+                        .long("old-key-file")
+                        .help("Key file of the mark to replace"),
+                )
+                .arg(
+                    key_file
+                        .clone()
+                        .help("Key file of the new mark; may be the old one"),
+                )
+                .arg(
+                    Arg::new("layer")
+                        .long("layer")
+                        .action(ArgAction::SetTrue)
+                        .help("Keep the old mark readable where the new one leaves it, instead of erasing it"),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Reads the mark of a key from an image; exits with 1 if there is none")
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Input image, - for standard input"),
+                )
+                .arg(key_file.clone())
+                .arg(
+                    Arg::new("payload")
+                        .long("payload")
+                        .help("Expected payload, to measure the bit error rate against"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the result as a JSON object"),
+                ),
+        )
+        .subcommand(
+            Command::new("attack-test")
+                .about("Marks an image and checks the mark after each attack; exits with 1 if any breaks it")
+                .arg(
+                    Arg::new("input")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("payload")
+                        .long("payload")
+                        .required(true)
+                        .help("Text to embed"),
+                )
+                .arg(
+                    Arg::new("attacks")
+                        .long("attacks")
+                        .value_name("LIST")
+                        .value_delimiter(',')
+                        .default_value(DEFAULT_ATTACKS)
+                        .help("Comma-separated attacks: jpeg:Q, scale:F, crop:F, blur:SIGMA, noise:LEVELS, brightness:OFFSET, or a plugin as NAME or NAME:PARAMETER"),
+                )
+                .arg(
+                    Arg::new("plugin")
+                        .long("plugin")
+                        .value_name("NAME=COMMAND")
+                        .action(ArgAction::Append)
+                        .help("Adds the attack NAME, run as COMMAND with the image as PNG on stdin and the attacked image on stdout; a parameter is passed as the last argument"),
+                )
+                .arg(
+                    key_file
+                        .clone()
+                        .required(false)
+                        .help("Key file to mark with [default: a built-in trial key]"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the table as a JSON array"),
+                ),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Creates and checks signed forensic reports for takedown submissions")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Verifies a suspect image and writes a signed report")
+                        .arg(
+                            Arg::new("input")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .help("Suspect image"),
+                        )
+                        .arg(key_file.clone())
+                        .arg(
+                            Arg::new("payload")
+                                .long("payload")
+                                .help("Expected payload, to measure the bit error rate against"),
+                        )
+                        .arg(
+                            Arg::new("original")
+                                .long("original")
+                                .value_name("FILE")
+                                .value_parser(value_parser!(PathBuf))
+                                .help("Original image, for quality metrics and an SSIM heatmap"),
+                        )
+                        .arg(
+                            Arg::new("out")
+                                .long("out")
+                                .value_name("FILE")
+                                .value_parser(value_parser!(PathBuf))
+                                .help("Report file to write [default: print to standard output]"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("check")
+                        .about("Checks a report's signature; exits with 1 if it is invalid")
+                        .arg(
+                            Arg::new("report")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf)),
+                        )
+                        .arg(key_file.clone()),
+                ),
+        )
+        .subcommand(
+            Command::new("audit")
+                .about("Verifies many images under a key ring and prints corpus statistics")
+                .arg(
+                    Arg::new("inputs")
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Images, or directories whose files to verify"),
+                )
+                .arg(
+                    key_file
+                        .clone()
+                        .action(ArgAction::Append)
+                        .help("Key file of the key ring; repeat for more keys"),
+                )
+                .arg(
+                    Arg::new("jobs")
+                        .long("jobs")
+                        .short('j')
+                        .value_name("N")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help("Number of images verified at once [default: one per core]"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the results and statistics as a JSON object"),
+                ),
+        )
+        .subcommand(
+            Command::new("key")
+                .about("Generates, exports and imports key files")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("generate")
+                        .about("Creates a key with a random secret")
+                        .arg(
+                            Arg::new("id")
+                                .long("id")
+                                .required(true)
+                                .help("Name of the key, reported with every verification"),
+                        )
+                        .arg(
+                            Arg::new("payload-len")
+                                .long("payload-len")
+                                .value_name("BYTES")
+                                .value_parser(value_parser!(u64).range(1..))
+                                .default_value("16")
+                                .help("Payload length marks made with the key carry"),
+                        )
+                        .arg(
+                            Arg::new("compress")
+                                .long("compress")
+                                .action(ArgAction::SetTrue)
+                                .help("Compress payloads, so the payload length fits longer URLs, UUIDs and copyright lines"),
+                        )
+                        .arg(
+                            Arg::new("out")
+                                .long("out")
+                                .value_name("FILE")
+                                .value_parser(value_parser!(PathBuf))
+                                .help("New key file to write [default: print to standard output]"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("export")
+                        .about("Validates a key file and prints it as one line of JSON")
+                        .arg(
+                            Arg::new("key-file")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Validates exported key JSON and writes it to a new key file")
+                        .arg(
+                            Arg::new("input")
+                                .value_parser(value_parser!(PathBuf))
+                                .default_value(pipe::STDIO)
+                                .help("Exported key JSON, - for standard input"),
+                        )
+                        .arg(
+                            Arg::new("out")
+                                .long("out")
+                                .value_name("FILE")
+                                .required(true)
+                                .value_parser(value_parser!(PathBuf))
+                                .help("New key file to write"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("batch")
+                .about("Marks many images in parallel into a directory")
+                .arg(
+                    Arg::new("inputs")
+                        .required(true)
+                        .num_args(1..)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Images, or directories whose files to mark"),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("DIR")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory for the marked images, under the same names"),
+                )
+                .arg(
+                    Arg::new("payload")
+                        .long("payload")
+                        .required(true)
+                        .help("Text to embed, at most the key's payload length in bytes"),
+                )
+                .arg(key_file.clone())
+                .arg(
+                    Arg::new("jobs")
+                        .long("jobs")
+                        .short('j')
+                        .value_name("N")
+                        .value_parser(value_parser!(u64).range(1..))
+                        .help("Number of images marked at once [default: one per core]"),
+                )
+                .arg(
+                    Arg::new("overwrite")
+                        .long("overwrite")
+                        .action(ArgAction::SetTrue)
+                        .help("Replace existing outputs instead of skipping their inputs"),
+                ),
+        )
+        .subcommand(
+            Command::new("compare")
+                .about("Reports PSNR, SSIM and perceptual distance between two images")
+                .arg(
+                    Arg::new("a")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("b")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("diff")
+                        .long("diff")
+                        .value_name("FILE")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Also write the amplified difference to this image"),
+                )
+                .arg(
+                    Arg::new("gain")
+                        .long("gain")
+                        .value_parser(value_parser!(f32))
+                        .default_value("10")
+                        .help("Amplification of the difference image"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the metrics as a JSON object"),
+                ),
+        )
+        .subcommand(
+            Command::new("watch")
+                .about("Marks every image that appears in a directory, until stopped")
+                .arg(
+                    Arg::new("dir")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    Arg::new("out")
+                        .long("out")
+                        .value_name("DIR")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory for the marked images, under the same names"),
+                )
+                .arg(
+                    Arg::new("quarantine")
+                        .long("quarantine")
+                        .value_name("DIR")
+                        .value_parser(value_parser!(PathBuf))
+                        .help("Directory failed files are moved to [default: <out>/quarantine]"),
+                )
+                .arg(
+                    Arg::new("payload")
+                        .long("payload")
+                        .required(true)
+                        .help("Text to embed, at most the key's payload length in bytes"),
+                )
+                .arg(
+                    Arg::new("settle-ms")
+                        .long("settle-ms")
+                        .value_parser(value_parser!(u64))
+                        .default_value("2000")
+                        .help("How long a file must stay unchanged before it is read"),
+                )
+                .arg(key_file)
+                .arg(
+                    Arg::new("interval-ms")
+                        .long("interval-ms")
+                        .value_parser(value_parser!(u64))
+                        .default_value("500")
+                        .help("Time between scans of the directory"),
+                ),
+        )
+}
+
+fn main() -> ExitCode {
+    match run(&cli().get_matches()) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<ExitCode> {
+    match matches.subcommand() {
+        Some(("embed", matches)) => embed(matches),
+        Some(("remark", matches)) => remark(matches),
+        Some(("verify", matches)) => verify(matches),
+        Some(("watch", matches)) => watch(matches),
+        Some(("attack-test", matches)) => attack_test(matches),
+        Some(("compare", matches)) => compare(matches),
+        Some(("batch", matches)) => batch(matches),
+        Some(("key", matches)) => key(matches),
+        Some(("audit", matches)) => audit(matches),
+        Some(("report", matches)) => report(matches),
+        _ => unreachable!("a subcommand is required"),
+    }
+}
+
+fn embed(matches: &ArgMatches) -> Result<ExitCode> {
+    let key = KeyFile::load(path(matches, "key-file"))?;
+    let (image, input_format) = read(path(matches, "input"))?;
+    let payload = matches.get_one::<String>("payload").expect("required");
+
+    let marked = mark::embed(&image, payload, &key)?;
+    pipe::write(
+        &marked,
+        path(matches, "output"),
+        matches.get_one("format").copied(),
+        input_format,
+    )?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn remark(matches: &ArgMatches) -> Result<ExitCode> {
+    let old_key = KeyFile::load(path(matches, "old-key-file"))?;
+    let new_key = KeyFile::load(path(matches, "key-file"))?;
+    let (image, input_format) = read(path(matches, "input"))?;
+    let payload = matches.get_one::<String>("payload").expect("required");
+    let precedence = if matches.get_flag("layer") {
+        mark::Precedence::Layer
+    } else {
+        mark::Precedence::Supersede
+    };
+
+    let (old, marked) = mark::remark(&image, &old_key, payload, &new_key, precedence)?;
+    pipe::write(
+        &marked,
+        path(matches, "output"),
+        matches.get_one("format").copied(),
+        input_format,
+    )?;
+    // Standard output may carry the image.
+    eprintln!(
+        "remarked {} under {} as {} under {}",
+        old.payload, old.key_id, payload, new_key.id
+    );
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn verify(matches: &ArgMatches) -> Result<ExitCode> {
+    let key = KeyFile::load(path(matches, "key-file"))?;
+    let image = open(path(matches, "input"))?;
+    let expected = matches.get_one::<String>("payload").map(String::as_str);
+
+    let verification = mark::verify(&image, &key, expected)?;
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&verification)?);
+    } else {
+        println!("key: {}", verification.key_id);
+        println!(
+            "detected: {}",
+            if verification.detected { "yes" } else { "no" }
+        );
+        if verification.detected {
+            println!("payload: {}", verification.payload);
+        }
+        println!("confidence: {:.4}", verification.confidence);
+        println!(
+            "ber: {:.4} ({})",
+            verification.ber,
+            if verification.ber_measured {
+                "measured"
+            } else {
+                "estimated"
+            }
+        );
+    }
+
+    Ok(if verification.detected {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_NOT_DETECTED)
+    })
+}
+
+fn attack_test(matches: &ArgMatches) -> Result<ExitCode> {
+    let payload = matches.get_one::<String>("payload").expect("required");
+    let key = match matches.get_one::<PathBuf>("key-file") {
+        Some(path) => KeyFile::load(path)?,
+        None => KeyFile {
+            id: "trial".into(),
+            secret: TRIAL_SECRET.into(),
+            payload_len: payload.len().max(1),
+            compress: false,
+        },
+    };
+    let image = open(path(matches, "input"))?;
+    let mut registry = Registry::default();
+    for plugin in matches.get_many::<String>("plugin").into_iter().flatten() {
+        let (name, command) = plugin
+            .split_once('=')
+            .ok_or_else(|| format!("Plugin {} is not NAME=COMMAND", plugin))?;
+        registry.register_command(name, command)?;
+    }
+    let attacks = matches
+        .get_many::<String>("attacks")
+        .expect("default")
+        .map(|spec| registry.parse(spec))
+        .collect::<Result<Vec<_>>>()?;
+
+    let rows = attack::run(&image, payload, &key, &attacks)?;
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&rows)?);
+    } else {
+        println!(
+            "{:<16} {:<9} {:<8} {:>7} {:>11}",
+            "attack", "detected", "payload", "ber", "confidence"
+        );
+        for row in &rows {
+            println!(
+                "{:<16} {:<9} {:<8} {:>7.4} {:>11.4}",
+                row.attack,
+                if row.detected { "yes" } else { "no" },
+                if row.payload_intact { "intact" } else { "lost" },
+                row.ber,
+                row.confidence
+            );
+        }
+    }
+
+    Ok(if rows.iter().all(|row| row.payload_intact) {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_NOT_DETECTED)
+    })
+}
+
+fn report(matches: &ArgMatches) -> Result<ExitCode> {
+    let read = |path: &std::path::Path| {
+        std::fs::read(path).map_err(|e| format!("Cannot read {}; {}", path.display(), e))
+    };
+
+    match matches.subcommand() {
+        Some(("create", matches)) => {
+            let key = KeyFile::load(path(matches, "key-file"))?;
+            let input = path(matches, "input");
+            let original = match matches.get_one::<PathBuf>("original") {
+                Some(original) => Some(read(original)?),
+                None => None,
+            };
+            let created = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs();
+            let name = input
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+
+            let report = forensics::report(
+                &read(input)?,
+                &name,
+                &key,
+                matches.get_one::<String>("payload").map(String::as_str),
+                original.as_deref(),
+                created,
+            )?;
+            match matches.get_one::<PathBuf>("out") {
+                Some(out) => std::fs::write(out, report + "\n")?,
+                None => println!("{}", report),
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(("check", matches)) => {
+            let key = KeyFile::load(path(matches, "key-file"))?;
+            let report = String::from_utf8(read(path(matches, "report"))?)?;
+            if forensics::check(&report, &key)? {
+                println!("valid signature of key {}", key.id);
+                Ok(ExitCode::SUCCESS)
+            } else {
+                println!("invalid signature");
+                Ok(ExitCode::from(EXIT_NOT_DETECTED))
+            }
+        }
+        _ => unreachable!("a subcommand is required"),
+    }
+}
+
+fn audit(matches: &ArgMatches) -> Result<ExitCode> {
+    let inputs: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("inputs")
+        .expect("required")
+        .cloned()
+        .collect();
+    let keyring = matches
+        .get_many::<PathBuf>("key-file")
+        .expect("required")
+        .map(|path| KeyFile::load(path))
+        .collect::<Result<Vec<_>>>()?;
+
+    let report = audit::verify_batch(&batch::files(&inputs)?, &keyring, jobs(matches))?;
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&report)?);
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    for file in &report.files {
+        match (&file.error, &file.key_id) {
+            (Some(error), _) => println!("{}: error: {}", file.path.display(), error),
+            (None, Some(key_id)) => println!(
+                "{}: {} {} ({:.4})",
+                file.path.display(),
+                key_id,
+                file.payload.as_deref().unwrap_or_default(),
+                file.confidence
+            ),
+            (None, None) => println!(
+                "{}: not detected ({:.4})",
+                file.path.display(),
+                file.confidence
+            ),
+        }
+    }
+    let statistics = &report.statistics;
+    println!(
+        "files {}, detected {}, errors {}, detection rate {:.1}%",
+        statistics.files,
+        statistics.detected,
+        statistics.errors,
+        statistics.detection_rate * 100.0
+    );
+    for (key_id, count) in &statistics.per_key {
+        println!("key {}: {}", key_id, count);
+    }
+    for (bin, count) in statistics.histogram.iter().enumerate() {
+        let width = 1.0 / audit::HISTOGRAM_BINS as f64;
+        println!(
+            "confidence {:.1}-{:.1}: {}",
+            bin as f64 * width,
+            (bin + 1) as f64 * width,
+            count
+        );
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn key(matches: &ArgMatches) -> Result<ExitCode> {
+    match matches.subcommand() {
+        Some(("generate", matches)) => {
+            let id = matches.get_one::<String>("id").expect("required");
+            let payload_len = *matches.get_one::<u64>("payload-len").expect("default");
+            let key = KeyFile::generate(id, payload_len as usize, matches.get_flag("compress"))?;
+            match matches.get_one::<PathBuf>("out") {
+                Some(out) => key.save(out)?,
+                None => println!("{}", key.to_json()?),
+            }
+        }
+        Some(("export", matches)) => {
+            let key = KeyFile::load(path(matches, "key-file"))?;
+            println!("{}", serde_json::to_string(&key)?);
+        }
+        Some(("import", matches)) => {
+            let input = path(matches, "input");
+            let text = if input == std::path::Path::new(pipe::STDIO) {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(input)
+                    .map_err(|e| format!("Cannot read {}; {}", input.display(), e))?
+            };
+            let key = KeyFile::parse(&text).map_err(|e| format!("Invalid key JSON; {}", e))?;
+            key.save(path(matches, "out"))?;
+        }
+        _ => unreachable!("a subcommand is required"),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn batch(matches: &ArgMatches) -> Result<ExitCode> {
+    const BAR_WIDTH: usize = 30;
+
+    let inputs: Vec<PathBuf> = matches
+        .get_many::<PathBuf>("inputs")
+        .expect("required")
+        .cloned()
+        .collect();
+    let options = batch::Options {
+        output: path(matches, "out").to_path_buf(),
+        payload: matches
+            .get_one::<String>("payload")
+            .expect("required")
+            .clone(),
+        key: KeyFile::load(path(matches, "key-file"))?,
+        jobs: jobs(matches),
+        overwrite: matches.get_flag("overwrite"),
+    };
+
+    let files = batch::files(&inputs)?;
+    // The bar is redrawn in place, which only makes sense on a terminal.
+    let interactive = std::io::stderr().is_terminal();
+    let summary = batch::run(&files, &options, |summary| {
+        if interactive {
+            eprint!("\r{}", summary.progress(files.len(), BAR_WIDTH));
+        }
+    })?;
+    if interactive {
+        eprintln!();
+    }
+
+    println!(
+        "succeeded {}, failed {}, skipped {}",
+        summary.succeeded,
+        summary.failed.len(),
+        summary.skipped.len()
+    );
+    for (file, reason) in &summary.failed {
+        println!("failed {}: {}", file.display(), reason);
+    }
+    for (file, reason) in &summary.skipped {
+        println!("skipped {}: {}", file.display(), reason);
+    }
+
+    Ok(if summary.failed.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_ERROR)
+    })
+}
+
+fn compare(matches: &ArgMatches) -> Result<ExitCode> {
+    let a = open(path(matches, "a"))?;
+    let b = open(path(matches, "b"))?;
+
+    let comparison = compare::compare(&a, &b)?;
+    if let Some(diff) = matches.get_one::<PathBuf>("diff") {
+        let gain = *matches.get_one::<f32>("gain").expect("default");
+        lf_watermark::metrics::diff_image(&a, &b, gain)?.save(diff)?;
+    }
+    if matches.get_flag("json") {
+        println!("{}", serde_json::to_string(&comparison)?);
+    } else {
+        println!("psnr: {:.2} dB", comparison.psnr);
+        println!("ssim: {:.6}", comparison.ssim);
+        println!("perceptual distance: {:.4}", comparison.perceptual_distance);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn watch(matches: &ArgMatches) -> Result<ExitCode> {
+    let key = KeyFile::load(path(matches, "key-file"))?;
+    let out = path(matches, "out");
+    let quarantine = matches
+        .get_one::<PathBuf>("quarantine")
+        .cloned()
+        .unwrap_or_else(|| out.join("quarantine"));
+    let millis = |name| Duration::from_millis(*matches.get_one::<u64>(name).expect("default"));
+
+    let mut watcher = watch::Watcher::new(
+        path(matches, "dir"),
+        out,
+        &quarantine,
+        millis("settle-ms"),
+        matches.get_one::<String>("payload").expect("required"),
+        key,
+    )?;
+    watcher.run(millis("interval-ms"), |input, outcome| match outcome {
+        watch::Outcome::Marked(target) => {
+            println!("marked {} -> {}", input.display(), target.display())
+        }
+        watch::Outcome::Quarantined(target) => {
+            eprintln!("quarantined {} -> {}", input.display(), target.display())
+        }
+    })?;
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// The `--jobs` option, by default one per core.
+fn jobs(matches: &ArgMatches) -> usize {
+    match matches.get_one::<u64>("jobs") {
+        Some(&jobs) => jobs as usize,
+        None => std::thread::available_parallelism().map_or(1, |n| n.get()),
+    }
+}
+
+fn open(path: &std::path::Path) -> Result<image::DynamicImage> {
+    read(path).map(|(image, _)| image)
+}
+
+fn read(path: &std::path::Path) -> Result<(image::DynamicImage, image::ImageFormat)> {
+    pipe::read(path).map_err(|e| format!("Cannot open {}; {}", path.display(), e).into())
+}
+
+fn path<'a>(matches: &'a ArgMatches, name: &str) -> &'a std::path::Path {
+    matches.get_one::<PathBuf>(name).expect("required")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        cli().debug_assert();
+
+        let matches = cli()
+            .try_get_matches_from([
+                "lf-watermark",
+                "verify",
+                "a.jpg",
+                "--key-file",
+                "k.json",
+                "--json",
+            ])
+            .unwrap();
+        let (name, verify) = matches.subcommand().unwrap();
+        assert_eq!(name, "verify");
+        assert!(verify.get_flag("json"));
+        assert_eq!(path(verify, "key-file"), std::path::Path::new("k.json"));
+
+        assert!(cli()
+            .try_get_matches_from([
+                "lf-watermark",
+                "embed",
+                "a.png",
+                "b.png",
+                "--key-file",
+                "k.json"
+            ])
+            .is_err());
+    }
+}
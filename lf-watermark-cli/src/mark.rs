@@ -0,0 +1,333 @@
+//! Payload marks made and read by the CLI.
+//!
+//! The payload, compressed first if the key says so, is padded to the key's `payload_len` and
+//! carried as lattice-coded bits under the key's secret, followed by a 32-bit check: the start of
+//! a SHA-256 over the secret and the padded payload. The verifier reads the same bits back without the original. The mark counts as
+//! detected when the check matches. An unmarked image, or one marked under another key, matches
+//! only by chance, once in 2^32.
+//!
+//! The bits use the low band, a lattice step of 64 and four times the default repetition. The
+//! decoder reads 8-bit luma, which rounds away the default lattice's changes on real photos;
+//! these keep the PSNR near 48 dB and survive JPEG at quality 75 and halving the size.
+//!
+//! [`remark`] migrates a marked image to a new payload or key, as after a change of the payload
+//! schema across an archive. The lattice mark cannot be undone, but with the old key its carriers
+//! can be erased before the new mark goes in.
+
+use image::{DynamicImage, RgbImage};
+use lf_watermark::compress::{compress, decompress};
+use lf_watermark::lattice::{carrier_agreement, decode_scs, embed_scs, erase_scs, ScsConfig};
+use lf_watermark::Band;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::key::KeyFile;
+use crate::Result;
+
+/// Bytes of the check appended to the payload.
+pub const CHECK_LEN: usize = 4;
+
+/// Lattice settings of every mark the CLI makes.
+pub fn scs_config() -> ScsConfig {
+    ScsConfig {
+        step: 64.0,
+        repetition: 32,
+        band: Band::Low,
+        ..Default::default()
+    }
+}
+
+/// Outcome of [`verify`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Verification {
+    pub key_id: String,
+    /// Whether the check over the decoded payload matches.
+    pub detected: bool,
+    /// Decoded payload, without padding. Meaningless unless `detected`.
+    pub payload: String,
+    /// Presence of the mark from the carriers' agreement with their bits: near 1 for a clean
+    /// mark, near 0 without one.
+    pub confidence: f64,
+    /// Bit error rate: measured against the expected payload if one was given, otherwise
+    /// estimated from the carriers' agreement.
+    pub ber: f64,
+    /// Whether `ber` was measured against an expected payload.
+    pub ber_measured: bool,
+}
+
+/// Embeds `payload` under `key` into `image`.
+pub fn embed(image: &DynamicImage, payload: &str, key: &KeyFile) -> Result<RgbImage> {
+    let secret = key.secret_bytes()?;
+    let bits = to_bits(&frame(payload, key, &secret)?);
+
+    embed_scs(image, &bits, &secret, &scs_config())
+}
+
+/// What [`remark`] does with the old mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precedence {
+    /// Erases the old mark first, so only the new one reads.
+    Supersede,
+    /// Leaves the old mark where the new one does not overwrite it, for readers that still know
+    /// only the old key. A key ring lists the new key first, as the first key detected wins.
+    /// Under the same key the new mark always replaces the old one.
+    Layer,
+}
+
+/// Replaces the mark of `old_key` in `image` by `payload` under `new_key`, following
+/// `precedence`. Returns the old mark, as read before, with the remarked image.
+///
+/// Fails if `image` carries no mark of `old_key`, so an archive run leaves unmarked files alone.
+pub fn remark(
+    image: &DynamicImage,
+    old_key: &KeyFile,
+    payload: &str,
+    new_key: &KeyFile,
+    precedence: Precedence,
+) -> Result<(Verification, RgbImage)> {
+    let old = verify(image, old_key, None)?;
+    if !old.detected {
+        return Err(format!("The image carries no mark of key {}", old_key.id).into());
+    }
+
+    let marked = match precedence {
+        Precedence::Supersede => {
+            let len = bit_len(old_key)?;
+            let erased = erase_scs(image, len, &old_key.secret_bytes()?, &scs_config())?;
+            embed(&DynamicImage::ImageRgb8(erased), payload, new_key)?
+        }
+        Precedence::Layer => embed(image, payload, new_key)?,
+    };
+
+    Ok((old, marked))
+}
+
+/// Reads the mark of `key` from `image`, comparing it against `expected` if given.
+pub fn verify(image: &DynamicImage, key: &KeyFile, expected: Option<&str>) -> Result<Verification> {
+    let secret = key.secret_bytes()?;
+    let len = bit_len(key)?;
+    let config = scs_config();
+    let bits = decode_scs(image, len, &secret, &config)?;
+    let agreement = carrier_agreement(image, len, &secret, &config)?;
+
+    let bytes = from_bits(&bits);
+    let (padded, check) = bytes.split_at(key.payload_len);
+    let detected = check == checksum(&secret, padded);
+    let payload = trim_padding(padded);
+    let payload = key
+        .compress
+        .then(|| decompress(payload).ok())
+        .flatten()
+        .unwrap_or_else(|| String::from_utf8_lossy(payload).into_owned());
+
+    // Without the mark half the carriers agree, plus what deciding by their own majority adds.
+    let confidence = (2.0 * agreement - 1.0).clamp(0.0, 1.0);
+    let (ber, ber_measured) = match expected {
+        Some(expected) => {
+            let reference = to_bits(&frame(expected, key, &secret)?);
+            let errors = reference.iter().zip(&bits).filter(|(a, b)| a != b).count();
+            (errors as f64 / len as f64, true)
+        }
+        None => (majority_error(1.0 - agreement, config.repetition), false),
+    };
+
+    Ok(Verification {
+        key_id: key.id.clone(),
+        detected,
+        payload,
+        confidence,
+        ber,
+        ber_measured,
+    })
+}
+
+/// Probability that the majority of `votes` carriers is wrong when each is wrong with
+/// probability `p`, a tie counting as half wrong.
+fn majority_error(p: f64, votes: usize) -> f64 {
+    let mut choose = 1.0;
+    let mut error = 0.0;
+    for wrong in 0..=votes {
+        let probability = choose * p.powi(wrong as i32) * (1.0 - p).powi((votes - wrong) as i32);
+        if 2 * wrong > votes {
+            error += probability;
+        } else if 2 * wrong == votes {
+            error += probability / 2.0;
+        }
+        choose = choose * (votes - wrong) as f64 / (wrong + 1) as f64;
+    }
+
+    error
+}
+
+/// Number of bits marks of `key` carry, the padded payload and its check.
+fn bit_len(key: &KeyFile) -> Result<usize> {
+    key.payload_len
+        .checked_add(CHECK_LEN)
+        .and_then(|len| len.checked_mul(8))
+        .ok_or_else(|| format!("payload_len {} is too long", key.payload_len).into())
+}
+
+/// The padded payload followed by its check.
+fn frame(payload: &str, key: &KeyFile, secret: &[u8]) -> Result<Vec<u8>> {
+    let mut framed = if key.compress {
+        compress(payload)?
+    } else {
+        payload.as_bytes().to_vec()
+    };
+    if framed.len() > key.payload_len {
+        return Err(format!(
+            "The payload is {} bytes{}, but key {} carries {}",
+            framed.len(),
+            if key.compress { " compressed" } else { "" },
+            key.id,
+            key.payload_len
+        )
+        .into());
+    }
+    framed.resize(key.payload_len, 0);
+    let check = checksum(secret, &framed);
+    framed.extend_from_slice(&check);
+
+    Ok(framed)
+}
+
+fn checksum(secret: &[u8], padded: &[u8]) -> [u8; CHECK_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(secret);
+    hasher.update(padded);
+
+    let digest = hasher.finalize();
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+fn trim_padding(padded: &[u8]) -> &[u8] {
+    let len = padded.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &padded[..len]
+}
+
+/// Bits of `bytes`, most significant first.
+fn to_bits(bytes: &[u8]) -> Vec<bool> {
+    bytes
+        .iter()
+        .flat_map(|&byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+        .collect()
+}
+
+fn from_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| chunk.iter().fold(0, |byte, &bit| byte << 1 | bit as u8))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use lf_watermark::test_utils::grainy;
+
+    use super::*;
+
+    fn key(secret: &str) -> KeyFile {
+        KeyFile {
+            id: "test".into(),
+            secret: secret.into(),
+            payload_len: 8,
+            compress: false,
+        }
+    }
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(grainy(320, 240, 41))
+    }
+
+    #[test]
+    fn test_bits() {
+        let bytes = vec![0x80, 0x01, 0x5a];
+        assert_eq!(
+            to_bits(&bytes)[..9],
+            [true, false, false, false, false, false, false, false, false]
+        );
+        assert_eq!(from_bits(&to_bits(&bytes)), bytes);
+    }
+
+    #[test]
+    fn test_majority_error() {
+        assert_eq!(majority_error(0.0, 32), 0.0);
+        assert!((majority_error(0.5, 32) - 0.5).abs() < 1e-9);
+        assert!((majority_error(0.1, 3) - 0.028).abs() < 1e-9);
+        assert!(majority_error(0.2, 32) < 1e-3);
+    }
+
+    #[test]
+    fn test_verify() {
+        let key = key("0123456789abcdef");
+        let marked = DynamicImage::ImageRgb8(embed(&image(), "Press", &key).unwrap());
+
+        let found = verify(&marked, &key, None).unwrap();
+        assert!(found.detected, "{:?}", found);
+        assert_eq!(found.payload, "Press");
+        assert!(found.confidence > 0.95 && found.ber < 0.01, "{:?}", found);
+
+        let measured = verify(&marked, &key, Some("Press")).unwrap();
+        assert!(measured.ber_measured);
+        assert_eq!(measured.ber, 0.0);
+        assert!(verify(&marked, &key, Some("Other")).unwrap().ber > 0.0);
+
+        for (image, key) in [
+            (&marked, self::key("fedcba9876543210")),
+            (&image(), key.clone()),
+        ] {
+            let missed = verify(image, &key, None).unwrap();
+            assert!(!missed.detected);
+            assert!(missed.confidence < 0.5, "{:?}", missed);
+        }
+        assert!(embed(&image(), "Much too long", &key).is_err());
+
+        let huge = KeyFile {
+            payload_len: usize::MAX,
+            ..key
+        };
+        assert!(verify(&marked, &huge, None).is_err());
+    }
+
+    #[test]
+    fn test_verify_compressed() {
+        let payload = "https://example.com/licence/1234";
+        // As much as the test image carries, under half the length of the URL.
+        let plain = KeyFile {
+            payload_len: 14,
+            ..key("0123456789abcdef")
+        };
+        assert!(embed(&image(), payload, &plain).is_err());
+
+        let compressed = KeyFile {
+            compress: true,
+            ..plain
+        };
+        let marked = DynamicImage::ImageRgb8(embed(&image(), payload, &compressed).unwrap());
+        let found = verify(&marked, &compressed, Some(payload)).unwrap();
+        assert!(found.detected, "{:?}", found);
+        assert_eq!((found.payload.as_str(), found.ber), (payload, 0.0));
+    }
+
+    #[test]
+    fn test_remark() {
+        let (old_key, new_key) = (key("0123456789abcdef"), key("fedcba9876543210"));
+        let marked = DynamicImage::ImageRgb8(embed(&image(), "v1:42", &old_key).unwrap());
+
+        for precedence in [Precedence::Supersede, Precedence::Layer] {
+            let (old, remarked) = remark(&marked, &old_key, "v2:42", &new_key, precedence).unwrap();
+            assert_eq!(old.payload, "v1:42");
+            let remarked = DynamicImage::ImageRgb8(remarked);
+            let new = verify(&remarked, &new_key, None).unwrap();
+            assert!(new.detected && new.payload == "v2:42", "{:?}", new);
+
+            let left = verify(&remarked, &old_key, None).unwrap();
+            assert_eq!(left.detected, precedence == Precedence::Layer, "{:?}", left);
+        }
+
+        let (_, same) = remark(&marked, &old_key, "v2:42", &old_key, Precedence::Layer).unwrap();
+        let read = verify(&DynamicImage::ImageRgb8(same), &old_key, None).unwrap();
+        assert_eq!(read.payload, "v2:42");
+        assert!(remark(&image(), &old_key, "v2:42", &new_key, Precedence::Supersede).is_err());
+    }
+}
@@ -0,0 +1,98 @@
+//! Image input and output through files or, for the path `-`, standard input and output.
+//!
+//! The format of an input is detected from its content, so an image piped in needs no
+//! extension. An image written to standard output uses the `--format` given, or else the format
+//! of the input, so `lf-watermark embed - -` passes PNG through as PNG and JPEG as JPEG.
+
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+
+use image::io::Reader;
+use image::{DynamicImage, ImageFormat, RgbImage};
+
+use crate::Result;
+
+/// Path that stands for standard input or output.
+pub const STDIO: &str = "-";
+
+/// Reads an image from `path`, or from standard input for `-`, together with its format.
+pub fn read(path: &Path) -> Result<(DynamicImage, ImageFormat)> {
+    if path == Path::new(STDIO) {
+        let mut bytes = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut bytes)?;
+        return decode(&bytes);
+    }
+
+    let reader = Reader::open(path)?.with_guessed_format()?;
+    let format = reader.format().ok_or("Unknown image format")?;
+    Ok((reader.decode()?, format))
+}
+
+/// Writes `image` to `path`, or to standard output for `-`.
+///
+/// A file takes `format` if given, otherwise the one its extension names; standard output takes
+/// `format`, falling back to `fallback`.
+pub fn write(
+    image: &RgbImage,
+    path: &Path,
+    format: Option<ImageFormat>,
+    fallback: ImageFormat,
+) -> Result<()> {
+    if path == Path::new(STDIO) {
+        let encoded = encode(image, format.unwrap_or(fallback))?;
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&encoded)?;
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    match format {
+        Some(format) => image.save_with_format(path, format)?,
+        None => image.save(path)?,
+    }
+    Ok(())
+}
+
+/// Parses a format named by its usual extension, as in `png` or `jpg`.
+pub fn parse_format(name: &str) -> std::result::Result<ImageFormat, String> {
+    ImageFormat::from_extension(name).ok_or_else(|| format!("Unknown image format {}", name))
+}
+
+fn decode(bytes: &[u8]) -> Result<(DynamicImage, ImageFormat)> {
+    if bytes.is_empty() {
+        return Err("No image on standard input".into());
+    }
+    let format = image::guess_format(bytes)?;
+    Ok((image::load_from_memory_with_format(bytes, format)?, format))
+}
+
+fn encode(image: &RgbImage, format: ImageFormat) -> Result<Vec<u8>> {
+    let mut encoded = Cursor::new(Vec::new());
+    image.write_to(&mut encoded, format)?;
+    Ok(encoded.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let image = RgbImage::from_fn(16, 8, |x, y| Rgb([x as u8 * 16, y as u8 * 32, 96]));
+
+        for format in [ImageFormat::Png, ImageFormat::Jpeg, ImageFormat::Bmp] {
+            let (decoded, detected) = decode(&encode(&image, format).unwrap()).unwrap();
+            assert_eq!(detected, format);
+            assert_eq!(decoded.to_rgb8().dimensions(), (16, 8));
+        }
+        let (decoded, _) = decode(&encode(&image, ImageFormat::Png).unwrap()).unwrap();
+        assert_eq!(decoded.to_rgb8(), image);
+
+        assert!(decode(b"").is_err());
+        assert!(decode(b"not an image").is_err());
+        assert_eq!(parse_format("jpg"), Ok(ImageFormat::Jpeg));
+        assert!(parse_format("doc").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+//! Hot-folder watching: marks every image that appears in a directory.
+//!
+//! The directory is polled rather than subscribed to, which works the same on network shares,
+//! where file system events are unreliable. A file is only picked up once its size and
+//! modification time have been stable for the settle time, so images still being copied in are
+//! not read half-written. A file that cannot be marked is moved to the quarantine directory
+//! together with a `.error.txt` file holding the reason, so one bad upload does not stall the
+//! folder or get retried forever.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::key::KeyFile;
+use crate::{mark, Result};
+
+/// Where a watched file ended up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// Marked and written to this path.
+    Marked(PathBuf),
+    /// Moved to this path in the quarantine directory.
+    Quarantined(PathBuf),
+}
+
+/// State of a watch over one directory.
+pub struct Watcher {
+    input: PathBuf,
+    output: PathBuf,
+    quarantine: PathBuf,
+    settle: Duration,
+    payload: String,
+    key: KeyFile,
+    /// Last seen size and modification time of pending files, and since when they were seen.
+    pending: HashMap<PathBuf, (u64, Option<SystemTime>, Instant)>,
+}
+
+impl Watcher {
+    /// Watches `input`, writing marked images to `output` and failures to `quarantine`. All
+    /// three directories are created if needed.
+    pub fn new(
+        input: &Path,
+        output: &Path,
+        quarantine: &Path,
+        settle: Duration,
+        payload: &str,
+        key: KeyFile,
+    ) -> Result<Self> {
+        for dir in [input, output, quarantine] {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Cannot create {}; {}", dir.display(), e))?;
+        }
+        if same_dir(input, output)? || same_dir(input, quarantine)? {
+            return Err("The output and quarantine directories must differ from the input".into());
+        }
+
+        Ok(Watcher {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            quarantine: quarantine.to_path_buf(),
+            settle,
+            payload: payload.to_string(),
+            key,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Scans the input directory once at time `now` and processes the files that have settled.
+    ///
+    /// Files that already have an output of the same name are skipped, so restarting a watch
+    /// does not mark the backlog twice.
+    pub fn poll(&mut self, now: Instant) -> Result<Vec<(PathBuf, Outcome)>> {
+        let mut settled = Vec::new();
+        let mut present = Vec::new();
+        for entry in fs::read_dir(&self.input)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            let path = entry.path();
+            let name = entry.file_name();
+            if !metadata.is_file() || self.output.join(&name).exists() {
+                continue;
+            }
+            present.push(path.clone());
+
+            let state = (metadata.len(), metadata.modified().ok());
+            match self.pending.get(&path) {
+                Some(&(len, modified, since)) if (len, modified) == state => {
+                    if now.duration_since(since) >= self.settle {
+                        settled.push(path);
+                    }
+                }
+                _ => {
+                    self.pending.insert(path, (state.0, state.1, now));
+                }
+            }
+        }
+        self.pending.retain(|path, _| present.contains(path));
+
+        let mut outcomes = Vec::new();
+        settled.sort();
+        for path in settled {
+            self.pending.remove(&path);
+            let outcome = self.process(&path)?;
+            outcomes.push((path, outcome));
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Polls every `interval` until the process is stopped, reporting each outcome to `report`.
+    pub fn run(
+        &mut self,
+        interval: Duration,
+        mut report: impl FnMut(&Path, &Outcome),
+    ) -> Result<()> {
+        loop {
+            for (path, outcome) in self.poll(Instant::now())? {
+                report(&path, &outcome);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+
+    fn process(&self, path: &Path) -> Result<Outcome> {
+        let name = path.file_name().ok_or("File without a name")?;
+        let result = image::open(path)
+            .map_err(|e| e.into())
+            .and_then(|image| mark::embed(&image, &self.payload, &self.key))
+            .and_then(|marked| {
+                let target = self.output.join(name);
+                marked.save(&target)?;
+                Ok(target)
+            });
+
+        match result {
+            Ok(target) => Ok(Outcome::Marked(target)),
+            Err(e) => {
+                let target = self.quarantine.join(name);
+                fs::rename(path, &target).map_err(|move_error| {
+                    format!(
+                        "Cannot quarantine {} after \"{}\"; {}",
+                        path.display(),
+                        e,
+                        move_error
+                    )
+                })?;
+                let mut reason = target.clone().into_os_string();
+                reason.push(".error.txt");
+                fs::write(reason, format!("{}\n", e))?;
+                Ok(Outcome::Quarantined(target))
+            }
+        }
+    }
+}
+
+fn same_dir(a: &Path, b: &Path) -> Result<bool> {
+    Ok(fs::canonicalize(a)? == fs::canonicalize(b)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_poll() {
+        let root = std::env::temp_dir().join(format!("lf-watermark-watch-{}", std::process::id()));
+        let (input, output, quarantine) =
+            (root.join("in"), root.join("out"), root.join("quarantine"));
+        let key = KeyFile {
+            id: "test".into(),
+            secret: "0123456789abcdef".into(),
+            payload_len: 8,
+            compress: false,
+        };
+        let settle = Duration::from_secs(2);
+        let mut watcher =
+            Watcher::new(&input, &output, &quarantine, settle, "Press", key.clone()).unwrap();
+
+        let image = RgbImage::from_fn(320, 240, |x, y| Rgb([x as u8, y as u8, 96]));
+        image.save(input.join("good.png")).unwrap();
+        fs::write(input.join("bad.png"), b"not an image").unwrap();
+
+        let start = Instant::now();
+        assert!(watcher.poll(start).unwrap().is_empty());
+        // Still settling.
+        assert!(watcher.poll(start + settle / 2).unwrap().is_empty());
+        let outcomes = watcher.poll(start + settle).unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                (
+                    input.join("bad.png"),
+                    Outcome::Quarantined(quarantine.join("bad.png"))
+                ),
+                (
+                    input.join("good.png"),
+                    Outcome::Marked(output.join("good.png"))
+                ),
+            ]
+        );
+        let reason = fs::read_to_string(quarantine.join("bad.png.error.txt")).unwrap();
+        let marked = image::open(output.join("good.png")).unwrap();
+        let found = mark::verify(&marked, &key, None).unwrap();
+        // Done files are not picked up again.
+        let again = watcher.poll(start + settle * 3).unwrap();
+        let same = Watcher::new(&input, &input, &quarantine, settle, "Press", key).is_err();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(!reason.is_empty());
+        assert!(found.detected && found.payload == "Press", "{:?}", found);
+        assert!(again.is_empty());
+        assert!(same);
+    }
+}
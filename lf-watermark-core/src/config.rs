@@ -0,0 +1,252 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::Error;
+
+/// Settings for watermark embedding.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct WatermarkConfig {
+    /// Multiplier applied to the value derived from the watermark text.
+    pub strength: f32,
+    /// Which luma DCT coefficients receive the mark.
+    pub band: Band,
+    /// How the strength is distributed over the coefficients of `band`.
+    pub curve: StrengthCurve,
+    /// What to do when the input uses an indexed (palette) color type.
+    pub palette: PalettePolicy,
+    /// How an EXIF orientation tag on the input is handled.
+    pub orientation: OrientationPolicy,
+    /// Whether outputs must be bit-identical across platforms.
+    pub determinism: Determinism,
+    /// Multipliers of `strength` for the luma and chroma planes.
+    pub channels: ChannelStrength,
+}
+
+impl Default for WatermarkConfig {
+    /// DC-only embedding with the strength taken from `WATERMARK_STRENGTH` at build time, which
+    /// is exactly what `lf_watermark::embed_watermark_color` does.
+    fn default() -> Self {
+        WatermarkConfig {
+            strength: default_strength(),
+            band: Band::Dc,
+            curve: StrengthCurve::Flat,
+            palette: PalettePolicy::default(),
+            orientation: OrientationPolicy::default(),
+            determinism: Determinism::default(),
+            channels: ChannelStrength::default(),
+        }
+    }
+}
+
+pub(crate) fn default_strength() -> f32 {
+    option_env!("WATERMARK_STRENGTH")
+        .unwrap_or("0.01")
+        .parse::<f32>()
+        .unwrap_or(0.01)
+}
+
+/// Range of luma DCT coefficients the mark is added to.
+///
+/// Lower bands survive compression and scaling better but are more visible; higher bands are
+/// less visible but fragile. The named bands are fractions of the coefficient count so they scale
+/// with the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Band {
+    /// Only the DC coefficient: a uniform luma offset.
+    Dc,
+    /// The first sixteenth of the AC coefficients.
+    Low,
+    /// From a sixteenth to a quarter of the coefficients.
+    Mid,
+    /// The remaining three quarters.
+    High,
+    /// Explicit coefficient indices.
+    Range(Range<usize>),
+}
+
+impl Band {
+    /// Resolves the band to coefficient indices for a signal of `len` coefficients.
+    pub fn indices(&self, len: usize) -> Result<Range<usize>, Error> {
+        let range = match self {
+            Band::Dc => 0..1,
+            Band::Low => 1..(len / 16).max(2),
+            Band::Mid => (len / 16).max(1)..(len / 4).max(2),
+            Band::High => (len / 4).max(1)..len,
+            Band::Range(range) => range.clone(),
+        };
+
+        if range.is_empty() || range.end > len {
+            return Err(Error::InvalidBand {
+                band: self.clone(),
+                len,
+            });
+        }
+
+        Ok(range)
+    }
+}
+
+/// Handling of palette-based inputs (indexed PNG, GIF).
+///
+/// Embedding works on truecolor pixels, so writing the result back to a palette needs a new
+/// quantization, and naive quantization can damage the mark.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum PalettePolicy {
+    /// Re-quantize the marked image to a palette of at most 256 colors, optionally with
+    /// Floyd-Steinberg dithering to hide banding.
+    Requantize { dither: bool },
+    /// Fail with an error instead of producing an indexed output.
+    Refuse,
+}
+
+impl Default for PalettePolicy {
+    fn default() -> Self {
+        PalettePolicy::Requantize { dither: true }
+    }
+}
+
+/// Handling of inputs whose EXIF orientation says the stored pixels must be rotated or flipped for
+/// display.
+///
+/// Either way the mark is embedded in the upright image, which is what a detector looking at the
+/// displayed picture sees.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum OrientationPolicy {
+    /// Output the upright pixels; the output carries no orientation tag.
+    #[default]
+    Normalize,
+    /// Rotate the marked pixels back to the stored orientation and keep the original EXIF data.
+    Restore,
+}
+
+/// Reproducibility of the output bytes across CPU architectures.
+///
+/// The float transform runs through SIMD code paths chosen per CPU, and their rounding
+/// differences occasionally move a sample by one level. Content-addressed storage that hashes
+/// outputs needs the same bytes everywhere instead.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Determinism {
+    /// Float transform. Fastest, results may differ by one level between architectures.
+    #[default]
+    Fast,
+    /// Integer-only arithmetic, giving identical bytes on x86_64, aarch64, wasm32 and every
+    /// other target. Only [`Band::Dc`] can be embedded this way.
+    Strict,
+}
+
+/// Per-plane multipliers of [`WatermarkConfig::strength`].
+///
+/// The eye resolves color differences far less finely than brightness differences, so chroma
+/// planes take a stronger mark for the same visibility. The default marks luma only, as every
+/// release before this setting did; [`ChannelStrength::HVS`] spreads the mark over all three
+/// planes. Chroma marking is done by `lf_watermark::embed_watermark_with_config` and the
+/// functions built on it; the other embedders reject nonzero chroma multipliers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelStrength {
+    pub y: f32,
+    pub cb: f32,
+    pub cr: f32,
+}
+
+impl ChannelStrength {
+    /// Multipliers matched to visibility, from the JPEG Annex K quantization tables, which encode
+    /// the HVS thresholds: over the lowest 4x4 frequencies, where the mark lives, the chrominance
+    /// table is 853 / 259 = 3.3 times coarser than the luminance table.
+    pub const HVS: ChannelStrength = ChannelStrength {
+        y: 1.0,
+        cb: 3.3,
+        cr: 3.3,
+    };
+
+    /// Whether either chroma plane receives a mark.
+    pub fn has_chroma(&self) -> bool {
+        self.cb != 0.0 || self.cr != 0.0
+    }
+}
+
+impl Default for ChannelStrength {
+    fn default() -> Self {
+        ChannelStrength {
+            y: 1.0,
+            cb: 0.0,
+            cr: 0.0,
+        }
+    }
+}
+
+/// Per-coefficient weighting inside a [`Band`].
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StrengthCurve {
+    /// Every coefficient gets the full strength.
+    Flat,
+    /// Weight changes linearly from `start` at the first coefficient to `end` at the last.
+    Linear { start: f32, end: f32 },
+    /// Weights sampled across the band with linear interpolation, so the table does not have to
+    /// match the band length.
+    Table(Vec<f32>),
+}
+
+impl StrengthCurve {
+    /// Weight of the `index`-th coefficient of a band of `len` coefficients.
+    pub fn weight(&self, index: usize, len: usize) -> f32 {
+        let t = if len > 1 {
+            index as f32 / (len - 1) as f32
+        } else {
+            0.0
+        };
+
+        match self {
+            StrengthCurve::Flat => 1.0,
+            StrengthCurve::Linear { start, end } => start + (end - start) * t,
+            StrengthCurve::Table(table) => match table.len() {
+                0 => 1.0,
+                1 => table[0],
+                n => {
+                    let pos = t * (n - 1) as f32;
+                    let i = (pos as usize).min(n - 2);
+                    let frac = pos - i as f32;
+                    table[i] + (table[i + 1] - table[i]) * frac
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    #[test]
+    fn test_band_indices() {
+        assert_eq!(Band::Dc.indices(1024).unwrap(), 0..1);
+        assert_eq!(Band::Low.indices(1024).unwrap(), 1..64);
+        assert_eq!(Band::Mid.indices(1024).unwrap(), 64..256);
+        assert_eq!(Band::High.indices(1024).unwrap(), 256..1024);
+        assert!(Band::Range(10..2000).indices(1024).is_err());
+    }
+
+    #[test]
+    fn test_strength_curve_weight() {
+        assert_eq!(StrengthCurve::Flat.weight(3, 10), 1.0);
+
+        let linear = StrengthCurve::Linear {
+            start: 1.0,
+            end: 0.0,
+        };
+        assert_eq!(linear.weight(0, 11), 1.0);
+        assert_eq!(linear.weight(10, 11), 0.0);
+
+        let table = StrengthCurve::Table(vec![0.0, 2.0]);
+        assert_eq!(table.weight(5, 11), 1.0);
+    }
+}
@@ -0,0 +1,71 @@
+use core::fmt;
+
+use crate::Band;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The watermark text contains a character outside the supported set.
+    InvalidCharacter(char),
+    /// The band does not fit a signal of `len` coefficients.
+    InvalidBand { band: Band, len: usize },
+    /// The band cannot be embedded by the requested path.
+    UnsupportedBand(Band),
+    /// Chroma planes cannot be marked by the requested path.
+    UnsupportedChroma,
+    /// A pixel buffer does not match the given dimensions.
+    BufferSize { expected: usize, actual: usize },
+    /// Strict determinism was requested from an embedder that only has the float transform.
+    UnsupportedDeterminism,
+    /// The image has more pixels than the host can address.
+    ImageTooLarge { width: u32, height: u32 },
+}
+
+impl Error {
+    /// Stable numeric code of the variant, for bindings that cannot match on the enum. Codes are
+    /// never reused or renumbered: 1 invalid character, 2 invalid band, 3 unsupported band,
+    /// 4 unsupported chroma, 5 buffer size, 6 unsupported determinism, 7 image too large.
+    pub fn code(&self) -> u32 {
+        match self {
+            Error::InvalidCharacter(_) => 1,
+            Error::InvalidBand { .. } => 2,
+            Error::UnsupportedBand(_) => 3,
+            Error::UnsupportedChroma => 4,
+            Error::BufferSize { .. } => 5,
+            Error::UnsupportedDeterminism => 6,
+            Error::ImageTooLarge { .. } => 7,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidCharacter(c) => write!(f, "Invalid character; {}", c),
+            Error::InvalidBand { band, len } => {
+                write!(f, "Invalid band; {:?} for {} coefficients", band, len)
+            }
+            Error::UnsupportedBand(band) => {
+                write!(f, "Unsupported band for fixed-point embedding; {:?}", band)
+            }
+            Error::UnsupportedChroma => {
+                write!(f, "Chroma strength is not supported by this embedder")
+            }
+            Error::BufferSize { expected, actual } => {
+                write!(
+                    f,
+                    "Invalid buffer size; expected {}, got {}",
+                    expected, actual
+                )
+            }
+            Error::UnsupportedDeterminism => write!(
+                f,
+                "Strict determinism is only supported by the 8-bit RGB embedders"
+            ),
+            Error::ImageTooLarge { width, height } => {
+                write!(f, "Image too large; {}x{} pixels", width, height)
+            }
+        }
+    }
+}
+
+impl core::error::Error for Error {}
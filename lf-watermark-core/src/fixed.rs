@@ -0,0 +1,123 @@
+//! Integer-only embedding on raw pixel buffers, for targets without an FPU.
+//!
+//! Color conversion uses Q16 fixed-point with the same BT.601 coefficients as the float path in
+//! `lf-watermark`, so the output matches it to within one level per channel.
+
+use crate::{payload_value, Band, Error, WatermarkConfig};
+
+const ONE: i32 = 1 << 16;
+const HALF: i32 = 1 << 15;
+
+/// Embeds `watermark` in place into an interleaved 8-bit RGB buffer of `width * height` pixels.
+///
+/// Only [`Band::Dc`] is supported: the DC coefficient of the DCT is a uniform luma offset, so it
+/// can be applied without a transform at all. The strength is converted to fixed-point once per
+/// call; every per-pixel operation is integer arithmetic.
+pub fn embed_rgb8(
+    pixels: &mut [u8],
+    width: u32,
+    height: u32,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<(), Error> {
+    if config.band != Band::Dc {
+        return Err(Error::UnsupportedBand(config.band.clone()));
+    }
+    if config.channels.has_chroma() {
+        return Err(Error::UnsupportedChroma);
+    }
+
+    let expected = width as usize * height as usize * 3;
+    if pixels.len() != expected {
+        return Err(Error::BufferSize {
+            expected,
+            actual: pixels.len(),
+        });
+    }
+
+    let offset = to_fixed(payload_value(watermark)? * config.strength * config.channels.y);
+    for pixel in pixels.chunks_exact_mut(3) {
+        let (y, cb, cr) = rgb_to_ycbcr(pixel[0], pixel[1], pixel[2]);
+        pixel.copy_from_slice(&ycbcr_to_rgb((y << 16) + offset, cb, cr));
+    }
+
+    Ok(())
+}
+
+/// Rounds to Q16 without `f32::round`, which is not available in `core`.
+fn to_fixed(value: f32) -> i32 {
+    let scaled = value * ONE as f32;
+    if scaled >= 0.0 {
+        (scaled + 0.5) as i32
+    } else {
+        (scaled - 0.5) as i32
+    }
+}
+
+fn rgb_to_ycbcr(r: u8, g: u8, b: u8) -> (i32, i32, i32) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+
+    let y = (19595 * r + 38470 * g + 7471 * b + HALF) >> 16;
+    let cb = (-11076 * r - 21692 * g + 32768 * b + HALF + (128 << 16)) >> 16;
+    let cr = (32768 * r - 27460 * g - 5308 * b + HALF + (128 << 16)) >> 16;
+
+    (y.clamp(0, 255), cb.clamp(0, 255), cr.clamp(0, 255))
+}
+
+/// `y` is Q16, chroma is plain 8-bit.
+fn ycbcr_to_rgb(y: i32, cb: i32, cr: i32) -> [u8; 3] {
+    let cb = cb - 128;
+    let cr = cr - 128;
+
+    let r = (y + 91881 * cr + HALF) >> 16;
+    let g = (y - 22554 * cb - 46802 * cr + HALF) >> 16;
+    let b = (y + 116130 * cb + HALF) >> 16;
+
+    [
+        r.clamp(0, 255) as u8,
+        g.clamp(0, 255) as u8,
+        b.clamp(0, 255) as u8,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::ChannelStrength;
+
+    #[test]
+    fn test_embed_rgb8() {
+        let mut pixels = vec![100u8; 4 * 4 * 3];
+        let config = WatermarkConfig {
+            strength: 0.01,
+            ..Default::default()
+        };
+        embed_rgb8(&mut pixels, 4, 4, "Hello, World!", &config).unwrap();
+        assert!(pixels.iter().all(|&p| p == 105), "{:?}", pixels);
+
+        assert_eq!(
+            embed_rgb8(&mut pixels, 5, 4, "Hello, World!", &config),
+            Err(Error::BufferSize {
+                expected: 60,
+                actual: 48
+            })
+        );
+
+        let config = WatermarkConfig {
+            band: Band::Low,
+            ..Default::default()
+        };
+        assert!(embed_rgb8(&mut pixels, 4, 4, "Hello, World!", &config).is_err());
+
+        let config = WatermarkConfig {
+            channels: ChannelStrength::HVS,
+            ..Default::default()
+        };
+        assert_eq!(
+            embed_rgb8(&mut pixels, 4, 4, "Hello, World!", &config),
+            Err(Error::UnsupportedChroma)
+        );
+    }
+}
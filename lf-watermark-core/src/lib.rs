@@ -0,0 +1,21 @@
+//! `no_std` core of `lf-watermark`.
+//!
+//! Holds everything that does not need the standard library: payload coding, the embedding
+//! configuration, and the fixed-point embedding on raw pixel buffers. The whole-image DCT depends
+//! on `rustdct`, which needs `std`, so it stays in `lf-watermark` together with the `image` I/O
+//! layer.
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+mod config;
+mod error;
+pub mod fixed;
+mod payload;
+
+pub use config::{
+    Band, ChannelStrength, Determinism, OrientationPolicy, PalettePolicy, StrengthCurve,
+    WatermarkConfig,
+};
+pub use error::Error;
+pub use payload::{payload_value, CHAR_MAP};
@@ -0,0 +1,29 @@
+use crate::Error;
+
+/// Characters a payload may contain. Each contributes its index here to [`payload_value`].
+pub const CHAR_MAP: &str =
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!@#$%^&*(),.<>/?; ";
+
+/// Value derived from the watermark text before the strength is applied.
+pub fn payload_value(words: &str) -> Result<f32, Error> {
+    let mut ret = 0.0;
+
+    for c in words.chars() {
+        let char_idx = CHAR_MAP.find(c).ok_or(Error::InvalidCharacter(c))?;
+        ret += char_idx as f32;
+    }
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_value() {
+        assert_eq!(payload_value("ABC").unwrap(), 3.0);
+        assert_eq!(payload_value("Hello, World!").unwrap(), 535.0);
+        assert_eq!(payload_value("é"), Err(Error::InvalidCharacter('é')));
+    }
+}
@@ -0,0 +1,110 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use image::DynamicImage;
+use image::{Rgb, RgbImage};
+use lf_watermark::carrier::Carrier;
+use lf_watermark::{embed_luma_plane, embed_raw, Embedder, PixelFormat, WatermarkConfig};
+
+const WATERMARK: &str = "Hello, World!";
+
+/// 1, 12 and 50 megapixels at a 4:3 aspect ratio.
+const SIZES: [(u32, u32); 3] = [(1152, 864), (4000, 3000), (8160, 6120)];
+
+fn gradient(width: u32, height: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+    })
+}
+
+fn megapixels(width: u32, height: u32) -> String {
+    format!("{}MP", (width as u64 * height as u64 + 500_000) / 1_000_000)
+}
+
+/// The luma plane path runs only the transform, so the difference to the RGB path below is the
+/// cost of color conversion.
+fn dct(c: &mut Criterion) {
+    let mut group = c.benchmark_group("dct");
+    group.sample_size(10);
+    for (width, height) in SIZES {
+        let mut plane = vec![128u8; width as usize * height as usize];
+        let config = WatermarkConfig::default();
+        group.throughput(Throughput::Elements(width as u64 * height as u64));
+        group.bench_function(
+            BenchmarkId::from_parameter(megapixels(width, height)),
+            |b| {
+                b.iter(|| {
+                    embed_luma_plane(
+                        &mut plane,
+                        width,
+                        height,
+                        width as usize,
+                        WATERMARK,
+                        &config,
+                    )
+                    .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn color_conversion_and_dct(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rgb8");
+    group.sample_size(10);
+    for (width, height) in SIZES {
+        let mut pixels = gradient(width, height).into_raw();
+        let config = WatermarkConfig::default();
+        group.throughput(Throughput::Elements(width as u64 * height as u64));
+        group.bench_function(
+            BenchmarkId::from_parameter(megapixels(width, height)),
+            |b| {
+                b.iter(|| {
+                    embed_raw(
+                        &mut pixels,
+                        width,
+                        height,
+                        PixelFormat::Rgb8,
+                        WATERMARK,
+                        &config,
+                    )
+                    .unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn embedder(c: &mut Criterion) {
+    let mut group = c.benchmark_group("embedder");
+    group.sample_size(10);
+    for (width, height) in SIZES {
+        let mut image = gradient(width, height);
+        let mut embedder = Embedder::new(WatermarkConfig::default());
+        group.throughput(Throughput::Elements(width as u64 * height as u64));
+        group.bench_function(
+            BenchmarkId::from_parameter(megapixels(width, height)),
+            |b| b.iter(|| embedder.embed_in_place(&mut image, WATERMARK).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+/// A stamp into a prepared carrier, the per-request cost of personalized downloads.
+fn carrier(c: &mut Criterion) {
+    let mut group = c.benchmark_group("carrier");
+    group.sample_size(10);
+    for (width, height) in SIZES {
+        let image = DynamicImage::ImageRgb8(gradient(width, height));
+        let carrier = Carrier::new(&image, &WatermarkConfig::default()).unwrap();
+        group.throughput(Throughput::Elements(width as u64 * height as u64));
+        group.bench_function(
+            BenchmarkId::from_parameter(megapixels(width, height)),
+            |b| b.iter(|| carrier.stamp(WATERMARK).unwrap()),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dct, color_conversion_and_dct, embedder, carrier);
+criterion_main!(benches);
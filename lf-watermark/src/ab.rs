@@ -0,0 +1,246 @@
+//! A/B segment watermarking for HLS and DASH.
+//!
+//! Marking every viewer's stream individually does not scale to a CDN. Instead each segment is
+//! encoded twice, as variant A and variant B with different marks, and the edge serves every user
+//! a keyed sequence of A and B segments that spells out their ID. A recording of the stream
+//! carries that sequence: [`identify_variant`] tells which variant a recorded segment came from,
+//! and [`decode_sequence`] turns the observed variants back into the user ID.
+//!
+//! The code repeats over the stream, so a recording of a few times [`AbConfig::code_len`]
+//! segments is enough, and segments lost or misidentified in one round are outvoted by the
+//! others.
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+use sha2::{Digest, Sha256};
+
+use crate::prng::Prng;
+use crate::{embed_watermark_with_config, payload_value, Result, WatermarkConfig};
+
+/// Bits of the check value appended to the ID.
+const CHECK_BITS: u32 = 8;
+
+/// Rendition a segment is served from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    A,
+    B,
+}
+
+/// Layout of the segment sequence, which must be the same for the edge and the detector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbConfig {
+    /// Bits of the user ID, at most 64. Each one costs a segment per round.
+    pub id_bits: u32,
+}
+
+impl Default for AbConfig {
+    fn default() -> Self {
+        AbConfig { id_bits: 32 }
+    }
+}
+
+impl AbConfig {
+    /// Segments per round of the code: the ID bits and a check value.
+    pub fn code_len(&self) -> usize {
+        (self.id_bits + CHECK_BITS) as usize
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.id_bits == 0 || self.id_bits > 64 {
+            return Err(format!("ID bits must be 1 to 64, got {}", self.id_bits).into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Renders the two variants of a segment frame, marked with `watermark_a` and `watermark_b`.
+pub fn render_variants(
+    image: &DynamicImage,
+    watermark_a: &str,
+    watermark_b: &str,
+    config: &WatermarkConfig,
+) -> Result<[RgbImage; 2]> {
+    if payload_value(watermark_a)? == payload_value(watermark_b)? {
+        return Err("The two variants need watermarks of different value".into());
+    }
+
+    Ok([
+        embed_watermark_with_config(image, watermark_a, config)?,
+        embed_watermark_with_config(image, watermark_b, config)?,
+    ])
+}
+
+/// Variant of each of the first `segments` segments for `user_id` under `key`.
+pub fn variant_sequence(
+    user_id: u64,
+    segments: usize,
+    key: &[u8],
+    config: &AbConfig,
+) -> Result<Vec<Variant>> {
+    config.validate()?;
+    if config.id_bits < 64 && user_id >> config.id_bits != 0 {
+        return Err(format!("User ID {} does not fit {} bits", user_id, config.id_bits).into());
+    }
+
+    let code = code(user_id, config);
+    let mask = mask(key, segments);
+
+    Ok((0..segments)
+        .map(|i| variant(code[i % code.len()] ^ mask[i]))
+        .collect())
+}
+
+/// Tells which of the two `renditions` of a segment `frame` was recorded from.
+///
+/// The frame is projected onto the difference of the renditions, which ignores everything the two
+/// variants share, image content included.
+pub fn identify_variant(frame: &DynamicImage, renditions: &[RgbImage; 2]) -> Result<Variant> {
+    let [a, b] = renditions;
+    if frame.dimensions() != a.dimensions() || a.dimensions() != b.dimensions() {
+        return Err("The frame and both renditions must have the same size".into());
+    }
+
+    let frame = frame.to_rgb8();
+    let mut projection = 0.0;
+    for ((x, a), b) in frame.as_raw().iter().zip(a.as_raw()).zip(b.as_raw()) {
+        let (x, a, b) = (*x as f64, *a as f64, *b as f64);
+        projection += (x - (a + b) / 2.0) * (b - a);
+    }
+
+    Ok(if projection > 0.0 {
+        Variant::B
+    } else {
+        Variant::A
+    })
+}
+
+/// Recovers the user ID from the variants observed in a recording, indexed by segment number;
+/// `None` marks segments that are missing or could not be identified.
+pub fn decode_sequence(observed: &[Option<Variant>], key: &[u8], config: &AbConfig) -> Result<u64> {
+    config.validate()?;
+
+    let len = config.code_len();
+    let mask = mask(key, observed.len());
+    let mut votes = vec![0i32; len];
+    for (i, variant) in observed.iter().enumerate() {
+        if let Some(variant) = variant {
+            let bit = (*variant == Variant::B) ^ mask[i];
+            votes[i % len] += if bit { 1 } else { -1 };
+        }
+    }
+    if let Some(position) = votes.iter().position(|&v| v == 0) {
+        return Err(format!(
+            "Code bit {} was not observed, or observed undecidedly; {} segments per round",
+            position, len
+        )
+        .into());
+    }
+
+    let bits: Vec<bool> = votes.iter().map(|&v| v > 0).collect();
+    let user_id = bits[..config.id_bits as usize]
+        .iter()
+        .fold(0u64, |acc, &bit| acc << 1 | bit as u64);
+    if code(user_id, config) != bits {
+        return Err(
+            "Check value mismatch; the recording is too damaged or uses another key".into(),
+        );
+    }
+
+    Ok(user_id)
+}
+
+/// The ID bits, most significant first, followed by their check value.
+fn code(user_id: u64, config: &AbConfig) -> Vec<bool> {
+    let check = Sha256::digest(user_id.to_le_bytes())[0];
+    let id = (0..config.id_bits).rev().map(|i| user_id >> i & 1 == 1);
+    let check = (0..CHECK_BITS).rev().map(|i| check >> i & 1 == 1);
+
+    id.chain(check).collect()
+}
+
+/// Keyed bit flips per segment, so that the sequence reveals nothing without the key and users
+/// with similar IDs do not get similar sequences.
+fn mask(key: &[u8], segments: usize) -> Vec<bool> {
+    let mut prng = Prng::from_key(key);
+    (0..segments).map(|_| prng.next_below(2) == 1).collect()
+}
+
+fn variant(bit: bool) -> Variant {
+    if bit {
+        Variant::B
+    } else {
+        Variant::A
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn test_sequence_roundtrip() {
+        let config = AbConfig::default();
+        let sequence =
+            variant_sequence(0xDEAD_BEEF, 3 * config.code_len(), b"key", &config).unwrap();
+        let observed: Vec<Option<Variant>> = sequence.iter().copied().map(Some).collect();
+        assert_eq!(
+            decode_sequence(&observed, b"key", &config).unwrap(),
+            0xDEAD_BEEF
+        );
+
+        // A lost and a flipped segment are outvoted by the other two rounds.
+        let mut damaged = observed.clone();
+        damaged[3] = None;
+        damaged[7] = damaged[7].map(|v| {
+            if v == Variant::A {
+                Variant::B
+            } else {
+                Variant::A
+            }
+        });
+        assert_eq!(
+            decode_sequence(&damaged, b"key", &config).unwrap(),
+            0xDEAD_BEEF
+        );
+
+        assert!(decode_sequence(&observed[..20], b"key", &config).is_err());
+        assert!(decode_sequence(&observed, b"other", &config).is_err());
+        assert_ne!(
+            variant_sequence(0xDEAD_BEEE, sequence.len(), b"key", &config).unwrap(),
+            sequence
+        );
+        assert!(variant_sequence(1 << 32, 10, b"key", &config).is_err());
+    }
+
+    #[test]
+    fn test_identify_variant() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(48, 32, |x, y| {
+            Rgb([(x * 5) as u8, (y * 7) as u8, 80])
+        }));
+        let config = WatermarkConfig {
+            strength: 0.1,
+            ..Default::default()
+        };
+        let renditions = render_variants(&image, "Variant one", "Variant two", &config).unwrap();
+
+        for (rendition, variant) in renditions.iter().zip([Variant::A, Variant::B]) {
+            // A recording adds noise of its own.
+            let recorded = RgbImage::from_fn(48, 32, |x, y| {
+                let noise = ((x * 31 + y * 17) % 5) as i16 - 2;
+                Rgb(rendition
+                    .get_pixel(x, y)
+                    .0
+                    .map(|c| (c as i16 + noise).clamp(0, 255) as u8))
+            });
+            let recorded = DynamicImage::ImageRgb8(recorded);
+            assert_eq!(identify_variant(&recorded, &renditions).unwrap(), variant);
+        }
+
+        assert!(render_variants(&image, "ab", "ba", &config).is_err());
+        let small = DynamicImage::ImageRgb8(RgbImage::new(8, 8));
+        assert!(identify_variant(&small, &renditions).is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! Analysis of catalog masters, saved to disk and reloaded.
+//!
+//! Catalog masters are marked over and over, and the content analysis of every embed comes out
+//! the same each time. An [`Analysis`] holds it for one image: the classes of its blocks, from
+//! which the perceptual masks of [`BlockSelection`] follow. A [`Carrier`](crate::carrier::Carrier)
+//! holds the transform of one image, see [`Carrier::to_bytes`](crate::carrier::Carrier::to_bytes).
+//! Both are written in a small binary format and remember a SHA-256 of the pixels they were
+//! computed from, so a stale file is refused instead of silently marking the wrong image.
+//!
+//! All numbers are little-endian. A file starts with the magic `LFWA`, the kind of artifact and
+//! its format version.
+
+use std::fs;
+use std::path::Path;
+
+use image::RgbImage;
+use sha2::{Digest, Sha256};
+
+use crate::blocks::{classify_blocks, BlockClass, BlockSelection, BLOCK};
+use crate::Result;
+
+const MAGIC: &[u8; 4] = b"LFWA";
+/// Format version of every artifact kind.
+const VERSION: u8 = 1;
+
+/// Kinds of artifacts, the fifth byte of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Analysis = 1,
+    Carrier = 2,
+}
+
+/// Content analysis of one image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Analysis {
+    pub width: u32,
+    pub height: u32,
+    /// SHA-256 of the image's RGB pixels.
+    pub source: [u8; 32],
+    /// Classes of the blocks, row by row, as [`classify_blocks`] returns them.
+    pub blocks: Vec<BlockClass>,
+}
+
+impl Analysis {
+    /// Analyzes `image`.
+    pub fn new(image: &RgbImage) -> Self {
+        Analysis {
+            width: image.width(),
+            height: image.height(),
+            source: pixel_digest(image),
+            blocks: classify_blocks(image),
+        }
+    }
+
+    /// Whether this analysis was computed from `image`.
+    pub fn is_for(&self, image: &RgbImage) -> bool {
+        image.dimensions() == (self.width, self.height) && pixel_digest(image) == self.source
+    }
+
+    /// [`BlockSelection::mask`] of the analyzed image.
+    pub fn mask(&self, selection: BlockSelection) -> Vec<bool> {
+        selection.mask_with(self.width, self.height, &self.blocks)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(Kind::Analysis);
+        writer.u32(self.width);
+        writer.u32(self.height);
+        writer.bytes(&self.source);
+        writer.u32(self.blocks.len() as u32);
+        for class in &self.blocks {
+            writer.bytes(&[match class {
+                BlockClass::Smooth => 0,
+                BlockClass::Edge => 1,
+                BlockClass::Texture => 2,
+            }]);
+        }
+
+        writer.finish()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader::new(bytes, Kind::Analysis)?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let source = reader.digest()?;
+        let count = reader.u32()? as usize;
+        let expected = width.div_ceil(BLOCK) as u64 * height.div_ceil(BLOCK) as u64;
+        if count as u64 != expected {
+            return Err(format!(
+                "{} block classes for a {}x{} image, which has {}",
+                count, width, height, expected
+            )
+            .into());
+        }
+        let blocks = reader
+            .take(count)?
+            .iter()
+            .map(|&class| match class {
+                0 => Ok(BlockClass::Smooth),
+                1 => Ok(BlockClass::Edge),
+                2 => Ok(BlockClass::Texture),
+                _ => Err(format!("Invalid block class {}", class)),
+            })
+            .collect::<std::result::Result<_, _>>()?;
+        reader.end()?;
+
+        Ok(Analysis {
+            width,
+            height,
+            source,
+            blocks,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
+
+/// SHA-256 of the RGB pixels of `image`, with its dimensions.
+pub(crate) fn pixel_digest(image: &RgbImage) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(image.width().to_le_bytes())
+        .chain_update(image.height().to_le_bytes())
+        .chain_update(image.as_raw())
+        .finalize()
+        .into()
+}
+
+/// Builds an artifact file, starting with its header.
+pub(crate) struct Writer(Vec<u8>);
+
+impl Writer {
+    pub(crate) fn new(kind: Kind) -> Self {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([kind as u8, VERSION]);
+        Writer(bytes)
+    }
+
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    pub(crate) fn u32(&mut self, value: u32) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    pub(crate) fn f32s(&mut self, values: &[f32]) {
+        self.u32(values.len() as u32);
+        for value in values {
+            self.0.extend(value.to_le_bytes());
+        }
+    }
+
+    pub(crate) fn finish(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+/// Reads an artifact file after checking its header.
+pub(crate) struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], kind: Kind) -> Result<Self> {
+        let header = bytes.get(..6).ok_or("Not an lf-watermark artifact")?;
+        if &header[..4] != MAGIC {
+            return Err("Not an lf-watermark artifact".into());
+        }
+        if header[4] != kind as u8 {
+            return Err(format!(
+                "Artifact of kind {} where {:?} was expected",
+                header[4], kind
+            )
+            .into());
+        }
+        if header[5] != VERSION {
+            return Err(format!("Unsupported artifact version {}", header[5]).into());
+        }
+
+        Ok(Reader(&bytes[6..]))
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.0.len() {
+            return Err("Truncated artifact".into());
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().expect("four bytes")))
+    }
+
+    pub(crate) fn digest(&mut self) -> Result<[u8; 32]> {
+        Ok(self.take(32)?.try_into().expect("32 bytes"))
+    }
+
+    pub(crate) fn f32s(&mut self) -> Result<Vec<f32>> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len.checked_mul(4).ok_or("Truncated artifact")?)?;
+        Ok(bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().expect("four bytes")))
+            .collect())
+    }
+
+    /// Fails unless everything was read.
+    pub(crate) fn end(&self) -> Result<()> {
+        if !self.0.is_empty() {
+            return Err(format!("{} trailing bytes in artifact", self.0.len()).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::test_utils::grain;
+
+    fn image() -> RgbImage {
+        RgbImage::from_fn(36, 20, |x, y| {
+            let level = if x < 16 { 120 } else { grain(x, y, 97) as u8 };
+            Rgb([level; 3])
+        })
+    }
+
+    #[test]
+    fn test_analysis_roundtrip() {
+        let image = image();
+        let analysis = Analysis::new(&image);
+        assert_eq!(analysis.blocks.len(), 5 * 3);
+        assert!(analysis.is_for(&image));
+        assert_eq!(
+            analysis.mask(BlockSelection::Texture),
+            BlockSelection::Texture.mask(&image)
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("lf-watermark-analysis-{}.lfwa", std::process::id()));
+        analysis.save(&path).unwrap();
+        let loaded = Analysis::load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), analysis);
+
+        let mut other = image.clone();
+        other.put_pixel(0, 0, Rgb([0, 0, 0]));
+        assert!(!analysis.is_for(&other));
+    }
+
+    #[test]
+    fn test_analysis_rejects_bad_files() {
+        let bytes = Analysis::new(&image()).to_bytes();
+        assert!(Analysis::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Analysis::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+        assert!(Analysis::from_bytes(b"PNG").is_err());
+
+        let mut kind = bytes.clone();
+        kind[4] = Kind::Carrier as u8;
+        assert!(Analysis::from_bytes(&kind).is_err());
+        let mut class = bytes.clone();
+        *class.last_mut().unwrap() = 7;
+        assert!(Analysis::from_bytes(&class).is_err());
+    }
+}
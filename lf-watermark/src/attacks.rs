@@ -0,0 +1,7 @@
+//! Attacks against embedded watermarks.
+//!
+//! This module exists for research: robustness claims are only meaningful when they are checked
+//! against realistic adversaries, so the attacks here are the ones someone trying to strip a mark
+//! would actually run. It is only compiled with the `attacks` feature.
+
+pub mod removal;
@@ -0,0 +1,221 @@
+//! Removal attacks: attempts to erase the mark while keeping the image usable.
+
+use std::ops::Range;
+
+use image::{DynamicImage, GenericImageView, GrayImage, Luma, Rgb, RgbImage};
+use rustdct::DctPlanner;
+
+use crate::prng::Prng;
+use crate::{forward_dct, inverse_dct, merge_ycbcr, split_ycbcr, Result};
+
+/// Re-randomizes the luma DCT coefficients in `band`.
+///
+/// Each coefficient is scaled by a random factor in `[1 - strength, 1 + strength]`, which is what
+/// an adversary does when they know roughly where the mark lives but not its exact values.
+pub fn rerandomize_coefficients(
+    image: &DynamicImage,
+    band: Range<usize>,
+    strength: f32,
+    seed: u64,
+) -> Result<RgbImage> {
+    let (width, height) = image.dimensions();
+    let (mut y_channel, cbcr_channel) = split_ycbcr(&image.to_rgb8());
+
+    if band.is_empty() || band.end > y_channel.len() {
+        return Err(format!(
+            "Invalid coefficient band; {:?} for {} coefficients",
+            band,
+            y_channel.len()
+        )
+        .into());
+    }
+
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    let mut rng = Prng::new(seed);
+    for c in y_channel[band].iter_mut() {
+        *c *= 1.0 + strength * (rng.next_f32() * 2.0 - 1.0);
+    }
+
+    inverse_dct(&mut dct_planner, &mut y_channel);
+
+    Ok(merge_ycbcr(width, height, &y_channel, &cbcr_channel))
+}
+
+/// Averages several copies of the same image pixel by pixel.
+///
+/// This is the classic collusion attack: differently-marked copies of one master tend to cancel
+/// each other's marks when averaged.
+pub fn average(images: &[DynamicImage]) -> Result<RgbImage> {
+    let first = images.first().ok_or("No images to average")?;
+    let (width, height) = first.dimensions();
+
+    let mut sums = vec![[0.0f64; 3]; width as usize * height as usize];
+    for image in images {
+        if image.dimensions() != (width, height) {
+            return Err(format!(
+                "Image dimensions differ; {:?} != {:?}",
+                image.dimensions(),
+                (width, height)
+            )
+            .into());
+        }
+
+        for (idx, pixel) in image.to_rgb8().pixels().enumerate() {
+            for c in 0..3 {
+                sums[idx][c] += pixel[c] as f64;
+            }
+        }
+    }
+
+    let n = images.len() as f64;
+    let mut img_buffer = RgbImage::new(width, height);
+    for (idx, pixel) in img_buffer.pixels_mut().enumerate() {
+        let [r, g, b] = sums[idx];
+        *pixel = Rgb([
+            (r / n).round() as u8,
+            (g / n).round() as u8,
+            (b / n).round() as u8,
+        ]);
+    }
+
+    Ok(img_buffer)
+}
+
+/// Builds a mask of random square patches covering roughly `coverage` of the image.
+///
+/// Intended as input to [`inpaint`]; non-zero pixels are the ones to be reconstructed.
+pub fn random_patch_mask(
+    width: u32,
+    height: u32,
+    patch: u32,
+    coverage: f32,
+    seed: u64,
+) -> Result<GrayImage> {
+    if patch == 0 || patch > width || patch > height {
+        return Err(format!("Invalid patch size; {}", patch).into());
+    }
+
+    let mut mask = GrayImage::new(width, height);
+    let patches =
+        ((width as f64 * height as f64) as f32 * coverage / (patch * patch) as f32).ceil() as u32;
+    let mut rng = Prng::new(seed);
+
+    for _ in 0..patches {
+        let x0 = rng.next_below((width - patch + 1) as u64) as u32;
+        let y0 = rng.next_below((height - patch + 1) as u64) as u32;
+        for y in y0..y0 + patch {
+            for x in x0..x0 + patch {
+                mask.put_pixel(x, y, Luma([255]));
+            }
+        }
+    }
+
+    Ok(mask)
+}
+
+/// Reconstructs masked pixels from their surroundings by iterative diffusion.
+///
+/// Every masked pixel is repeatedly replaced by the mean of its 4-neighbours, so the content
+/// inside each region is regenerated from its border and whatever the mark contributed there is
+/// lost.
+pub fn inpaint(image: &DynamicImage, mask: &GrayImage, iterations: usize) -> Result<RgbImage> {
+    let (width, height) = image.dimensions();
+    if mask.dimensions() != (width, height) {
+        return Err(format!(
+            "Mask dimensions differ; {:?} != {:?}",
+            mask.dimensions(),
+            (width, height)
+        )
+        .into());
+    }
+
+    let idx_fn = |x: u32, y: u32| y as usize * width as usize + x as usize;
+    let mut pixels: Vec<[f32; 3]> = image
+        .to_rgb8()
+        .pixels()
+        .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+        .collect();
+    let masked: Vec<(u32, u32)> = mask
+        .enumerate_pixels()
+        .filter(|(_, _, m)| m[0] != 0)
+        .map(|(x, y, _)| (x, y))
+        .collect();
+
+    for _ in 0..iterations {
+        for &(x, y) in masked.iter() {
+            let mut sum = [0.0; 3];
+            let mut n = 0.0;
+            let neighbours = [
+                (x.wrapping_sub(1), y),
+                (x + 1, y),
+                (x, y.wrapping_sub(1)),
+                (x, y + 1),
+            ];
+            for (nx, ny) in neighbours {
+                if nx < width && ny < height {
+                    let p = pixels[idx_fn(nx, ny)];
+                    for c in 0..3 {
+                        sum[c] += p[c];
+                    }
+                    n += 1.0;
+                }
+            }
+
+            let idx = idx_fn(x, y);
+            for c in 0..3 {
+                pixels[idx][c] = sum[c] / n;
+            }
+        }
+    }
+
+    let mut img_buffer = RgbImage::new(width, height);
+    for (idx, pixel) in img_buffer.pixels_mut().enumerate() {
+        let [r, g, b] = pixels[idx];
+        *pixel = Rgb([r.round() as u8, g.round() as u8, b.round() as u8]);
+    }
+
+    Ok(img_buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, 128])
+        }))
+    }
+
+    #[test]
+    fn test_rerandomize_coefficients() {
+        let img = gradient(32, 32);
+        let attacked = rerandomize_coefficients(&img, 1..64, 0.5, 1).unwrap();
+        assert_ne!(img.to_rgb8(), attacked);
+
+        assert!(rerandomize_coefficients(&img, 0..2048, 0.5, 1).is_err());
+    }
+
+    #[test]
+    fn test_average() {
+        let black = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([0, 0, 0])));
+        let white = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([255, 255, 255])));
+        let averaged = average(&[black, white]).unwrap();
+        assert_eq!(averaged.get_pixel(0, 0), &Rgb([128, 128, 128]));
+
+        assert!(average(&[]).is_err());
+    }
+
+    #[test]
+    fn test_inpaint() {
+        let mut img = RgbImage::from_pixel(16, 16, Rgb([100, 100, 100]));
+        img.put_pixel(8, 8, Rgb([255, 0, 0]));
+        let mut mask = GrayImage::new(16, 16);
+        mask.put_pixel(8, 8, Luma([255]));
+
+        let inpainted = inpaint(&DynamicImage::ImageRgb8(img), &mask, 4).unwrap();
+        assert_eq!(inpainted.get_pixel(8, 8), &Rgb([100, 100, 100]));
+    }
+}
@@ -0,0 +1,326 @@
+//! Audit records of embedding operations.
+//!
+//! Compliance teams need to show which asset was marked with which identity, and when. Every
+//! embed through [`embed_encoded_audited`] produces an [`AuditRecord`] and hands it to an
+//! [`AuditSink`]; where the records end up is up to the sink.
+//!
+//! A record only proves when the mark existed if a third party vouches for its time.
+//! [`embed_encoded_timestamped`] also has the SHA-256 of the output stamped by a
+//! [`Timestamper`], usually an RFC 3161 timestamping authority, and stores the returned token in
+//! the record. The crate does no networking itself: [`rfc3161_request`] builds the request, and
+//! the caller's timestamper posts it with its own HTTP client.
+
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::codec::embed_watermark_encoded;
+use crate::{Result, WatermarkConfig};
+
+/// What one embedding operation did.
+///
+/// The payload itself is not recorded, only its fingerprint, so the log does not become a list
+/// of customer identities. The hashes are plain SHA-256 of the encoded bytes, in hex, so they can
+/// be checked with `sha256sum`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuditRecord {
+    /// Seconds since the Unix epoch at which the operation finished.
+    pub timestamp: u64,
+    pub input_sha256: String,
+    pub output_sha256: String,
+    pub payload_sha256: String,
+    pub config: WatermarkConfig,
+    pub duration_micros: u64,
+    /// Token the [`Timestamper`] returned for the output's SHA-256, in hex; for an RFC 3161
+    /// authority, the DER-encoded `TimeStampResp`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub timestamp_token: Option<String>,
+}
+
+/// Destination of audit records.
+pub trait AuditSink {
+    fn record(&self, record: &AuditRecord) -> Result<()>;
+}
+
+/// Has a digest stamped with a trusted time.
+///
+/// Closures taking the SHA-256 of the output and returning the token implement it, so a
+/// timestamper can be as small as posting [`rfc3161_request`] to an authority:
+///
+/// ```ignore
+/// let tsa = |digest: &[u8; 32]| -> Result<Vec<u8>> {
+///     let request = rfc3161_request(digest, Some(rand::random()));
+///     http.post("https://freetsa.org/tsr", "application/timestamp-query", &request)
+/// };
+/// ```
+pub trait Timestamper {
+    fn timestamp(&self, sha256: &[u8; 32]) -> Result<Vec<u8>>;
+}
+
+impl<F: Fn(&[u8; 32]) -> Result<Vec<u8>>> Timestamper for F {
+    fn timestamp(&self, sha256: &[u8; 32]) -> Result<Vec<u8>> {
+        self(sha256)
+    }
+}
+
+/// DER-encoded RFC 3161 `TimeStampReq` for a SHA-256 digest, asking for the authority's
+/// certificate in the response. A `nonce` lets the caller match the response to the request.
+pub fn rfc3161_request(sha256: &[u8; 32], nonce: Option<u64>) -> Vec<u8> {
+    // id-sha256, 2.16.840.1.101.3.4.2.1.
+    const SHA256_OID: [u8; 9] = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+
+    let algorithm = der(0x30, &[der(0x06, &SHA256_OID), der(0x05, &[])].concat());
+    let imprint = der(0x30, &[algorithm, der(0x04, sha256)].concat());
+    let mut fields = vec![der(0x02, &[1]), imprint];
+    if let Some(nonce) = nonce {
+        fields.push(der(0x02, &der_unsigned(nonce)));
+    }
+    fields.push(der(0x01, &[0xff]));
+
+    der(0x30, &fields.concat())
+}
+
+/// A DER element; every length here fits the short or one-byte long form.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut element = vec![tag];
+    if content.len() < 0x80 {
+        element.push(content.len() as u8);
+    } else {
+        element.extend([0x81, content.len() as u8]);
+    }
+    element.extend_from_slice(content);
+    element
+}
+
+/// Minimal big-endian bytes of a non-negative INTEGER.
+fn der_unsigned(value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let start = bytes.iter().position(|&b| b != 0).unwrap_or(7);
+    let mut content = bytes[start..].to_vec();
+    // A set top bit would make the integer negative.
+    if content[0] & 0x80 != 0 {
+        content.insert(0, 0);
+    }
+    content
+}
+
+/// Keeps records in memory, for tests and for callers that forward them in batches.
+#[derive(Debug, Default)]
+pub struct MemorySink {
+    records: Mutex<Vec<AuditRecord>>,
+}
+
+impl MemorySink {
+    pub fn records(&self) -> Vec<AuditRecord> {
+        self.records
+            .lock()
+            .map(|records| records.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl AuditSink for MemorySink {
+    fn record(&self, record: &AuditRecord) -> Result<()> {
+        self.records
+            .lock()
+            .map_err(|_| "Audit sink poisoned")?
+            .push(record.clone());
+
+        Ok(())
+    }
+}
+
+/// Writes one JSON object per line, the usual input format of log shippers.
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct JsonLinesSink<W: std::io::Write> {
+    writer: Mutex<W>,
+}
+
+#[cfg(feature = "serde")]
+impl<W: std::io::Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<W: std::io::Write> AuditSink for JsonLinesSink<W> {
+    fn record(&self, record: &AuditRecord) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|_| "Audit sink poisoned")?;
+        serde_json::to_writer(&mut *writer, record)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// [`embed_watermark_encoded`] followed by an audit record sent to `sink`.
+///
+/// A sink error fails the call even though the output was produced, so that no marked asset
+/// leaves without its record.
+pub fn embed_encoded_audited(
+    input: &[u8],
+    watermark: &str,
+    config: &WatermarkConfig,
+    sink: &dyn AuditSink,
+) -> Result<Vec<u8>> {
+    embed_and_record(input, watermark, config, sink, None)
+}
+
+/// [`embed_encoded_audited`] with the output's SHA-256 stamped by `timestamper` and the token
+/// stored in the record.
+///
+/// A timestamper error fails the call like a sink error, so no record goes without its token.
+pub fn embed_encoded_timestamped(
+    input: &[u8],
+    watermark: &str,
+    config: &WatermarkConfig,
+    sink: &dyn AuditSink,
+    timestamper: &dyn Timestamper,
+) -> Result<Vec<u8>> {
+    embed_and_record(input, watermark, config, sink, Some(timestamper))
+}
+
+fn embed_and_record(
+    input: &[u8],
+    watermark: &str,
+    config: &WatermarkConfig,
+    sink: &dyn AuditSink,
+    timestamper: Option<&dyn Timestamper>,
+) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let output = embed_watermark_encoded(input, watermark, config)?;
+    let duration = start.elapsed();
+
+    let output_digest: [u8; 32] = Sha256::digest(&output).into();
+    let timestamp_token = match timestamper {
+        Some(timestamper) => Some(hex(&timestamper.timestamp(&output_digest)?)),
+        None => None,
+    };
+    sink.record(&AuditRecord {
+        timestamp: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        input_sha256: sha256_hex(input),
+        output_sha256: hex(&output_digest),
+        payload_sha256: sha256_hex(watermark.as_bytes()),
+        config: config.clone(),
+        duration_micros: duration.as_micros() as u64,
+        timestamp_token,
+    })?;
+
+    Ok(output)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex(&Sha256::digest(data))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    use super::*;
+
+    fn png() -> Vec<u8> {
+        let img = RgbImage::from_fn(8, 8, |x, y| Rgb([(x * 32) as u8, (y * 32) as u8, 0]));
+        let mut input = Vec::new();
+        img.write_to(&mut Cursor::new(&mut input), ImageFormat::Png)
+            .unwrap();
+        input
+    }
+
+    #[test]
+    fn test_embed_encoded_audited() {
+        let input = png();
+        let sink = MemorySink::default();
+        let config = WatermarkConfig::default();
+        let output = embed_encoded_audited(&input, "Hello", &config, &sink).unwrap();
+
+        let records = sink.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].input_sha256, sha256_hex(&input));
+        assert_eq!(records[0].output_sha256, sha256_hex(&output));
+        assert_eq!(
+            records[0].payload_sha256,
+            "185f8db32271fe25f561a6fc938b2e264306ec304eda518007d1764826381969"
+        );
+        assert_eq!(records[0].config, config);
+        assert_eq!(records[0].timestamp_token, None);
+    }
+
+    #[test]
+    fn test_embed_encoded_timestamped() {
+        let input = png();
+        let sink = MemorySink::default();
+        let stamped = Mutex::new(Vec::new());
+        let timestamper = |digest: &[u8; 32]| -> Result<Vec<u8>> {
+            stamped.lock().unwrap().push(*digest);
+            Ok(vec![0x30, 0x03, 0x02, 0x01, 0x00])
+        };
+        let output = embed_encoded_timestamped(
+            &input,
+            "Hello",
+            &WatermarkConfig::default(),
+            &sink,
+            &timestamper,
+        )
+        .unwrap();
+
+        let records = sink.records();
+        assert_eq!(records[0].timestamp_token.as_deref(), Some("3003020100"));
+        assert_eq!(hex(&stamped.lock().unwrap()[0]), sha256_hex(&output));
+
+        let failing = |_: &[u8; 32]| -> Result<Vec<u8>> { Err("Authority unreachable".into()) };
+        let config = WatermarkConfig::default();
+        assert!(embed_encoded_timestamped(&input, "Hello", &config, &sink, &failing).is_err());
+        assert_eq!(sink.records().len(), 1);
+    }
+
+    #[test]
+    fn test_rfc3161_request() {
+        let digest = [0xab; 32];
+        let request = rfc3161_request(&digest, Some(0x80));
+        let mut expected = vec![
+            0x30, 0x3d, 0x02, 0x01, 0x01, 0x30, 0x31, 0x30, 0x0d, 0x06, 0x09,
+        ];
+        expected.extend([
+            0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+        ]);
+        expected.extend([0x04, 0x20]);
+        expected.extend(digest);
+        expected.extend([0x02, 0x02, 0x00, 0x80, 0x01, 0x01, 0xff]);
+        assert_eq!(request, expected);
+
+        let without_nonce = rfc3161_request(&digest, None);
+        assert_eq!(without_nonce.len(), request.len() - 4);
+        assert_eq!(without_nonce[1] as usize, without_nonce.len() - 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_lines_sink() {
+        let sink = JsonLinesSink::new(Vec::new());
+        let config = WatermarkConfig::default();
+        embed_encoded_audited(&png(), "Hello", &config, &sink).unwrap();
+        embed_encoded_audited(&png(), "World", &config, &sink).unwrap();
+
+        let log = String::from_utf8(sink.writer.into_inner().unwrap()).unwrap();
+        let records: Vec<AuditRecord> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].config, config);
+    }
+}
@@ -0,0 +1,206 @@
+//! Embedding many images at once on a bounded rayon thread pool.
+//!
+//! A watermarker living inside a web server must not take every core for a catalog import.
+//! [`Threads`] decides where a batch runs: on rayon's global pool, on a pool capped to a number
+//! of threads, or on a pool the caller already owns and budgets for.
+
+use std::num::NonZeroUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use image::{DynamicImage, RgbImage};
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::{Embedder, Result, WatermarkConfig};
+
+/// Where the work of a batch runs.
+#[derive(Debug, Clone, Default)]
+pub enum Threads {
+    /// Rayon's global pool, one thread per core unless `RAYON_NUM_THREADS` says otherwise.
+    #[default]
+    Global,
+    /// A dedicated pool of at most this many threads, built for each batch. Prefer
+    /// [`Threads::Pool`] when running many batches, to pay for spawning the threads only once.
+    Limit(NonZeroUsize),
+    /// The caller's own pool.
+    Pool(Arc<ThreadPool>),
+}
+
+impl Threads {
+    /// Runs `op` inside the selected pool, so rayon iterators in it use that pool's threads.
+    pub fn install<R: Send>(&self, op: impl FnOnce() -> R + Send) -> Result<R> {
+        match self {
+            Threads::Global => Ok(op()),
+            Threads::Limit(threads) => Ok(ThreadPoolBuilder::new()
+                .num_threads(threads.get())
+                .build()?
+                .install(op)),
+            Threads::Pool(pool) => Ok(pool.install(op)),
+        }
+    }
+}
+
+/// Embeds `watermark` into every image of `images`, in parallel on `threads`.
+///
+/// Each worker thread reuses one [`Embedder`], so batches of same-sized images allocate little
+/// beyond their outputs. The results are in input order, and a failing image does not stop the
+/// others.
+pub fn embed_batch(
+    images: &[DynamicImage],
+    watermark: &str,
+    config: &WatermarkConfig,
+    threads: &Threads,
+) -> Result<Vec<Result<RgbImage>>> {
+    embed_batch_with_progress(images, watermark, config, threads, |_| {})
+}
+
+/// An image of a batch that has finished, passed to the callback of
+/// [`embed_batch_with_progress`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress<'a> {
+    /// Position of the image in the batch.
+    pub index: usize,
+    /// Images finished so far, this one included.
+    pub done: usize,
+    pub total: usize,
+    /// Why the image could not be marked.
+    pub error: Option<&'a str>,
+}
+
+impl Progress<'_> {
+    /// Share of the batch finished, from 0 to 1.
+    pub fn fraction(&self) -> f64 {
+        self.done as f64 / self.total as f64
+    }
+}
+
+/// [`embed_batch`], calling `on_progress` as each image finishes.
+///
+/// Images finish in any order, and the callback runs on the worker thread that marked the image,
+/// so a UI should hand the updates over to its own thread. `done` counts up by one with each
+/// call.
+pub fn embed_batch_with_progress(
+    images: &[DynamicImage],
+    watermark: &str,
+    config: &WatermarkConfig,
+    threads: &Threads,
+    on_progress: impl Fn(Progress) + Sync,
+) -> Result<Vec<Result<RgbImage>>> {
+    let done = AtomicUsize::new(0);
+    let results: Vec<std::result::Result<RgbImage, String>> = threads.install(|| {
+        images
+            .par_iter()
+            .enumerate()
+            .map_init(
+                || Embedder::new(config.clone()),
+                |embedder, (index, image)| {
+                    let result = embedder
+                        .embed(image, watermark)
+                        .map_err(|err| err.to_string());
+                    on_progress(Progress {
+                        index,
+                        done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                        total: images.len(),
+                        error: result.as_ref().err().map(String::as_str),
+                    });
+                    result
+                },
+            )
+            .collect()
+    })?;
+
+    Ok(results
+        .into_iter()
+        .map(|result| result.map_err(|err| err.into()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::embed_watermark_with_config;
+
+    #[test]
+    fn test_embed_batch() {
+        let images: Vec<DynamicImage> = (0..6)
+            .map(|i| {
+                DynamicImage::ImageRgb8(RgbImage::from_fn(16, 8 + i, |x, y| {
+                    Rgb([(x * 16) as u8, (y * 8) as u8, (i * 40) as u8])
+                }))
+            })
+            .collect();
+        let config = WatermarkConfig::default();
+
+        let pool = Arc::new(ThreadPoolBuilder::new().num_threads(2).build().unwrap());
+        for threads in [
+            Threads::Global,
+            Threads::Limit(NonZeroUsize::new(1).unwrap()),
+            Threads::Pool(pool),
+        ] {
+            let marked = embed_batch(&images, "Hello", &config, &threads).unwrap();
+            for (image, marked) in images.iter().zip(marked) {
+                let expected = embed_watermark_with_config(image, "Hello", &config).unwrap();
+                assert_eq!(marked.unwrap(), expected);
+            }
+        }
+
+        let marked = embed_batch(&images, "Hello\u{1F600}", &config, &Threads::Global).unwrap();
+        assert!(marked.iter().all(|result| result.is_err()));
+    }
+
+    #[test]
+    fn test_embed_batch_with_progress() {
+        let images: Vec<DynamicImage> = (0..5)
+            .map(|i| DynamicImage::ImageRgb8(RgbImage::from_pixel(16, 8 + i, Rgb([90, 120, 150]))))
+            .collect();
+        let updates = std::sync::Mutex::new(Vec::new());
+        let marked = embed_batch_with_progress(
+            &images,
+            "Hello",
+            &WatermarkConfig::default(),
+            &Threads::Limit(NonZeroUsize::new(2).unwrap()),
+            |progress| {
+                assert_eq!(progress.total, 5);
+                assert!(progress.error.is_none());
+                updates
+                    .lock()
+                    .unwrap()
+                    .push((progress.index, progress.done, progress.fraction()));
+            },
+        )
+        .unwrap();
+        assert!(marked.iter().all(|result| result.is_ok()));
+
+        let mut updates = updates.into_inner().unwrap();
+        updates.sort_by_key(|&(_, done, _)| done);
+        let done: Vec<usize> = updates.iter().map(|&(_, done, _)| done).collect();
+        assert_eq!(done, [1, 2, 3, 4, 5]);
+        assert_eq!(updates[4].2, 1.0);
+        let mut indices: Vec<usize> = updates.iter().map(|&(index, _, _)| index).collect();
+        indices.sort();
+        assert_eq!(indices, [0, 1, 2, 3, 4]);
+
+        let errors = AtomicUsize::new(0);
+        embed_batch_with_progress(
+            &images[..2],
+            "Hello\u{1F600}",
+            &WatermarkConfig::default(),
+            &Threads::Global,
+            |progress| {
+                assert!(progress.error.is_some());
+                errors.fetch_add(1, Ordering::Relaxed);
+            },
+        )
+        .unwrap();
+        assert_eq!(errors.into_inner(), 2);
+    }
+
+    #[test]
+    fn test_threads_limit() {
+        let threads = Threads::Limit(NonZeroUsize::new(3).unwrap());
+        assert_eq!(threads.install(rayon::current_num_threads).unwrap(), 3);
+    }
+}
@@ -0,0 +1,308 @@
+//! Classification of 8x8 luma blocks by local content.
+//!
+//! Changes hide best in texture: flat areas show every level of noise, and edges make a ringing
+//! halo out of it. Embedders that work on pixels can restrict themselves to texture blocks. The
+//! classification only looks at the image, so a detector that runs it on the image under test
+//! selects the same blocks without the original; blocks close to a threshold may flip, which
+//! costs a little detection strength but no false alarms.
+
+use image::RgbImage;
+
+/// Side of a block in pixels. Blocks at the right and bottom border may be smaller, see
+/// [`Padding`].
+pub const BLOCK: u32 = 8;
+
+/// Luma variance below which a block counts as smooth.
+const SMOOTH_VARIANCE: f64 = 25.0;
+/// Gradient coherence above which a block counts as an edge: 0 for gradients in all directions,
+/// 1 for gradients that all point the same way.
+const EDGE_COHERENCE: f64 = 0.6;
+
+/// Content class of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockClass {
+    /// Nearly flat; any change is visible.
+    Smooth,
+    /// Dominated by one oriented structure.
+    Edge,
+    /// Busy in every direction; changes are masked.
+    Texture,
+}
+
+/// Which blocks a pixel-domain embedder may change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockSelection {
+    /// Every pixel of the image.
+    All,
+    /// Only pixels of [`BlockClass::Texture`] blocks.
+    #[default]
+    Texture,
+}
+
+impl BlockSelection {
+    /// Whether each pixel of `image`, in raster order, is selected.
+    pub fn mask(&self, image: &RgbImage) -> Vec<bool> {
+        let (width, height) = image.dimensions();
+        match self {
+            BlockSelection::All => vec![true; width as usize * height as usize],
+            BlockSelection::Texture => self.mask_with(width, height, &classify_blocks(image)),
+        }
+    }
+
+    /// [`BlockSelection::mask`] of a `width` x `height` image whose blocks were classified
+    /// before, as [`classify_blocks`] returns them.
+    pub fn mask_with(&self, width: u32, height: u32, classes: &[BlockClass]) -> Vec<bool> {
+        let columns = width.div_ceil(BLOCK) as usize;
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| match self {
+                BlockSelection::All => true,
+                BlockSelection::Texture => {
+                    let block = (y / BLOCK) as usize * columns + (x / BLOCK) as usize;
+                    classes[block] == BlockClass::Texture
+                }
+            })
+            .collect()
+    }
+}
+
+/// How [`classify_blocks_with`] fills the part of a window that falls outside the image, as the
+/// partial blocks at the right and bottom border do when a dimension is not a multiple of
+/// [`BLOCK`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// Leaves it out, so a border block is judged on the pixels it has. A block one pixel wide
+    /// has no horizontal gradient and always counts as an edge.
+    #[default]
+    Crop,
+    /// Reflects the image at its border, which keeps the local texture.
+    Mirror,
+    /// Repeats the border pixel, which flattens the padded part.
+    Replicate,
+    /// Fills in black, which makes a step at the border that reads as an edge.
+    Zero,
+}
+
+/// Window layout of [`classify_blocks_with`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockLayout {
+    pub padding: Padding,
+    /// Judges each block on a window overlapping its neighbours by half a block on every side,
+    /// weighted with a Hann window, so content straddling a block seam counts on both sides and
+    /// the classes change less abruptly from block to block.
+    pub overlap: bool,
+}
+
+/// Classes of the blocks of `image`, row by row.
+pub fn classify_blocks(image: &RgbImage) -> Vec<BlockClass> {
+    classify_blocks_with(image, &BlockLayout::default())
+}
+
+/// [`classify_blocks`] with the border and overlap handling of `layout`. A detector has to use
+/// the layout the embedder used.
+pub fn classify_blocks_with(image: &RgbImage, layout: &BlockLayout) -> Vec<BlockClass> {
+    let (width, height) = image.dimensions();
+    let luma: Vec<f64> = image.pixels().map(|p| luma(&p.0)).collect();
+    let (w, h) = (width as i64, height as i64);
+    let reflect = |v: i64, len: i64| {
+        let period = 2 * len;
+        let v = v.rem_euclid(period);
+        if v < len {
+            v
+        } else {
+            period - 1 - v
+        }
+    };
+    // The luma at any position, or `None` where the padding leaves it out.
+    let at = |x: i64, y: i64| -> Option<f64> {
+        let inside = (0..w).contains(&x) && (0..h).contains(&y);
+        let (x, y) = match layout.padding {
+            _ if inside => (x, y),
+            Padding::Crop => return None,
+            Padding::Mirror => (reflect(x, w), reflect(y, h)),
+            Padding::Replicate => (x.clamp(0, w - 1), y.clamp(0, h - 1)),
+            Padding::Zero => return Some(0.0),
+        };
+        Some(luma[y as usize * width as usize + x as usize])
+    };
+
+    let margin = if layout.overlap { BLOCK as i64 / 2 } else { 0 };
+    let size = BLOCK as i64 + 2 * margin;
+    let weight = |i: i64| {
+        if layout.overlap {
+            (std::f64::consts::PI * (i as f64 + 0.5) / size as f64)
+                .sin()
+                .powi(2)
+        } else {
+            1.0
+        }
+    };
+
+    let mut classes = Vec::new();
+    for by in (0..h).step_by(BLOCK as usize) {
+        for bx in (0..w).step_by(BLOCK as usize) {
+            // A block without padding ends at the border.
+            let (x0, y0) = (bx - margin, by - margin);
+            let (x1, y1) = match layout.padding {
+                Padding::Crop => ((x0 + size).min(w), (y0 + size).min(h)),
+                _ => (x0 + size, y0 + size),
+            };
+            let window: Vec<(i64, i64, f64)> = (y0..y1)
+                .flat_map(|y| (x0..x1).map(move |x| (x, y)))
+                .filter_map(|(x, y)| at(x, y).map(|s| (x, y, s)))
+                .collect();
+            let total: f64 = window
+                .iter()
+                .map(|&(x, y, _)| weight(x - x0) * weight(y - y0))
+                .sum();
+            let mean = window
+                .iter()
+                .map(|&(x, y, s)| weight(x - x0) * weight(y - y0) * s)
+                .sum::<f64>()
+                / total;
+            let variance = window
+                .iter()
+                .map(|&(x, y, s)| weight(x - x0) * weight(y - y0) * (s - mean).powi(2))
+                .sum::<f64>()
+                / total;
+            if variance < SMOOTH_VARIANCE {
+                classes.push(BlockClass::Smooth);
+                continue;
+            }
+
+            // Structure tensor of the forward differences inside the window.
+            let (mut jxx, mut jyy, mut jxy) = (0.0, 0.0, 0.0);
+            for &(x, y, s) in &window {
+                let gx = match at(x + 1, y) {
+                    Some(next) if x + 1 < x1 => next - s,
+                    _ => 0.0,
+                };
+                let gy = match at(x, y + 1) {
+                    Some(next) if y + 1 < y1 => next - s,
+                    _ => 0.0,
+                };
+                let weight = weight(x - x0) * weight(y - y0);
+                jxx += weight * gx * gx;
+                jyy += weight * gy * gy;
+                jxy += weight * gx * gy;
+            }
+            let energy = jxx + jyy;
+            let coherence = if energy > 0.0 {
+                ((jxx - jyy).powi(2) + 4.0 * jxy * jxy).sqrt() / energy
+            } else {
+                0.0
+            };
+            classes.push(if coherence > EDGE_COHERENCE {
+                BlockClass::Edge
+            } else {
+                BlockClass::Texture
+            });
+        }
+    }
+
+    classes
+}
+
+fn luma(rgb: &[u8; 3]) -> f64 {
+    0.299 * rgb[0] as f64 + 0.587 * rgb[1] as f64 + 0.114 * rgb[2] as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    /// Hashed, direction-free noise in `0..modulus`.
+    fn noise(x: u32, y: u32, modulus: u32) -> u8 {
+        let mut h = x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663);
+        h ^= h >> 13;
+        h = h.wrapping_mul(0x5bd1_e995);
+        h ^= h >> 15;
+        (h % modulus) as u8
+    }
+
+    #[test]
+    fn test_classify_blocks() {
+        // Flat, a vertical step, and noise, one 8-pixel column each.
+        let image = RgbImage::from_fn(24, 8, |x, y| {
+            let level = match x / 8 {
+                0 => 100,
+                1 if x % 8 < 4 => 40,
+                1 => 200,
+                _ => noise(x, y, 97) + 60,
+            };
+            Rgb([level; 3])
+        });
+        assert_eq!(
+            classify_blocks(&image),
+            vec![BlockClass::Smooth, BlockClass::Edge, BlockClass::Texture]
+        );
+
+        let mask = BlockSelection::Texture.mask(&image);
+        assert_eq!(mask.iter().filter(|&&m| m).count(), 64);
+        assert!(mask.iter().enumerate().all(|(i, &m)| m == (i % 24 >= 16)));
+        assert!(BlockSelection::All.mask(&image).iter().all(|&m| m));
+    }
+
+    #[test]
+    fn test_partial_blocks() {
+        let image = RgbImage::from_pixel(10, 9, Rgb([50, 50, 50]));
+        assert_eq!(classify_blocks(&image), vec![BlockClass::Smooth; 4]);
+    }
+
+    #[test]
+    fn test_padding() {
+        // Noise in two blocks and a column of one pixel.
+        let image = RgbImage::from_fn(17, 16, |x, y| Rgb([noise(x, y, 97) + 60; 3]));
+        let classes = |padding| {
+            let layout = BlockLayout {
+                padding,
+                overlap: false,
+            };
+            let classes = classify_blocks_with(&image, &layout);
+            [classes[2], classes[5]]
+        };
+        assert_eq!(classes(Padding::Crop), [BlockClass::Edge; 2]);
+        assert_eq!(classes(Padding::Mirror), [BlockClass::Texture; 2]);
+        assert_eq!(classes(Padding::Replicate), [BlockClass::Edge; 2]);
+        assert_eq!(classes(Padding::Zero), [BlockClass::Edge; 2]);
+
+        let flat = RgbImage::from_pixel(10, 9, Rgb([50, 50, 50]));
+        for padding in [Padding::Crop, Padding::Mirror, Padding::Replicate] {
+            for overlap in [false, true] {
+                let layout = BlockLayout { padding, overlap };
+                let classes = classify_blocks_with(&flat, &layout);
+                assert_eq!(classes, vec![BlockClass::Smooth; 4], "{:?}", layout);
+            }
+        }
+    }
+
+    #[test]
+    fn test_overlap() {
+        let image = RgbImage::from_fn(24, 8, |x, y| {
+            let level = match x / 8 {
+                0 => 100,
+                1 if x % 8 < 4 => 40,
+                1 => 200,
+                _ => noise(x, y, 97) + 60,
+            };
+            Rgb([level; 3])
+        });
+        let layout = BlockLayout::default();
+        assert_eq!(
+            classify_blocks_with(&image, &layout),
+            classify_blocks(&image)
+        );
+
+        // The step in the middle block reaches into the flat block through the overlap.
+        let layout = BlockLayout {
+            padding: Padding::Mirror,
+            overlap: true,
+        };
+        assert_eq!(
+            classify_blocks_with(&image, &layout),
+            vec![BlockClass::Edge, BlockClass::Edge, BlockClass::Texture]
+        );
+    }
+}
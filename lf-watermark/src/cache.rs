@@ -0,0 +1,300 @@
+//! On-disk cache of embedded outputs.
+//!
+//! Catalog pipelines mark the same product image with the same owner ID over and over. Embedding
+//! is deterministic, so the output can be looked up by a hash of everything that determines it
+//! instead of being recomputed.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use sha2::{Digest, Sha256};
+
+use crate::codec::embed_watermark_encoded;
+use crate::{
+    Band, ChannelStrength, Determinism, OrientationPolicy, PalettePolicy, Result, StrengthCurve,
+    WatermarkConfig,
+};
+
+/// Distinguishes the temporary files of concurrent [`DiskCache::put`] calls within a process.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A directory of cached outputs, one file per key.
+#[derive(Debug, Clone)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    /// Opens the cache at `dir`, creating the directory if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+
+        Ok(DiskCache {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// SHA-256 over the encoded input, the payload, the config and the crate version, in hex.
+    ///
+    /// The crate version is part of the key so that a release changing the embedding math never
+    /// serves outputs produced by an older one.
+    pub fn key(input: &[u8], watermark: &str, config: &WatermarkConfig) -> String {
+        let config = config_bytes(config);
+        let mut hasher = Sha256::new();
+        for field in [
+            env!("CARGO_PKG_VERSION").as_bytes(),
+            input,
+            watermark.as_bytes(),
+            &config,
+        ] {
+            // Length-prefixed so that no two different field splits hash alike.
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field);
+        }
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(output) => Ok(Some(output)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Stores `output` under `key`. The file is written under a temporary name and renamed, so
+    /// concurrent readers never see a partial entry.
+    pub fn put(&self, key: &str, output: &[u8]) -> Result<()> {
+        let n = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp = self
+            .dir
+            .join(format!("{}.{}.{}.tmp", key, std::process::id(), n));
+        fs::write(&tmp, output)?;
+        fs::rename(&tmp, self.path(key))?;
+
+        Ok(())
+    }
+
+    /// [`embed_watermark_encoded`] through the cache: returns the stored output for this input,
+    /// payload and config if there is one, and embeds and stores it otherwise.
+    pub fn embed_encoded(
+        &self,
+        input: &[u8],
+        watermark: &str,
+        config: &WatermarkConfig,
+    ) -> Result<Vec<u8>> {
+        let key = Self::key(input, watermark, config);
+        if let Some(output) = self.get(&key)? {
+            return Ok(output);
+        }
+
+        let output = embed_watermark_encoded(input, watermark, config)?;
+        self.put(&key, &output)?;
+
+        Ok(output)
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(key)
+    }
+}
+
+/// `config` in a fixed byte layout for [`DiskCache::key`], unlike `Debug` output, which may
+/// change with any derive or field order. The matches are exhaustive on purpose: a new field or
+/// variant does not compile until it is given an encoding here.
+fn config_bytes(config: &WatermarkConfig) -> Vec<u8> {
+    let WatermarkConfig {
+        strength,
+        band,
+        curve,
+        palette,
+        orientation,
+        determinism,
+        channels,
+    } = config;
+    let mut bytes = Vec::new();
+    let u64 = |bytes: &mut Vec<u8>, value: usize| bytes.extend((value as u64).to_le_bytes());
+
+    bytes.extend(strength.to_le_bytes());
+    match band {
+        Band::Dc => bytes.push(0),
+        Band::Low => bytes.push(1),
+        Band::Mid => bytes.push(2),
+        Band::High => bytes.push(3),
+        Band::Range(range) => {
+            bytes.push(4);
+            u64(&mut bytes, range.start);
+            u64(&mut bytes, range.end);
+        }
+    }
+    match curve {
+        StrengthCurve::Flat => bytes.push(0),
+        StrengthCurve::Linear { start, end } => {
+            bytes.push(1);
+            bytes.extend(start.to_le_bytes());
+            bytes.extend(end.to_le_bytes());
+        }
+        StrengthCurve::Table(table) => {
+            bytes.push(2);
+            u64(&mut bytes, table.len());
+            for weight in table {
+                bytes.extend(weight.to_le_bytes());
+            }
+        }
+    }
+    match palette {
+        PalettePolicy::Requantize { dither } => bytes.extend([0, *dither as u8]),
+        PalettePolicy::Refuse => bytes.push(1),
+    }
+    bytes.push(match orientation {
+        OrientationPolicy::Normalize => 0,
+        OrientationPolicy::Restore => 1,
+    });
+    bytes.push(match determinism {
+        Determinism::Fast => 0,
+        Determinism::Strict => 1,
+    });
+    let ChannelStrength { y, cb, cr } = channels;
+    for strength in [y, cb, cr] {
+        bytes.extend(strength.to_le_bytes());
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_disk_cache() {
+        let dir = std::env::temp_dir().join(format!("lf-watermark-cache-{}", std::process::id()));
+        let cache = DiskCache::new(&dir).unwrap();
+
+        let img = RgbImage::from_fn(8, 8, |x, y| Rgb([(x * 32) as u8, (y * 32) as u8, 0]));
+        let mut input = Vec::new();
+        img.write_to(&mut Cursor::new(&mut input), ImageFormat::Png)
+            .unwrap();
+        let config = WatermarkConfig::default();
+
+        let key = DiskCache::key(&input, "Hello", &config);
+        assert_eq!(key.len(), 64);
+        assert_ne!(key, DiskCache::key(&input, "Hello!", &config));
+        let stronger = WatermarkConfig {
+            strength: config.strength * 2.0,
+            ..config.clone()
+        };
+        assert_ne!(key, DiskCache::key(&input, "Hello", &stronger));
+
+        assert_eq!(cache.get(&key).unwrap(), None);
+        let output = cache.embed_encoded(&input, "Hello", &config).unwrap();
+        assert_eq!(cache.get(&key).unwrap().as_ref(), Some(&output));
+
+        // A hit is served from disk without embedding again.
+        cache.put(&key, b"cached").unwrap();
+        assert_eq!(
+            cache.embed_encoded(&input, "Hello", &config).unwrap(),
+            b"cached"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_config_bytes() {
+        let config = WatermarkConfig::default();
+        let encoded = config_bytes(&config);
+        assert_eq!(encoded, config_bytes(&config.clone()));
+
+        // Every field, and the payload of every variant, changes the encoding.
+        let variants = [
+            WatermarkConfig {
+                strength: config.strength + 1.0,
+                ..config.clone()
+            },
+            WatermarkConfig {
+                band: Band::Range(1..4),
+                ..config.clone()
+            },
+            WatermarkConfig {
+                band: Band::Range(1..5),
+                ..config.clone()
+            },
+            WatermarkConfig {
+                curve: StrengthCurve::Linear {
+                    start: 1.0,
+                    end: 0.5,
+                },
+                ..config.clone()
+            },
+            WatermarkConfig {
+                curve: StrengthCurve::Table(vec![1.0, 0.5]),
+                ..config.clone()
+            },
+            WatermarkConfig {
+                curve: StrengthCurve::Table(vec![1.0, 0.25]),
+                ..config.clone()
+            },
+            WatermarkConfig {
+                palette: PalettePolicy::Refuse,
+                ..config.clone()
+            },
+            WatermarkConfig {
+                orientation: OrientationPolicy::Restore,
+                ..config.clone()
+            },
+            WatermarkConfig {
+                determinism: Determinism::Strict,
+                ..config.clone()
+            },
+            WatermarkConfig {
+                channels: ChannelStrength {
+                    cr: config.channels.cr + 1.0,
+                    ..config.channels
+                },
+                ..config.clone()
+            },
+        ];
+        let mut seen = vec![encoded];
+        for variant in &variants {
+            let encoded = config_bytes(variant);
+            assert!(!seen.contains(&encoded), "{:?}", variant);
+            seen.push(encoded);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_put() {
+        let dir = std::env::temp_dir().join(format!("lf-watermark-put-{}", std::process::id()));
+        let cache = DiskCache::new(&dir).unwrap();
+
+        // Writers of one key never share a temporary file, so every read sees a whole entry.
+        let outputs: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 64 * 1024]).collect();
+        let (cache, outputs) = (&cache, &outputs);
+        std::thread::scope(|scope| {
+            for output in outputs {
+                scope.spawn(move || {
+                    for _ in 0..8 {
+                        cache.put("key", output).unwrap();
+                        let read = cache.get("key").unwrap().unwrap();
+                        assert!(outputs.contains(&read));
+                    }
+                });
+            }
+        });
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
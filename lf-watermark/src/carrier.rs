@@ -0,0 +1,325 @@
+//! Precomputed carriers for stamping many payloads into one asset.
+//!
+//! Personalization at download time marks the same master with a different user ID per request.
+//! Most of an embed does not depend on the payload: decoding, the color conversion, the forward
+//! transform and the band or carrier selection. A carrier does that work once per asset and
+//! keeps the result, so each stamp only does what the payload changes.
+//!
+//! The additive mark is linear in the payload value, so [`Carrier`] keeps the luma and the mark
+//! of a unit value in pixel space, and a stamp is one multiply-add per pixel without any
+//! transform. The lattice mark moves each carrier coefficient depending on where it lies, so
+//! [`LatticeCarrier`] keeps the transformed luma and the keyed carriers, and a stamp quantizes the
+//! carriers and runs the inverse transform.
+//!
+//! A [`Carrier`] can be saved with the asset and loaded instead of being prepared again, see
+//! [`analysis`](crate::analysis).
+
+use std::fs;
+use std::path::Path;
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+use rustdct::DctPlanner;
+use sha2::{Digest, Sha256};
+
+use crate::analysis::{pixel_digest, Kind, Reader, Writer};
+use crate::lattice::{carriers, quantize, ScsConfig};
+use crate::{
+    forward_dct, inverse_dct, mark_coefficients, merge_ycbcr, payload_value, split_ycbcr, Result,
+    WatermarkConfig,
+};
+
+/// An asset prepared for [`embed_watermark_with_config`](crate::embed_watermark_with_config).
+#[derive(Debug, Clone)]
+pub struct Carrier {
+    width: u32,
+    height: u32,
+    config: WatermarkConfig,
+    /// SHA-256 of the prepared image's pixels.
+    source: [u8; 32],
+    y_channel: Vec<f32>,
+    cbcr_channel: Vec<(u8, u8)>,
+    /// Luma change of a payload value of 1, including `config.channels.y`.
+    pattern: Vec<f32>,
+}
+
+impl Carrier {
+    /// Prepares `image` for stamping with `config`. The carrier marks luma only, so a config with
+    /// chroma strength is rejected, and so is strict determinism.
+    pub fn new(image: &DynamicImage, config: &WatermarkConfig) -> Result<Self> {
+        let (width, height) = image.dimensions();
+        let image = image.to_rgb8();
+        let (y_channel, cbcr_channel) = split_ycbcr(&image);
+
+        let mut pattern = vec![0.0; y_channel.len()];
+        mark_coefficients(&mut pattern, 1.0, config)?;
+        let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+        inverse_dct(&mut dct_planner, &mut pattern);
+
+        Ok(Carrier {
+            width,
+            height,
+            config: config.clone(),
+            source: pixel_digest(&image),
+            y_channel,
+            cbcr_channel,
+            pattern,
+        })
+    }
+
+    /// Loads a carrier saved with [`Carrier::to_bytes`], which must have been prepared with
+    /// `config`.
+    pub fn from_bytes(bytes: &[u8], config: &WatermarkConfig) -> Result<Self> {
+        let mut reader = Reader::new(bytes, Kind::Carrier)?;
+        let width = reader.u32()?;
+        let height = reader.u32()?;
+        let source = reader.digest()?;
+        if reader.digest()? != config_digest(config) {
+            return Err("The carrier was prepared with another config".into());
+        }
+        let y_channel = reader.f32s()?;
+        let len = y_channel.len();
+        if len as u64 != width as u64 * height as u64 {
+            return Err(format!("{} luma samples for a {}x{} image", len, width, height).into());
+        }
+        let cbcr_channel = reader
+            .take(len * 2)?
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect();
+        let pattern = reader.f32s()?;
+        if pattern.len() != len {
+            return Err(format!("{} pattern samples for {} pixels", pattern.len(), len).into());
+        }
+        reader.end()?;
+
+        Ok(Carrier {
+            width,
+            height,
+            config: config.clone(),
+            source,
+            y_channel,
+            cbcr_channel,
+            pattern,
+        })
+    }
+
+    /// The carrier in the format of [`analysis`](crate::analysis). The config is not stored, only
+    /// a SHA-256 of it, so [`Carrier::from_bytes`] can refuse another one.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(Kind::Carrier);
+        writer.u32(self.width);
+        writer.u32(self.height);
+        writer.bytes(&self.source);
+        writer.bytes(&config_digest(&self.config));
+        writer.f32s(&self.y_channel);
+        let cbcr: Vec<u8> = self
+            .cbcr_channel
+            .iter()
+            .flat_map(|&(cb, cr)| [cb, cr])
+            .collect();
+        writer.bytes(&cbcr);
+        writer.f32s(&self.pattern);
+
+        writer.finish()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        Ok(fs::write(path, self.to_bytes())?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P, config: &WatermarkConfig) -> Result<Self> {
+        Self::from_bytes(&fs::read(path)?, config)
+    }
+
+    /// Whether this carrier was prepared from `image`.
+    pub fn is_for(&self, image: &RgbImage) -> bool {
+        image.dimensions() == (self.width, self.height) && pixel_digest(image) == self.source
+    }
+
+    pub fn config(&self) -> &WatermarkConfig {
+        &self.config
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// The asset marked with `watermark`, the same as
+    /// [`embed_watermark_with_config`](crate::embed_watermark_with_config) up to float rounding.
+    pub fn stamp(&self, watermark: &str) -> Result<RgbImage> {
+        let value = payload_value(watermark)? * self.config.strength;
+        let y_channel: Vec<f32> = self
+            .y_channel
+            .iter()
+            .zip(&self.pattern)
+            .map(|(&y, &p)| y + value * p)
+            .collect();
+
+        Ok(merge_ycbcr(
+            self.width,
+            self.height,
+            &y_channel,
+            &self.cbcr_channel,
+        ))
+    }
+}
+
+/// SHA-256 of the config's debug form, which covers every field.
+fn config_digest(config: &WatermarkConfig) -> [u8; 32] {
+    Sha256::digest(format!("{:?}", config).as_bytes()).into()
+}
+
+/// An asset prepared for [`embed_scs`](crate::lattice::embed_scs) of a fixed number of bits
+/// under one key.
+#[derive(Debug, Clone)]
+pub struct LatticeCarrier {
+    width: u32,
+    height: u32,
+    config: ScsConfig,
+    coefficients: Vec<f32>,
+    cbcr_channel: Vec<(u8, u8)>,
+    carriers: Vec<(usize, f32)>,
+}
+
+impl LatticeCarrier {
+    /// Prepares `image` for stamping `len` bits under `key` with `config`.
+    pub fn new(image: &DynamicImage, len: usize, key: &[u8], config: &ScsConfig) -> Result<Self> {
+        let (width, height) = image.dimensions();
+        let carriers = carriers(width, height, len, key, config)?;
+
+        let (mut coefficients, cbcr_channel) = split_ycbcr(&image.to_rgb8());
+        let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+        forward_dct(&mut dct_planner, &mut coefficients);
+
+        Ok(LatticeCarrier {
+            width,
+            height,
+            config: config.clone(),
+            coefficients,
+            cbcr_channel,
+            carriers,
+        })
+    }
+
+    pub fn config(&self) -> &ScsConfig {
+        &self.config
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Number of bits a stamp carries.
+    pub fn len(&self) -> usize {
+        self.carriers.len() / self.config.repetition
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.carriers.is_empty()
+    }
+
+    /// The asset marked with `bits`, the same as [`embed_scs`](crate::lattice::embed_scs).
+    pub fn stamp(&self, bits: &[bool]) -> Result<RgbImage> {
+        if bits.len() != self.len() {
+            return Err(format!(
+                "The carrier was prepared for {} bits, not {}",
+                self.len(),
+                bits.len()
+            )
+            .into());
+        }
+
+        let mut y_channel = self.coefficients.clone();
+        quantize(&mut y_channel, &self.carriers, bits, &self.config);
+        let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+        inverse_dct(&mut dct_planner, &mut y_channel);
+
+        Ok(merge_ycbcr(
+            self.width,
+            self.height,
+            &y_channel,
+            &self.cbcr_channel,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lattice::embed_scs;
+    use crate::test_utils::grainy;
+    use crate::{embed_watermark_with_config, Band, ChannelStrength};
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(grainy(128, 96, 41))
+    }
+
+    #[test]
+    fn test_carrier_matches_embed() {
+        let config = WatermarkConfig {
+            strength: 0.1,
+            band: Band::Low,
+            ..Default::default()
+        };
+        let carrier = Carrier::new(&image(), &config).unwrap();
+        assert_eq!(carrier.dimensions(), (128, 96));
+
+        for user in ["User A", "User B"] {
+            let stamped = carrier.stamp(user).unwrap();
+            let embedded = embed_watermark_with_config(&image(), user, &config).unwrap();
+            for (a, b) in stamped.pixels().zip(embedded.pixels()) {
+                for c in 0..3 {
+                    assert!(a[c].abs_diff(b[c]) <= 1, "{:?} {:?}", a, b);
+                }
+            }
+        }
+        assert_ne!(
+            carrier.stamp("User A").unwrap(),
+            carrier.stamp("User B").unwrap()
+        );
+
+        let chroma = WatermarkConfig {
+            channels: ChannelStrength {
+                cb: 1.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(Carrier::new(&image(), &chroma).is_err());
+    }
+
+    #[test]
+    fn test_carrier_bytes() {
+        let config = WatermarkConfig {
+            band: Band::Low,
+            ..Default::default()
+        };
+        let carrier = Carrier::new(&image(), &config).unwrap();
+        let bytes = carrier.to_bytes();
+        let loaded = Carrier::from_bytes(&bytes, &config).unwrap();
+        assert!(loaded.is_for(&image().to_rgb8()));
+        assert_eq!(
+            loaded.stamp("Hello").unwrap(),
+            carrier.stamp("Hello").unwrap()
+        );
+
+        assert!(Carrier::from_bytes(&bytes, &WatermarkConfig::default()).is_err());
+        assert!(Carrier::from_bytes(&bytes[..bytes.len() - 4], &config).is_err());
+        assert!(!carrier.is_for(&RgbImage::new(128, 96)));
+    }
+
+    #[test]
+    fn test_lattice_carrier_matches_embed_scs() {
+        let config = ScsConfig::default();
+        let carrier = LatticeCarrier::new(&image(), 32, b"key", &config).unwrap();
+        assert_eq!(carrier.len(), 32);
+
+        let bits: Vec<bool> = (0..32).map(|i| i % 3 == 0).collect();
+        assert_eq!(
+            carrier.stamp(&bits).unwrap(),
+            embed_scs(&image(), &bits, b"key", &config).unwrap()
+        );
+        assert!(carrier.stamp(&bits[..8]).is_err());
+        assert!(LatticeCarrier::new(&image(), 1000, b"key", &config).is_err());
+    }
+}
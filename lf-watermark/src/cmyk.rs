@@ -0,0 +1,188 @@
+//! CMYK support for print workflows.
+//!
+//! `image` converts everything to RGB, which would lose the color separations a pre-press
+//! workflow depends on. This module reads and writes 8-bit CMYK TIFFs directly and embeds without
+//! ever leaving CMYK.
+
+use std::borrow::Cow;
+use std::io::{Read, Seek, Write};
+
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::encoder::{colortype, TiffEncoder, TiffValue};
+use tiff::tags::{Tag, Type};
+use tiff::ColorType;
+
+use crate::{add_to_band, payload_value, Result, WatermarkConfig};
+
+const ICC_PROFILE_TAG: u16 = 34675;
+
+/// 8-bit CMYK image with its embedded ICC profile, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmykImage {
+    pub width: u32,
+    pub height: u32,
+    /// Interleaved `C, M, Y, K` samples, `0` meaning no ink.
+    pub data: Vec<u8>,
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl CmykImage {
+    pub fn new(width: u32, height: u32, data: Vec<u8>) -> Result<Self> {
+        let expected = width as usize * height as usize * 4;
+        if data.len() != expected {
+            return Err(format!(
+                "Invalid buffer size; expected {}, got {}",
+                expected,
+                data.len()
+            )
+            .into());
+        }
+
+        Ok(CmykImage {
+            width,
+            height,
+            data,
+            icc_profile: None,
+        })
+    }
+
+    /// Decodes an 8-bit CMYK TIFF, keeping its ICC profile.
+    pub fn read_tiff<R: Read + Seek>(reader: R) -> Result<Self> {
+        let mut decoder = Decoder::new(reader)?;
+        let color_type = decoder.colortype()?;
+        if color_type != ColorType::CMYK(8) {
+            return Err(format!("Unsupported TIFF color type; {:?}", color_type).into());
+        }
+
+        let (width, height) = decoder.dimensions()?;
+        let icc_profile = match decoder.find_tag(Tag::Unknown(ICC_PROFILE_TAG))? {
+            Some(value) => Some(value.into_u8_vec()?),
+            None => None,
+        };
+        let data = match decoder.read_image()? {
+            DecodingResult::U8(data) => data,
+            _ => return Err("Unsupported TIFF sample format".into()),
+        };
+
+        let mut image = CmykImage::new(width, height, data)?;
+        image.icc_profile = icc_profile;
+
+        Ok(image)
+    }
+
+    /// Encodes as an uncompressed 8-bit CMYK TIFF, writing the ICC profile back if present.
+    pub fn write_tiff<W: Write + Seek>(&self, writer: W) -> Result<()> {
+        let mut encoder = TiffEncoder::new(writer)?;
+        let mut image = encoder.new_image::<colortype::CMYK8>(self.width, self.height)?;
+        if let Some(icc_profile) = &self.icc_profile {
+            image
+                .encoder()
+                .write_tag(Tag::Unknown(ICC_PROFILE_TAG), IccProfile(icc_profile))?;
+        }
+        image.write_data(&self.data)?;
+
+        Ok(())
+    }
+}
+
+/// ICC profiles are stored as `UNDEFINED` rather than `BYTE`, which is what `[u8]` would write.
+struct IccProfile<'a>(&'a [u8]);
+
+impl TiffValue for IccProfile<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: Type = Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
+
+/// Embeds `watermark` into a CMYK image without converting it to RGB.
+///
+/// The mark is computed on a luminance equivalent of the naive CMYK-to-RGB conversion, and each
+/// pixel's luminance change is then applied as an equal amount of C, M and Y ink. The change is
+/// therefore neutral in hue, the K plate is left untouched and the ICC profile is carried over,
+/// so the separation the file was prepared with survives.
+pub fn embed_watermark_cmyk(
+    image: &CmykImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<CmykImage> {
+    let watermark = payload_value(watermark)? * config.strength;
+
+    let luma = |p: &[u8]| {
+        let k = 255.0 - p[3] as f32;
+        let rgb = [0, 1, 2].map(|c| (255.0 - p[c] as f32) * k / 255.0);
+        0.299 * rgb[0] + 0.587 * rgb[1] + 0.114 * rgb[2]
+    };
+
+    let original: Vec<f32> = image.data.chunks_exact(4).map(luma).collect();
+    let mut y_channel = original.clone();
+    add_to_band(&mut y_channel, watermark, config)?;
+
+    let mut marked = image.clone();
+    for (idx, pixel) in marked.data.chunks_exact_mut(4).enumerate() {
+        let k = 255.0 - pixel[3] as f32;
+        if k <= 0.0 {
+            // Full black ink: there is no CMY headroom that would change the result.
+            continue;
+        }
+
+        // Raising every RGB channel by `delta` takes `delta * 255 / (255 - K)` less of C, M, Y.
+        let ink = (y_channel[idx] - original[idx]) * 255.0 / k;
+        for c in pixel[..3].iter_mut() {
+            *c = (*c as f32 - ink).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    Ok(marked)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn sample() -> CmykImage {
+        let data = (0..16 * 16)
+            .flat_map(|i| [(i % 16 * 8) as u8, 100, (i / 16 * 8) as u8, 40])
+            .collect();
+        let mut image = CmykImage::new(16, 16, data).unwrap();
+        image.icc_profile = Some(b"fake icc profile".to_vec());
+        image
+    }
+
+    #[test]
+    fn test_cmyk_tiff_roundtrip() {
+        let image = sample();
+        let mut buffer = Cursor::new(Vec::new());
+        image.write_tiff(&mut buffer).unwrap();
+
+        buffer.set_position(0);
+        assert_eq!(CmykImage::read_tiff(buffer).unwrap(), image);
+    }
+
+    #[test]
+    fn test_embed_watermark_cmyk() {
+        let image = sample();
+        let config = WatermarkConfig {
+            strength: 0.01,
+            ..Default::default()
+        };
+        let marked = embed_watermark_cmyk(&image, "Hello, World!", &config).unwrap();
+
+        assert_eq!(marked.icc_profile, image.icc_profile);
+        for (a, b) in image.data.chunks(4).zip(marked.data.chunks(4)) {
+            // K untouched, an equal amount of C, M and Y removed to brighten.
+            assert_eq!(a[3], b[3]);
+            assert_eq!(a[1] - b[1], 6, "{:?} {:?}", a, b);
+        }
+
+        assert!(CmykImage::new(2, 2, vec![0; 15]).is_err());
+    }
+}
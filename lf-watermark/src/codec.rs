@@ -0,0 +1,710 @@
+//! Embedding straight from and to encoded files.
+
+use std::io::Cursor;
+use std::time::Instant;
+
+use color_quant::NeuQuant;
+use image::codecs::gif::GifEncoder;
+use image::{imageops, DynamicImage, Frame, ImageFormat, RgbImage, RgbaImage};
+use jpeg_encoder::SamplingFactor;
+
+use crate::orientation::{
+    apply_orientation, exif_segment, insert_segment, read_orientation, reset_orientation,
+    undo_orientation,
+};
+use crate::{
+    embed_watermark_with_config, split_ycbcr, telemetry, OrientationPolicy, PalettePolicy, Result,
+    WatermarkConfig,
+};
+
+/// NeuQuant sampling factor: 1 is best quality, 30 fastest. 10 is the usual trade-off.
+const QUANT_SAMPLE_FACTOR: i32 = 10;
+
+/// Below this share of the mark surviving an encode, [`embed_and_encode`] warns.
+const MIN_RETENTION: f32 = 0.5;
+
+/// Quality `image`'s JPEG encoder uses, which re-encoded JPEG inputs keep.
+const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+/// Chroma subsampling of JPEG output. The mark lives in luma, so this mostly trades file size
+/// against color fidelity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    Yuv444,
+    Yuv422,
+    Yuv420,
+}
+
+/// Output encoding of [`encode`] and [`embed_and_encode`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Tiff,
+    /// `quality` is 1 to 100.
+    Jpeg {
+        quality: u8,
+        subsampling: ChromaSubsampling,
+    },
+    /// Like [`OutputFormat::Jpeg`], in progressive scans that web browsers show coarse-to-fine.
+    ProgressiveJpeg {
+        quality: u8,
+        subsampling: ChromaSubsampling,
+    },
+}
+
+/// Coding process of a JPEG, from its start-of-frame marker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JpegMode {
+    /// Sequential DCT, baseline or extended.
+    Baseline,
+    Progressive,
+    /// Predictive lossless coding, used in medical and archival imaging.
+    Lossless,
+}
+
+/// Coding process and luma subsampling of a JPEG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JpegInfo {
+    pub mode: JpegMode,
+    pub subsampling: ChromaSubsampling,
+}
+
+impl JpegInfo {
+    /// Reads the start-of-frame segment of `jpeg`. Returns `None` if there is none, or if the
+    /// file uses arithmetic coding or hierarchical frames, which no decoder here supports.
+    pub fn read(jpeg: &[u8]) -> Option<Self> {
+        if !jpeg.starts_with(&[0xFF, 0xD8]) {
+            return None;
+        }
+
+        let mut pos = 2;
+        while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+            let marker = jpeg[pos + 1];
+            let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+            let mode = match marker {
+                0xC0 | 0xC1 => JpegMode::Baseline,
+                0xC2 => JpegMode::Progressive,
+                0xC3 => JpegMode::Lossless,
+                0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF | 0xDA => return None,
+                _ => {
+                    pos += 2 + len;
+                    continue;
+                }
+            };
+
+            // Component count at 9, then the first component's sampling factors at 11.
+            let factors = *jpeg.get(pos + 11)?;
+            let subsampling = match (*jpeg.get(pos + 9)?, factors >> 4, factors & 0x0F) {
+                (1, _, _) | (_, 1, 1) => ChromaSubsampling::Yuv444,
+                (_, 2, 1) => ChromaSubsampling::Yuv422,
+                _ => ChromaSubsampling::Yuv420,
+            };
+            return Some(JpegInfo { mode, subsampling });
+        }
+
+        None
+    }
+
+    /// Output format that re-emits an image in this JPEG's coding process at `quality`.
+    /// Lossless JPEGs cannot be written, so they are an error rather than quietly becoming lossy.
+    pub fn output_format(&self, quality: u8) -> Result<OutputFormat> {
+        let subsampling = self.subsampling;
+        match self.mode {
+            JpegMode::Baseline => Ok(OutputFormat::Jpeg {
+                quality,
+                subsampling,
+            }),
+            JpegMode::Progressive => Ok(OutputFormat::ProgressiveJpeg {
+                quality,
+                subsampling,
+            }),
+            JpegMode::Lossless => Err(
+                "Lossless JPEG cannot be re-encoded losslessly; convert it to PNG or TIFF".into(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodeWarning {
+    /// Only `retention` (1.0 being all of it) of the embedded luma change is left after encoding,
+    /// so extraction from the output is unlikely to work.
+    MarkAttenuated { retention: f32 },
+}
+
+/// Result of [`embed_and_encode`].
+#[derive(Debug, Clone)]
+pub struct Encoded {
+    pub bytes: Vec<u8>,
+    /// Share of the embedded luma change that survived encoding, measured by decoding the output.
+    pub retention: f32,
+    pub warnings: Vec<EncodeWarning>,
+}
+
+/// Encodes `image` with explicit encoder settings.
+pub fn encode(image: &RgbImage, format: OutputFormat) -> Result<Vec<u8>> {
+    stage!("encode", format = ?format);
+    let mut output = Vec::new();
+    match format {
+        OutputFormat::Png => {
+            image.write_to(&mut Cursor::new(&mut output), ImageFormat::Png)?;
+        }
+        OutputFormat::Tiff => {
+            image.write_to(&mut Cursor::new(&mut output), ImageFormat::Tiff)?;
+        }
+        OutputFormat::Jpeg {
+            quality,
+            subsampling,
+        }
+        | OutputFormat::ProgressiveJpeg {
+            quality,
+            subsampling,
+        } => {
+            let width = u16::try_from(image.width()).map_err(|_| "Image too wide for JPEG")?;
+            let height = u16::try_from(image.height()).map_err(|_| "Image too tall for JPEG")?;
+
+            let mut encoder = jpeg_encoder::Encoder::new(&mut output, quality.clamp(1, 100));
+            encoder.set_sampling_factor(match subsampling {
+                ChromaSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+                ChromaSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+                ChromaSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+            });
+            encoder.set_progressive(matches!(format, OutputFormat::ProgressiveJpeg { .. }));
+            encoder.encode(image.as_raw(), width, height, jpeg_encoder::ColorType::Rgb)?;
+        }
+    }
+
+    Ok(output)
+}
+
+/// Embeds `watermark` and encodes the result, checking that the encoder kept the mark.
+///
+/// The output is decoded again and the luma change it carries is compared to the one that was
+/// embedded. A low [`Encoded::retention`], typically from low JPEG quality combined with a high
+/// [`Band`](crate::Band), produces an [`EncodeWarning::MarkAttenuated`].
+pub fn embed_and_encode(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+    format: OutputFormat,
+) -> Result<Encoded> {
+    let marked = embed_watermark_with_config(image, watermark, config)?;
+    let bytes = encode(&marked, format)?;
+    let decoded = {
+        stage!("decode", bytes = bytes.len());
+        image::load_from_memory(&bytes)?
+    };
+
+    let (original, _) = split_ycbcr(&image.to_rgb8());
+    let (embedded, _) = split_ycbcr(&marked);
+    let (received, _) = split_ycbcr(&decoded.to_rgb8());
+
+    let (mut projected, mut energy) = (0.0f64, 0.0f64);
+    for i in 0..original.len() {
+        let mark = (embedded[i] - original[i]) as f64;
+        projected += mark * (received[i] - original[i]) as f64;
+        energy += mark * mark;
+    }
+    let retention = if energy > 0.0 {
+        (projected / energy) as f32
+    } else {
+        1.0
+    };
+
+    let mut warnings = Vec::new();
+    if retention < MIN_RETENTION {
+        warnings.push(EncodeWarning::MarkAttenuated { retention });
+    }
+
+    Ok(Encoded {
+        bytes,
+        retention,
+        warnings,
+    })
+}
+
+/// Normalization of decoded inputs before they are marked, for services that take uploads of
+/// any size and want one call from upload to delivery.
+///
+/// Orientation is normalized according to [`WatermarkConfig::orientation`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preprocess {
+    /// Downscales inputs whose longer side exceeds this many pixels, keeping the aspect ratio.
+    /// Smaller inputs are never upscaled.
+    pub max_dimension: Option<u32>,
+    pub filter: imageops::FilterType,
+    /// Drops the EXIF data of JPEG inputs. When kept, its orientation tag is reset to upright
+    /// unless the orientation is restored. With [`OrientationPolicy::Restore`] the EXIF data is
+    /// always kept, since the output depends on its orientation tag. Other metadata, ICC profiles
+    /// among it, is never written.
+    pub strip_metadata: bool,
+}
+
+impl Default for Preprocess {
+    /// No resizing and stripped metadata: what [`embed_watermark_encoded`] does.
+    fn default() -> Self {
+        Preprocess {
+            max_dimension: None,
+            filter: imageops::FilterType::Lanczos3,
+            strip_metadata: true,
+        }
+    }
+}
+
+/// Decodes `input`, embeds `watermark` and re-encodes it in the input's format.
+///
+/// Palette-based inputs (indexed PNG, GIF) are handled according to `config.palette`: they are
+/// either re-quantized back to an indexed output or refused with an error. Only the first frame of
+/// an animated GIF is processed.
+///
+/// JPEG inputs with an EXIF orientation are made upright before embedding; `config.orientation`
+/// decides whether the output stays upright or is turned back and keeps the original EXIF data.
+pub fn embed_watermark_encoded(
+    input: &[u8],
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<Vec<u8>> {
+    embed_watermark_preprocessed(input, watermark, config, &Preprocess::default())
+}
+
+/// Same as [`embed_watermark_encoded`], normalizing the decoded input with `preprocess` first.
+pub fn embed_watermark_preprocessed(
+    input: &[u8],
+    watermark: &str,
+    config: &WatermarkConfig,
+    preprocess: &Preprocess,
+) -> Result<Vec<u8>> {
+    let start = Instant::now();
+    let result = embed_encoded(input, watermark, config, preprocess);
+
+    let format = image::guess_format(input)
+        .ok()
+        .and_then(|format| format.extensions_str().first().copied())
+        .unwrap_or("unknown");
+    let output_bytes = result.as_ref().ok().map(Vec::len);
+    telemetry::record_encoded(format, input.len(), output_bytes, start.elapsed());
+
+    result
+}
+
+fn embed_encoded(
+    input: &[u8],
+    watermark: &str,
+    config: &WatermarkConfig,
+    preprocess: &Preprocess,
+) -> Result<Vec<u8>> {
+    let format = image::guess_format(input)?;
+    // Progressive and lossless inputs are re-emitted as such, so they are told apart before
+    // paying for the decode.
+    let jpeg = match format {
+        ImageFormat::Jpeg => JpegInfo::read(input).filter(|info| info.mode != JpegMode::Baseline),
+        _ => None,
+    };
+    let jpeg_format = jpeg
+        .map(|info| info.output_format(DEFAULT_JPEG_QUALITY))
+        .transpose()?;
+    let image = {
+        stage!("decode", format = ?format, bytes = input.len());
+        image::load_from_memory_with_format(input, format)?
+    };
+    let orientation = match format {
+        ImageFormat::Jpeg => read_orientation(input).unwrap_or(1),
+        _ => 1,
+    };
+    let mut upright = apply_orientation(&image, orientation);
+    if let Some(max) = preprocess.max_dimension {
+        if upright.width().max(upright.height()) > max {
+            stage!("resize", max);
+            upright = upright.resize(max, max, preprocess.filter);
+        }
+    }
+    let marked = DynamicImage::ImageRgb8(embed_watermark_with_config(&upright, watermark, config)?);
+
+    stage!("encode", format = ?format);
+    if !is_indexed(input, format) {
+        let restore = config.orientation == OrientationPolicy::Restore;
+        let marked = if restore {
+            undo_orientation(&marked, orientation)
+        } else {
+            marked
+        };
+
+        let output = match jpeg_format {
+            Some(jpeg_format) => encode(&marked.to_rgb8(), jpeg_format)?,
+            None => {
+                let mut output = Cursor::new(Vec::new());
+                marked.write_to(&mut output, format)?;
+                output.into_inner()
+            }
+        };
+
+        return Ok(match exif_segment(input) {
+            Some(segment) if restore && format == ImageFormat::Jpeg => {
+                insert_segment(&output, segment)
+            }
+            Some(segment) if !preprocess.strip_metadata && format == ImageFormat::Jpeg => {
+                insert_segment(&output, &reset_orientation(segment))
+            }
+            _ => output,
+        });
+    }
+
+    let dither = match config.palette {
+        PalettePolicy::Requantize { dither } => dither,
+        PalettePolicy::Refuse => {
+            return Err(format!(
+                "Indexed-color {:?} input refused; re-encoding it would need new quantization",
+                format
+            )
+            .into())
+        }
+    };
+
+    let (palette, indices, rgba) = quantize(marked.to_rgba8(), dither);
+    let mut output = Vec::new();
+    match format {
+        ImageFormat::Gif => {
+            // The frame already has at most 256 colors, so the GIF encoder keeps them as they are.
+            let mut encoder = GifEncoder::new(&mut output);
+            encoder.encode_frame(Frame::new(rgba))?;
+        }
+        _ => {
+            let mut encoder = png::Encoder::new(&mut output, marked.width(), marked.height());
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_palette(palette);
+            encoder.write_header()?.write_image_data(&indices)?;
+        }
+    }
+
+    Ok(output)
+}
+
+/// Whether the encoded image stores palette indices rather than colors.
+pub fn is_indexed(input: &[u8], format: ImageFormat) -> bool {
+    const PNG_COLOR_TYPE_OFFSET: usize = 25;
+    const PNG_INDEXED: u8 = 3;
+
+    match format {
+        ImageFormat::Gif => true,
+        ImageFormat::Png => input.get(PNG_COLOR_TYPE_OFFSET) == Some(&PNG_INDEXED),
+        _ => false,
+    }
+}
+
+/// Returns the RGB palette, the per-pixel indices and the image with quantized colors.
+fn quantize(mut rgba: RgbaImage, dither: bool) -> (Vec<u8>, Vec<u8>, RgbaImage) {
+    let quant = NeuQuant::new(QUANT_SAMPLE_FACTOR, 256, rgba.as_raw());
+    if dither {
+        imageops::dither(&mut rgba, &quant);
+    }
+
+    let indices = imageops::index_colors(&rgba, &quant).into_raw();
+    let palette = quant.color_map_rgb();
+    for (pixel, &index) in rgba.pixels_mut().zip(indices.iter()) {
+        let i = index as usize * 3;
+        pixel.0 = [palette[i], palette[i + 1], palette[i + 2], pixel[3]];
+    }
+
+    (palette, indices, rgba)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::orientation::test_exif_segment;
+
+    fn indexed_png() -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut encoder = png::Encoder::new(&mut output, 16, 16);
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(vec![0, 0, 0, 200, 100, 50, 250, 250, 250]);
+        let indices: Vec<u8> = (0..256).map(|i| (i % 3) as u8).collect();
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&indices)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn test_embed_watermark_encoded_indexed_png() {
+        let input = indexed_png();
+        assert!(is_indexed(&input, ImageFormat::Png));
+
+        let config = WatermarkConfig::default();
+        let output = embed_watermark_encoded(&input, "Hello, World!", &config).unwrap();
+        assert!(is_indexed(&output, ImageFormat::Png));
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 16);
+
+        let config = WatermarkConfig {
+            palette: PalettePolicy::Refuse,
+            ..Default::default()
+        };
+        assert!(embed_watermark_encoded(&input, "Hello, World!", &config).is_err());
+    }
+
+    #[test]
+    fn test_embed_watermark_encoded_truecolor_png() {
+        let img = RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, 10]));
+        let mut input = Cursor::new(Vec::new());
+        img.write_to(&mut input, ImageFormat::Png).unwrap();
+        let input = input.into_inner();
+        assert!(!is_indexed(&input, ImageFormat::Png));
+
+        let config = WatermarkConfig {
+            palette: PalettePolicy::Refuse,
+            ..Default::default()
+        };
+        let output = embed_watermark_encoded(&input, "Hello, World!", &config).unwrap();
+        let expected =
+            embed_watermark_with_config(&DynamicImage::ImageRgb8(img), "Hello, World!", &config)
+                .unwrap();
+        assert_eq!(
+            image::load_from_memory(&output).unwrap().to_rgb8(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_embed_watermark_encoded_keeps_jpeg_mode() {
+        let img = RgbImage::from_fn(32, 16, |x, y| Rgb([(x * 8) as u8, (y * 16) as u8, 80]));
+        let config = WatermarkConfig::default();
+        for (mode, subsampling) in [
+            (JpegMode::Baseline, ChromaSubsampling::Yuv420),
+            (JpegMode::Progressive, ChromaSubsampling::Yuv422),
+            (JpegMode::Progressive, ChromaSubsampling::Yuv444),
+        ] {
+            let info = JpegInfo { mode, subsampling };
+            let input = encode(&img, info.output_format(90).unwrap()).unwrap();
+            assert_eq!(JpegInfo::read(&input), Some(info));
+
+            let output = embed_watermark_encoded(&input, "Hello", &config).unwrap();
+            assert_eq!(JpegInfo::read(&output).unwrap().mode, mode);
+            if mode == JpegMode::Progressive {
+                assert_eq!(JpegInfo::read(&output), Some(info));
+            }
+        }
+
+        // A lossless frame header is refused before decoding.
+        let lossless = [
+            0xFF, 0xD8, 0xFF, 0xC3, 0, 11, 8, 0, 16, 0, 32, 1, 1, 0x11, 0,
+        ];
+        let info = JpegInfo::read(&lossless).unwrap();
+        assert_eq!(info.mode, JpegMode::Lossless);
+        assert!(info.output_format(90).is_err());
+        assert!(embed_watermark_encoded(&lossless, "Hello", &config).is_err());
+    }
+
+    #[test]
+    fn test_embed_watermark_encoded_exif_orientation() {
+        let img = RgbImage::from_fn(32, 16, |x, y| Rgb([(x * 8) as u8, (y * 16) as u8, 80]));
+        let mut jpeg = Cursor::new(Vec::new());
+        img.write_to(&mut jpeg, ImageFormat::Jpeg).unwrap();
+        let input = insert_segment(&jpeg.into_inner(), &test_exif_segment(6));
+
+        let config = WatermarkConfig::default();
+        let output = embed_watermark_encoded(&input, "Hello", &config).unwrap();
+        assert_eq!(read_orientation(&output), None);
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 16);
+
+        let config = WatermarkConfig {
+            orientation: OrientationPolicy::Restore,
+            ..Default::default()
+        };
+        let output = embed_watermark_encoded(&input, "Hello", &config).unwrap();
+        assert_eq!(read_orientation(&output), Some(6));
+        assert_eq!(image::load_from_memory(&output).unwrap().width(), 32);
+    }
+
+    #[test]
+    fn test_embed_watermark_preprocessed() {
+        let img = RgbImage::from_fn(64, 32, |x, y| Rgb([(x * 4) as u8, (y * 8) as u8, 80]));
+        let mut jpeg = Cursor::new(Vec::new());
+        img.write_to(&mut jpeg, ImageFormat::Jpeg).unwrap();
+        let input = insert_segment(&jpeg.into_inner(), &test_exif_segment(6));
+
+        // Made upright (32x64), then scaled to fit 40 pixels, with the EXIF data kept upright.
+        let preprocess = Preprocess {
+            max_dimension: Some(40),
+            strip_metadata: false,
+            ..Default::default()
+        };
+        let config = WatermarkConfig::default();
+        let output = embed_watermark_preprocessed(&input, "Hello", &config, &preprocess).unwrap();
+        let decoded = image::load_from_memory(&output).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (20, 40));
+        assert_eq!(read_orientation(&output), Some(1));
+
+        let stripped = Preprocess {
+            max_dimension: Some(100),
+            ..Default::default()
+        };
+        let output = embed_watermark_preprocessed(&input, "Hello", &config, &stripped).unwrap();
+        assert_eq!(
+            output,
+            embed_watermark_encoded(&input, "Hello", &config).unwrap()
+        );
+        assert_eq!(exif_segment(&output), None);
+    }
+
+    /// Stable-toolchain companion of the fuzz targets in `fuzz/`: every truncation of valid files
+    /// must give an error or an output, never a panic.
+    #[test]
+    fn test_truncated_inputs_do_not_panic() {
+        let img = RgbImage::from_fn(16, 8, |x, y| Rgb([(x * 16) as u8, (y * 32) as u8, 80]));
+        let mut jpeg = Cursor::new(Vec::new());
+        img.write_to(&mut jpeg, ImageFormat::Jpeg).unwrap();
+        let jpeg = insert_segment(&jpeg.into_inner(), &test_exif_segment(6));
+
+        let config = WatermarkConfig {
+            orientation: OrientationPolicy::Restore,
+            ..Default::default()
+        };
+        for input in [jpeg, indexed_png()] {
+            for len in 0..input.len() {
+                let _ = embed_watermark_encoded(&input[..len], "Hello", &config);
+                let _ = read_orientation(&input[..len]);
+            }
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_tracing_stages() {
+        use std::sync::{Arc, Mutex};
+
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        #[derive(Default)]
+        struct Stages(Arc<Mutex<Vec<&'static str>>>);
+
+        impl Subscriber for Stages {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut stages = self.0.lock().unwrap();
+                stages.push(span.metadata().name());
+                Id::from_u64(stages.len() as u64)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, _: &Event<'_>) {}
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let subscriber = Stages::default();
+        let stages = subscriber.0.clone();
+        let input = indexed_png();
+        tracing::subscriber::with_default(subscriber, || {
+            embed_watermark_encoded(&input, "Hello", &WatermarkConfig::default()).unwrap();
+        });
+
+        let stages = stages.lock().unwrap();
+        for stage in ["decode", "color_conversion", "transform", "embed", "encode"] {
+            assert!(
+                stages.contains(&stage),
+                "{} missing from {:?}",
+                stage,
+                stages
+            );
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let input = indexed_png();
+        metrics::with_local_recorder(&recorder, || {
+            embed_watermark_encoded(&input, "Hello", &WatermarkConfig::default()).unwrap();
+            assert!(
+                embed_watermark_encoded(b"nope", "Hello", &WatermarkConfig::default()).is_err()
+            );
+        });
+
+        let snapshot = snapshotter.snapshot().into_hashmap();
+        let counter = |name: &str| {
+            snapshot
+                .iter()
+                .filter(|(key, _)| key.key().name() == name)
+                .map(|(_, (_, _, value))| match value {
+                    DebugValue::Counter(count) => *count,
+                    _ => 0,
+                })
+                .sum::<u64>()
+        };
+        assert_eq!(counter("lf_watermark_images_total"), 1);
+        assert_eq!(counter("lf_watermark_failures_total"), 1);
+        assert_eq!(
+            counter("lf_watermark_input_bytes_total"),
+            input.len() as u64 + 4
+        );
+    }
+
+    #[test]
+    fn test_embed_and_encode() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        let config = WatermarkConfig::default();
+
+        let jpeg = OutputFormat::Jpeg {
+            quality: 90,
+            subsampling: ChromaSubsampling::Yuv420,
+        };
+        let encoded = embed_and_encode(&img, "Hello, World!", &config, jpeg).unwrap();
+        assert_eq!(
+            image::guess_format(&encoded.bytes).unwrap(),
+            ImageFormat::Jpeg
+        );
+        assert!(encoded.warnings.is_empty(), "{:?}", encoded.warnings);
+
+        let png = embed_and_encode(&img, "Hello, World!", &config, OutputFormat::Png).unwrap();
+        assert!((png.retention - 1.0).abs() < 1e-6, "{}", png.retention);
+
+        let config = WatermarkConfig {
+            strength: 0.002,
+            band: crate::Band::High,
+            ..Default::default()
+        };
+        let jpeg = OutputFormat::Jpeg {
+            quality: 20,
+            subsampling: ChromaSubsampling::Yuv444,
+        };
+        let encoded = embed_and_encode(&img, "Hello, World!", &config, jpeg).unwrap();
+        assert!(
+            matches!(encoded.warnings[..], [EncodeWarning::MarkAttenuated { .. }]),
+            "{}",
+            encoded.retention
+        );
+    }
+
+    #[test]
+    fn test_embed_watermark_encoded_gif() {
+        let img = RgbaImage::from_fn(8, 8, |x, _| image::Rgba([(x * 30) as u8, 0, 0, 255]));
+        let mut input = Vec::new();
+        GifEncoder::new(&mut input)
+            .encode_frame(Frame::new(img))
+            .unwrap();
+
+        let config = WatermarkConfig::default();
+        let output = embed_watermark_encoded(&input, "Hello", &config).unwrap();
+        assert_eq!(image::guess_format(&output).unwrap(), ImageFormat::Gif);
+    }
+}
@@ -0,0 +1,296 @@
+//! Embedding in linear light, with the primaries of the source's color space.
+//!
+//! The default embedders add the mark to gamma-encoded luma, so the same strength is far more
+//! light in the highlights than in the shadows, and a Display P3 or Adobe RGB file gets a
+//! different mark than its sRGB conversion. [`embed_watermark_linear`] decodes the samples to
+//! linear light first, adds the mark to the relative luminance of the source's primaries and
+//! encodes the result back in the same color space, so the mark is the same amount of light
+//! whatever the source is tagged with. The output keeps the source's encoding and should be
+//! tagged with the same profile.
+
+use std::io::Cursor;
+
+use image::{DynamicImage, GenericImageView, ImageDecoder, Rgb, RgbImage};
+
+use crate::{add_to_band, payload_value, pixel_count, Result, WatermarkConfig};
+
+/// Adobe RGB (1998) transfer exponent.
+const ADOBE_GAMMA: f32 = 563.0 / 256.0;
+
+/// RGB color space of a source image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// sRGB, also assumed for untagged images.
+    #[default]
+    Srgb,
+    /// Display P3: DCI-P3 primaries with the sRGB curve and D65 white.
+    DisplayP3,
+    /// Adobe RGB (1998).
+    AdobeRgb,
+}
+
+impl ColorSpace {
+    /// Recognizes the color space of an ICC profile by its description, which is how the
+    /// standard profiles shipped by operating systems and cameras name themselves.
+    pub fn from_icc(profile: &[u8]) -> Option<Self> {
+        let description = icc_description(profile)?.to_ascii_lowercase();
+        if description.contains("p3") {
+            Some(ColorSpace::DisplayP3)
+        } else if description.contains("adobe rgb") || description.contains("adobergb") {
+            Some(ColorSpace::AdobeRgb)
+        } else if description.contains("srgb") {
+            Some(ColorSpace::Srgb)
+        } else {
+            None
+        }
+    }
+
+    /// Color space of an encoded image from its embedded ICC profile; sRGB if it has none.
+    pub fn of_encoded(input: &[u8]) -> Result<Self> {
+        let profile = match image::guess_format(input)? {
+            image::ImageFormat::Png => {
+                image::codecs::png::PngDecoder::new(Cursor::new(input))?.icc_profile()
+            }
+            image::ImageFormat::Jpeg => {
+                image::codecs::jpeg::JpegDecoder::new(Cursor::new(input))?.icc_profile()
+            }
+            image::ImageFormat::Tiff => {
+                image::codecs::tiff::TiffDecoder::new(Cursor::new(input))?.icc_profile()
+            }
+            _ => None,
+        };
+
+        match profile {
+            None => Ok(ColorSpace::Srgb),
+            Some(profile) => ColorSpace::from_icc(&profile).ok_or_else(|| {
+                "Unsupported ICC profile; expected sRGB, Display P3 or Adobe RGB".into()
+            }),
+        }
+    }
+
+    /// Linear-light value of an encoded sample in `[0, 1]`.
+    pub fn decode(self, v: f32) -> f32 {
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => {
+                if v <= 0.04045 {
+                    v / 12.92
+                } else {
+                    ((v + 0.055) / 1.055).powf(2.4)
+                }
+            }
+            ColorSpace::AdobeRgb => v.max(0.0).powf(ADOBE_GAMMA),
+        }
+    }
+
+    /// Encoded sample of a linear-light value in `[0, 1]`.
+    pub fn encode(self, v: f32) -> f32 {
+        let v = v.max(0.0);
+        match self {
+            ColorSpace::Srgb | ColorSpace::DisplayP3 => {
+                if v <= 0.0031308 {
+                    v * 12.92
+                } else {
+                    1.055 * v.powf(1.0 / 2.4) - 0.055
+                }
+            }
+            ColorSpace::AdobeRgb => v.powf(1.0 / ADOBE_GAMMA),
+        }
+    }
+
+    /// Relative luminance weights of the primaries: the Y row of the RGB to XYZ matrix.
+    pub fn luminance(self) -> [f32; 3] {
+        match self {
+            ColorSpace::Srgb => [0.2126, 0.7152, 0.0722],
+            ColorSpace::DisplayP3 => [0.2290, 0.6917, 0.0793],
+            ColorSpace::AdobeRgb => [0.2974, 0.6274, 0.0753],
+        }
+    }
+}
+
+/// Embeds `watermark` into the linear-light luminance of `image`, whose samples are encoded in
+/// `space`.
+///
+/// The mark is `strength`-scaled levels of 1/255 of the white luminance, added as a neutral change
+/// to all three linear channels, so hue and saturation stay as they are.
+pub fn embed_watermark_linear(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+    space: ColorSpace,
+) -> Result<RgbImage> {
+    let watermark = payload_value(watermark)? * config.strength;
+    let (width, height) = image.dimensions();
+    let image = image.to_rgb8();
+
+    let lut: Vec<f32> = (0..=255)
+        .map(|v| space.decode(v as f32 / 255.0) * 255.0)
+        .collect();
+    let weights = space.luminance();
+    let mut linear = Vec::with_capacity(pixel_count(width, height)?);
+    let mut luminance = Vec::with_capacity(linear.capacity());
+    for pixel in image.pixels() {
+        let rgb = pixel.0.map(|c| lut[c as usize]);
+        luminance.push(weights[0] * rgb[0] + weights[1] * rgb[1] + weights[2] * rgb[2]);
+        linear.push(rgb);
+    }
+    let original = luminance.clone();
+
+    add_to_band(&mut luminance, watermark, config)?;
+
+    // The weights sum to one, so adding the change to every channel moves the luminance by it.
+    let encode = |v: f32| (space.encode(v / 255.0) * 255.0).round().clamp(0.0, 255.0) as u8;
+    let mut img_buffer = RgbImage::new(width, height);
+    for (index, pixel) in img_buffer.pixels_mut().enumerate() {
+        let delta = luminance[index] - original[index];
+        *pixel = Rgb(linear[index].map(|c| encode(c + delta)));
+    }
+
+    Ok(img_buffer)
+}
+
+/// Description text of an ICC profile, from a v2 `desc` or a v4 `mluc` tag.
+fn icc_description(profile: &[u8]) -> Option<String> {
+    let u32_at = |at: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(
+            profile.get(at..at + 4)?.try_into().ok()?,
+        ))
+    };
+    if profile.get(36..40)? != b"acsp" {
+        return None;
+    }
+
+    let count = u32_at(128)? as usize;
+    let (offset, size) = (0..count.min(256)).find_map(|i| {
+        let entry = 132 + i * 12;
+        if profile.get(entry..entry + 4)? != b"desc" {
+            return None;
+        }
+        Some((u32_at(entry + 4)? as usize, u32_at(entry + 8)? as usize))
+    })?;
+    let tag = profile.get(offset..offset.checked_add(size)?)?;
+
+    match tag.get(..4)? {
+        b"desc" => {
+            let len = u32::from_be_bytes(tag.get(8..12)?.try_into().ok()?) as usize;
+            let text = tag.get(12..12usize.checked_add(len)?)?;
+            Some(
+                String::from_utf8_lossy(text)
+                    .trim_end_matches('\0')
+                    .to_string(),
+            )
+        }
+        b"mluc" => {
+            let record = tag.get(16..28)?;
+            let len = u32::from_be_bytes(record[4..8].try_into().ok()?) as usize;
+            let start = u32::from_be_bytes(record[8..12].try_into().ok()?) as usize;
+            let text: Vec<u16> = tag
+                .get(start..start.checked_add(len)?)?
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            Some(String::from_utf16_lossy(&text))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal ICC profile with nothing but a v4 description tag.
+    fn icc_profile(description: &str) -> Vec<u8> {
+        let text: Vec<u8> = description
+            .encode_utf16()
+            .flat_map(|c| c.to_be_bytes())
+            .collect();
+        let mut tag = b"mluc\0\0\0\0".to_vec();
+        tag.extend_from_slice(&1u32.to_be_bytes());
+        tag.extend_from_slice(&12u32.to_be_bytes());
+        tag.extend_from_slice(b"enUS");
+        tag.extend_from_slice(&(text.len() as u32).to_be_bytes());
+        tag.extend_from_slice(&28u32.to_be_bytes());
+        tag.extend_from_slice(&text);
+
+        let mut profile = vec![0; 128];
+        profile[36..40].copy_from_slice(b"acsp");
+        profile.extend_from_slice(&1u32.to_be_bytes());
+        profile.extend_from_slice(b"desc");
+        profile.extend_from_slice(&144u32.to_be_bytes());
+        profile.extend_from_slice(&(tag.len() as u32).to_be_bytes());
+        profile.extend_from_slice(&tag);
+        profile
+    }
+
+    #[test]
+    fn test_from_icc() {
+        assert_eq!(
+            ColorSpace::from_icc(&icc_profile("Display P3")),
+            Some(ColorSpace::DisplayP3)
+        );
+        assert_eq!(
+            ColorSpace::from_icc(&icc_profile("Adobe RGB (1998)")),
+            Some(ColorSpace::AdobeRgb)
+        );
+        assert_eq!(
+            ColorSpace::from_icc(&icc_profile("sRGB IEC61966-2.1")),
+            Some(ColorSpace::Srgb)
+        );
+        assert_eq!(ColorSpace::from_icc(&icc_profile("ProPhoto")), None);
+        assert_eq!(ColorSpace::from_icc(b"garbage"), None);
+
+        let untagged = crate::encode(&RgbImage::new(4, 4), crate::OutputFormat::Png).unwrap();
+        assert_eq!(ColorSpace::of_encoded(&untagged).unwrap(), ColorSpace::Srgb);
+    }
+
+    #[test]
+    fn test_transfer_roundtrip() {
+        for space in [
+            ColorSpace::Srgb,
+            ColorSpace::DisplayP3,
+            ColorSpace::AdobeRgb,
+        ] {
+            for v in 0..=255 {
+                let v = v as f32 / 255.0;
+                assert!((space.encode(space.decode(v)) - v).abs() < 1e-4);
+            }
+            assert!((space.luminance().iter().sum::<f32>() - 1.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_embed_watermark_linear() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(x * 4) as u8, (y * 5) as u8, 120])
+        }));
+        let config = WatermarkConfig {
+            strength: 0.05,
+            ..Default::default()
+        };
+
+        // The DC mark is the same amount of linear light in every pixel, so the encoded change
+        // is larger in the shadows than in the highlights.
+        let marked = embed_watermark_linear(&image, "Hello", &config, ColorSpace::Srgb).unwrap();
+        let source = image.to_rgb8();
+        let change = |x, y| {
+            let before = source
+                .get_pixel(x, y)
+                .0
+                .map(|c| ColorSpace::Srgb.decode(c as f32 / 255.0));
+            let after = marked
+                .get_pixel(x, y)
+                .0
+                .map(|c| ColorSpace::Srgb.decode(c as f32 / 255.0));
+            (0..3).map(|i| after[i] - before[i]).sum::<f32>() / 3.0
+        };
+        let (dark, light) = (change(2, 2), change(60, 44));
+        assert!(dark > 0.0 && light > 0.0);
+        assert!((dark - light).abs() < 0.3 * dark.max(light));
+
+        let adobe = embed_watermark_linear(&image, "Hello", &config, ColorSpace::AdobeRgb).unwrap();
+        assert_ne!(adobe, marked);
+        assert!(
+            embed_watermark_linear(&image, "Hello\u{1F600}", &config, ColorSpace::Srgb).is_err()
+        );
+    }
+}
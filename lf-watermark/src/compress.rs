@@ -0,0 +1,294 @@
+//! Compression of text payloads before they become bits.
+//!
+//! Bit payloads pay for every byte with capacity, and the typical ones, copyright lines, URLs and
+//! UUIDs, are mostly boilerplate. [`compress`] replaces the common parts with single bytes: words
+//! of a small built-in dictionary, runs of decimal digits two to a byte and UUIDs in 20 bytes
+//! instead of 36. Everything else, ASCII text in particular, costs one byte as before, so the
+//! output is never longer than the text except for characters outside ASCII.
+//!
+//! The output never contains a zero byte, so it survives zero padding: trailing zeros can be
+//! trimmed before [`decompress`]. Text containing NUL cannot be compressed for the same reason.
+
+use crate::Result;
+
+/// Words replaced by a single byte, from `0x80` up. Where several match, the longest wins.
+const DICTIONARY: [&str; 64] = [
+    "https://www.",
+    "http://www.",
+    "https://",
+    "http://",
+    "www.",
+    ".com/",
+    ".com",
+    ".org/",
+    ".org",
+    ".net/",
+    ".net",
+    ".io/",
+    ".io",
+    ".html",
+    ".jpg",
+    ".png",
+    "/licence/",
+    "/license/",
+    "licence",
+    "license",
+    "Licensed ",
+    "Copyright ",
+    "copyright ",
+    "(c) ",
+    "(C) ",
+    "\u{a9} ",
+    "\u{a9}",
+    "All rights reserved",
+    "All Rights Reserved",
+    "Creative Commons ",
+    "CC BY",
+    "-NC",
+    "-SA",
+    "-ND",
+    "Photo by ",
+    "photo",
+    "image",
+    "Inc.",
+    "Ltd.",
+    "LLC",
+    "GmbH",
+    " and ",
+    " by ",
+    " of ",
+    "the ",
+    "The ",
+    "ing",
+    "tion",
+    "er",
+    "re",
+    "an",
+    "on",
+    "en",
+    "in",
+    "es",
+    "st",
+    ", ",
+    ". ",
+    "/",
+    "-",
+    "_",
+    ":",
+    "?id=",
+    "&",
+];
+
+const WORD: u8 = 0x80;
+/// Followed by `n + MIN_PAIRS` bytes of two digits each, for the marker `DIGITS + n`.
+const DIGITS: u8 = 0xc0;
+const MIN_PAIRS: usize = 2;
+const MAX_PAIRS: usize = MIN_PAIRS + 0x1f;
+/// Followed by the 128 bits of a UUID in 7-bit groups.
+const UUID_LOWER: u8 = 0xe0;
+const UUID_UPPER: u8 = 0xe1;
+const UUID_GROUPS: usize = 19;
+/// Followed by one character outside ASCII in UTF-8.
+const ESCAPE: u8 = 0xff;
+
+/// `text` in the compressed form, which contains no zero byte.
+pub fn compress(text: &str) -> Result<Vec<u8>> {
+    if text.contains('\0') {
+        return Err("Payloads with NUL cannot be compressed".into());
+    }
+
+    let bytes = text.as_bytes();
+    let mut compressed = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &text[i..];
+        if let Some((marker, value)) = uuid(rest) {
+            compressed.push(marker);
+            compressed.extend((0..UUID_GROUPS).map(|g| 0x80 | (value >> (7 * g)) as u8 & 0x7f));
+            i += 36;
+            continue;
+        }
+
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let pairs = (digits / 2).min(MAX_PAIRS);
+        if pairs >= MIN_PAIRS {
+            compressed.push(DIGITS + (pairs - MIN_PAIRS) as u8);
+            for pair in rest.as_bytes()[..2 * pairs].chunks_exact(2) {
+                compressed.push(0x80 | ((pair[0] - b'0') * 10 + pair[1] - b'0'));
+            }
+            i += 2 * pairs;
+            continue;
+        }
+
+        let word = DICTIONARY
+            .iter()
+            .enumerate()
+            .filter(|(_, word)| rest.starts_with(*word))
+            .max_by_key(|(_, word)| word.len());
+        if let Some((index, word)) = word {
+            compressed.push(WORD + index as u8);
+            i += word.len();
+            continue;
+        }
+
+        let c = rest.chars().next().expect("not at the end");
+        if c.is_ascii() {
+            compressed.push(c as u8);
+        } else {
+            compressed.push(ESCAPE);
+            compressed.extend_from_slice(&rest.as_bytes()[..c.len_utf8()]);
+        }
+        i += c.len_utf8();
+    }
+
+    Ok(compressed)
+}
+
+/// The text [`compress`] made `compressed` from.
+pub fn decompress(compressed: &[u8]) -> Result<String> {
+    let mut bytes = Vec::with_capacity(compressed.len() * 2);
+    let mut i = 0;
+    while i < compressed.len() {
+        let marker = compressed[i];
+        i += 1;
+        match marker {
+            0 => return Err("Zero byte in a compressed payload".into()),
+            0x01..=0x7f => bytes.push(marker),
+            WORD..DIGITS => {
+                let word = DICTIONARY
+                    .get((marker - WORD) as usize)
+                    .ok_or_else(|| format!("Unknown word 0x{:02x}", marker))?;
+                bytes.extend_from_slice(word.as_bytes());
+            }
+            DIGITS..UUID_LOWER => {
+                let pairs = (marker - DIGITS) as usize + MIN_PAIRS;
+                let run = compressed
+                    .get(i..i + pairs)
+                    .ok_or("Truncated digits in a compressed payload")?;
+                for &pair in run {
+                    let value = pair.checked_sub(0x80).filter(|&v| v < 100);
+                    let value = value.ok_or("Invalid digits in a compressed payload")?;
+                    bytes.extend_from_slice(&[b'0' + value / 10, b'0' + value % 10]);
+                }
+                i += pairs;
+            }
+            UUID_LOWER | UUID_UPPER => {
+                let groups = compressed
+                    .get(i..i + UUID_GROUPS)
+                    .ok_or("Truncated UUID in a compressed payload")?;
+                let value = groups.iter().enumerate().fold(0u128, |value, (g, &b)| {
+                    value | ((b & 0x7f) as u128) << (7 * g)
+                });
+                let text = format!("{:032x}", value);
+                let text = format!(
+                    "{}-{}-{}-{}-{}",
+                    &text[..8],
+                    &text[8..12],
+                    &text[12..16],
+                    &text[16..20],
+                    &text[20..]
+                );
+                let text = match marker {
+                    UUID_UPPER => text.to_ascii_uppercase(),
+                    _ => text,
+                };
+                bytes.extend_from_slice(text.as_bytes());
+                i += UUID_GROUPS;
+            }
+            ESCAPE => {
+                let len = match compressed.get(i) {
+                    Some(0xc0..=0xdf) => 2,
+                    Some(0xe0..=0xef) => 3,
+                    Some(0xf0..=0xf7) => 4,
+                    _ => return Err("Invalid escape in a compressed payload".into()),
+                };
+                let c = compressed
+                    .get(i..i + len)
+                    .ok_or("Truncated escape in a compressed payload")?;
+                bytes.extend_from_slice(c);
+                i += len;
+            }
+            _ => return Err(format!("Unknown marker 0x{:02x}", marker).into()),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| "A compressed payload decodes to invalid UTF-8".into())
+}
+
+/// The marker and value of the UUID `text` starts with, if it does, in either case but not in a
+/// mix of both.
+fn uuid(text: &str) -> Option<(u8, u128)> {
+    let candidate = text.get(..36)?.as_bytes();
+    let mut value = 0u128;
+    let (mut lower, mut upper) = (false, false);
+    for (i, &b) in candidate.iter().enumerate() {
+        if matches!(i, 8 | 13 | 18 | 23) {
+            if b != b'-' {
+                return None;
+            }
+            continue;
+        }
+        lower |= b.is_ascii_lowercase();
+        upper |= b.is_ascii_uppercase();
+        value = value << 4 | (b as char).to_digit(16)? as u128;
+    }
+
+    match (lower, upper) {
+        (_, false) => Some((UUID_LOWER, value)),
+        (false, true) => Some((UUID_UPPER, value)),
+        (true, true) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let texts = [
+            "",
+            "Hello, World!",
+            "Copyright Jane Doe",
+            "\u{a9} 2026 Example Photo Ltd. All rights reserved",
+            "https://example.com/licence/1234",
+            "asset 123e4567-e89b-12d3-a456-426614174000 of 2026-10-14",
+            "ASSET 123E4567-E89B-12D3-A456-426614174000",
+            "mixed 123e4567-E89B-12d3-a456-426614174000",
+            "digits 1 12 123 12345678901234567890123456789012345678901234567890123456789012345678",
+            "Hello\u{1F600} caf\u{e9}",
+        ];
+        for text in texts {
+            let compressed = compress(text).unwrap();
+            assert!(!compressed.contains(&0), "{:?}", text);
+            assert_eq!(decompress(&compressed).unwrap(), text);
+        }
+    }
+
+    #[test]
+    fn test_compress_gains() {
+        let copyright = "\u{a9} 2026 Example Photo Ltd. All rights reserved";
+        assert!(compress(copyright).unwrap().len() < copyright.len() * 2 / 3);
+        let url = "https://www.example.com/licence/1234";
+        assert!(compress(url).unwrap().len() <= 16);
+        let uuid = "123e4567-e89b-12d3-a456-426614174000";
+        assert_eq!(compress(uuid).unwrap().len(), 20);
+        assert_eq!(compress("Hello").unwrap(), b"Hello");
+    }
+
+    #[test]
+    fn test_compress_rejects() {
+        assert!(compress("a\0b").is_err());
+        for compressed in [
+            &[0x41, 0x00][..],
+            &[0xc0, 0x81],
+            &[0xc0, 0x81, 0xff],
+            &[0xe0, 0x80],
+            &[0xff, 0x41],
+            &[0xff, 0xc3],
+            &[0xf0],
+        ] {
+            assert!(decompress(compressed).is_err(), "{:?}", compressed);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Loading [`WatermarkConfig`] from a TOML file, with environment overrides.
+//!
+//! The file holds the same fields as the struct, and every one is optional:
+//!
+//! ```toml
+//! strength = 0.05
+//! band = "Low"
+//! determinism = "Strict"
+//! channels = { y = 1.0, cb = 0.5, cr = 0.5 }
+//! ```
+//!
+//! Each field can be overridden by an environment variable named [`ENV_PREFIX`] plus the field
+//! name in upper case, such as `LF_WATERMARK_STRENGTH=0.02`. Its value is read as a TOML value,
+//! or as a string if it is none, so one file can serve every deployment and the values that
+//! differ stay out of it. Unknown keys in the file and unknown prefixed variables are errors
+//! rather than silently ignored typos, and so are prefixed variables that are not UTF-8.
+//!
+//! Only compiled with the `toml` feature.
+
+use std::ffi::OsString;
+use std::path::Path;
+
+use crate::{Result, WatermarkConfig};
+
+/// Prefix of the environment variables that override config fields.
+pub const ENV_PREFIX: &str = "LF_WATERMARK_";
+
+/// Declarative construction of a config.
+pub trait ConfigFile: Sized {
+    /// Reads the TOML file at `path` and applies the overrides from the process environment.
+    fn from_path(path: impl AsRef<Path>) -> Result<Self>;
+
+    /// Parses `text` as TOML and applies the prefixed `overrides`; other variables are ignored.
+    fn from_toml(text: &str, overrides: impl IntoIterator<Item = (String, String)>)
+        -> Result<Self>;
+}
+
+impl ConfigFile for WatermarkConfig {
+    fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}; {}", path.display(), e))?;
+
+        env_overrides(std::env::vars_os())
+            .and_then(|overrides| Self::from_toml(&text, overrides))
+            .map_err(|e| format!("Invalid config {}; {}", path.display(), e).into())
+    }
+
+    fn from_toml(
+        text: &str,
+        overrides: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self> {
+        let known = toml::Table::try_from(WatermarkConfig::default())?;
+        let mut table: toml::Table = text.parse()?;
+        if let Some(key) = table.keys().find(|key| !known.contains_key(*key)) {
+            return Err(format!("Unknown key {}", key).into());
+        }
+
+        for (name, value) in overrides {
+            let Some(field) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let key = field.to_ascii_lowercase();
+            if !known.contains_key(&key) {
+                return Err(format!("Unknown override {}", name).into());
+            }
+            table.insert(key, parse_value(&value));
+        }
+
+        Ok(table.try_into()?)
+    }
+}
+
+/// The prefixed variables of `vars`, the process environment outside tests. Other variables are
+/// skipped without being decoded, so one that is not UTF-8 does no harm unless it carries the
+/// prefix.
+fn env_overrides(
+    vars: impl IntoIterator<Item = (OsString, OsString)>,
+) -> Result<Vec<(String, String)>> {
+    vars.into_iter()
+        .filter(|(name, _)| name.as_encoded_bytes().starts_with(ENV_PREFIX.as_bytes()))
+        .map(
+            |(name, value)| match (name.into_string(), value.into_string()) {
+                (Ok(name), Ok(value)) => Ok((name, value)),
+                (Ok(name), Err(_)) => Err(format!("{} is not valid UTF-8", name).into()),
+                (Err(name), _) => Err(format!("{:?} is not valid UTF-8", name).into()),
+            },
+        )
+        .collect()
+}
+
+/// `text` as a TOML value, or as a string if it is not one.
+fn parse_value(text: &str) -> toml::Value {
+    format!("value = {}", text)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(text.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Band, Determinism};
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    const FILE: &str =
+        "strength = 0.05\nband = \"Low\"\nchannels = { y = 1.0, cb = 0.5, cr = 0.5 }\n";
+
+    #[test]
+    fn test_from_toml() {
+        let config = WatermarkConfig::from_toml(FILE, vars(&[("HOME", "/root")])).unwrap();
+        assert_eq!(config.strength, 0.05);
+        assert_eq!(config.band, Band::Low);
+        assert_eq!(config.channels.cb, 0.5);
+        assert_eq!(config.determinism, WatermarkConfig::default().determinism);
+        assert_eq!(
+            WatermarkConfig::from_toml("", vars(&[])).unwrap(),
+            WatermarkConfig::default()
+        );
+
+        let config = WatermarkConfig::from_toml(
+            FILE,
+            vars(&[
+                ("LF_WATERMARK_STRENGTH", "0.02"),
+                ("LF_WATERMARK_DETERMINISM", "Strict"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.strength, 0.02);
+        assert_eq!(config.determinism, Determinism::Strict);
+        assert_eq!(config.band, Band::Low);
+
+        assert!(WatermarkConfig::from_toml("strenght = 0.05", vars(&[])).is_err());
+        assert!(WatermarkConfig::from_toml(FILE, vars(&[("LF_WATERMARK_BAN", "Dc")])).is_err());
+        assert!(WatermarkConfig::from_toml("band = \"Wide\"", vars(&[])).is_err());
+    }
+
+    #[test]
+    fn test_from_path() {
+        let path = std::env::temp_dir().join(format!("lf-watermark-{}.toml", std::process::id()));
+        std::fs::write(&path, FILE).unwrap();
+        let config = WatermarkConfig::from_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(config.unwrap().band, Band::Low);
+
+        let error = WatermarkConfig::from_path(&path).unwrap_err().to_string();
+        assert!(error.contains("Cannot read"), "{}", error);
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let os = |name: &str, value: &str| (OsString::from(name), OsString::from(value));
+        let overrides =
+            env_overrides([os("HOME", "/root"), os("LF_WATERMARK_STRENGTH", "0.02")]).unwrap();
+        assert_eq!(overrides, vars(&[("LF_WATERMARK_STRENGTH", "0.02")]));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStringExt;
+
+            let bytes = || OsString::from_vec(b"\xff\xfe".to_vec());
+            let unrelated = env_overrides([(OsString::from("LFW_TEST_NOT_UTF8"), bytes())]);
+            assert!(unrelated.unwrap().is_empty());
+
+            let prefixed = env_overrides([(OsString::from("LF_WATERMARK_STRENGTH"), bytes())]);
+            let error = prefixed.unwrap_err().to_string();
+            assert!(error.contains("LF_WATERMARK_STRENGTH"), "{}", error);
+
+            let mut name = b"LF_WATERMARK_".to_vec();
+            name.push(0xff);
+            let prefixed = env_overrides([(OsString::from_vec(name), OsString::from("1"))]);
+            assert!(prefixed.is_err());
+        }
+    }
+}
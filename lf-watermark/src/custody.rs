@@ -0,0 +1,200 @@
+//! Chain of custody: successive marks of the parties an asset passed through.
+//!
+//! An asset often moves from a photographer to an agency to a licensee, and a dispute needs the
+//! order in which they held it, not just the last holder. Each party appends its ID to the next
+//! free slot of a [lattice](crate::lattice) mark: the slots use disjoint coefficients, so adding
+//! a slot leaves the earlier ones readable. Every slot carries a keyed check value, which is how
+//! the reader tells filled slots from empty ones. All parties of a chain share the key.
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+use rustdct::DctPlanner;
+use sha2::{Digest, Sha256};
+
+use crate::lattice::{carriers, llrs, quantize, ScsConfig};
+use crate::{forward_dct, inverse_dct, merge_ycbcr, split_ycbcr, Result};
+
+/// Bytes of the check value of a slot.
+const CHECK_LEN: usize = 2;
+
+/// Layout of the chain, which must be the same for every party and the reader.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CustodyConfig {
+    /// Number of parties the chain can hold.
+    pub slots: usize,
+    /// Longest party ID in bytes; shorter IDs are padded.
+    pub id_len: usize,
+    /// Lattice the slots are embedded with.
+    pub scs: ScsConfig,
+}
+
+impl Default for CustodyConfig {
+    fn default() -> Self {
+        CustodyConfig {
+            slots: 4,
+            id_len: 8,
+            scs: ScsConfig::default(),
+        }
+    }
+}
+
+impl CustodyConfig {
+    fn slot_bits(&self) -> usize {
+        8 * (self.id_len + CHECK_LEN)
+    }
+}
+
+/// Appends `party` to the chain of `image` and returns the marked image.
+pub fn append_custody(
+    image: &DynamicImage,
+    party: &str,
+    key: &[u8],
+    config: &CustodyConfig,
+) -> Result<RgbImage> {
+    if party.is_empty() || party.len() > config.id_len || party.contains('\0') {
+        return Err(format!(
+            "Party IDs must be 1 to {} bytes without NUL, got {:?}",
+            config.id_len, party
+        )
+        .into());
+    }
+
+    let (width, height) = image.dimensions();
+    let carriers = chain_carriers(width, height, key, config)?;
+    let (mut y_channel, cbcr_channel) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    let slot = read_slots(&y_channel, &carriers, key, config).len();
+    if slot == config.slots {
+        return Err(format!("All {} custody slots are taken", config.slots).into());
+    }
+
+    let mut id = party.as_bytes().to_vec();
+    id.resize(config.id_len, 0);
+    id.extend_from_slice(&check(key, slot, &id));
+    let bits: Vec<bool> = id
+        .iter()
+        .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+        .collect();
+
+    let per_slot = config.slot_bits() * config.scs.repetition;
+    let slot_carriers = &carriers[slot * per_slot..(slot + 1) * per_slot];
+    quantize(&mut y_channel, slot_carriers, &bits, &config.scs);
+    inverse_dct(&mut dct_planner, &mut y_channel);
+
+    Ok(merge_ycbcr(width, height, &y_channel, &cbcr_channel))
+}
+
+/// Reads the chain of `image`, earliest party first.
+pub fn read_custody(
+    image: &DynamicImage,
+    key: &[u8],
+    config: &CustodyConfig,
+) -> Result<Vec<String>> {
+    let (width, height) = image.dimensions();
+    let carriers = chain_carriers(width, height, key, config)?;
+    let (mut y_channel, _) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    Ok(read_slots(&y_channel, &carriers, key, config))
+}
+
+/// Carriers of all slots, slot by slot.
+fn chain_carriers(
+    width: u32,
+    height: u32,
+    key: &[u8],
+    config: &CustodyConfig,
+) -> Result<Vec<(usize, f32)>> {
+    if config.slots == 0 || config.id_len == 0 {
+        return Err("A chain needs at least one slot and one byte per ID".into());
+    }
+    let bits = config.slots * config.slot_bits();
+
+    carriers(width, height, bits, key, &config.scs)
+}
+
+/// IDs of the filled slots, up to the first empty or damaged one.
+fn read_slots(
+    coefficients: &[f32],
+    carriers: &[(usize, f32)],
+    key: &[u8],
+    config: &CustodyConfig,
+) -> Vec<String> {
+    let per_slot = config.slot_bits() * config.scs.repetition;
+    let mut chain = Vec::new();
+    for (slot, slot_carriers) in carriers.chunks_exact(per_slot).enumerate() {
+        let bytes: Vec<u8> = llrs(coefficients, slot_carriers, &config.scs)
+            .chunks_exact(8)
+            .map(|byte| {
+                byte.iter()
+                    .fold(0, |acc, &llr| acc << 1 | (llr > 0.0) as u8)
+            })
+            .collect();
+        let (id, stored) = bytes.split_at(config.id_len);
+        if stored != check(key, slot, id) {
+            break;
+        }
+        let id = id.iter().position(|&b| b == 0).map_or(id, |end| &id[..end]);
+        chain.push(String::from_utf8_lossy(id).into_owned());
+    }
+
+    chain
+}
+
+/// Keyed check value binding an ID to its slot, so a slot cannot be copied into another.
+fn check(key: &[u8], slot: usize, id: &[u8]) -> [u8; CHECK_LEN] {
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update((slot as u32).to_le_bytes());
+    hasher.update(id);
+    let digest = hasher.finalize();
+
+    [digest[0], digest[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::psnr;
+    use crate::test_utils::grainy;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(grainy(192, 160, 41))
+    }
+
+    #[test]
+    fn test_custody_chain() {
+        let config = CustodyConfig::default();
+        let image = image();
+        assert!(read_custody(&image, b"key", &config).unwrap().is_empty());
+
+        let mut marked = image.clone();
+        for party in ["studio", "agency", "client"] {
+            marked =
+                DynamicImage::ImageRgb8(append_custody(&marked, party, b"key", &config).unwrap());
+        }
+        assert!(psnr(&image, &marked).unwrap() > 35.0);
+        assert_eq!(
+            read_custody(&marked, b"key", &config).unwrap(),
+            ["studio", "agency", "client"]
+        );
+        assert!(read_custody(&marked, b"other", &config).unwrap().is_empty());
+
+        let full = append_custody(&marked, "buyer", b"key", &config).unwrap();
+        let full = DynamicImage::ImageRgb8(full);
+        assert_eq!(read_custody(&full, b"key", &config).unwrap().len(), 4);
+        assert!(append_custody(&full, "late", b"key", &config).is_err());
+    }
+
+    #[test]
+    fn test_custody_limits() {
+        let config = CustodyConfig::default();
+        assert!(append_custody(&image(), "", b"key", &config).is_err());
+        assert!(append_custody(&image(), "much too long", b"key", &config).is_err());
+
+        let small = DynamicImage::ImageRgb8(RgbImage::new(32, 32));
+        assert!(read_custody(&small, b"key", &config).is_err());
+    }
+}
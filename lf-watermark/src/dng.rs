@@ -0,0 +1,447 @@
+//! Marking DNG raw files straight off the card.
+//!
+//! A DNG is a TIFF holding the raw sensor data together with JPEG previews that viewers,
+//! catalogs and web galleries show instead of developing the raw. [`mark_dng`] marks each JPEG
+//! preview and writes the payload into the copyright field of the main directory. Like a PDF
+//! incremental update, the new previews and directory are appended and only the offsets pointing
+//! at them are patched, so the raw data, the color calibration and every other tag keep their
+//! bytes.
+
+use std::collections::BTreeSet;
+
+use image::DynamicImage;
+
+use crate::codec::{encode, ChromaSubsampling, JpegInfo, JpegMode};
+use crate::exif::ascii;
+use crate::orientation::Tiff;
+use crate::{embed_watermark_with_config, payload_value, Result, WatermarkConfig};
+
+const NEW_SUBFILE_TYPE: u16 = 254;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const SUB_IFDS: u16 = 330;
+const JPEG_OFFSET: u16 = 513;
+const JPEG_LENGTH: u16 = 514;
+const COPYRIGHT: u16 = 0x8298;
+const DNG_VERSION: u16 = 50706;
+
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const IFD: u16 = 13;
+
+/// Most directories followed, which bounds the walk on hostile input with cyclic offsets.
+const MAX_IFDS: usize = 64;
+
+/// How [`mark_dng`] treats the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DngOptions {
+    pub config: WatermarkConfig,
+    /// JPEG quality of the re-encoded previews, 1 to 100.
+    pub quality: u8,
+    /// Whether to write the payload into the copyright field, replacing the one from the camera.
+    pub copyright: bool,
+}
+
+impl Default for DngOptions {
+    fn default() -> Self {
+        DngOptions {
+            config: WatermarkConfig::default(),
+            quality: 90,
+            copyright: true,
+        }
+    }
+}
+
+/// Result of [`mark_dng`].
+#[derive(Debug, Clone)]
+pub struct MarkedDng {
+    pub bytes: Vec<u8>,
+    /// Number of previews that were marked.
+    pub marked: usize,
+    /// Number of previews left as they were because of their layout or encoding.
+    pub skipped: usize,
+}
+
+/// Marks the previews and metadata of `dng` with `watermark`.
+pub fn mark_dng(dng: &[u8], watermark: &str, options: &DngOptions) -> Result<MarkedDng> {
+    payload_value(watermark)?;
+    let tiff = Tiff::new(dng).ok_or("Not a TIFF file")?;
+    let ifd0 = tiff.u32_at(4).ok_or("Truncated TIFF header")? as usize;
+    if tiff.find(ifd0, DNG_VERSION).is_none() {
+        return Err("Not a DNG file; the main directory has no DNGVersion".into());
+    }
+
+    let mut output = dng.to_vec();
+    let (mut marked, mut skipped) = (0, 0);
+    for ifd in directories(&tiff, ifd0) {
+        let Some(preview) = Preview::find(&tiff, ifd) else {
+            continue;
+        };
+        let Some(jpeg) = mark_preview(&tiff, &preview, watermark, options)? else {
+            skipped += 1;
+            continue;
+        };
+
+        let offset = append(&mut output, &jpeg)?;
+        let le = tiff.little_endian();
+        put_u16(&mut output, preview.offset_entry + 2, LONG, le);
+        put_u32(&mut output, preview.offset_entry + 8, offset, le);
+        put_u16(&mut output, preview.length_entry + 2, LONG, le);
+        put_u32(&mut output, preview.length_entry + 8, jpeg.len() as u32, le);
+        marked += 1;
+    }
+
+    if options.copyright {
+        set_copyright(&mut output, ifd0, watermark)?;
+    }
+
+    Ok(MarkedDng {
+        bytes: output,
+        marked,
+        skipped,
+    })
+}
+
+/// Reads the payload [`mark_dng`] wrote into the copyright field of `dng`.
+pub fn read_dng_mark(dng: &[u8]) -> Option<String> {
+    let tiff = Tiff::new(dng)?;
+    ascii(&tiff, tiff.u32_at(4)? as usize, COPYRIGHT)
+}
+
+/// A JPEG preview stored in one piece, with the entries that locate it.
+struct Preview {
+    offset_entry: usize,
+    length_entry: usize,
+    data: std::ops::Range<usize>,
+}
+
+impl Preview {
+    /// The preview described by the directory at `ifd`, if it is a reduced-resolution JPEG in RGB
+    /// or YCbCr.
+    fn find(tiff: &Tiff, ifd: usize) -> Option<Self> {
+        let subfile = value(tiff, tiff.find(ifd, NEW_SUBFILE_TYPE)?)?;
+        let compression = value(tiff, tiff.find(ifd, COMPRESSION)?)?;
+        // Full-resolution directories hold the raw data, which may be lossless JPEG as well.
+        if subfile & 1 == 0 || !matches!(compression, 6 | 7) {
+            return None;
+        }
+
+        let (offset_entry, length_entry) = match tiff.find(ifd, JPEG_OFFSET) {
+            Some(offset_entry) => (offset_entry, tiff.find(ifd, JPEG_LENGTH)?),
+            None => (
+                tiff.find(ifd, STRIP_OFFSETS)?,
+                tiff.find(ifd, STRIP_BYTE_COUNTS)?,
+            ),
+        };
+        let photometric = tiff
+            .find(ifd, PHOTOMETRIC)
+            .and_then(|entry| value(tiff, entry));
+        if !matches!(photometric, Some(2 | 6) | None) {
+            return None;
+        }
+        let start = value(tiff, offset_entry)? as usize;
+        let len = value(tiff, length_entry)? as usize;
+        tiff.bytes(start, len)?;
+
+        Some(Preview {
+            offset_entry,
+            length_entry,
+            data: start..start + len,
+        })
+    }
+}
+
+/// The re-encoded preview, or `None` if it cannot be marked.
+fn mark_preview(
+    tiff: &Tiff,
+    preview: &Preview,
+    watermark: &str,
+    options: &DngOptions,
+) -> Result<Option<Vec<u8>>> {
+    let data = tiff
+        .bytes(preview.data.start, preview.data.len())
+        .ok_or("Truncated preview")?;
+    let mode = JpegInfo::read(data).map(|info| info.mode);
+    if mode == Some(JpegMode::Lossless) {
+        return Ok(None);
+    }
+    let Ok(image) = image::load_from_memory_with_format(data, image::ImageFormat::Jpeg) else {
+        return Ok(None);
+    };
+
+    let image = DynamicImage::ImageRgb8(image.to_rgb8());
+    let marked = embed_watermark_with_config(&image, watermark, &options.config)?;
+    // The chroma layout is not kept, only the coding process.
+    let info = JpegInfo {
+        mode: mode.unwrap_or(JpegMode::Baseline),
+        subsampling: ChromaSubsampling::Yuv420,
+    };
+
+    Ok(Some(encode(&marked, info.output_format(options.quality)?)?))
+}
+
+/// Every directory reachable from `ifd0`: the chain of main directories and their sub-directories.
+fn directories(tiff: &Tiff, ifd0: usize) -> Vec<usize> {
+    let mut seen = BTreeSet::new();
+    let mut pending = vec![ifd0];
+    let mut order = Vec::new();
+    while let Some(ifd) = pending.pop() {
+        if ifd == 0 || order.len() == MAX_IFDS || !seen.insert(ifd) {
+            continue;
+        }
+        let Some(count) = tiff.u16_at(ifd) else {
+            continue;
+        };
+        order.push(ifd);
+
+        if let Some(next) = tiff.u32_at(ifd + 2 + count as usize * 12) {
+            pending.push(next as usize);
+        }
+        if let Some(entry) = tiff.find(ifd, SUB_IFDS) {
+            let count = tiff.u32_at(entry + 4).unwrap_or(0) as usize;
+            let list = if count == 1 {
+                Some(entry + 8)
+            } else {
+                tiff.u32_at(entry + 8).map(|offset| offset as usize)
+            };
+            for i in 0..count.min(MAX_IFDS) {
+                if let Some(sub) = list.and_then(|list| tiff.u32_at(list + i * 4)) {
+                    pending.push(sub as usize);
+                }
+            }
+        }
+    }
+
+    order
+}
+
+/// Single SHORT or LONG value stored in the entry.
+fn value(tiff: &Tiff, entry: usize) -> Option<u32> {
+    if tiff.u32_at(entry + 4)? != 1 {
+        return None;
+    }
+    match tiff.u16_at(entry + 2)? {
+        SHORT => tiff.u16_at(entry + 8).map(u32::from),
+        LONG | IFD => tiff.u32_at(entry + 8),
+        _ => None,
+    }
+}
+
+/// Appends a copy of the main directory with the copyright field set to `text`, and points the
+/// header at it.
+fn set_copyright(output: &mut Vec<u8>, ifd0: usize, text: &str) -> Result<()> {
+    let tiff = Tiff::new(output).ok_or("Not a TIFF file")?;
+    let le = tiff.little_endian();
+    let count = tiff.u16_at(ifd0).ok_or("Truncated directory")? as usize;
+    let entries = tiff
+        .bytes(ifd0 + 2, count * 12 + 4)
+        .ok_or("Truncated directory")?;
+    let (entries, next) = entries.split_at(count * 12);
+    let mut entries: Vec<[u8; 12]> = entries
+        .chunks_exact(12)
+        .map(|entry| entry.try_into().unwrap_or([0; 12]))
+        .filter(|entry| tiff_u16(&entry[..2], le) != COPYRIGHT)
+        .collect();
+    let next = next.to_vec();
+
+    let mut value = text.as_bytes().to_vec();
+    value.push(0);
+    let mut entry = [0; 12];
+    put_u16(&mut entry, 0, COPYRIGHT, le);
+    put_u16(&mut entry, 2, ASCII, le);
+    put_u32(&mut entry, 4, value.len() as u32, le);
+    if value.len() <= 4 {
+        entry[8..8 + value.len()].copy_from_slice(&value);
+    } else {
+        let offset = append(output, &value)?;
+        put_u32(&mut entry, 8, offset, le);
+    }
+    let at = entries
+        .iter()
+        .position(|e| tiff_u16(&e[..2], le) > COPYRIGHT)
+        .unwrap_or(entries.len());
+    entries.insert(at, entry);
+
+    let mut directory = Vec::with_capacity(2 + entries.len() * 12 + 4);
+    directory.extend_from_slice(&[0; 2]);
+    put_u16(&mut directory, 0, entries.len() as u16, le);
+    directory.extend(entries.iter().flatten());
+    directory.extend_from_slice(&next);
+    let offset = append(output, &directory)?;
+    put_u32(output, 4, offset, le);
+
+    Ok(())
+}
+
+/// Appends `data` at a word boundary, as TIFF requires, and returns its offset.
+fn append(output: &mut Vec<u8>, data: &[u8]) -> Result<u32> {
+    if output.len() % 2 == 1 {
+        output.push(0);
+    }
+    let offset = u32::try_from(output.len()).map_err(|_| "DNG exceeds 4 GiB")?;
+    output.extend_from_slice(data);
+
+    Ok(offset)
+}
+
+fn tiff_u16(bytes: &[u8], le: bool) -> u16 {
+    let bytes = [bytes[0], bytes[1]];
+    if le {
+        u16::from_le_bytes(bytes)
+    } else {
+        u16::from_be_bytes(bytes)
+    }
+}
+
+fn put_u16(output: &mut [u8], at: usize, value: u16, le: bool) {
+    let bytes = if le {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    };
+    output[at..at + 2].copy_from_slice(&bytes);
+}
+
+fn put_u32(output: &mut [u8], at: usize, value: u32, le: bool) {
+    let bytes = if le {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    };
+    output[at..at + 4].copy_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::codec::OutputFormat;
+    use crate::metrics::psnr;
+
+    const RAW: &[u8] = &[0x5A; 64];
+
+    fn preview() -> Vec<u8> {
+        let image = RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(x * 3) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+        });
+        let format = OutputFormat::Jpeg {
+            quality: 95,
+            subsampling: ChromaSubsampling::Yuv420,
+        };
+        encode(&image, format).unwrap()
+    }
+
+    /// Big- or little-endian DNG with a JPEG preview in IFD0 and uncompressed raw data in a
+    /// sub-directory, laid out as many cameras write it.
+    fn dng(le: bool) -> Vec<u8> {
+        let preview = preview();
+        let mut out = if le { b"II*\0" } else { b"MM\0*" }.to_vec();
+        out.extend_from_slice(&[0; 4]);
+        let entry = |out: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: u32| {
+            let mut bytes = [0; 12];
+            put_u16(&mut bytes, 0, tag, le);
+            put_u16(&mut bytes, 2, kind, le);
+            put_u32(&mut bytes, 4, count, le);
+            if kind == SHORT {
+                put_u16(&mut bytes, 8, value as u16, le);
+            } else if kind == ASCII || kind == 1 {
+                // Byte values are stored as they are, whatever the byte order.
+                bytes[8..].copy_from_slice(&value.to_be_bytes());
+            } else {
+                put_u32(&mut bytes, 8, value, le);
+            }
+            out.extend_from_slice(&bytes);
+        };
+
+        let ifd0 = 8;
+        let sub_ifd = ifd0 + 2 + 8 * 12 + 4;
+        let raw = sub_ifd + 2 + 5 * 12 + 4;
+        let jpeg = raw + RAW.len();
+        put_u32(&mut out, 4, ifd0 as u32, le);
+
+        out.extend_from_slice(&[0; 2]);
+        put_u16(&mut out, ifd0, 8, le);
+        entry(&mut out, NEW_SUBFILE_TYPE, LONG, 1, 1);
+        entry(&mut out, COMPRESSION, SHORT, 1, 7);
+        entry(&mut out, PHOTOMETRIC, SHORT, 1, 6);
+        entry(&mut out, STRIP_OFFSETS, LONG, 1, jpeg as u32);
+        entry(&mut out, STRIP_BYTE_COUNTS, LONG, 1, preview.len() as u32);
+        entry(&mut out, SUB_IFDS, LONG, 1, sub_ifd as u32);
+        entry(&mut out, COPYRIGHT, ASCII, 4, u32::from_be_bytes(*b"Cam\0"));
+        entry(
+            &mut out,
+            DNG_VERSION,
+            1,
+            4,
+            u32::from_be_bytes([1, 4, 0, 0]),
+        );
+        out.extend_from_slice(&[0; 4]);
+
+        out.extend_from_slice(&[0; 2]);
+        put_u16(&mut out, sub_ifd, 5, le);
+        entry(&mut out, NEW_SUBFILE_TYPE, LONG, 1, 0);
+        entry(&mut out, COMPRESSION, SHORT, 1, 1);
+        entry(&mut out, PHOTOMETRIC, SHORT, 1, 32803);
+        entry(&mut out, STRIP_OFFSETS, LONG, 1, raw as u32);
+        entry(&mut out, STRIP_BYTE_COUNTS, LONG, 1, RAW.len() as u32);
+        out.extend_from_slice(&[0; 4]);
+
+        out.extend_from_slice(RAW);
+        out.extend_from_slice(&preview);
+        out
+    }
+
+    #[test]
+    fn test_mark_dng() {
+        for le in [true, false] {
+            let original = dng(le);
+            let marked = mark_dng(&original, "Jane Doe 2024", &DngOptions::default()).unwrap();
+            assert_eq!((marked.marked, marked.skipped), (1, 0));
+            assert_eq!(read_dng_mark(&original).as_deref(), Some("Cam"));
+            assert_eq!(
+                read_dng_mark(&marked.bytes).as_deref(),
+                Some("Jane Doe 2024")
+            );
+
+            // Only the header and the preview location entries of the original bytes change;
+            // the raw data among them.
+            let patched = 10 + 3 * 12..10 + 5 * 12;
+            assert_eq!(marked.bytes[8..patched.start], original[8..patched.start]);
+            assert_eq!(
+                marked.bytes[patched.end..original.len()],
+                original[patched.end..]
+            );
+
+            let tiff = Tiff::new(&marked.bytes).unwrap();
+            let ifd0 = tiff.u32_at(4).unwrap() as usize;
+            assert_ne!(ifd0, 8);
+            assert_eq!(directories(&tiff, ifd0).len(), 2);
+            let preview = Preview::find(&tiff, ifd0).unwrap();
+            let original_preview = image::load_from_memory(&self::preview()).unwrap();
+            let marked_preview = image::load_from_memory(&marked.bytes[preview.data]).unwrap();
+            assert!(psnr(&original_preview, &marked_preview).unwrap() > 30.0);
+        }
+    }
+
+    #[test]
+    fn test_mark_dng_options() {
+        let original = dng(true);
+        let options = DngOptions {
+            copyright: false,
+            ..Default::default()
+        };
+        let marked = mark_dng(&original, "Jane", &options).unwrap();
+        assert_eq!(read_dng_mark(&marked.bytes).as_deref(), Some("Cam"));
+
+        assert!(mark_dng(b"not a dng", "Jane", &options).is_err());
+        assert!(mark_dng(&original, "Jane\u{1F600}", &options).is_err());
+        // Without DNGVersion it is a plain TIFF.
+        let mut tiff = original.clone();
+        tiff[10 + 7 * 12] = 0;
+        assert!(mark_dng(&tiff, "Jane", &options).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Reusable embedding state for services that mark many images in a row.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use image::{DynamicImage, Rgb, RgbImage};
+use rustdct::DctPlanner;
+
+use crate::{
+    dct_normalization, mark_coefficients, payload_value, rgb_to_ycbcr, telemetry, ycbcr_to_rgb,
+    Determinism, Result, WatermarkConfig,
+};
+
+const BENCHMARK_WATERMARK: &str = "Hello, World!";
+
+/// Image sizes worth warming an [`EmbedderPool`] for: 1080p, 4K and the 12 and 48 megapixel
+/// outputs of common phone cameras.
+pub const COMMON_SIZES: [(u32, u32); 4] = [(1920, 1080), (3840, 2160), (4032, 3024), (8064, 6048)];
+
+/// Holds a [`WatermarkConfig`] together with the DCT plans and scratch buffers an embed needs.
+///
+/// The free functions allocate two full-size channel vectors and a DCT scratch buffer on every
+/// call. An `Embedder` keeps them between calls, so once it has seen an image size, embedding
+/// further images of that size through [`Embedder::embed_in_place`] does not touch the heap.
+pub struct Embedder {
+    config: WatermarkConfig,
+    dct_planner: DctPlanner<f32>,
+    y_channel: Vec<f32>,
+    cbcr_channel: Vec<(u8, u8)>,
+    scratch: Vec<f32>,
+}
+
+impl Embedder {
+    pub fn new(config: WatermarkConfig) -> Self {
+        Embedder {
+            config,
+            dct_planner: DctPlanner::new(),
+            y_channel: Vec::new(),
+            cbcr_channel: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &WatermarkConfig {
+        &self.config
+    }
+
+    /// Same as [`embed_watermark_with_config`](crate::embed_watermark_with_config) with this
+    /// embedder's config. Only the returned image is allocated. The embedder marks luma only, so
+    /// a config with chroma strength is rejected.
+    pub fn embed(&mut self, image: &DynamicImage, watermark: &str) -> Result<RgbImage> {
+        let mut image = image.to_rgb8();
+        self.embed_in_place(&mut image, watermark)?;
+
+        Ok(image)
+    }
+
+    /// Embeds `watermark` into `image`, overwriting its pixels.
+    pub fn embed_in_place(&mut self, image: &mut RgbImage, watermark: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.mark(image, watermark);
+        let pixels = image.width() as usize * image.height() as usize;
+        telemetry::record_pixels(pixels, result.is_ok(), start.elapsed());
+
+        result
+    }
+
+    fn mark(&mut self, image: &mut RgbImage, watermark: &str) -> Result<()> {
+        if self.config.determinism == Determinism::Strict {
+            let (width, height) = image.dimensions();
+            lf_watermark_core::fixed::embed_rgb8(image, width, height, watermark, &self.config)?;
+            return Ok(());
+        }
+
+        let watermark = payload_value(watermark)? * self.config.strength;
+        let len = image.width() as usize * image.height() as usize;
+        self.config.band.indices(len)?;
+
+        self.y_channel.clear();
+        self.cbcr_channel.clear();
+        {
+            stage!(
+                "color_conversion",
+                width = image.width(),
+                height = image.height()
+            );
+            for pixel in image.pixels() {
+                let (y, cb, cr) = rgb_to_ycbcr(pixel);
+                self.y_channel.push(y as f32);
+                self.cbcr_channel.push((cb, cr));
+            }
+        }
+
+        let normalization_factor: f32 = dct_normalization(len);
+        let dct2 = self.dct_planner.plan_dct2(len);
+        let dct3 = self.dct_planner.plan_dct3(len);
+        let scratch_len = dct2.get_scratch_len().max(dct3.get_scratch_len());
+        self.scratch.resize(scratch_len, 0.0);
+
+        {
+            stage!("transform", len);
+            dct2.process_dct2_with_scratch(&mut self.y_channel, &mut self.scratch);
+            self.y_channel
+                .iter_mut()
+                .for_each(|s| *s *= normalization_factor);
+        }
+        mark_coefficients(&mut self.y_channel, watermark, &self.config)?;
+        {
+            stage!("transform", len, inverse = true);
+            dct3.process_dct3_with_scratch(&mut self.y_channel, &mut self.scratch);
+            self.y_channel
+                .iter_mut()
+                .for_each(|s| *s *= normalization_factor);
+        }
+
+        stage!(
+            "color_conversion",
+            width = image.width(),
+            height = image.height()
+        );
+        for (idx, pixel) in image.pixels_mut().enumerate() {
+            let (cb, cr) = self.cbcr_channel[idx];
+            *pixel = ycbcr_to_rgb(self.y_channel[idx], cb as f32, cr as f32);
+        }
+
+        Ok(())
+    }
+
+    /// Measures this embedder's throughput in megapixels per second on a synthetic
+    /// `width` x `height` image, averaged over `iterations` embeds after one warm-up run.
+    ///
+    /// Meant for capacity planning on the deployment hardware; `cargo bench` gives the detailed
+    /// breakdown by stage.
+    pub fn benchmark(&mut self, width: u32, height: u32, iterations: u32) -> Result<f64> {
+        if width == 0 || height == 0 || iterations == 0 {
+            return Err("Empty benchmark".into());
+        }
+
+        let mut image = RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8])
+        });
+        self.embed_in_place(&mut image, BENCHMARK_WATERMARK)?;
+
+        let start = Instant::now();
+        for _ in 0..iterations {
+            self.embed_in_place(&mut image, BENCHMARK_WATERMARK)?;
+        }
+        let seconds = start.elapsed().as_secs_f64();
+        let megapixels = width as f64 * height as f64 * iterations as f64 / 1e6;
+
+        Ok(megapixels / seconds.max(f64::MIN_POSITIVE))
+    }
+}
+
+/// Warm [`Embedder`]s shared between threads, such as the request handlers of a service.
+///
+/// An embedder is fastest for the image sizes it has already seen, but one per handler per size
+/// holds a lot of memory. The pool holds at most `capacity` idle embedders, each keyed by the
+/// pixel count of its last image, since that is what the DCT plans and buffers depend on; a
+/// portrait and a landscape photo of the same resolution share one. Embedding checks one out
+/// for the image's pixel count, or a new one if none is idle, and returns it afterwards, dropping
+/// the least recently used embedder when the pool is full.
+pub struct EmbedderPool {
+    config: WatermarkConfig,
+    capacity: usize,
+    /// Idle embedders with their pixel counts, least recently used first.
+    idle: Mutex<Vec<(usize, Embedder)>>,
+}
+
+impl EmbedderPool {
+    pub fn new(config: WatermarkConfig, capacity: usize) -> Self {
+        EmbedderPool {
+            config,
+            capacity,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn config(&self) -> &WatermarkConfig {
+        &self.config
+    }
+
+    /// Number of idle embedders held.
+    pub fn idle(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Prepares an idle embedder for each of `sizes` by embedding into a synthetic image of that
+    /// size, so the first requests at those sizes do not pay for planning. See [`COMMON_SIZES`].
+    pub fn warm(&self, sizes: &[(u32, u32)]) -> Result<()> {
+        for &(width, height) in sizes {
+            let mut image = RgbImage::new(width, height);
+            self.embed_in_place(&mut image, BENCHMARK_WATERMARK)?;
+        }
+
+        Ok(())
+    }
+
+    /// Same as [`Embedder::embed`] on an embedder from the pool.
+    pub fn embed(&self, image: &DynamicImage, watermark: &str) -> Result<RgbImage> {
+        let mut image = image.to_rgb8();
+        self.embed_in_place(&mut image, watermark)?;
+
+        Ok(image)
+    }
+
+    /// Same as [`Embedder::embed_in_place`] on an embedder from the pool.
+    pub fn embed_in_place(&self, image: &mut RgbImage, watermark: &str) -> Result<()> {
+        let pixels = image.width() as usize * image.height() as usize;
+        let mut embedder = self.check_out(pixels);
+        let result = embedder.embed_in_place(image, watermark);
+        self.give_back(pixels, embedder);
+
+        result
+    }
+
+    fn check_out(&self, pixels: usize) -> Embedder {
+        let mut idle = self.lock();
+        match idle.iter().rposition(|(len, _)| *len == pixels) {
+            Some(index) => idle.remove(index).1,
+            None => Embedder::new(self.config.clone()),
+        }
+    }
+
+    fn give_back(&self, pixels: usize, embedder: Embedder) {
+        let mut idle = self.lock();
+        idle.push((pixels, embedder));
+        if idle.len() > self.capacity {
+            idle.remove(0);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<(usize, Embedder)>> {
+        // An embedder is only held outside the lock, so a panic elsewhere leaves the list intact.
+        self.idle.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::{embed_watermark_with_config, Band};
+
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn test_embedder_matches_free_function() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(24, 20, |x, y| {
+            Rgb([(x * 10) as u8, (y * 12) as u8, 90])
+        }));
+        let config = WatermarkConfig {
+            band: Band::Low,
+            ..Default::default()
+        };
+
+        let expected = embed_watermark_with_config(&img, "Hello, World!", &config).unwrap();
+        let mut embedder = Embedder::new(config);
+        assert_eq!(embedder.embed(&img, "Hello, World!").unwrap(), expected);
+        assert_eq!(embedder.embed(&img, "Hello, World!").unwrap(), expected);
+    }
+
+    #[test]
+    fn test_embedder_steady_state_does_not_allocate() {
+        let img = RgbImage::from_fn(32, 32, |x, y| Rgb([(x * 8) as u8, (y * 8) as u8, 40]));
+        let mut embedder = Embedder::new(WatermarkConfig::default());
+
+        let mut warm_up = img.clone();
+        embedder.embed_in_place(&mut warm_up, "Hello").unwrap();
+
+        let mut image = img.clone();
+        let before = ALLOCATIONS.with(Cell::get);
+        embedder.embed_in_place(&mut image, "Hello").unwrap();
+        assert_eq!(ALLOCATIONS.with(Cell::get), before);
+        assert_eq!(image, warm_up);
+    }
+
+    #[test]
+    fn test_embedder_pool() {
+        let config = WatermarkConfig {
+            band: Band::Low,
+            ..Default::default()
+        };
+        let pool = EmbedderPool::new(config.clone(), 2);
+        pool.warm(&[(24, 20), (20, 24), (16, 16)]).unwrap();
+        // The portrait size shares the landscape one's embedder.
+        assert_eq!(pool.idle(), 2);
+
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(24, 20, |x, y| {
+            Rgb([(x * 10) as u8, (y * 12) as u8, 90])
+        }));
+        let expected = embed_watermark_with_config(&img, "Hello, World!", &config).unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| assert_eq!(pool.embed(&img, "Hello, World!").unwrap(), expected));
+            }
+        });
+        assert!(pool.idle() <= 2);
+
+        let pool = EmbedderPool::new(config, 1);
+        assert!(pool.embed(&img, "\u{1F600}").is_err());
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_embedder_benchmark() {
+        let mut embedder = Embedder::new(WatermarkConfig::default());
+        assert!(embedder.benchmark(64, 64, 2).unwrap() > 0.0);
+        assert!(embedder.benchmark(64, 64, 0).is_err());
+    }
+}
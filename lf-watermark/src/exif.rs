@@ -0,0 +1,258 @@
+//! Visible overlay text composed from EXIF fields.
+//!
+//! Photographers mark a whole shoot with their name and the capture date. A template such as
+//! `"{copyright} / {date}"` is filled from each file's own EXIF data, so no per-file strings are
+//! needed.
+
+use image::RgbImage;
+
+use crate::orientation::{apply_orientation, read_orientation, Tiff};
+use crate::visible::{stamp_text, TextStamp};
+use crate::Result;
+
+const ARTIST_TAG: u16 = 0x013B;
+const DATE_TIME_TAG: u16 = 0x0132;
+const COPYRIGHT_TAG: u16 = 0x8298;
+const EXIF_IFD_TAG: u16 = 0x8769;
+const DATE_TIME_ORIGINAL_TAG: u16 = 0x9003;
+const ASCII: u16 = 2;
+
+/// Text fields of a JPEG's EXIF data.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExifFields {
+    pub artist: Option<String>,
+    pub copyright: Option<String>,
+    /// `DateTimeOriginal` if present, else `DateTime`, as `YYYY:MM:DD HH:MM:SS`.
+    pub date_time: Option<String>,
+}
+
+impl ExifFields {
+    /// Reads the fields of `jpeg`. Missing, malformed or empty fields are `None`.
+    pub fn read(jpeg: &[u8]) -> Self {
+        let Some(tiff) = Tiff::of_jpeg(jpeg) else {
+            return ExifFields::default();
+        };
+        let Some(ifd0) = tiff.u32_at(4).map(|offset| offset as usize) else {
+            return ExifFields::default();
+        };
+
+        let original = tiff
+            .find(ifd0, EXIF_IFD_TAG)
+            .and_then(|entry| tiff.u32_at(entry + 8))
+            .and_then(|exif_ifd| ascii(&tiff, exif_ifd as usize, DATE_TIME_ORIGINAL_TAG));
+
+        ExifFields {
+            artist: ascii(&tiff, ifd0, ARTIST_TAG),
+            copyright: ascii(&tiff, ifd0, COPYRIGHT_TAG),
+            date_time: original.or_else(|| ascii(&tiff, ifd0, DATE_TIME_TAG)),
+        }
+    }
+
+    /// Fills `template` from the fields.
+    ///
+    /// Placeholders are `{artist}`, `{copyright}`, `{date}` (`YYYY-MM-DD`), `{year}` and
+    /// `{datetime}` (as stored); `{{` and `}}` are literal braces. A placeholder whose field is
+    /// missing is an error rather than an empty string, so a batch reports the files that need
+    /// attention instead of marking them with half a caption.
+    pub fn render(&self, template: &str) -> Result<String> {
+        render_template(template, |name| self.placeholder(name))
+    }
+
+    fn placeholder(&self, name: &str) -> Result<String> {
+        let missing = |field: &str| format!("EXIF has no {} for {{{}}}", field, name);
+        let date_time = || self.date_time.as_deref().ok_or_else(|| missing("date"));
+
+        Ok(match name {
+            "artist" => self.artist.clone().ok_or_else(|| missing("Artist"))?,
+            "copyright" => self.copyright.clone().ok_or_else(|| missing("Copyright"))?,
+            "datetime" => date_time()?.to_string(),
+            "date" => date_time()?
+                .get(..10)
+                .ok_or_else(|| missing("valid date"))?
+                .replace(':', "-"),
+            "year" => date_time()?
+                .get(..4)
+                .ok_or_else(|| missing("valid date"))?
+                .to_string(),
+            _ => return Err(format!("Unknown placeholder {{{}}}", name).into()),
+        })
+    }
+}
+
+/// Fills the `{name}` placeholders of `template` through `placeholder`; `{{` and `}}` are literal
+/// braces.
+pub(crate) fn render_template(
+    template: &str,
+    placeholder: impl Fn(&str) -> Result<String>,
+) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.as_str().starts_with('{') => {
+                chars.next();
+                output.push('{');
+            }
+            '}' if chars.as_str().starts_with('}') => {
+                chars.next();
+                output.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| format!("Unclosed placeholder in {:?}", template))?;
+                output.push_str(&placeholder(&rest[..end])?);
+                chars = rest[end + 1..].chars();
+            }
+            '}' => return Err(format!("Unmatched '}}' in {:?}", template).into()),
+            c => output.push(c),
+        }
+    }
+
+    Ok(output)
+}
+
+/// Decodes `jpeg`, makes it upright and stamps `template` filled from its EXIF fields.
+pub fn stamp_exif_text(jpeg: &[u8], template: &str, stamp: &TextStamp) -> Result<RgbImage> {
+    let text = ExifFields::read(jpeg).render(template)?;
+    let image = image::load_from_memory(jpeg)?;
+    let image = match read_orientation(jpeg) {
+        Some(orientation) => apply_orientation(&image, orientation),
+        None => image,
+    };
+
+    stamp_text(&image, &text, stamp)
+}
+
+/// Value of the ASCII entry `tag` of the IFD at `ifd`, without its terminating NULs.
+pub(crate) fn ascii(tiff: &Tiff, ifd: usize, tag: u16) -> Option<String> {
+    let entry = tiff.find(ifd, tag)?;
+    if tiff.u16_at(entry + 2)? != ASCII {
+        return None;
+    }
+
+    let len = tiff.u32_at(entry + 4)? as usize;
+    // Values of up to four bytes are stored in the entry itself.
+    let offset = if len <= 4 {
+        entry + 8
+    } else {
+        tiff.u32_at(entry + 8)? as usize
+    };
+    let text = std::str::from_utf8(tiff.bytes(offset, len)?).ok()?;
+    let text = text.trim_end_matches('\0').trim();
+
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, Rgb};
+
+    use super::*;
+    use crate::orientation::insert_segment;
+    use crate::visible::Placement;
+
+    /// Little-endian APP1 Exif segment with artist and copyright in IFD0 and the capture date in
+    /// the Exif IFD.
+    fn exif_segment(artist: &str, copyright: &str, date: &str) -> Vec<u8> {
+        let strings = [artist, copyright, date].map(|s| format!("{}\0", s).into_bytes());
+        let ifd0 = 8usize;
+        let exif_ifd = ifd0 + 2 + 3 * 12 + 4;
+        let mut data_offset = exif_ifd + 2 + 12 + 4;
+
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&(ifd0 as u32).to_le_bytes());
+        let entry = |tiff: &mut Vec<u8>, tag: u16, kind: u16, count: usize, value: u32| {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&(count as u32).to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        };
+
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        for (tag, text) in [(ARTIST_TAG, &strings[0]), (COPYRIGHT_TAG, &strings[1])] {
+            entry(&mut tiff, tag, ASCII, text.len(), data_offset as u32);
+            data_offset += text.len();
+        }
+        entry(&mut tiff, EXIF_IFD_TAG, 4, 1, exif_ifd as u32);
+        tiff.extend_from_slice(&[0; 4]);
+
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        let date = &strings[2];
+        entry(
+            &mut tiff,
+            DATE_TIME_ORIGINAL_TAG,
+            ASCII,
+            date.len(),
+            data_offset as u32,
+        );
+        tiff.extend_from_slice(&[0; 4]);
+        for text in &strings {
+            tiff.extend_from_slice(text);
+        }
+
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&((2 + 6 + tiff.len()) as u16).to_be_bytes());
+        segment.extend_from_slice(b"Exif\0\0");
+        segment.extend_from_slice(&tiff);
+        segment
+    }
+
+    fn jpeg() -> Vec<u8> {
+        let image = RgbImage::from_pixel(160, 40, Rgb([30, 30, 30]));
+        let mut jpeg = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let segment = exif_segment("Jane Doe", "Copyright Jane Doe", "2024:05:17 09:30:00");
+        insert_segment(&jpeg, &segment)
+    }
+
+    #[test]
+    fn test_read_fields() {
+        let fields = ExifFields::read(&jpeg());
+        assert_eq!(fields.artist.as_deref(), Some("Jane Doe"));
+        assert_eq!(fields.copyright.as_deref(), Some("Copyright Jane Doe"));
+        assert_eq!(fields.date_time.as_deref(), Some("2024:05:17 09:30:00"));
+
+        assert_eq!(ExifFields::read(b"not a jpeg"), ExifFields::default());
+    }
+
+    #[test]
+    fn test_render() {
+        let fields = ExifFields::read(&jpeg());
+        assert_eq!(
+            fields.render("{artist} {{{year}}} {date}").unwrap(),
+            "Jane Doe {2024} 2024-05-17"
+        );
+        assert!(fields.render("{camera}").is_err());
+        assert!(fields.render("{artist").is_err());
+        assert!(ExifFields::default().render("{copyright}").is_err());
+        assert_eq!(ExifFields::default().render("plain").unwrap(), "plain");
+    }
+
+    #[test]
+    fn test_stamp_exif_text() {
+        let jpeg = jpeg();
+        let stamp = TextStamp {
+            placement: Placement {
+                margin: 4,
+                ..Default::default()
+            },
+            scale: 1,
+            ..Default::default()
+        };
+        let stamped = stamp_exif_text(&jpeg, "(c) {artist} {year}", &stamp).unwrap();
+
+        let text = "(c) Jane Doe 2024";
+        let expected = stamp_text(&image::load_from_memory(&jpeg).unwrap(), text, &stamp).unwrap();
+        assert_eq!(stamped, expected);
+        assert_ne!(
+            DynamicImage::ImageRgb8(stamped),
+            image::load_from_memory(&jpeg).unwrap()
+        );
+    }
+}
@@ -0,0 +1,270 @@
+//! C ABI for language bindings.
+//!
+//! Every entry point returns [`OK`] or a stable error code, and keeps the message of the failure
+//! on the calling thread until the next call, so C, Python and Node bindings can branch on the code
+//! and show the message without parsing it. Codes 1 to 7 are those of
+//! [`lf_watermark_core::Error::code`]; the others are the constants of this module. Error
+//! messages that have no code of their own are reported as [`ERROR_OTHER`].
+//!
+//! A panic inside the crate never unwinds into the caller: the entry point catches it and fails
+//! with [`ERROR_PANIC`].
+//!
+//! Only compiled with the `ffi` feature. Build a shared library with
+//! `cargo rustc -p lf-watermark --features ffi --crate-type cdylib`.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::error::Error;
+use std::ffi::{c_char, CStr};
+use std::panic::{self, AssertUnwindSafe};
+
+use image::{ImageError, RgbImage};
+
+use crate::{embed_watermark_with_config, WatermarkConfig};
+
+/// Success.
+pub const OK: i32 = 0;
+/// The input image could not be decoded.
+pub const ERROR_DECODING: i32 = 20;
+/// The output image could not be encoded.
+pub const ERROR_ENCODING: i32 = 21;
+/// The image format or color type is not supported.
+pub const ERROR_UNSUPPORTED: i32 = 22;
+/// The image exceeds the decoder's size or memory limits.
+pub const ERROR_LIMITS: i32 = 23;
+/// An image parameter, such as the dimensions, is invalid.
+pub const ERROR_PARAMETER: i32 = 24;
+/// Reading or writing a file failed.
+pub const ERROR_IO: i32 = 25;
+/// A required pointer argument is null.
+pub const ERROR_NULL_POINTER: i32 = 40;
+/// A string argument is not valid UTF-8.
+pub const ERROR_INVALID_UTF8: i32 = 41;
+/// The crate panicked; the message is the panic's. A bug, worth reporting.
+pub const ERROR_PANIC: i32 = 50;
+/// Any other failure; the message tells what.
+pub const ERROR_OTHER: i32 = 255;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<(i32, String)>> = const { RefCell::new(None) };
+}
+
+/// Stable code of `error`; [`ERROR_OTHER`] for errors that are only a message.
+pub fn error_code(error: &(dyn Error + 'static)) -> i32 {
+    if let Some(error) = error.downcast_ref::<lf_watermark_core::Error>() {
+        return error.code() as i32;
+    }
+    if let Some(error) = error.downcast_ref::<ImageError>() {
+        return match error {
+            ImageError::Decoding(_) => ERROR_DECODING,
+            ImageError::Encoding(_) => ERROR_ENCODING,
+            ImageError::Unsupported(_) => ERROR_UNSUPPORTED,
+            ImageError::Limits(_) => ERROR_LIMITS,
+            ImageError::Parameter(_) => ERROR_PARAMETER,
+            ImageError::IoError(_) => ERROR_IO,
+        };
+    }
+    if error.is::<std::io::Error>() {
+        return ERROR_IO;
+    }
+
+    ERROR_OTHER
+}
+
+/// Code of the last failure on this thread, [`OK`] if the last call succeeded.
+pub fn last_error_code() -> i32 {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map_or(OK, |(code, _)| *code))
+}
+
+/// Message of the last failure on this thread, if the last call failed.
+pub fn last_error_message() -> Option<String> {
+    LAST_ERROR.with(|last| last.borrow().as_ref().map(|(_, message)| message.clone()))
+}
+
+/// Records the outcome of an entry point and returns its code.
+fn record(result: std::result::Result<(), (i32, String)>) -> i32 {
+    let code = result.as_ref().map_or_else(|(code, _)| *code, |_| OK);
+    LAST_ERROR.with(|last| *last.borrow_mut() = result.err());
+    code
+}
+
+fn failure(error: Box<dyn Error>) -> (i32, String) {
+    (error_code(error.as_ref()), error.to_string())
+}
+
+/// Runs the body of an entry point, recording a panic as [`ERROR_PANIC`] and returning
+/// `on_panic` instead of unwinding across the C boundary.
+fn guard<R>(on_panic: R, body: impl FnOnce() -> R) -> R {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|payload| {
+        record(Err((ERROR_PANIC, panic_message(payload.as_ref()))));
+        on_panic
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause");
+    format!("Panic; {}", message)
+}
+
+/// Embeds `payload` into the RGB8 pixels at `pixels` in place, with the default config at
+/// `strength`. Returns [`OK`] or an error code.
+///
+/// # Safety
+///
+/// `pixels` must point to `width * height * 3` writable bytes in raster order, and `payload` to a
+/// NUL-terminated string. Neither may be used by another thread during the call.
+#[no_mangle]
+pub unsafe extern "C" fn lf_watermark_embed_rgb8(
+    pixels: *mut u8,
+    width: u32,
+    height: u32,
+    payload: *const c_char,
+    strength: f32,
+) -> i32 {
+    guard(ERROR_PANIC, || {
+        if pixels.is_null() || payload.is_null() {
+            return record(Err((ERROR_NULL_POINTER, "Null pointer argument".into())));
+        }
+        let payload = match unsafe { CStr::from_ptr(payload) }.to_str() {
+            Ok(payload) => payload,
+            Err(e) => return record(Err((ERROR_INVALID_UTF8, format!("Invalid payload; {}", e)))),
+        };
+        let len = match crate::pixel_count(width, height) {
+            Ok(pixels) => pixels.checked_mul(3),
+            Err(e) => return record(Err(failure(e))),
+        };
+        let Some(len) = len else {
+            return record(Err(failure(
+                lf_watermark_core::Error::ImageTooLarge { width, height }.into(),
+            )));
+        };
+        let buffer = unsafe { std::slice::from_raw_parts_mut(pixels, len) };
+
+        let result = (|| {
+            let image =
+                RgbImage::from_raw(width, height, buffer.to_vec()).ok_or("Invalid buffer")?;
+            let config = WatermarkConfig {
+                strength,
+                ..Default::default()
+            };
+            let marked = embed_watermark_with_config(&image.into(), payload, &config)?;
+            buffer.copy_from_slice(&marked);
+            Ok(())
+        })();
+
+        record(result.map_err(failure))
+    })
+}
+
+/// Code of the last failure on the calling thread, [`OK`] if its last call succeeded.
+#[no_mangle]
+pub extern "C" fn lf_watermark_last_error_code() -> i32 {
+    guard(ERROR_PANIC, last_error_code)
+}
+
+/// Copies the message of the last failure on the calling thread into `buffer` as a
+/// NUL-terminated string, truncated to fit `len` bytes. Returns the length of the whole message
+/// in bytes without the NUL, so a caller can retry with a larger buffer; 0 if there is none.
+///
+/// # Safety
+///
+/// `buffer` must be null or point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn lf_watermark_last_error_message(buffer: *mut c_char, len: usize) -> usize {
+    guard(0, || {
+        let Some(message) = last_error_message() else {
+            return 0;
+        };
+        if !buffer.is_null() && len > 0 {
+            let copied = message.len().min(len - 1);
+            let buffer = unsafe { std::slice::from_raw_parts_mut(buffer as *mut u8, len) };
+            buffer[..copied].copy_from_slice(&message.as_bytes()[..copied]);
+            buffer[copied] = 0;
+        }
+
+        message.len()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    #[test]
+    fn test_error_code() {
+        let core: Box<dyn Error> = lf_watermark_core::Error::InvalidCharacter('\u{1F600}').into();
+        assert_eq!(error_code(core.as_ref()), 1);
+        let io: Box<dyn Error> = std::io::Error::other("gone").into();
+        assert_eq!(error_code(io.as_ref()), ERROR_IO);
+        let decoding = image::load_from_memory(b"not an image").unwrap_err();
+        assert_eq!(error_code(&decoding), ERROR_UNSUPPORTED);
+        let message: Box<dyn Error> = "Something else".into();
+        assert_eq!(error_code(message.as_ref()), ERROR_OTHER);
+
+        let strict = WatermarkConfig {
+            determinism: crate::Determinism::Strict,
+            ..Default::default()
+        };
+        let rejected =
+            crate::carrier::Carrier::new(&RgbImage::new(8, 8).into(), &strict).unwrap_err();
+        assert_eq!(error_code(rejected.as_ref()), 6);
+        let huge: Box<dyn Error> = lf_watermark_core::Error::ImageTooLarge {
+            width: u32::MAX,
+            height: u32::MAX,
+        }
+        .into();
+        assert_eq!(error_code(huge.as_ref()), 7);
+    }
+
+    #[test]
+    fn test_guard() {
+        assert_eq!(guard(ERROR_PANIC, || OK), OK);
+        let code = guard(ERROR_PANIC, || -> i32 { panic!("boom") });
+        assert_eq!(code, ERROR_PANIC);
+        assert_eq!(last_error_code(), ERROR_PANIC);
+        assert_eq!(last_error_message().unwrap(), "Panic; boom");
+
+        let code = guard(ERROR_PANIC, || -> i32 { panic!("{} {}", "formatted", 1) });
+        assert_eq!(code, ERROR_PANIC);
+        assert_eq!(last_error_message().unwrap(), "Panic; formatted 1");
+    }
+
+    #[test]
+    fn test_embed_rgb8() {
+        let image = RgbImage::from_fn(16, 8, |x, y| Rgb([(x * 9) as u8, (y * 20) as u8, 90]));
+        let mut pixels = image.to_vec();
+        let code =
+            unsafe { lf_watermark_embed_rgb8(pixels.as_mut_ptr(), 16, 8, c"Hello".as_ptr(), 1.0) };
+        assert_eq!(code, OK);
+        assert_eq!(last_error_message(), None);
+        let config = WatermarkConfig {
+            strength: 1.0,
+            ..Default::default()
+        };
+        let expected =
+            embed_watermark_with_config(&image.clone().into(), "Hello", &config).unwrap();
+        assert_eq!(pixels, expected.into_raw());
+
+        let code = unsafe {
+            lf_watermark_embed_rgb8(pixels.as_mut_ptr(), 16, 8, c"\u{1F600}".as_ptr(), 1.0)
+        };
+        assert_eq!(code, 1);
+        assert_eq!(lf_watermark_last_error_code(), 1);
+        let mut buffer = [0 as c_char; 8];
+        let len = unsafe { lf_watermark_last_error_message(buffer.as_mut_ptr(), buffer.len()) };
+        let message = last_error_message().unwrap();
+        assert_eq!(len, message.len());
+        let truncated = unsafe { CStr::from_ptr(buffer.as_ptr()) };
+        assert_eq!(truncated.to_bytes(), &message.as_bytes()[..7]);
+
+        let code =
+            unsafe { lf_watermark_embed_rgb8(std::ptr::null_mut(), 16, 8, c"Hello".as_ptr(), 1.0) };
+        assert_eq!(code, ERROR_NULL_POINTER);
+    }
+}
@@ -0,0 +1,96 @@
+//! Integer-only embedding for targets without an FPU.
+//!
+//! Thin wrapper over [`lf_watermark_core::fixed`] for callers that already hold a
+//! [`DynamicImage`].
+
+use image::{DynamicImage, RgbImage};
+
+use crate::{Result, WatermarkConfig};
+
+/// Embeds `watermark` using only integer arithmetic per pixel.
+///
+/// Only [`Band::Dc`](crate::Band::Dc) is supported; see [`lf_watermark_core::fixed::embed_rgb8`].
+pub fn embed_watermark(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<RgbImage> {
+    let mut image = image.to_rgb8();
+    let (width, height) = image.dimensions();
+    lf_watermark_core::fixed::embed_rgb8(&mut image, width, height, watermark, config)?;
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+    use crate::{embed_watermark_with_config, Band, Determinism, Embedder};
+
+    #[test]
+    fn test_embed_watermark_fixed() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| {
+            Rgb([(x * 8) as u8, (y * 8) as u8, 200])
+        }));
+        let watermark = "Hello, World!";
+        let config = WatermarkConfig::default();
+
+        let fixed = embed_watermark(&img, watermark, &config).unwrap();
+        let float = embed_watermark_with_config(&img, watermark, &config).unwrap();
+        for (a, b) in fixed.pixels().zip(float.pixels()) {
+            for c in 0..3 {
+                assert!(a[c].abs_diff(b[c]) <= 1, "{:?} {:?}", a, b);
+            }
+        }
+
+        let config = WatermarkConfig {
+            band: Band::Low,
+            ..Default::default()
+        };
+        assert!(embed_watermark(&img, watermark, &config).is_err());
+    }
+
+    #[test]
+    fn test_strict_determinism() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 30, |x, y| {
+            Rgb([(x * 6) as u8, (y * 8) as u8, ((x * y) % 256) as u8])
+        }));
+        let watermark = "Hello, World!";
+        let config = WatermarkConfig {
+            strength: 0.01,
+            determinism: Determinism::Strict,
+            ..Default::default()
+        };
+
+        let strict = embed_watermark_with_config(&img, watermark, &config).unwrap();
+        assert_eq!(strict, embed_watermark(&img, watermark, &config).unwrap());
+        assert_eq!(
+            strict,
+            Embedder::new(config.clone())
+                .embed(&img, watermark)
+                .unwrap()
+        );
+        // Pinned so that a change of output on any target fails here.
+        let digest: String = Sha256::digest(strict.as_raw())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            digest,
+            "41a723ac94540bb211a77070df6c46a7d8d68b59f12101a2673d998010b73c47"
+        );
+        // The float paths refuse rather than silently losing the guarantee.
+        let mut plane = [128u8; 16];
+        assert!(crate::embed_luma_plane(&mut plane, 4, 4, 4, watermark, &config).is_err());
+
+        let config = WatermarkConfig {
+            band: Band::Low,
+            ..config
+        };
+        assert!(embed_watermark_with_config(&img, watermark, &config).is_err());
+    }
+}
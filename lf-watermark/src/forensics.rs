@@ -0,0 +1,397 @@
+//! Forgery detection for images that were never marked.
+//!
+//! A mark proves where an image came from only if it was embedded before the image left; for
+//! everything else these tools look for traces of editing instead.
+//!
+//! [`detect_copy_move`] finds regions copied elsewhere within the same image, the usual way to
+//! hide or duplicate an object. It describes every overlapping block by its quantized low
+//! frequencies, sorts the descriptions so equal blocks become neighbours, and reports the shifts
+//! that many equal pairs share: a single pair is chance, hundreds with the same shift are a copy.
+//!
+//! [`error_levels`] runs error level analysis: recompressing a JPEG at its own quality changes
+//! it little, since it already sits on that quality's grid, while a region spliced in from another
+//! source or edited after compression changes more and stands out.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+
+use crate::codec::{encode, ChromaSubsampling, OutputFormat};
+use crate::region::Rect;
+use crate::{split_ycbcr, Result};
+
+/// Side of the blocks [`detect_copy_move`] compares.
+pub const BLOCK: u32 = 8;
+/// Low frequencies per side that describe a block.
+const FEATURES: usize = 4;
+
+/// Settings of [`detect_copy_move`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct CopyMoveOptions {
+    /// Step the low frequency coefficients are rounded to. Larger steps survive recompression of
+    /// the forgery but also match blocks that merely look alike.
+    pub quantization: f32,
+    /// Luma variance below which a block is ignored. Flat areas such as sky match all over.
+    pub min_variance: f32,
+    /// Shortest shift reported, in pixels. Overlapping blocks of smooth areas match their close
+    /// neighbours.
+    pub min_distance: u32,
+    /// Fewest equal block pairs a shift needs to count as a copy.
+    pub min_blocks: usize,
+    /// Entries after each block in sorted order it is compared with.
+    pub neighbors: usize,
+}
+
+impl Default for CopyMoveOptions {
+    fn default() -> Self {
+        CopyMoveOptions {
+            quantization: 4.0,
+            min_variance: 25.0,
+            min_distance: 16,
+            min_blocks: 32,
+            neighbors: 4,
+        }
+    }
+}
+
+/// A region found copied within the image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyMove {
+    /// From the source to the target, in pixels. The sorting cannot tell which of the two was
+    /// there first: `offset` always points down, or right within a row.
+    pub offset: (i32, i32),
+    /// Bounding box of the matching blocks of the source.
+    pub source: Rect,
+    /// Bounding box of the matching blocks of the target, `source` moved by `offset`.
+    pub target: Rect,
+    /// Number of equal block pairs.
+    pub blocks: usize,
+}
+
+/// Finds regions of `image` copied to another place in it, most matching blocks first.
+pub fn detect_copy_move(image: &DynamicImage, options: &CopyMoveOptions) -> Result<Vec<CopyMove>> {
+    let (width, height) = image.dimensions();
+    if width < BLOCK || height < BLOCK {
+        return Err(format!(
+            "A {}x{} image holds no {}x{} block",
+            width, height, BLOCK, BLOCK
+        )
+        .into());
+    }
+    if options.quantization.is_nan() || options.quantization <= 0.0 {
+        return Err("Quantization must be positive".into());
+    }
+
+    let (luma, _) = split_ycbcr(&image.to_rgb8());
+    let basis = dct_basis();
+    let mut features = Vec::new();
+    for y in 0..=height - BLOCK {
+        for x in 0..=width - BLOCK {
+            if let Some(feature) = describe(&luma, width, x, y, &basis, options) {
+                features.push((feature, (x, y)));
+            }
+        }
+    }
+    features.sort_unstable();
+
+    let mut shifts: HashMap<(i32, i32), Vec<(u32, u32)>> = HashMap::new();
+    let min_distance = options.min_distance as i64 * options.min_distance as i64;
+    for (i, (feature, a)) in features.iter().enumerate() {
+        for (other, b) in features[i + 1..].iter().take(options.neighbors) {
+            if other != feature {
+                break;
+            }
+            // Raster order: the earlier block is the source.
+            let (source, target) = if (a.1, a.0) < (b.1, b.0) {
+                (*a, *b)
+            } else {
+                (*b, *a)
+            };
+            let offset = (
+                target.0 as i32 - source.0 as i32,
+                target.1 as i32 - source.1 as i32,
+            );
+            let distance = offset.0 as i64 * offset.0 as i64 + offset.1 as i64 * offset.1 as i64;
+            if distance >= min_distance {
+                shifts.entry(offset).or_default().push(source);
+            }
+        }
+    }
+
+    let mut found: Vec<CopyMove> = shifts
+        .into_iter()
+        .filter(|(_, sources)| sources.len() >= options.min_blocks)
+        .map(|(offset, sources)| {
+            let source = bounding_box(&sources);
+            CopyMove {
+                offset,
+                source,
+                target: Rect {
+                    x: (source.x as i64 + offset.0 as i64) as u32,
+                    y: (source.y as i64 + offset.1 as i64) as u32,
+                    ..source
+                },
+                blocks: sources.len(),
+            }
+        })
+        .collect();
+    found.sort_by(|a, b| b.blocks.cmp(&a.blocks).then(a.offset.cmp(&b.offset)));
+
+    Ok(found)
+}
+
+/// Quantized low frequencies of the block at (`x`, `y`), or `None` for a flat block.
+fn describe(
+    luma: &[f32],
+    width: u32,
+    x: u32,
+    y: u32,
+    basis: &[[f32; BLOCK as usize]; FEATURES],
+    options: &CopyMoveOptions,
+) -> Option<[i32; FEATURES * FEATURES]> {
+    let n = BLOCK as usize;
+    let mut block = [[0.0; BLOCK as usize]; BLOCK as usize];
+    for (row, target) in block.iter_mut().enumerate() {
+        let start = (y as usize + row) * width as usize + x as usize;
+        target.copy_from_slice(&luma[start..start + n]);
+    }
+
+    let mean = block.iter().flatten().sum::<f32>() / (n * n) as f32;
+    let variance = block
+        .iter()
+        .flatten()
+        .map(|v| (v - mean).powi(2))
+        .sum::<f32>()
+        / (n * n) as f32;
+    if variance < options.min_variance {
+        return None;
+    }
+
+    // Separable DCT of the low frequencies only: rows first, then columns.
+    let mut rows = [[0.0; FEATURES]; BLOCK as usize];
+    for (values, out) in block.iter().zip(rows.iter_mut()) {
+        for (u, cosines) in basis.iter().enumerate() {
+            out[u] = values.iter().zip(cosines).map(|(v, c)| v * c).sum();
+        }
+    }
+    let mut feature = [0; FEATURES * FEATURES];
+    for (v, cosines) in basis.iter().enumerate() {
+        for u in 0..FEATURES {
+            let coefficient: f32 = rows.iter().zip(cosines).map(|(row, c)| row[u] * c).sum();
+            feature[v * FEATURES + u] = (coefficient / options.quantization).round() as i32;
+        }
+    }
+
+    Some(feature)
+}
+
+/// Orthonormal DCT-II basis vectors of the lowest frequencies.
+fn dct_basis() -> [[f32; BLOCK as usize]; FEATURES] {
+    let n = BLOCK as f32;
+    std::array::from_fn(|u| {
+        let scale = if u == 0 {
+            (1.0 / n).sqrt()
+        } else {
+            (2.0 / n).sqrt()
+        };
+        std::array::from_fn(|i| scale * (PI * (2 * i + 1) as f32 * u as f32 / (2.0 * n)).cos())
+    })
+}
+
+fn bounding_box(blocks: &[(u32, u32)]) -> Rect {
+    let (mut left, mut top) = (u32::MAX, u32::MAX);
+    let (mut right, mut bottom) = (0, 0);
+    for &(x, y) in blocks {
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x + BLOCK);
+        bottom = bottom.max(y + BLOCK);
+    }
+
+    Rect {
+        x: left,
+        y: top,
+        width: right - left,
+        height: bottom - top,
+    }
+}
+
+/// How much each pixel changed when recompressed, see [`error_levels`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorLevels {
+    pub width: u32,
+    pub height: u32,
+    /// Largest change over the three channels per pixel, in raster order.
+    pub values: Vec<u8>,
+}
+
+impl ErrorLevels {
+    pub fn get(&self, x: u32, y: u32) -> Option<u8> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+
+        self.values
+            .get(y as usize * self.width as usize + x as usize)
+            .copied()
+    }
+
+    /// Mean error level within `rect`, or `None` if it does not fit the image.
+    pub fn mean(&self, rect: Rect) -> Option<f64> {
+        if rect.width == 0
+            || rect.height == 0
+            || rect.x as u64 + rect.width as u64 > self.width as u64
+            || rect.y as u64 + rect.height as u64 > self.height as u64
+        {
+            return None;
+        }
+
+        let sum: u64 = (rect.y..rect.y + rect.height)
+            .flat_map(|y| (rect.x..rect.x + rect.width).map(move |x| (x, y)))
+            .map(|(x, y)| self.values[y as usize * self.width as usize + x as usize] as u64)
+            .sum();
+        Some(sum as f64 / (rect.width as u64 * rect.height as u64) as f64)
+    }
+
+    /// Renders the levels multiplied by `gain` in gray, bright where the image changed most.
+    pub fn image(&self, gain: f32) -> RgbImage {
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let level = self.values[y as usize * self.width as usize + x as usize];
+            let v = (level as f32 * gain).round().min(255.0) as u8;
+            Rgb([v, v, v])
+        })
+    }
+}
+
+/// Error levels of `image` recompressed as a JPEG at `quality`, 1 to 100.
+///
+/// Use the quality the image was last saved with, if known; at others every region changes
+/// alike. Uniformly textured regions should show uniform levels, and a region brighter than
+/// others of its kind is suspect. Without any JPEG history, as for a PNG screenshot, the levels
+/// follow the texture and say little.
+pub fn error_levels(image: &DynamicImage, quality: u8) -> Result<ErrorLevels> {
+    let (width, height) = image.dimensions();
+    let original = image.to_rgb8();
+    let recompressed = image::load_from_memory(&encode(
+        &original,
+        OutputFormat::Jpeg {
+            quality,
+            subsampling: ChromaSubsampling::Yuv444,
+        },
+    )?)?
+    .to_rgb8();
+
+    let values = original
+        .pixels()
+        .zip(recompressed.pixels())
+        .map(|(a, b)| (0..3).map(|c| a[c].abs_diff(b[c])).max().unwrap_or(0))
+        .collect();
+
+    Ok(ErrorLevels {
+        width,
+        height,
+        values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GenericImage;
+
+    use super::*;
+
+    fn noise(width: u32, height: u32, seed: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let mut h = x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663) ^ seed;
+            h = h
+                .wrapping_mul(0x045d_9f3b)
+                .rotate_left(13)
+                .wrapping_mul(0x045d_9f3b);
+            Rgb([(h >> 24) as u8, (h >> 16) as u8, (h >> 8) as u8])
+        })
+    }
+
+    #[test]
+    fn test_detect_copy_move() {
+        let mut image = noise(160, 120, 1);
+        let patch = image.view(10, 10, 32, 32).to_image();
+        image.copy_from(&patch, 100, 60).unwrap();
+        let image = DynamicImage::ImageRgb8(image);
+
+        let found = detect_copy_move(&image, &CopyMoveOptions::default()).unwrap();
+        assert_eq!(found.len(), 1, "{:?}", found);
+        assert_eq!(found[0].offset, (90, 50));
+        let source = Rect {
+            x: 10,
+            y: 10,
+            width: 32,
+            height: 32,
+        };
+        assert_eq!(found[0].source, source);
+        assert_eq!(
+            found[0].target,
+            Rect {
+                x: 100,
+                y: 60,
+                ..source
+            }
+        );
+        assert_eq!(found[0].blocks, 25 * 25);
+
+        let untouched = DynamicImage::ImageRgb8(noise(160, 120, 1));
+        assert!(detect_copy_move(&untouched, &CopyMoveOptions::default())
+            .unwrap()
+            .is_empty());
+        let tiny = DynamicImage::ImageRgb8(noise(4, 4, 1));
+        assert!(detect_copy_move(&tiny, &CopyMoveOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_error_levels_expose_a_splice() {
+        let jpeg = |image: &RgbImage| {
+            let bytes = encode(
+                image,
+                OutputFormat::Jpeg {
+                    quality: 75,
+                    subsampling: ChromaSubsampling::Yuv444,
+                },
+            )
+            .unwrap();
+            image::load_from_memory(&bytes).unwrap().to_rgb8()
+        };
+        let mut image = jpeg(&noise(128, 128, 2));
+        image.copy_from(&noise(32, 32, 3), 64, 32).unwrap();
+
+        let levels = error_levels(&DynamicImage::ImageRgb8(image), 75).unwrap();
+        let splice = levels
+            .mean(Rect {
+                x: 64,
+                y: 32,
+                width: 32,
+                height: 32,
+            })
+            .unwrap();
+        let elsewhere = levels
+            .mean(Rect {
+                x: 0,
+                y: 80,
+                width: 128,
+                height: 48,
+            })
+            .unwrap();
+        assert!(splice > 2.0 * elsewhere, "{} against {}", splice, elsewhere);
+
+        assert_eq!(levels.get(128, 0), None);
+        assert_eq!(
+            levels.mean(Rect {
+                x: 100,
+                y: 0,
+                width: 32,
+                height: 1
+            }),
+            None
+        );
+        assert_eq!(levels.image(4.0).dimensions(), (128, 128));
+    }
+}
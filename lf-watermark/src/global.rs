@@ -0,0 +1,63 @@
+//! Process-wide default configuration.
+//!
+//! Large codebases embed from many call sites that should all use the same settings. Instead of
+//! threading a [`WatermarkConfig`] through every one, the application sets it once at startup
+//! with [`set_default_config`] and calls [`embed`]. Reads after that are a plain load, with no
+//! lock. Call sites that need other settings keep passing their own config to
+//! [`embed_watermark_with_config`].
+
+use std::sync::OnceLock;
+
+use image::{DynamicImage, RgbImage};
+
+use crate::{embed_watermark_with_config, Result, WatermarkConfig};
+
+static DEFAULT_CONFIG: OnceLock<WatermarkConfig> = OnceLock::new();
+
+/// Sets the config [`embed`] uses for the rest of the process. Fails if it was already set, or
+/// if [`default_config`] was read before, which fixes it at [`WatermarkConfig::default`].
+pub fn set_default_config(config: WatermarkConfig) -> Result<()> {
+    DEFAULT_CONFIG
+        .set(config)
+        .map_err(|_| "The default watermark config is already set".into())
+}
+
+/// The process-wide default config: the one given to [`set_default_config`], otherwise
+/// [`WatermarkConfig::default`] from the first read on.
+pub fn default_config() -> &'static WatermarkConfig {
+    DEFAULT_CONFIG.get_or_init(WatermarkConfig::default)
+}
+
+/// Embeds `watermark` into `image` with the [`default_config`].
+pub fn embed(image: &DynamicImage, watermark: &str) -> Result<RgbImage> {
+    embed_watermark_with_config(image, watermark, default_config())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::Band;
+
+    // The only test in the crate that touches the process-wide config, since it can be set once.
+    #[test]
+    fn test_default_config() {
+        let config = WatermarkConfig {
+            strength: 0.5,
+            band: Band::Low,
+            ..Default::default()
+        };
+        set_default_config(config.clone()).unwrap();
+        assert_eq!(default_config(), &config);
+        assert!(set_default_config(WatermarkConfig::default()).is_err());
+
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 24, |x, y| {
+            Rgb([(x * 7) as u8, (y * 9) as u8, 80])
+        }));
+        assert_eq!(
+            embed(&image, "Hello").unwrap(),
+            embed_watermark_with_config(&image, "Hello", &config).unwrap()
+        );
+    }
+}
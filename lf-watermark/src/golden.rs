@@ -0,0 +1,200 @@
+//! Regression checks of embedding outputs against stored golden images.
+//!
+//! Refactoring the transform or color conversion should not change outputs beyond float noise.
+//! A [`GoldenSet`] compares each output against a PNG of the same name in a directory and
+//! reports every case that drifts past the [`Tolerance`], so an algorithm change is validated
+//! across a whole corpus at once.
+//!
+//! Setting `UPDATE_GOLDEN=1` in the environment writes the outputs as the new goldens instead of
+//! comparing, for changes that are meant to alter the output.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+
+use crate::metrics::psnr;
+use crate::Result;
+
+const UPDATE_ENV: &str = "UPDATE_GOLDEN";
+
+/// How far an output may be from its golden image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    /// Largest allowed absolute difference of any sample, per R, G, B channel.
+    pub max_channel_diff: [u8; 3],
+    /// Lowest allowed PSNR in dB over the whole image.
+    pub min_psnr: f64,
+}
+
+impl Default for Tolerance {
+    /// Off by one at most, which float rounding differences across platforms can cause.
+    fn default() -> Self {
+        Tolerance {
+            max_channel_diff: [1, 1, 1],
+            min_psnr: 50.0,
+        }
+    }
+}
+
+/// A directory of golden PNGs.
+#[derive(Debug, Clone)]
+pub struct GoldenSet {
+    dir: PathBuf,
+    tolerance: Tolerance,
+    update: bool,
+}
+
+impl GoldenSet {
+    pub fn new<P: AsRef<Path>>(dir: P, tolerance: Tolerance) -> Self {
+        GoldenSet {
+            dir: dir.as_ref().to_path_buf(),
+            tolerance,
+            update: std::env::var_os(UPDATE_ENV).is_some_and(|v| v == "1"),
+        }
+    }
+
+    /// Compares `actual` against the golden image `name`.png.
+    pub fn check(&self, name: &str, actual: &RgbImage) -> Result<()> {
+        let path = self.dir.join(format!("{}.png", name));
+        if self.update {
+            fs::create_dir_all(&self.dir)?;
+            actual.save(&path)?;
+            return Ok(());
+        }
+
+        let golden = image::open(&path)
+            .map_err(|err| format!("{}: cannot open golden {:?}; {}", name, path, err))?;
+        if golden.dimensions() != actual.dimensions() {
+            return Err(format!(
+                "{}: dimensions {:?} != golden {:?}",
+                name,
+                actual.dimensions(),
+                golden.dimensions()
+            )
+            .into());
+        }
+
+        let golden = golden.to_rgb8();
+        let mut max_diff = [0u8; 3];
+        for (a, b) in actual.pixels().zip(golden.pixels()) {
+            for c in 0..3 {
+                max_diff[c] = max_diff[c].max(a[c].abs_diff(b[c]));
+            }
+        }
+        let quality = psnr(
+            &DynamicImage::ImageRgb8(golden),
+            &DynamicImage::ImageRgb8(actual.clone()),
+        )?;
+
+        let over = (0..3).any(|c| max_diff[c] > self.tolerance.max_channel_diff[c]);
+        if over || quality < self.tolerance.min_psnr {
+            return Err(format!(
+                "{}: max channel difference {:?} (allowed {:?}), PSNR {:.2} dB (floor {:.2} dB)",
+                name, max_diff, self.tolerance.max_channel_diff, quality, self.tolerance.min_psnr
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    /// Checks every `(name, output)` case and reports all failures together.
+    pub fn check_all(&self, cases: impl IntoIterator<Item = (String, RgbImage)>) -> Result<()> {
+        let failures: Vec<String> = cases
+            .into_iter()
+            .filter_map(|(name, actual)| self.check(&name, &actual).err())
+            .map(|err| err.to_string())
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} golden mismatches:\n{}",
+                failures.len(),
+                failures.join("\n")
+            )
+            .into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{gradient, noise, texture};
+    use crate::{embed_watermark_with_config, Band, StrengthCurve, WatermarkConfig};
+
+    fn corpus() -> Vec<(String, DynamicImage)> {
+        let mut images = vec![("gradient".to_string(), gradient(64, 48).into())];
+        for seed in 0..3 {
+            images.push((format!("texture{}", seed), texture(64, 48, seed).into()));
+        }
+        images.push(("noise".to_string(), noise(48, 64, 7).into()));
+        images
+    }
+
+    #[test]
+    fn test_golden_corpus() {
+        let configs = [
+            ("dc", WatermarkConfig::default()),
+            (
+                "low",
+                WatermarkConfig {
+                    strength: 0.01,
+                    band: Band::Low,
+                    ..Default::default()
+                },
+            ),
+            (
+                "mid-linear",
+                WatermarkConfig {
+                    strength: 0.01,
+                    band: Band::Mid,
+                    curve: StrengthCurve::Linear {
+                        start: 1.0,
+                        end: 0.2,
+                    },
+                    ..Default::default()
+                },
+            ),
+        ];
+
+        let cases = corpus().into_iter().flat_map(|(name, image)| {
+            configs.iter().map(move |(config_name, config)| {
+                let marked = embed_watermark_with_config(&image, "Hello, World!", config);
+                (format!("{}-{}", name, config_name), marked.unwrap())
+            })
+        });
+
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/golden");
+        let golden = GoldenSet::new(dir, Tolerance::default());
+        if let Err(err) = golden.check_all(cases) {
+            panic!("{}", err);
+        }
+    }
+
+    #[test]
+    fn test_golden_mismatch() {
+        let dir = std::env::temp_dir().join(format!("lf-watermark-golden-{}", std::process::id()));
+        let image = gradient(8, 8);
+        fs::create_dir_all(&dir).unwrap();
+        image.save(dir.join("ramp.png")).unwrap();
+
+        let golden = GoldenSet {
+            dir: dir.clone(),
+            tolerance: Tolerance::default(),
+            update: false,
+        };
+        assert!(golden.check("ramp", &image).is_ok());
+
+        let mut shifted = image.clone();
+        shifted.get_pixel_mut(3, 3)[1] += 5;
+        let err = golden.check("ramp", &shifted).unwrap_err().to_string();
+        assert!(err.contains("[0, 5, 0]"), "{}", err);
+        assert!(golden.check("missing", &image).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+//! Embedding into floating-point (HDR) images without tone-mapping them to 8 bits first.
+
+use image::{DynamicImage, GenericImageView, Rgb, Rgb32FImage};
+
+use crate::{add_to_band, payload_value, pixel_count, Result, WatermarkConfig};
+
+/// SMPTE ST 2084 reference: PQ signal 1.0 is 10000 nits.
+const PQ_MAX_NITS: f32 = 10000.0;
+const PQ_M1: f32 = 2610.0 / 16384.0;
+const PQ_M2: f32 = 2523.0 / 4096.0 * 128.0;
+const PQ_C1: f32 = 3424.0 / 4096.0;
+const PQ_C2: f32 = 2413.0 / 4096.0 * 32.0;
+const PQ_C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+/// Signal domain the embedding math runs in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HdrDomain {
+    /// Scene-linear values as stored, with `1.0` as the reference white. The mark is
+    /// `strength`-scaled 8-bit levels divided by 255, so it is relative to reference white.
+    Linear,
+    /// Values are encoded with the PQ curve before embedding and decoded afterwards, so the mark
+    /// is spread evenly in perceptual terms across the whole dynamic range. Linear `1.0` maps to
+    /// `white_nits`.
+    Pq { white_nits: f32 },
+}
+
+/// Embeds `watermark` into a float image, returning a float image of the same dimensions.
+///
+/// Integer inputs are accepted too and are converted to `[0, 1]` floats first. Values above
+/// `1.0` are preserved; only negative results are clamped.
+pub fn embed_watermark_hdr(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+    domain: HdrDomain,
+) -> Result<Rgb32FImage> {
+    if let HdrDomain::Pq { white_nits } = domain {
+        if !(white_nits > 0.0 && white_nits <= PQ_MAX_NITS) {
+            return Err(format!("Invalid reference white; {} nits", white_nits).into());
+        }
+    }
+
+    let watermark = payload_value(watermark)? * config.strength / 255.0;
+    let (width, height) = image.dimensions();
+    let image = image.to_rgb32f();
+
+    let encode = |v: f32| match domain {
+        HdrDomain::Linear => v,
+        HdrDomain::Pq { white_nits } => pq_encode(v * white_nits / PQ_MAX_NITS),
+    };
+    let decode = |v: f32| match domain {
+        HdrDomain::Linear => v,
+        HdrDomain::Pq { white_nits } => pq_decode(v) * PQ_MAX_NITS / white_nits,
+    };
+
+    let len = pixel_count(width, height)?;
+    let mut y_channel = Vec::with_capacity(len);
+    let mut cbcr_channel = Vec::with_capacity(len);
+    for pixel in image.pixels() {
+        let (r, g, b) = (encode(pixel[0]), encode(pixel[1]), encode(pixel[2]));
+
+        y_channel.push(0.299 * r + 0.587 * g + 0.114 * b);
+        cbcr_channel.push((
+            -0.169 * r - 0.331 * g + 0.5 * b,
+            0.5 * r - 0.419 * g - 0.081 * b,
+        ));
+    }
+
+    add_to_band(&mut y_channel, watermark, config)?;
+
+    let mut img_buffer = Rgb32FImage::new(width, height);
+    for (index, pixel) in img_buffer.pixels_mut().enumerate() {
+        let y = y_channel[index];
+        let (cb, cr) = cbcr_channel[index];
+
+        *pixel = Rgb([
+            decode(y + 1.402 * cr).max(0.0),
+            decode(y - 0.34414 * cb - 0.71414 * cr).max(0.0),
+            decode(y + 1.772 * cb).max(0.0),
+        ]);
+    }
+
+    Ok(img_buffer)
+}
+
+/// Linear light relative to 10000 nits to PQ signal.
+fn pq_encode(linear: f32) -> f32 {
+    let l = linear.max(0.0).powf(PQ_M1);
+    ((PQ_C1 + PQ_C2 * l) / (1.0 + PQ_C3 * l)).powf(PQ_M2)
+}
+
+/// Inverse of [`pq_encode`]. Signals below zero decode to black.
+fn pq_decode(signal: f32) -> f32 {
+    let e = signal.max(0.0).powf(1.0 / PQ_M2);
+    ((e - PQ_C1).max(0.0) / (PQ_C2 - PQ_C3 * e)).powf(1.0 / PQ_M1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hdr_gradient() -> DynamicImage {
+        DynamicImage::ImageRgb32F(Rgb32FImage::from_fn(32, 32, |x, y| {
+            Rgb([x as f32 / 8.0, y as f32 / 16.0, 0.5])
+        }))
+    }
+
+    #[test]
+    fn test_pq_roundtrip() {
+        for v in [0.0, 0.001, 0.0203, 0.5, 1.0] {
+            assert!((pq_decode(pq_encode(v)) - v).abs() < 1e-4, "{}", v);
+        }
+    }
+
+    #[test]
+    fn test_embed_watermark_hdr_linear() {
+        let img = hdr_gradient();
+        let config = WatermarkConfig {
+            strength: 0.01,
+            ..Default::default()
+        };
+
+        let marked =
+            embed_watermark_hdr(&img, "Hello, World!", &config, HdrDomain::Linear).unwrap();
+        let original = img.as_rgb32f().unwrap();
+
+        // Values above 1.0 survive and the shift is 5.35 / 255 of reference white.
+        let (before, after) = (original.get_pixel(31, 31), marked.get_pixel(31, 31));
+        assert!(after[0] > 3.0);
+        assert!(
+            (after[2] - before[2] - 5.35 / 255.0).abs() < 1e-3,
+            "{:?}",
+            after
+        );
+    }
+
+    #[test]
+    fn test_embed_watermark_hdr_pq() {
+        let img = hdr_gradient();
+        let config = WatermarkConfig::default();
+        let domain = HdrDomain::Pq { white_nits: 203.0 };
+
+        let marked = embed_watermark_hdr(&img, "Hello, World!", &config, domain).unwrap();
+        let original = img.as_rgb32f().unwrap();
+
+        // In PQ the same signal step is a much larger linear step in highlights than in shadows.
+        let dark = marked.get_pixel(1, 1)[2] - original.get_pixel(1, 1)[2];
+        let bright = marked.get_pixel(31, 31)[0] - original.get_pixel(31, 31)[0];
+        assert!(dark > 0.0 && bright > dark, "{} {}", dark, bright);
+
+        let domain = HdrDomain::Pq { white_nits: 0.0 };
+        assert!(embed_watermark_hdr(&img, "Hello", &config, domain).is_err());
+    }
+}
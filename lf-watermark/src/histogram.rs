@@ -0,0 +1,218 @@
+//! Embedding in the shape of the luma histogram.
+//!
+//! A histogram ignores where pixels are, so a mark stored in it survives cropping, rotation and
+//! flips that move every pixel, as long as enough of the image is left to keep the histogram's
+//! shape. Each bit owns two adjacent bins: a 1 makes the lower bin hold at least `ratio` times as
+//! many pixels as the upper one, a 0 the reverse. Pixels are moved between the two bins by
+//! shifting them by one bin width. The capacity is a few dozen bits, far below the DCT embedding.
+
+use image::{DynamicImage, RgbImage};
+
+use crate::Result;
+
+/// Fewest pixels a bin pair must hold to carry a bit; emptier pairs are too easy to disturb.
+const MIN_PAIR_PIXELS: usize = 32;
+
+/// Settings shared by [`embed_histogram`] and [`extract_histogram`], which must match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistogramConfig {
+    /// Luma level where the first bin pair starts.
+    pub first_level: u8,
+    /// Width of one bin in luma levels, and the largest change made to a pixel.
+    pub bin_width: u8,
+    /// Count ratio between the bins of a pair. Larger ratios survive more processing but move
+    /// more pixels.
+    pub ratio: f64,
+}
+
+impl Default for HistogramConfig {
+    fn default() -> Self {
+        HistogramConfig {
+            first_level: 32,
+            bin_width: 2,
+            ratio: 1.5,
+        }
+    }
+}
+
+impl HistogramConfig {
+    /// Number of bits the bin pairs between `first_level` and white can carry.
+    pub fn capacity(&self) -> usize {
+        match self.bin_width {
+            0 => 0,
+            width => (256 - self.first_level as usize) / (2 * width as usize),
+        }
+    }
+
+    /// Bin of a luma value, relative to `first_level`.
+    fn bin(&self, luma: f64) -> Option<usize> {
+        let offset = luma - self.first_level as f64;
+        (offset >= 0.0).then(|| (offset / self.bin_width as f64) as usize)
+    }
+}
+
+/// Embeds `bits` into the luma histogram of `image`.
+pub fn embed_histogram(
+    image: &DynamicImage,
+    bits: &[bool],
+    config: &HistogramConfig,
+) -> Result<RgbImage> {
+    validate(bits.len(), config)?;
+
+    let mut image = image.to_rgb8();
+    let width = config.bin_width as f64;
+    let mut bins: Vec<Vec<usize>> = vec![Vec::new(); 2 * bits.len()];
+    for (index, pixel) in image.pixels().enumerate() {
+        if let Some(bin) = config.bin(luma(&pixel.0)) {
+            if let Some(members) = bins.get_mut(bin) {
+                members.push(index);
+            }
+        }
+    }
+
+    let raw: &mut [u8] = &mut image;
+    for (i, &bit) in bits.iter().enumerate() {
+        let (low, high) = (2 * i, 2 * i + 1);
+        let (a, b) = (bins[low].len(), bins[high].len());
+        if a + b < MIN_PAIR_PIXELS {
+            return Err(format!(
+                "Luma levels {} to {} hold only {} pixels, too few to carry bit {}",
+                config.first_level as f64 + low as f64 * width,
+                config.first_level as f64 + (high + 1) as f64 * width,
+                a + b,
+                i
+            )
+            .into());
+        }
+
+        // `from` loses pixels to `to` until `to` holds `ratio` times as many.
+        let (from, to, shift) = if bit {
+            (high, low, -(config.bin_width as i16))
+        } else {
+            (low, high, config.bin_width as i16)
+        };
+        let (source, target) = (bins[from].len() as f64, bins[to].len() as f64);
+        let needed = ((config.ratio * source - target) / (1.0 + config.ratio))
+            .ceil()
+            .max(0.0) as usize;
+
+        let mut moved = 0;
+        for &index in &bins[from] {
+            if moved == needed {
+                break;
+            }
+            let samples = &mut raw[index * 3..index * 3 + 3];
+            let shifted = [0, 1, 2].map(|c| samples[c] as i16 + shift);
+            // Pixels that would clip cannot move by a whole bin.
+            if shifted.iter().any(|s| !(0..=255).contains(s)) {
+                continue;
+            }
+            for (sample, value) in samples.iter_mut().zip(shifted) {
+                *sample = value as u8;
+            }
+            moved += 1;
+        }
+        if moved < needed {
+            return Err(format!("Too many pixels of bit {} are close to clipping", i).into());
+        }
+    }
+
+    Ok(image)
+}
+
+/// Reads `len` bits from the luma histogram of `image`.
+pub fn extract_histogram(
+    image: &DynamicImage,
+    len: usize,
+    config: &HistogramConfig,
+) -> Result<Vec<bool>> {
+    validate(len, config)?;
+
+    let mut counts = vec![0usize; 2 * len];
+    for pixel in image.to_rgb8().pixels() {
+        if let Some(count) = config
+            .bin(luma(&pixel.0))
+            .and_then(|bin| counts.get_mut(bin))
+        {
+            *count += 1;
+        }
+    }
+
+    Ok(counts
+        .chunks_exact(2)
+        .map(|pair| pair[0] >= pair[1])
+        .collect())
+}
+
+fn validate(len: usize, config: &HistogramConfig) -> Result<()> {
+    if config.bin_width == 0 || config.ratio < 1.0 {
+        return Err("Bin width must be positive and the ratio at least 1".into());
+    }
+    if len > config.capacity() {
+        return Err(format!(
+            "{} bits requested, but the histogram carries {}",
+            len,
+            config.capacity()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+fn luma(rgb: &[u8; 3]) -> f64 {
+    0.299 * rgb[0] as f64 + 0.587 * rgb[1] as f64 + 0.114 * rgb[2] as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::metrics::psnr;
+    use crate::test_utils::grain;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(256, 192, |x, y| {
+            let level = (x * 3 / 4 + y / 3 + grain(x, y, 23)).min(255) as u8;
+            Rgb([level, level.saturating_add(8), level.saturating_sub(8)])
+        }))
+    }
+
+    #[test]
+    fn test_histogram_roundtrip() {
+        let image = image();
+        let config = HistogramConfig::default();
+        assert_eq!(config.capacity(), 56);
+        let bits: Vec<bool> = (0..40).map(|i| (i * 7 + i / 3) % 2 == 0).collect();
+
+        let marked = DynamicImage::ImageRgb8(embed_histogram(&image, &bits, &config).unwrap());
+        assert!(psnr(&image, &marked).unwrap() > 35.0);
+        assert_eq!(
+            extract_histogram(&marked, bits.len(), &config).unwrap(),
+            bits
+        );
+
+        // Positions do not matter.
+        let rotated = marked.rotate90().fliph();
+        assert_eq!(
+            extract_histogram(&rotated, bits.len(), &config).unwrap(),
+            bits
+        );
+        let cropped = marked.crop_imm(12, 10, 230, 170);
+        assert_eq!(
+            extract_histogram(&cropped, bits.len(), &config).unwrap(),
+            bits
+        );
+    }
+
+    #[test]
+    fn test_histogram_limits() {
+        let config = HistogramConfig::default();
+        let bits = vec![true; config.capacity() + 1];
+        assert!(embed_histogram(&image(), &bits, &config).is_err());
+
+        let flat = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 64, Rgb([100, 100, 100])));
+        assert!(embed_histogram(&flat, &[true, false], &config).is_err());
+    }
+}
@@ -0,0 +1,589 @@
+//! Scalar Costa scheme: lattice-coded bits with host-interference rejection.
+//!
+//! A detector of an additive mark has to find it against the image content, which acts as strong
+//! noise. SCS instead quantizes each selected luma DCT coefficient onto one of two keyed,
+//! interleaved lattices of spacing `step`, one per bit value, so a blind decoder only has to find
+//! which lattice a coefficient is closest to; the image content does not interfere. `alpha`
+//! trades robustness against distortion by moving the coefficient only part of the way.
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+use rustdct::DctPlanner;
+
+use crate::metrics::psnr;
+use crate::prng::Prng;
+use crate::{forward_dct, inverse_dct, merge_ycbcr, pixel_count, split_ycbcr, Band, Result};
+
+/// Floor of the noise variance estimate, in squared steps, so an untouched marked image does not
+/// produce infinite ratios.
+const MIN_VARIANCE: f32 = 1e-4;
+
+/// Settings shared by [`embed_scs`] and [`decode_scs`], which must match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScsConfig {
+    /// Lattice spacing in coefficient units. The decoder tolerates noise up to about a quarter of
+    /// it per coefficient.
+    pub step: f32,
+    /// Fraction of the distance to the lattice point a coefficient is moved, in `(0, 1]`.
+    /// Around 0.7 is optimal for moderate noise; 1.0 is plain quantization index modulation.
+    pub alpha: f32,
+    /// Coefficients per bit; their decisions are summed.
+    pub repetition: usize,
+    /// Luma DCT coefficients that carry the bits.
+    pub band: Band,
+}
+
+impl Default for ScsConfig {
+    fn default() -> Self {
+        ScsConfig {
+            step: 8.0,
+            alpha: 0.7,
+            repetition: 8,
+            band: Band::Mid,
+        }
+    }
+}
+
+impl ScsConfig {
+    /// Number of bits a `width` x `height` image can carry.
+    pub fn capacity(&self, width: u32, height: u32) -> Result<usize> {
+        let band = self.band.indices(pixel_count(width, height)?)?;
+
+        Ok(band.len() / self.repetition.max(1))
+    }
+}
+
+/// Embeds `bits` under `key` into the luma of `image`.
+pub fn embed_scs(
+    image: &DynamicImage,
+    bits: &[bool],
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<RgbImage> {
+    let (width, height) = image.dimensions();
+    let carriers = carriers(width, height, bits.len(), key, config)?;
+
+    let (mut y_channel, cbcr_channel) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    quantize(&mut y_channel, &carriers, bits, config);
+    inverse_dct(&mut dct_planner, &mut y_channel);
+
+    Ok(merge_ycbcr(width, height, &y_channel, &cbcr_channel))
+}
+
+/// Erases `len` bits embedded under `key` from `image` by moving each carrier halfway between
+/// the lattices of a 0 and a 1, where it votes for neither.
+///
+/// The result is no mark, not the original: the carriers stay moved. Whatever decodes
+/// afterwards is noise, and a check in the payload fails.
+pub fn erase_scs(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<RgbImage> {
+    let (width, height) = image.dimensions();
+    let carriers = carriers(width, height, len, key, config)?;
+
+    let (mut y_channel, cbcr_channel) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    for &(index, dither) in &carriers {
+        // The midpoints lie a quarter step off either lattice, half a step apart.
+        let shift = dither + 0.25;
+        let u = y_channel[index] / config.step - shift;
+        y_channel[index] = ((2.0 * u).round() / 2.0 + shift) * config.step;
+    }
+    inverse_dct(&mut dct_planner, &mut y_channel);
+
+    Ok(merge_ycbcr(width, height, &y_channel, &cbcr_channel))
+}
+
+/// Decodes `len` bits embedded under `key` without the original image.
+///
+/// Hard decisions on [`decode_scs_llr`].
+pub fn decode_scs(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<Vec<bool>> {
+    Ok(decode_scs_llr(image, len, key, config)?
+        .into_iter()
+        .map(|llr| llr > 0.0)
+        .collect())
+}
+
+/// Log-likelihood ratios `ln(P(1) / P(0))` of `len` bits embedded under `key`, for a
+/// soft-decision error correction decoder.
+///
+/// Each coefficient's offset from the nearest point of either lattice is modeled as Gaussian
+/// noise, with the variance estimated from all carriers of the image. Unlike a hard bit, a value
+/// near zero tells the decoder the bit is unreliable.
+pub fn decode_scs_llr(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<Vec<f32>> {
+    let (width, height) = image.dimensions();
+    let carriers = carriers(width, height, len, key, config)?;
+
+    let (mut y_channel, _) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    Ok(llrs(&y_channel, &carriers, config))
+}
+
+/// Fraction of the carriers of `len` bits embedded under `key` that are nearer the lattice of
+/// their bit's decision.
+///
+/// About 1 in a cleanly marked image and a little over one half without the mark, which makes
+/// it a presence measure. The log-likelihood ratios cannot be one: they assume the mark is there,
+/// and look confident on any image.
+pub fn carrier_agreement(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<f64> {
+    let (width, height) = image.dimensions();
+    let carriers = carriers(width, height, len, key, config)?;
+    if carriers.is_empty() {
+        return Err("No bits to measure".into());
+    }
+
+    let (mut y_channel, _) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    Ok(agreement(&y_channel, &carriers, config))
+}
+
+/// How much of a mark is left, see [`estimate_strength`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrengthEstimate {
+    /// Effective `alpha`: one minus four times the mean distance, in steps, of the carriers from
+    /// the lattice of their bit. `config.alpha` in an untouched mark, less after 8-bit rounding
+    /// and any later processing, and near 0 without the mark.
+    pub strength: f64,
+    /// `strength` over `config.alpha`, the share of the embedded strength that is left.
+    pub remaining: f64,
+    /// Standard deviation of the noise processing added to the carriers, in coefficient units.
+    pub noise: f64,
+    /// Per bit, the carriers' summed vote for the decoded value relative to an untouched mark: 1
+    /// as embedded, 0 where the bit is about to flip.
+    pub bit_margins: Vec<f64>,
+}
+
+impl StrengthEstimate {
+    /// Margin of the bit closest to flipping; the mark is as sound as its weakest bit.
+    pub fn weakest(&self) -> f64 {
+        self.bit_margins
+            .iter()
+            .copied()
+            .fold(f64::INFINITY, f64::min)
+    }
+}
+
+/// Estimates the strength of the `len` bits embedded under `key` that is left in `image`, and
+/// how close each bit is to flipping, so the owner of a circulating copy can tell how much more
+/// processing its mark will take.
+///
+/// Like the decoder, the estimate only knows the decoded bits: a bit that already flipped counts
+/// with the margin of its wrong value. Tell flipped bits apart with a check in the payload, and
+/// whether there is a mark at all with [`carrier_agreement`].
+pub fn estimate_strength(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<StrengthEstimate> {
+    let (width, height) = image.dimensions();
+    let carriers = carriers(width, height, len, key, config)?;
+    if carriers.is_empty() {
+        return Err("No bits to measure".into());
+    }
+
+    let (mut y_channel, _) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+
+    let distance = |u: f32| (u - u.round()).abs() as f64;
+    let mut residual = 0.0;
+    let mut residual_squared = 0.0;
+    let mut bit_margins = Vec::with_capacity(len);
+    for chunk in carriers.chunks_exact(config.repetition) {
+        let distances: Vec<(f64, f64)> = chunk
+            .iter()
+            .map(|&(index, dither)| {
+                let u = y_channel[index] / config.step - dither;
+                (distance(u), distance(u - 0.5))
+            })
+            .collect();
+        let vote: f64 = distances.iter().map(|&(d0, d1)| d0 - d1).sum();
+        for &(d0, d1) in &distances {
+            let d = if vote > 0.0 { d1 } else { d0 };
+            residual += d;
+            residual_squared += d * d;
+        }
+        // An untouched carrier votes alpha / 2 for its bit: the lattices are half a step apart
+        // and alpha leaves a residual of (1 - alpha) / 4 on average.
+        bit_margins.push(vote.abs() / chunk.len() as f64 / (config.alpha as f64 / 2.0));
+    }
+
+    let count = carriers.len() as f64;
+    let strength = (1.0 - 4.0 * residual / count).max(0.0);
+    // An embedded carrier sits uniformly within (1 - alpha) / 2 of its lattice point.
+    let embedded_variance = (1.0 - config.alpha as f64).powi(2) / 12.0;
+    let noise = (residual_squared / count - embedded_variance)
+        .max(0.0)
+        .sqrt()
+        * config.step as f64;
+
+    Ok(StrengthEstimate {
+        strength,
+        remaining: strength / config.alpha as f64,
+        noise,
+        bit_margins,
+    })
+}
+
+/// Outcome of [`verify_roundtrip`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoundTripReport {
+    /// Bits embedded.
+    pub bits: usize,
+    /// Bits decoded wrong.
+    pub errors: usize,
+    /// PSNR in dB of the marked image against the original.
+    pub psnr: f64,
+    /// [`carrier_agreement`] of the marked image.
+    pub agreement: f64,
+}
+
+impl RoundTripReport {
+    /// Share of the bits decoded right, 1 for a clean round trip.
+    pub fn bit_accuracy(&self) -> f64 {
+        1.0 - self.errors as f64 / self.bits as f64
+    }
+}
+
+/// Embeds `bits` under `key` into `image`, decodes them straight back and reports how many came
+/// out right, so integrators can check a config against their own images at deploy time.
+///
+/// The decoder reads the marked image as delivered, in 8-bit RGB, so the report shows whether
+/// rounding alone already costs bits: a step too small for the content does on real photos.
+pub fn verify_roundtrip(
+    image: &DynamicImage,
+    bits: &[bool],
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<RoundTripReport> {
+    if bits.is_empty() {
+        return Err("No bits to embed".into());
+    }
+
+    let marked = DynamicImage::ImageRgb8(embed_scs(image, bits, key, config)?);
+    let decoded = decode_scs(&marked, bits.len(), key, config)?;
+
+    Ok(RoundTripReport {
+        bits: bits.len(),
+        errors: decoded.iter().zip(bits).filter(|(a, b)| a != b).count(),
+        psnr: psnr(image, &marked)?,
+        agreement: carrier_agreement(&marked, bits.len(), key, config)?,
+    })
+}
+
+/// [`carrier_agreement`] on transformed luma, for non-empty `carriers`.
+pub(crate) fn agreement(
+    coefficients: &[f32],
+    carriers: &[(usize, f32)],
+    config: &ScsConfig,
+) -> f64 {
+    let distance = |u: f32| (u - u.round()).abs();
+    let mut agreeing = 0;
+    for chunk in carriers.chunks_exact(config.repetition) {
+        // Positive where a carrier is nearer the lattice of a 1.
+        let votes: Vec<f32> = chunk
+            .iter()
+            .map(|&(index, dither)| {
+                let u = coefficients[index] / config.step - dither;
+                distance(u) - distance(u - 0.5)
+            })
+            .collect();
+        let bit = votes.iter().sum::<f32>() > 0.0;
+        agreeing += votes.iter().filter(|&&vote| (vote > 0.0) == bit).count();
+    }
+
+    agreeing as f64 / carriers.len() as f64
+}
+
+/// Moves the carrier coefficients of each bit towards its lattice.
+pub(crate) fn quantize(
+    coefficients: &mut [f32],
+    carriers: &[(usize, f32)],
+    bits: &[bool],
+    config: &ScsConfig,
+) {
+    for (&bit, chunk) in bits.iter().zip(carriers.chunks_exact(config.repetition)) {
+        let offset = if bit { 0.5 } else { 0.0 };
+        for &(index, dither) in chunk {
+            let x = coefficients[index];
+            let shift = dither + offset;
+            let target = ((x / config.step - shift).round() + shift) * config.step;
+            coefficients[index] = x + config.alpha * (target - x);
+        }
+    }
+}
+
+/// Log-likelihood ratios of the bits carried by `carriers`, see [`decode_scs_llr`].
+pub(crate) fn llrs(
+    coefficients: &[f32],
+    carriers: &[(usize, f32)],
+    config: &ScsConfig,
+) -> Vec<f32> {
+    // Distances in steps to the lattices of a 0 and a 1; they always sum to one half.
+    let distance = |u: f32| (u - u.round()).abs();
+    let distances: Vec<(f32, f32)> = carriers
+        .iter()
+        .map(|&(index, dither)| {
+            let u = coefficients[index] / config.step - dither;
+            (distance(u), distance(u - 0.5))
+        })
+        .collect();
+
+    // The residual of the nearer lattice measures the noise; alpha below 1 leaves some on purpose.
+    let variance = distances
+        .iter()
+        .map(|&(d0, d1)| d0.min(d1).powi(2))
+        .sum::<f32>()
+        / distances.len().max(1) as f32;
+    let variance = variance.max(MIN_VARIANCE);
+
+    distances
+        .chunks_exact(config.repetition)
+        .map(|chunk| {
+            // d0^2 - d1^2 = (d0 - d1) / 2, as d0 + d1 = 1 / 2.
+            chunk.iter().map(|&(d0, d1)| (d0 - d1) / 2.0).sum::<f32>() / (2.0 * variance)
+        })
+        .collect()
+}
+
+/// Keyed coefficient indices and lattice dithers, `repetition` per bit.
+pub(crate) fn carriers(
+    width: u32,
+    height: u32,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<Vec<(usize, f32)>> {
+    let valid = config.step > 0.0 && config.alpha > 0.0 && config.alpha <= 1.0;
+    if !valid {
+        return Err("Step must be positive and alpha in (0, 1]".into());
+    }
+    if config.repetition == 0 {
+        return Err("Repetition must be positive".into());
+    }
+    let capacity = config.capacity(width, height)?;
+    if len > capacity {
+        return Err(format!(
+            "{} bits requested, but a {}x{} image carries {} with this config",
+            len, width, height, capacity
+        )
+        .into());
+    }
+
+    let mut band: Vec<usize> = config.band.indices(pixel_count(width, height)?)?.collect();
+    let mut prng = Prng::from_key(key);
+    let count = len * config.repetition;
+    // Scattering the carriers over the band keeps a localized loss from taking out whole bits.
+    for i in 0..count {
+        let j = i + prng.next_below((band.len() - i) as u64) as usize;
+        band.swap(i, j);
+    }
+
+    Ok(band[..count]
+        .iter()
+        .map(|&index| (index, prng.next_f32()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::test_utils::grainy;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(grainy(128, 96, 41))
+    }
+
+    fn bits(len: usize) -> Vec<bool> {
+        (0..len).map(|i| (i * 13 + i / 5) % 3 == 0).collect()
+    }
+
+    #[test]
+    fn test_scs_roundtrip() {
+        let image = image();
+        let config = ScsConfig::default();
+        assert_eq!(config.capacity(128, 96).unwrap(), 288);
+
+        let bits = bits(128);
+        let marked = embed_scs(&image, &bits, b"key", &config).unwrap();
+        let marked = DynamicImage::ImageRgb8(marked);
+        assert!(psnr(&image, &marked).unwrap() > 40.0);
+        assert_eq!(
+            decode_scs(&marked, bits.len(), b"key", &config).unwrap(),
+            bits
+        );
+
+        let wrong = decode_scs(&marked, bits.len(), b"other", &config).unwrap();
+        let errors = wrong.iter().zip(&bits).filter(|(a, b)| a != b).count();
+        assert!(errors > 32, "{} errors with the wrong key", errors);
+    }
+
+    #[test]
+    fn test_verify_roundtrip() {
+        let image = image();
+        let report = verify_roundtrip(&image, &bits(128), b"key", &ScsConfig::default()).unwrap();
+        assert_eq!((report.bits, report.errors), (128, 0));
+        assert_eq!(report.bit_accuracy(), 1.0);
+        assert!(
+            report.psnr > 40.0 && report.agreement > 0.95,
+            "{:?}",
+            report
+        );
+
+        // Lattices finer than 8-bit rounding lose bits on the way out.
+        let fine = ScsConfig {
+            step: 0.5,
+            ..Default::default()
+        };
+        let report = verify_roundtrip(&image, &bits(128), b"key", &fine).unwrap();
+        assert!(
+            report.errors > 0 && report.bit_accuracy() < 1.0,
+            "{:?}",
+            report
+        );
+
+        assert!(verify_roundtrip(&image, &[], b"key", &ScsConfig::default()).is_err());
+        assert!(verify_roundtrip(&image, &bits(1000), b"key", &ScsConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_scs_llr() {
+        let image = image();
+        let config = ScsConfig::default();
+        let bits = bits(64);
+        let marked = embed_scs(&image, &bits, b"key", &config).unwrap();
+
+        // Noise of up to a fifth of a step leaves the bits readable but less certain.
+        let noisy = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            let p = marked.get_pixel(x, y);
+            let noise = ((x * 31 + y * 17) % 5) as i16 - 2;
+            Rgb(p.0.map(|c| (c as i16 + noise).clamp(0, 255) as u8))
+        }));
+        let marked = DynamicImage::ImageRgb8(marked);
+
+        let clean = decode_scs_llr(&marked, bits.len(), b"key", &config).unwrap();
+        let degraded = decode_scs_llr(&noisy, bits.len(), b"key", &config).unwrap();
+        for ((&bit, &a), &b) in bits.iter().zip(&clean).zip(&degraded) {
+            assert_eq!(a > 0.0, bit, "{} {}", a, b);
+            assert!(a.is_finite());
+        }
+        let confidence = |llrs: &[f32]| llrs.iter().map(|l| l.abs()).sum::<f32>();
+        assert!(confidence(&degraded) < confidence(&clean));
+        assert_eq!(
+            decode_scs(&noisy, bits.len(), b"key", &config).unwrap(),
+            degraded.iter().map(|&l| l > 0.0).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_carrier_agreement() {
+        let image = image();
+        let config = ScsConfig::default();
+        let marked = embed_scs(&image, &bits(64), b"key", &config).unwrap();
+        let marked = DynamicImage::ImageRgb8(marked);
+
+        let present = carrier_agreement(&marked, 64, b"key", &config).unwrap();
+        assert!(present > 0.95, "{}", present);
+        for (candidate, key) in [(&marked, &b"other"[..]), (&image, &b"key"[..])] {
+            let absent = carrier_agreement(candidate, 64, key, &config).unwrap();
+            assert!((0.5..0.75).contains(&absent), "{}", absent);
+        }
+        assert!(carrier_agreement(&image, 0, b"key", &config).is_err());
+    }
+
+    #[test]
+    fn test_estimate_strength() {
+        let image = image();
+        let config = ScsConfig {
+            step: 16.0,
+            ..Default::default()
+        };
+        let marked = embed_scs(&image, &bits(64), b"key", &config).unwrap();
+        let noisy = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 96, |x, y| {
+            let p = marked.get_pixel(x, y);
+            let h =
+                (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)).wrapping_mul(0x5bd1_e995);
+            let noise = (h >> 28) as i16 - 8;
+            Rgb(p.0.map(|c| (c as i16 + noise).clamp(0, 255) as u8))
+        }));
+        let marked = DynamicImage::ImageRgb8(marked);
+
+        let fresh = estimate_strength(&marked, 64, b"key", &config).unwrap();
+        assert!(fresh.strength > 0.5 && fresh.remaining > 0.7, "{:?}", fresh);
+        assert_eq!(fresh.bit_margins.len(), 64);
+        assert!(fresh.weakest() > 0.0);
+
+        let degraded = estimate_strength(&noisy, 64, b"key", &config).unwrap();
+        assert!(degraded.remaining < fresh.remaining, "{:?}", degraded);
+        assert!(degraded.noise > fresh.noise);
+        assert!(degraded.weakest() < fresh.weakest());
+
+        let absent = estimate_strength(&image, 64, b"key", &config).unwrap();
+        assert!(absent.strength < 0.25, "{:?}", absent);
+        assert!(estimate_strength(&image, 0, b"key", &config).is_err());
+    }
+
+    #[test]
+    fn test_erase_scs() {
+        let image = image();
+        let config = ScsConfig {
+            step: 16.0,
+            ..Default::default()
+        };
+        let marked =
+            DynamicImage::ImageRgb8(embed_scs(&image, &bits(64), b"key", &config).unwrap());
+        let erased = DynamicImage::ImageRgb8(erase_scs(&marked, 64, b"key", &config).unwrap());
+
+        let agreement = carrier_agreement(&erased, 64, b"key", &config).unwrap();
+        assert!(agreement < 0.75, "{}", agreement);
+        let decoded = decode_scs(&erased, 64, b"key", &config).unwrap();
+        let errors = decoded
+            .iter()
+            .zip(bits(64))
+            .filter(|(a, b)| **a != *b)
+            .count();
+        assert!(errors > 16, "{} errors after erasing", errors);
+        assert!(psnr(&marked, &erased).unwrap() > 35.0);
+    }
+
+    #[test]
+    fn test_scs_limits() {
+        let config = ScsConfig::default();
+        assert!(embed_scs(&image(), &bits(289), b"key", &config).is_err());
+
+        let config = ScsConfig {
+            alpha: 0.0,
+            ..config
+        };
+        assert!(decode_scs(&image(), 8, b"key", &config).is_err());
+    }
+}
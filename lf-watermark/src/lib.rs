@@ -1,25 +1,113 @@
 use std::error::Error;
 
-use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
-use rustdct::DctPlanner;
+use image::{DynamicImage, GenericImageView, ImageBuffer, Rgb, RgbImage};
+use rustdct::num_traits::Float;
+use rustdct::{DctNum, DctPlanner};
+
+/// Opens a `tracing` span for a pipeline stage that lasts until the end of the enclosing block.
+/// Expands to nothing without the `tracing` feature.
+#[cfg(feature = "tracing")]
+macro_rules! stage {
+    ($name:literal $(, $($fields:tt)*)?) => {
+        let _stage = tracing::info_span!($name $(, $($fields)*)?).entered();
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! stage {
+    ($($tokens:tt)*) => {};
+}
+
+pub mod ab;
+pub mod analysis;
+#[cfg(feature = "attacks")]
+pub mod attacks;
+pub mod audit;
+#[cfg(feature = "parallel")]
+pub mod batch;
+pub mod blocks;
+pub mod cache;
+pub mod carrier;
+pub mod cmyk;
+pub mod codec;
+pub mod color;
+pub mod compress;
+#[cfg(feature = "toml")]
+pub mod config_file;
+pub mod custody;
+pub mod dicom;
+pub mod dng;
+pub mod embedder;
+pub mod exif;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fixed;
+pub mod forensics;
+pub mod global;
+#[cfg(feature = "test-utils")]
+pub mod golden;
+pub mod hdr;
+pub mod histogram;
+pub mod lattice;
+pub mod manifest;
+pub mod mapped;
+pub mod metrics;
+pub mod orientation;
+pub mod patchwork;
+pub mod pdf;
+pub mod perceptual;
+pub mod preview;
+mod prng;
+pub mod pyramid;
+pub mod raw;
+pub mod region;
+#[cfg(feature = "removable")]
+pub mod removable;
+pub mod search;
+pub mod session;
+pub mod svg;
+mod telemetry;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod text;
+pub mod thumbnail;
+pub mod tiled;
+pub mod visible;
+
+pub use codec::{
+    embed_and_encode, embed_watermark_encoded, embed_watermark_preprocessed, encode, OutputFormat,
+    Preprocess,
+};
+#[cfg(feature = "toml")]
+pub use config_file::ConfigFile;
+pub use embedder::{Embedder, EmbedderPool};
+pub use global::{default_config, embed, set_default_config};
+pub use hdr::{embed_watermark_hdr, HdrDomain};
+use lf_watermark_core::payload_value;
+pub use lf_watermark_core::{
+    Band, ChannelStrength, Determinism, OrientationPolicy, PalettePolicy, StrengthCurve,
+    WatermarkConfig,
+};
+pub use raw::{
+    embed_luma_plane, embed_raw, embed_raw_with_matrix, ColorMatrix, MatrixCoefficients,
+    PixelFormat,
+};
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
-pub fn get_watermark_from_str(words: &str) -> Result<f32> {
-    let char_map =
-        "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!@#$%^&*(),.<>/?; ";
+/// 16-bit RGB image, as produced by [`embed_watermark_rgb16`].
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
 
-    let mut ret = 0.0;
+/// Float type the transform pipeline runs in.
+///
+/// `f32` is the default everywhere and is the fast choice; `f64` keeps full fidelity for 16-bit
+/// sources, where the rounding error of a whole-image DCT in `f32` becomes visible in the low bits.
+pub trait Precision: DctNum + Float {}
 
-    for i in 0..words.len() {
-        let c = words.chars().nth(i).ok_or("invalid index")?;
-        let char_idx = char_map
-            .find(c)
-            .ok_or(format!("Invalid character; {}", c))?;
-        ret += char_idx as f32;
-    }
+impl<T: DctNum + Float> Precision for T {}
 
-    Ok(ret
+pub fn get_watermark_from_str(words: &str) -> Result<f32> {
+    Ok(payload_value(words)?
         * option_env!("WATERMARK_STRENGTH")
             .unwrap_or("0.01")
             .parse::<f32>()?)
@@ -29,46 +117,261 @@ pub fn embed_watermark_color(image: &DynamicImage, watermark: &str) -> Result<Rg
     let watermark = get_watermark_from_str(watermark)?;
 
     let (width, height) = image.dimensions();
-    let len = (width * height) as usize;
-    let mut cbcr_channel = vec![(0, 0); len];
-    let mut y_channel = vec![0.0; len];
-    let idx_fn = |x: u32, y: u32| (y * width + x) as usize;
-    let normalization_factor = (2.0 / len as f32).sqrt();
+    let (mut y_channel, cbcr_channel) = split_ycbcr(&image.to_rgb8());
+    for y in y_channel.iter_mut() {
+        *y += watermark;
+    }
 
-    let image = image.to_rgb8();
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    forward_dct(&mut dct_planner, &mut y_channel);
+    inverse_dct(&mut dct_planner, &mut y_channel);
 
-    for (x, y, pixel) in image.enumerate_pixels() {
-        let idx = idx_fn(x, y);
+    Ok(merge_ycbcr(width, height, &y_channel, &cbcr_channel))
+}
 
-        let (y, u, v) = rgb_to_ycbcr(&pixel);
-        cbcr_channel[idx] = (u, v);
-        y_channel[idx] = y as f32 + watermark;
+/// Same as [`embed_watermark_color`], but the mark is added directly to the luma DCT
+/// coefficients selected by `config.band`, weighted by `config.curve`.
+///
+/// Each selected coefficient receives `value * weight * sqrt(2N / M)`, where `N` is the number of
+/// pixels and `M` the band width. This keeps the mark's energy independent of the band, so with
+/// the default config (DC only, flat curve) the result is the uniform luma offset of
+/// [`embed_watermark_color`], up to float rounding.
+///
+/// With [`Determinism::Strict`] the embed runs through [`fixed::embed_watermark`] instead, so the
+/// output is bit-identical on every platform.
+pub fn embed_watermark_with_config(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<RgbImage> {
+    if config.determinism == Determinism::Strict {
+        return fixed::embed_watermark(image, watermark, config);
     }
 
+    let watermark = payload_value(watermark)? * config.strength;
+    let channels = config.channels;
+
+    let (width, height) = image.dimensions();
+    let (mut y_channel, cbcr_channel) = split_ycbcr(&image.to_rgb8());
+    add_to_plane(&mut y_channel, watermark * channels.y, config)?;
+    if !channels.has_chroma() {
+        return Ok(merge_ycbcr(width, height, &y_channel, &cbcr_channel));
+    }
+
+    // Marked chroma stays in float until the final conversion, like the luma.
+    let (mut cb_channel, mut cr_channel): (Vec<f32>, Vec<f32>) = cbcr_channel
+        .iter()
+        .map(|&(cb, cr)| (cb as f32, cr as f32))
+        .unzip();
+    for (plane, multiplier) in [
+        (&mut cb_channel, channels.cb),
+        (&mut cr_channel, channels.cr),
+    ] {
+        if multiplier != 0.0 {
+            add_to_plane(plane, watermark * multiplier, config)?;
+        }
+    }
+
+    let mut img_buffer = RgbImage::new(width, height);
+    for (index, pixel) in img_buffer.pixels_mut().enumerate() {
+        *pixel = ycbcr_to_rgb(y_channel[index], cb_channel[index], cr_channel[index]);
+    }
+
+    Ok(img_buffer)
+}
+
+/// 16-bit variant of [`embed_watermark_with_config`], running the transform in `T`.
+///
+/// The color conversion keeps full precision instead of rounding to 8 bits, and the mark is scaled
+/// by 257 so a given `config.strength` has the same visual effect as on an 8-bit image. Use
+/// `T = f64` for 16-bit masters; `f32` works but loses low-order bits on large images.
+pub fn embed_watermark_rgb16<T: Precision>(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<Rgb16Image> {
+    let scale = T::from_f64(257.0).ok_or("Invalid precision")?;
+    let watermark = T::from_f32(payload_value(watermark)? * config.strength)
+        .ok_or("Invalid watermark value")?
+        * scale;
+
+    let (width, height) = image.dimensions();
+    let image = image.to_rgb16();
+    let offset = T::from_f64(32768.0).ok_or("Invalid precision")?;
+    let max = T::from_f64(65535.0).ok_or("Invalid precision")?;
+    let coef = |v: f64| T::from_f64(v).unwrap_or_else(T::zero);
+
+    let len = pixel_count(width, height)?;
+    let mut y_channel = Vec::with_capacity(len);
+    let mut cbcr_channel = Vec::with_capacity(len);
+    for pixel in image.pixels() {
+        let r = T::from_u16(pixel[0]).unwrap_or_else(T::zero);
+        let g = T::from_u16(pixel[1]).unwrap_or_else(T::zero);
+        let b = T::from_u16(pixel[2]).unwrap_or_else(T::zero);
+
+        y_channel.push(coef(0.299) * r + coef(0.587) * g + coef(0.114) * b);
+        cbcr_channel.push((
+            coef(-0.169) * r - coef(0.331) * g + coef(0.5) * b + offset,
+            coef(0.5) * r - coef(0.419) * g - coef(0.081) * b + offset,
+        ));
+    }
+
+    add_to_band(&mut y_channel, watermark, config)?;
+
+    let to_u16 = |v: T| v.round().max(T::zero()).min(max).to_u16().unwrap_or(0);
+    let mut img_buffer = Rgb16Image::new(width, height);
+    for (index, pixel) in img_buffer.pixels_mut().enumerate() {
+        let y = y_channel[index];
+        let (cb, cr) = cbcr_channel[index];
+        let (cb, cr) = (cb - offset, cr - offset);
+
+        *pixel = Rgb([
+            to_u16(y + coef(1.402) * cr),
+            to_u16(y - coef(0.34414) * cb - coef(0.71414) * cr),
+            to_u16(y + coef(1.772) * cb),
+        ]);
+    }
+
+    Ok(img_buffer)
+}
+
+/// Adds `watermark` to the band of `y_channel`'s DCT selected by `config`, in place.
+fn add_to_band<T: Precision>(
+    y_channel: &mut [T],
+    watermark: T,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    // Validate the band before paying for the transform.
+    config.band.indices(y_channel.len())?;
+
+    let mut dct_planner: DctPlanner<T> = DctPlanner::new();
+    forward_dct(&mut dct_planner, y_channel);
+    mark_coefficients(y_channel, watermark, config)?;
+    inverse_dct(&mut dct_planner, y_channel);
+
+    Ok(())
+}
+
+/// Transforms one plane, adds `watermark` to its band and transforms back, ignoring
+/// `config.channels`.
+fn add_to_plane(plane: &mut [f32], watermark: f32, config: &WatermarkConfig) -> Result<()> {
+    config.band.indices(plane.len())?;
+
     let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
-    let dct = dct_planner.plan_dct2(len);
-    dct.process_dct2(&mut y_channel);
+    forward_dct(&mut dct_planner, plane);
+    mark_plane(plane, watermark, config)?;
+    inverse_dct(&mut dct_planner, plane);
 
-    for y in y_channel.iter_mut() {
-        *y *= normalization_factor;
+    Ok(())
+}
+
+/// The coefficient step of [`add_to_band`], on an already transformed luma signal.
+///
+/// Scales the mark by `config.channels.y`. Embedders built on this mark luma only, so chroma
+/// multipliers are rejected rather than silently dropped.
+pub(crate) fn mark_coefficients<T: Precision>(
+    coefficients: &mut [T],
+    watermark: T,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    if config.determinism == Determinism::Strict {
+        return Err(lf_watermark_core::Error::UnsupportedDeterminism.into());
+    }
+    if config.channels.has_chroma() {
+        return Err(lf_watermark_core::Error::UnsupportedChroma.into());
     }
+    let y = T::from_f32(config.channels.y).ok_or("Invalid channel strength")?;
 
-    let idct = dct_planner.plan_dct3(len);
-    idct.process_dct3(&mut y_channel);
-    for y in y_channel.iter_mut() {
-        *y *= normalization_factor;
+    mark_plane(coefficients, watermark * y, config)
+}
+
+/// Adds `watermark` to the band of one transformed plane.
+fn mark_plane<T: Precision>(
+    coefficients: &mut [T],
+    watermark: T,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    stage!("embed", band = ?config.band);
+    let len = coefficients.len();
+    let band = config.band.indices(len)?;
+    let band_len = band.len();
+    let scale = T::from_f64((2.0 * len as f64 / band_len as f64).sqrt()).ok_or("Invalid scale")?;
+
+    for (i, c) in coefficients[band].iter_mut().enumerate() {
+        let weight = T::from_f32(config.curve.weight(i, band_len)).ok_or("Invalid weight")?;
+        *c = *c + watermark * scale * weight;
+    }
+
+    Ok(())
+}
+
+/// Number of pixels of a `width` x `height` image, failing instead of overflowing on 32-bit hosts.
+pub(crate) fn pixel_count(width: u32, height: u32) -> Result<usize> {
+    usize::try_from(width as u64 * height as u64)
+        .map_err(|_| lf_watermark_core::Error::ImageTooLarge { width, height }.into())
+}
+
+/// Splits an image into a float luma plane and the untouched chroma pairs, both in raster order.
+pub(crate) fn split_ycbcr(image: &RgbImage) -> (Vec<f32>, Vec<(u8, u8)>) {
+    stage!(
+        "color_conversion",
+        width = image.width(),
+        height = image.height()
+    );
+    let len = image.width() as usize * image.height() as usize;
+    let mut cbcr_channel = vec![(0, 0); len];
+    let mut y_channel = vec![0.0; len];
+
+    for (idx, pixel) in image.pixels().enumerate() {
+        let (y, u, v) = rgb_to_ycbcr(pixel);
+        cbcr_channel[idx] = (u, v);
+        y_channel[idx] = y as f32;
     }
 
+    (y_channel, cbcr_channel)
+}
+
+/// Inverse of [`split_ycbcr`].
+pub(crate) fn merge_ycbcr(
+    width: u32,
+    height: u32,
+    y_channel: &[f32],
+    cbcr_channel: &[(u8, u8)],
+) -> RgbImage {
+    stage!("color_conversion", width, height);
     let mut img_buffer = RgbImage::new(width, height);
-    for (x, y, pixel) in img_buffer.enumerate_pixels_mut() {
-        let index = idx_fn(x, y);
+    for (index, pixel) in img_buffer.pixels_mut().enumerate() {
         let y_ch = y_channel[index];
         let (cb, cr) = cbcr_channel[index];
 
         *pixel = ycbcr_to_rgb(y_ch, cb as f32, cr as f32);
     }
 
-    Ok(img_buffer)
+    img_buffer
+}
+
+/// Orthonormal DCT-II over the whole signal.
+pub(crate) fn forward_dct<T: Precision>(dct_planner: &mut DctPlanner<T>, signal: &mut [T]) {
+    stage!("transform", len = signal.len());
+    let normalization_factor = dct_normalization(signal.len());
+    dct_planner.plan_dct2(signal.len()).process_dct2(signal);
+    for s in signal.iter_mut() {
+        *s = *s * normalization_factor;
+    }
+}
+
+/// Inverse of [`forward_dct`].
+pub(crate) fn inverse_dct<T: Precision>(dct_planner: &mut DctPlanner<T>, signal: &mut [T]) {
+    stage!("transform", len = signal.len(), inverse = true);
+    let normalization_factor = dct_normalization(signal.len());
+    dct_planner.plan_dct3(signal.len()).process_dct3(signal);
+    for s in signal.iter_mut() {
+        *s = *s * normalization_factor;
+    }
+}
+
+fn dct_normalization<T: Precision>(len: usize) -> T {
+    (T::two() / T::from_usize(len).unwrap_or_else(T::one)).sqrt()
 }
 
 fn rgb_to_ycbcr(pixel: &Rgb<u8>) -> (u8, u8, u8) {
@@ -84,9 +387,9 @@ fn rgb_to_ycbcr(pixel: &Rgb<u8>) -> (u8, u8, u8) {
 }
 
 fn ycbcr_to_rgb(y: f32, cb: f32, cr: f32) -> Rgb<u8> {
-    let r = (y + 1.402 * (cr as f32 - 128.0)).round() as u8;
-    let g = (y - 0.34414 * (cb as f32 - 128.0) - 0.71414 * (cr as f32 - 128.0)).round() as u8;
-    let b = (y + 1.772 * (cb as f32 - 128.0)).round() as u8;
+    let r = (y + 1.402 * (cr - 128.0)).round() as u8;
+    let g = (y - 0.34414 * (cb - 128.0) - 0.71414 * (cr - 128.0)).round() as u8;
+    let b = (y + 1.772 * (cb - 128.0)).round() as u8;
 
     Rgb([r, g, b])
 }
@@ -107,7 +410,7 @@ mod tests {
     #[test]
     fn test_rgb_to_ycbcr() {
         // NOTE: this ycbcr conversion make a little changes to the original rgb value
-        for (r, g, b) in vec![
+        for (r, g, b) in [
             (255, 255, 255),
             (254, 0, 0),
             (0, 255, 1),
@@ -134,6 +437,105 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_embed_watermark_with_config() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            Rgb([(x * 3) as u8, (y * 3) as u8, 100])
+        }));
+        let watermark = "Hello, World!";
+
+        let offset = embed_watermark_color(&img, watermark).unwrap();
+        let dc = embed_watermark_with_config(&img, watermark, &WatermarkConfig::default()).unwrap();
+        for (a, b) in offset.pixels().zip(dc.pixels()) {
+            for c in 0..3 {
+                assert!(a[c].abs_diff(b[c]) <= 1, "{:?} {:?}", a, b);
+            }
+        }
+
+        let config = WatermarkConfig {
+            strength: 1.0,
+            band: Band::Mid,
+            curve: StrengthCurve::Linear {
+                start: 1.0,
+                end: 0.0,
+            },
+            ..Default::default()
+        };
+        let mid = embed_watermark_with_config(&img, watermark, &config).unwrap();
+        assert_ne!(offset, mid);
+
+        let config = WatermarkConfig {
+            band: Band::Range(0..1_000_000),
+            ..Default::default()
+        };
+        assert!(embed_watermark_with_config(&img, watermark, &config).is_err());
+    }
+
+    #[test]
+    fn test_channel_strength() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(32, 32, Rgb([100, 120, 140])));
+        let original = rgb_to_ycbcr(img.as_rgb8().unwrap().get_pixel(0, 0));
+        let watermark = "Hello, World!";
+        let config = WatermarkConfig {
+            strength: 0.01,
+            channels: ChannelStrength {
+                y: 0.0,
+                cb: 1.0,
+                cr: 0.0,
+            },
+            ..Default::default()
+        };
+
+        // A DC mark of 5.35 on Cb alone, with the luma and Cr left in place.
+        let marked = embed_watermark_with_config(&img, watermark, &config).unwrap();
+        let (y, cb, cr) = rgb_to_ycbcr(marked.get_pixel(5, 5));
+        assert!(y.abs_diff(original.0) <= 1, "{} {:?}", y, original);
+        assert!(
+            (cb as i32 - original.1 as i32 - 5).abs() <= 1,
+            "{} {:?}",
+            cb,
+            original
+        );
+        assert!(cr.abs_diff(original.2) <= 1, "{} {:?}", cr, original);
+
+        let config = WatermarkConfig {
+            channels: ChannelStrength::HVS,
+            ..config
+        };
+        let hvs = embed_watermark_with_config(&img, watermark, &config).unwrap();
+        let luma_only =
+            embed_watermark_with_config(&img, watermark, &WatermarkConfig::default()).unwrap();
+        assert_ne!(hvs, luma_only);
+
+        assert!(Embedder::new(config.clone())
+            .embed(&img, watermark)
+            .is_err());
+        assert!(embed_watermark_rgb16::<f32>(&img, watermark, &config).is_err());
+    }
+
+    #[test]
+    fn test_embed_watermark_rgb16() {
+        let img = DynamicImage::ImageRgb16(ImageBuffer::from_fn(64, 64, |x, y| {
+            Rgb([(x * 1000) as u16, (y * 1000) as u16, 30000])
+        }));
+        let watermark = "Hello, World!";
+        let config = WatermarkConfig::default();
+
+        let marked = embed_watermark_rgb16::<f64>(&img, watermark, &config).unwrap();
+        let marked_f32 = embed_watermark_rgb16::<f32>(&img, watermark, &config).unwrap();
+
+        // 5.35 luma levels at 8 bits is about 1375 at 16 bits.
+        let original = img.as_rgb16().unwrap();
+        let shift = marked.get_pixel(10, 10)[2] as i32 - original.get_pixel(10, 10)[2] as i32;
+        assert!((shift - 1375).abs() <= 2, "{}", shift);
+
+        for (a, b) in marked.pixels().zip(marked_f32.pixels()) {
+            for c in 0..3 {
+                assert!(a[c].abs_diff(b[c]) <= 8, "{:?} {:?}", a, b);
+            }
+        }
+    }
+
     #[test]
     fn test_psnr() {
         let img = image::open("image.png").unwrap();
@@ -0,0 +1,279 @@
+//! Resumable batch jobs.
+//!
+//! A [`Manifest`] records the status of every file of a batch in a small text file that is
+//! rewritten after each step, so a run that crashed hours in picks up where it stopped instead of
+//! starting over.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::mapped::embed_file;
+use crate::{Result, WatermarkConfig};
+
+const HEADER: &str = "# lf-watermark batch manifest v1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobStatus {
+    Pending,
+    /// Started but not recorded as finished, either still running or interrupted by a crash.
+    Running,
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchJob {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub status: JobStatus,
+}
+
+/// Counts of what one [`Manifest::run`] did.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    /// Embedded during this run.
+    pub done: usize,
+    /// Already finished by an earlier run, with their output verified.
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    path: PathBuf,
+    jobs: Vec<BatchJob>,
+}
+
+impl Manifest {
+    /// Starts a new batch of `(input, output)` pairs and writes its manifest to `path`.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        jobs: impl IntoIterator<Item = (PathBuf, PathBuf)>,
+    ) -> Result<Self> {
+        let jobs = jobs
+            .into_iter()
+            .map(|(input, output)| {
+                for p in [&input, &output] {
+                    if p.to_str().is_none_or(|s| s.contains(['\t', '\n'])) {
+                        return Err(format!("Unsupported path in manifest; {:?}", p).into());
+                    }
+                }
+
+                Ok(BatchJob {
+                    input,
+                    output,
+                    status: JobStatus::Pending,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        let manifest = Manifest {
+            path: path.as_ref().to_path_buf(),
+            jobs,
+        };
+        manifest.save()?;
+
+        Ok(manifest)
+    }
+
+    /// Reads the manifest of an earlier run back from `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = fs::read_to_string(&path)?;
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err("Invalid manifest header".into());
+        }
+
+        let jobs = lines
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let fields: Vec<&str> = line.splitn(4, '\t').collect();
+                let status = match fields[..] {
+                    ["pending", _, _] => JobStatus::Pending,
+                    ["running", _, _] => JobStatus::Running,
+                    ["done", _, _] => JobStatus::Done,
+                    ["failed", _, _, message] => JobStatus::Failed(message.to_string()),
+                    _ => return Err(format!("Invalid manifest line; {}", line).into()),
+                };
+
+                Ok(BatchJob {
+                    input: PathBuf::from(fields[1]),
+                    output: PathBuf::from(fields[2]),
+                    status,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Manifest {
+            path: path.as_ref().to_path_buf(),
+            jobs,
+        })
+    }
+
+    pub fn jobs(&self) -> &[BatchJob] {
+        &self.jobs
+    }
+
+    /// Embeds every job that has not finished yet, saving the manifest after each status change.
+    ///
+    /// Outputs are written under a temporary name and renamed into place when complete, so an
+    /// existing output is never a partial one. Jobs recorded as done or left running by a crash
+    /// are re-verified by checking that their output decodes to the input's dimensions; those
+    /// that pass are skipped, the others are embedded again. Failed jobs are retried.
+    pub fn run(&mut self, watermark: &str, config: &WatermarkConfig) -> Result<BatchSummary> {
+        let mut summary = BatchSummary::default();
+        for i in 0..self.jobs.len() {
+            let job = &self.jobs[i];
+            if matches!(job.status, JobStatus::Done | JobStatus::Running)
+                && verify_output(&job.input, &job.output)
+            {
+                if job.status != JobStatus::Done {
+                    self.jobs[i].status = JobStatus::Done;
+                    self.save()?;
+                }
+                summary.skipped += 1;
+                continue;
+            }
+
+            self.jobs[i].status = JobStatus::Running;
+            self.save()?;
+
+            let job = &self.jobs[i];
+            let status = match embed_atomically(&job.input, &job.output, watermark, config) {
+                Ok(()) => {
+                    summary.done += 1;
+                    JobStatus::Done
+                }
+                Err(err) => {
+                    summary.failed += 1;
+                    JobStatus::Failed(err.to_string().replace(['\t', '\n'], " "))
+                }
+            };
+            self.jobs[i].status = status;
+            self.save()?;
+        }
+
+        Ok(summary)
+    }
+
+    /// Rewrites the manifest file through a rename, so a crash leaves either the old or the new
+    /// version on disk.
+    fn save(&self) -> Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, self.to_string())?;
+        fs::rename(&tmp, &self.path)?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Manifest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        for job in &self.jobs {
+            let status = match &job.status {
+                JobStatus::Pending => "pending",
+                JobStatus::Running => "running",
+                JobStatus::Done => "done",
+                JobStatus::Failed(_) => "failed",
+            };
+            write!(
+                f,
+                "{}\t{}\t{}",
+                status,
+                job.input.display(),
+                job.output.display()
+            )?;
+            if let JobStatus::Failed(message) = &job.status {
+                write!(f, "\t{}", message)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn embed_atomically(
+    input: &Path,
+    output: &Path,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    let mut tmp = output.as_os_str().to_owned();
+    tmp.push(".partial");
+    let tmp = PathBuf::from(tmp);
+
+    embed_file(input, &tmp, watermark, config)?;
+    fs::rename(&tmp, output)?;
+
+    Ok(())
+}
+
+fn verify_output(input: &Path, output: &Path) -> bool {
+    let dimensions = |path: &Path| image::image_dimensions(path).ok();
+    dimensions(output).is_some_and(|d| Some(d) == dimensions(input))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_manifest_resume() {
+        let dir =
+            std::env::temp_dir().join(format!("lf-watermark-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let img = RgbImage::from_fn(8, 8, |x, y| Rgb([(x * 32) as u8, (y * 32) as u8, 0]));
+        let jobs: Vec<(PathBuf, PathBuf)> = (0..3)
+            .map(|i| {
+                let input = dir.join(format!("in{}.png", i));
+                img.save(&input).unwrap();
+                (input, dir.join(format!("out{}.png", i)))
+            })
+            .chain([(dir.join("missing.png"), dir.join("out-missing.png"))])
+            .collect();
+
+        let path = dir.join("job.manifest");
+        let mut manifest = Manifest::create(&path, jobs).unwrap();
+        let config = WatermarkConfig::default();
+        let summary = manifest.run("Hello", &config).unwrap();
+        assert_eq!(
+            summary,
+            BatchSummary {
+                done: 3,
+                skipped: 0,
+                failed: 1
+            }
+        );
+
+        // Simulate a crash: one job left running with a finished output, one with a broken one.
+        let mut text = fs::read_to_string(&path).unwrap();
+        text = text.replacen("done", "running", 2);
+        fs::write(&path, text).unwrap();
+        fs::write(dir.join("out1.png"), b"truncated").unwrap();
+
+        let mut manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest.jobs()[0].status, JobStatus::Running);
+        let summary = manifest.run("Hello", &config).unwrap();
+        assert_eq!(
+            summary,
+            BatchSummary {
+                done: 1,
+                skipped: 2,
+                failed: 1
+            }
+        );
+        assert!(matches!(
+            Manifest::load(&path).unwrap().jobs()[3].status,
+            JobStatus::Failed(_)
+        ));
+        assert!(image::open(dir.join("out1.png")).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
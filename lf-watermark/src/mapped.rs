@@ -0,0 +1,115 @@
+//! File input through memory maps, for batch jobs over many large files.
+//!
+//! Reading a file into a `Vec` first means every job holds the encoded bytes and the decoded
+//! pixels at once. A map lets the kernel page the encoded bytes in as the decoder reaches them and
+//! drop them again under pressure, and TIFF inputs are additionally decoded one band at a time.
+
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Write};
+use std::ops::Deref;
+use std::path::Path;
+
+use image::ImageFormat;
+use memmap2::Mmap;
+use tiff::decoder::Decoder;
+use tiff::ColorType;
+
+use crate::codec::embed_watermark_encoded;
+use crate::tiled::embed_tiff_tiled;
+use crate::{Result, WatermarkConfig};
+
+/// A read-only memory map of a whole file.
+#[derive(Debug)]
+pub struct MappedFile {
+    map: Mmap,
+}
+
+impl MappedFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the map is read-only and private to this process. Another process truncating
+        // the file while it is mapped is outside what a batch job can guard against, same as
+        // for any other mmap-based reader.
+        let map = unsafe { Mmap::map(&file)? };
+
+        Ok(MappedFile { map })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+/// Embeds `watermark` into the image file at `input` and writes the result to `output`, in the
+/// same format.
+///
+/// 8-bit RGB TIFFs go through [`embed_tiff_tiled`], so only one band of them is ever decoded.
+/// Everything else goes through [`embed_watermark_encoded`] and is decoded whole, but the
+/// encoded bytes are still only paged in, never copied.
+pub fn embed_file<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    let input = MappedFile::open(input)?;
+    let mut writer = BufWriter::new(File::create(output)?);
+
+    if image::guess_format(&input)? == ImageFormat::Tiff && is_rgb8_tiff(&input) {
+        embed_tiff_tiled(Cursor::new(&*input), &mut writer, watermark, config)?;
+    } else {
+        writer.write_all(&embed_watermark_encoded(&input, watermark, config)?)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn is_rgb8_tiff(input: &[u8]) -> bool {
+    Decoder::new(Cursor::new(input))
+        .and_then(|mut decoder| decoder.colortype())
+        .is_ok_and(|color_type| color_type == ColorType::RGB(8))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use super::*;
+    use crate::embed_watermark_with_config;
+
+    #[test]
+    fn test_embed_file() {
+        let dir = std::env::temp_dir().join(format!("lf-watermark-mapped-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let img = RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, 50]));
+        let config = WatermarkConfig::default();
+        let expected =
+            embed_watermark_with_config(&DynamicImage::ImageRgb8(img.clone()), "Hello", &config)
+                .unwrap();
+
+        for extension in ["tiff", "png"] {
+            let input = dir.join(format!("input.{}", extension));
+            let output = dir.join(format!("output.{}", extension));
+            img.save(&input).unwrap();
+
+            embed_file(&input, &output, "Hello", &config).unwrap();
+            let marked = image::open(&output).unwrap().to_rgb8();
+            for (a, b) in marked.pixels().zip(expected.pixels()) {
+                for c in 0..3 {
+                    assert!(a[c].abs_diff(b[c]) <= 1, "{} {:?} {:?}", extension, a, b);
+                }
+            }
+        }
+
+        assert!(MappedFile::open(dir.join("missing.png")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
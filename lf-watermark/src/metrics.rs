@@ -0,0 +1,366 @@
+//! Quality metrics for comparing an original image against its watermarked version.
+
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+use rustdct::DctPlanner;
+
+use crate::{split_ycbcr, Result};
+
+/// Visibility thresholds per 8x8 DCT frequency, from the luminance table of JPEG Annex K, which
+/// was itself derived from psychovisual threshold measurements.
+pub(crate) const BASE_THRESHOLDS: [f32; 64] = [
+    16.0, 11.0, 10.0, 16.0, 24.0, 40.0, 51.0, 61.0, //
+    12.0, 12.0, 14.0, 19.0, 26.0, 58.0, 60.0, 55.0, //
+    14.0, 13.0, 16.0, 24.0, 40.0, 57.0, 69.0, 56.0, //
+    14.0, 17.0, 22.0, 29.0, 51.0, 87.0, 80.0, 62.0, //
+    18.0, 22.0, 37.0, 56.0, 68.0, 109.0, 103.0, 77.0, //
+    24.0, 35.0, 55.0, 64.0, 81.0, 104.0, 113.0, 92.0, //
+    49.0, 64.0, 78.0, 87.0, 103.0, 121.0, 120.0, 101.0, //
+    72.0, 92.0, 95.0, 98.0, 112.0, 100.0, 103.0, 99.0,
+];
+
+/// Produces an image of the per-pixel, per-channel absolute difference multiplied by `gain`.
+///
+/// The mark is usually invisible, so the raw difference is too; amplifying it makes it possible to
+/// see where and how strongly the mark was placed while tuning settings.
+pub fn diff_image(
+    original: &DynamicImage,
+    watermarked: &DynamicImage,
+    gain: f32,
+) -> Result<RgbImage> {
+    ensure_same_dimensions(original, watermarked)?;
+
+    let (width, height) = original.dimensions();
+    let original = original.to_rgb8();
+    let watermarked = watermarked.to_rgb8();
+
+    let mut img_buffer = RgbImage::new(width, height);
+    for (x, y, pixel) in img_buffer.enumerate_pixels_mut() {
+        let a = original.get_pixel(x, y);
+        let b = watermarked.get_pixel(x, y);
+
+        let mut diff = [0; 3];
+        for c in 0..3 {
+            diff[c] = ((a[c] as f32 - b[c] as f32).abs() * gain).round() as u8;
+        }
+        *pixel = Rgb(diff);
+    }
+
+    Ok(img_buffer)
+}
+
+/// Peak signal-to-noise ratio in dB over all RGB samples. Identical images give infinity.
+pub fn psnr(original: &DynamicImage, watermarked: &DynamicImage) -> Result<f64> {
+    ensure_same_dimensions(original, watermarked)?;
+
+    let original = original.to_rgb8();
+    let watermarked = watermarked.to_rgb8();
+    let squared_error: f64 = original
+        .as_raw()
+        .iter()
+        .zip(watermarked.as_raw())
+        .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+        .sum();
+    let mse = squared_error / original.as_raw().len().max(1) as f64;
+
+    Ok(10.0 * (255.0 * 255.0 / mse).log10())
+}
+
+/// SSIM of the luma plane evaluated independently on each block of a grid.
+#[derive(Debug, Clone)]
+pub struct SsimMap {
+    pub block_size: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Number of blocks per row; the last column may be narrower than `block_size`.
+    pub columns: u32,
+    /// Number of block rows; the last row may be shorter than `block_size`.
+    pub rows: u32,
+    /// Per-block SSIM in raster order.
+    pub values: Vec<f64>,
+}
+
+impl SsimMap {
+    pub fn get(&self, column: u32, row: u32) -> Option<f64> {
+        if column >= self.columns || row >= self.rows {
+            return None;
+        }
+
+        self.values
+            .get((row * self.columns + column) as usize)
+            .copied()
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.values.iter().sum::<f64>() / self.values.len() as f64
+    }
+
+    /// Renders the map at the image's resolution, blue where the blocks are untouched and red
+    /// where they differ the most.
+    ///
+    /// Colors are relative to the worst block of this map, so even a faint mark shows where it
+    /// is most visible.
+    pub fn heatmap(&self) -> RgbImage {
+        let worst = self.values.iter().map(|v| 1.0 - v).fold(0.0, f64::max);
+
+        RgbImage::from_fn(self.width, self.height, |x, y| {
+            let ssim =
+                self.values[((y / self.block_size) * self.columns + x / self.block_size) as usize];
+            let t = if worst > 0.0 {
+                ((1.0 - ssim) / worst).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+
+            Rgb([
+                (255.0 * t).round() as u8,
+                0,
+                (255.0 * (1.0 - t)).round() as u8,
+            ])
+        })
+    }
+}
+
+/// Computes SSIM per `block_size` x `block_size` block of the luma plane.
+///
+/// Use it to find the regions where embedding is most visible.
+pub fn ssim_map(
+    original: &DynamicImage,
+    watermarked: &DynamicImage,
+    block_size: u32,
+) -> Result<SsimMap> {
+    ensure_same_dimensions(original, watermarked)?;
+    if block_size == 0 {
+        return Err("Block size must be positive".into());
+    }
+
+    let (width, height) = original.dimensions();
+    let (a, _) = split_ycbcr(&original.to_rgb8());
+    let (b, _) = split_ycbcr(&watermarked.to_rgb8());
+
+    let columns = width.div_ceil(block_size);
+    let rows = height.div_ceil(block_size);
+    let mut values = Vec::with_capacity(columns as usize * rows as usize);
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let x0 = column * block_size;
+            let y0 = row * block_size;
+            let x1 = x0.saturating_add(block_size).min(width);
+            let y1 = y0.saturating_add(block_size).min(height);
+
+            let indices = (y0..y1)
+                .flat_map(|y| (x0..x1).map(move |x| y as usize * width as usize + x as usize));
+            values.push(ssim_of(indices.map(|i| (a[i] as f64, b[i] as f64))));
+        }
+    }
+
+    Ok(SsimMap {
+        block_size,
+        width,
+        height,
+        columns,
+        rows,
+        values,
+    })
+}
+
+fn ssim_of(samples: impl Iterator<Item = (f64, f64)> + Clone) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+    let n = samples.clone().count() as f64;
+    let (sum_a, sum_b) = samples
+        .clone()
+        .fold((0.0, 0.0), |(sa, sb), (a, b)| (sa + a, sb + b));
+    let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+
+    let (mut var_a, mut var_b, mut cov) = (0.0, 0.0, 0.0);
+    for (a, b) in samples {
+        var_a += (a - mean_a) * (a - mean_a);
+        var_b += (b - mean_b) * (b - mean_b);
+        cov += (a - mean_a) * (b - mean_b);
+    }
+    var_a /= n;
+    var_b /= n;
+    cov /= n;
+
+    ((2.0 * mean_a * mean_b + C1) * (2.0 * cov + C2))
+        / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2))
+}
+
+/// Perceptual distance between two images in units of just-noticeable differences.
+///
+/// PSNR treats every error alike, but frequency-domain artifacts are far more visible in flat
+/// areas than in texture. This is a simplified Watson-style HVS model: luma is split into 8x8 DCT
+/// blocks, each coefficient error is divided by a visibility threshold adjusted for the block's
+/// brightness and for contrast masking by the original content, and the results are pooled.
+/// Values around `1.0` are at the edge of visibility, `0.0` means identical luma.
+pub fn perceptual_distance(original: &DynamicImage, watermarked: &DynamicImage) -> Result<f64> {
+    const BLOCK: u32 = 8;
+    const POOLING: f64 = 4.0;
+
+    ensure_same_dimensions(original, watermarked)?;
+
+    let (width, height) = original.dimensions();
+    let (a, _) = split_ycbcr(&original.to_rgb8());
+    let (b, _) = split_ycbcr(&watermarked.to_rgb8());
+
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    let dct = dct_planner.plan_dct2(BLOCK as usize);
+    let dct_block = |plane: &[f32], x0: u32, y0: u32| {
+        let mut block = [0.0f32; 64];
+        for y in 0..BLOCK {
+            for x in 0..BLOCK {
+                // Edge blocks replicate the last row/column.
+                let sx = (x0 + x).min(width - 1);
+                let sy = (y0 + y).min(height - 1);
+                block[(y * BLOCK + x) as usize] = plane[(sy * width + sx) as usize] - 128.0;
+            }
+        }
+
+        for row in block.chunks_mut(BLOCK as usize) {
+            dct.process_dct2(row);
+        }
+        let mut column = [0.0f32; BLOCK as usize];
+        for x in 0..BLOCK as usize {
+            for y in 0..BLOCK as usize {
+                column[y] = block[y * BLOCK as usize + x];
+            }
+            dct.process_dct2(&mut column);
+            for y in 0..BLOCK as usize {
+                block[y * BLOCK as usize + x] = column[y] / (BLOCK as f32 * 2.0);
+            }
+        }
+
+        block
+    };
+
+    let mut blocks = Vec::new();
+    for y0 in (0..height).step_by(BLOCK as usize) {
+        for x0 in (0..width).step_by(BLOCK as usize) {
+            blocks.push((dct_block(&a, x0, y0), dct_block(&b, x0, y0)));
+        }
+    }
+
+    // The DC coefficient of a block is four times its mean offset from mid-grey.
+    let brightness = |dc: f32| dc as f64 / 4.0 + 128.0;
+    let mean_brightness =
+        blocks.iter().map(|(c, _)| brightness(c[0])).sum::<f64>() / blocks.len() as f64;
+
+    let mut pooled = 0.0;
+    for (ca, cb) in blocks.iter() {
+        // Brighter blocks hide more error (luminance masking).
+        let luminance = if mean_brightness >= 1.0 {
+            (brightness(ca[0]) / mean_brightness).max(0.1).powf(0.649)
+        } else {
+            1.0
+        };
+
+        for k in 0..64 {
+            let threshold = BASE_THRESHOLDS[k] as f64 / 2.0 * luminance;
+            // Strong original content hides error at the same frequency (contrast masking).
+            let masked = threshold.max((ca[k] as f64).abs().powf(0.7) * threshold.powf(0.3));
+            let error = (ca[k] - cb[k]) as f64 / masked;
+            pooled += error.abs().powf(POOLING);
+        }
+    }
+
+    Ok((pooled / blocks.len() as f64).powf(1.0 / POOLING))
+}
+
+fn ensure_same_dimensions(image1: &DynamicImage, image2: &DynamicImage) -> Result<()> {
+    if image1.dimensions() != image2.dimensions() {
+        return Err(format!(
+            "Image dimensions differ; {:?} != {:?}",
+            image1.dimensions(),
+            image2.dimensions()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_image() {
+        let a = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([100, 100, 100])));
+        let mut b = RgbImage::from_pixel(4, 4, Rgb([100, 100, 100]));
+        b.put_pixel(1, 2, Rgb([102, 99, 100]));
+        let b = DynamicImage::ImageRgb8(b);
+
+        let diff = diff_image(&a, &b, 10.0).unwrap();
+        assert_eq!(diff.get_pixel(1, 2), &Rgb([20, 10, 0]));
+        assert_eq!(diff.get_pixel(0, 0), &Rgb([0, 0, 0]));
+
+        let small = DynamicImage::ImageRgb8(RgbImage::new(2, 2));
+        assert!(diff_image(&a, &small, 10.0).is_err());
+    }
+
+    #[test]
+    fn test_psnr() {
+        let a = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([100, 100, 100])));
+        let b = DynamicImage::ImageRgb8(RgbImage::from_pixel(4, 4, Rgb([110, 100, 100])));
+
+        // MSE of 100 / 3.
+        let value = psnr(&a, &b).unwrap();
+        assert!((value - 32.90).abs() < 0.01, "{}", value);
+        assert_eq!(psnr(&a, &a).unwrap(), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_ssim_map() {
+        let a = RgbImage::from_fn(20, 20, |x, y| Rgb([(x * 12) as u8, (y * 12) as u8, 64]));
+        let mut b = a.clone();
+        for y in 10..20 {
+            for x in 10..20 {
+                b.put_pixel(x, y, Rgb([255, 255, 255]));
+            }
+        }
+        let a = DynamicImage::ImageRgb8(a);
+        let b = DynamicImage::ImageRgb8(b);
+
+        let map = ssim_map(&a, &b, 8).unwrap();
+        assert_eq!((map.columns, map.rows), (3, 3));
+        assert!((map.get(0, 0).unwrap() - 1.0).abs() < 1e-9);
+        assert!(map.get(2, 2).unwrap() < 0.5);
+        assert!(map.get(3, 0).is_none());
+
+        let heatmap = map.heatmap();
+        assert_eq!(heatmap.dimensions(), (20, 20));
+        assert_eq!(heatmap.get_pixel(0, 0), &Rgb([0, 0, 255]));
+        assert!(heatmap.get_pixel(19, 19)[0] > heatmap.get_pixel(19, 19)[2]);
+    }
+
+    #[test]
+    fn test_perceptual_distance() {
+        let flat = RgbImage::from_pixel(32, 32, Rgb([120, 120, 120]));
+        let mut faint = flat.clone();
+        let mut strong = flat.clone();
+        for (x, y, pixel) in faint.enumerate_pixels_mut() {
+            if (x + y) % 2 == 0 {
+                *pixel = Rgb([122, 122, 122]);
+            }
+            strong.put_pixel(
+                x,
+                y,
+                if (x / 4) % 2 == 0 {
+                    Rgb([150, 150, 150])
+                } else {
+                    *pixel
+                },
+            );
+        }
+        let flat = DynamicImage::ImageRgb8(flat);
+
+        let same = perceptual_distance(&flat, &flat).unwrap();
+        let faint = perceptual_distance(&flat, &DynamicImage::ImageRgb8(faint)).unwrap();
+        let strong = perceptual_distance(&flat, &DynamicImage::ImageRgb8(strong)).unwrap();
+
+        assert_eq!(same, 0.0);
+        assert!(faint > 0.0 && faint < strong, "{} {}", faint, strong);
+    }
+}
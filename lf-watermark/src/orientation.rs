@@ -0,0 +1,208 @@
+//! EXIF orientation handling.
+//!
+//! Phones store pictures in sensor orientation and tag them with how to rotate them for display.
+//! Embedding into the stored buffer would put the mark in a rotated frame compared to what a
+//! detector later sees, so inputs are made upright first.
+
+use image::DynamicImage;
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_APP1: u8 = 0xE1;
+const JPEG_SOS: u8 = 0xDA;
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Reads the EXIF orientation (1 to 8) of a JPEG. Returns `None` if there is no valid tag.
+pub fn read_orientation(jpeg: &[u8]) -> Option<u8> {
+    let tiff = Tiff::of_jpeg(jpeg)?;
+    let entry = tiff.find(tiff.u32_at(4)? as usize, ORIENTATION_TAG)?;
+    let orientation = tiff.u16_at(entry + 8)?;
+
+    (1..=8).contains(&orientation).then_some(orientation as u8)
+}
+
+/// A TIFF structure, such as the one inside an Exif segment, with accessors in its byte order.
+pub(crate) struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    pub(crate) fn of_jpeg(jpeg: &'a [u8]) -> Option<Self> {
+        Tiff::new(exif_segment(jpeg)?.get(4 + EXIF_HEADER.len()..)?)
+    }
+
+    /// Reads the byte order of the TIFF starting at `data`.
+    pub(crate) fn new(data: &'a [u8]) -> Option<Self> {
+        let little_endian = match data.get(0..4)? {
+            b"II*\0" => true,
+            b"MM\0*" => false,
+            _ => return None,
+        };
+
+        Some(Tiff {
+            data,
+            little_endian,
+        })
+    }
+
+    pub(crate) fn little_endian(&self) -> bool {
+        self.little_endian
+    }
+
+    pub(crate) fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?];
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    pub(crate) fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes: [u8; 4] = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    pub(crate) fn bytes(&self, offset: usize, len: usize) -> Option<&'a [u8]> {
+        self.data.get(offset..offset.checked_add(len)?)
+    }
+
+    /// Offset of the 12-byte entry for `tag` in the IFD at `ifd`.
+    pub(crate) fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        (0..self.u16_at(ifd)? as usize)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16_at(entry) == Some(tag))
+    }
+}
+
+/// The complete APP1 Exif segment of a JPEG, marker and length included.
+pub(crate) fn exif_segment(jpeg: &[u8]) -> Option<&[u8]> {
+    if !jpeg.starts_with(&JPEG_SOI) {
+        return None;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= jpeg.len() && jpeg[pos] == 0xFF {
+        let marker = jpeg[pos + 1];
+        if marker == JPEG_SOS {
+            break;
+        }
+
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let segment = jpeg.get(pos..pos + 2 + len)?;
+        if marker == JPEG_APP1 && segment.get(4..).is_some_and(|s| s.starts_with(EXIF_HEADER)) {
+            return Some(segment);
+        }
+        pos += 2 + len;
+    }
+
+    None
+}
+
+/// Inserts `segment` right after the start-of-image marker of `jpeg`.
+pub(crate) fn insert_segment(jpeg: &[u8], segment: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(jpeg.len() + segment.len());
+    output.extend_from_slice(&jpeg[..2]);
+    output.extend_from_slice(segment);
+    output.extend_from_slice(&jpeg[2..]);
+    output
+}
+
+/// Copy of the APP1 Exif `segment` with its orientation tag, if any, set to upright.
+pub(crate) fn reset_orientation(segment: &[u8]) -> Vec<u8> {
+    let header = 4 + EXIF_HEADER.len();
+    let mut output = segment.to_vec();
+    let entry = segment.get(header..).and_then(Tiff::new).and_then(|tiff| {
+        let entry = tiff.find(tiff.u32_at(4)? as usize, ORIENTATION_TAG)?;
+        Some((entry, tiff.little_endian()))
+    });
+    if let Some((entry, little_endian)) = entry {
+        let value = if little_endian { [1, 0] } else { [0, 1] };
+        let at = header + entry + 8;
+        if let Some(dst) = output.get_mut(at..at + 2) {
+            dst.copy_from_slice(&value);
+        }
+    }
+
+    output
+}
+
+/// Turns the stored pixels into the upright image described by `orientation`.
+pub fn apply_orientation(image: &DynamicImage, orientation: u8) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image.clone(),
+    }
+}
+
+/// Inverse of [`apply_orientation`]: turns an upright image back into stored pixels.
+pub fn undo_orientation(image: &DynamicImage, orientation: u8) -> DynamicImage {
+    match orientation {
+        6 => image.rotate270(),
+        8 => image.rotate90(),
+        // Flips, the half turn, transpose and transverse are their own inverses.
+        _ => apply_orientation(image, orientation),
+    }
+}
+
+/// Big-endian APP1 Exif segment holding only an orientation tag.
+#[cfg(test)]
+pub(crate) fn test_exif_segment(orientation: u16) -> Vec<u8> {
+    let mut tiff = b"MM\0*".to_vec();
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    let mut segment = vec![0xFF, JPEG_APP1];
+    segment.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+
+    #[test]
+    fn test_read_orientation() {
+        let jpeg = |orientation| insert_segment(&JPEG_SOI, &test_exif_segment(orientation));
+        assert_eq!(read_orientation(&jpeg(6)), Some(6));
+        assert_eq!(read_orientation(&jpeg(9)), None);
+        assert_eq!(read_orientation(&JPEG_SOI), None);
+    }
+
+    #[test]
+    fn test_undo_orientation() {
+        let img =
+            DynamicImage::ImageRgb8(RgbImage::from_fn(3, 2, |x, y| Rgb([x as u8, y as u8, 0])));
+
+        for orientation in 1..=8 {
+            let upright = apply_orientation(&img, orientation);
+            assert_eq!(
+                undo_orientation(&upright, orientation),
+                img,
+                "{}",
+                orientation
+            );
+        }
+        assert_eq!(apply_orientation(&img, 6).width(), 2);
+    }
+}
@@ -0,0 +1,335 @@
+//! Patchwork: one-bit presence marking with a statistical detector.
+//!
+//! A keyed PRNG picks pixel pairs; the first pixel of each pair is made brighter by `delta` and
+//! the second darker. In an unmarked image the mean luma difference over the pairs is close to
+//! zero, in a marked one it is close to `2 * delta`, so a z-test on that mean tells whether the
+//! key's mark is present. The change per pixel is tiny and there is no transform, which makes it
+//! far cheaper than the DCT embedding, but it carries no payload beyond "marked with this key".
+//!
+//! By default the pairs are drawn from texture blocks only, where the change is masked; the
+//! detector classifies the image under test the same way, so it needs nothing but the key.
+
+use image::{DynamicImage, RgbImage};
+
+use crate::analysis::Analysis;
+use crate::blocks::BlockSelection;
+use crate::prng::Prng;
+use crate::Result;
+
+/// Settings shared by [`embed_patchwork`] and [`detect_patchwork`], which must match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatchworkConfig {
+    /// Number of pixel pairs. At most half the selected pixels; more pairs detect more reliably.
+    pub pairs: usize,
+    /// Luma change applied to each pixel of a pair, in 8-bit levels.
+    pub delta: u8,
+    /// Largest accepted probability of reporting an unmarked image as marked.
+    pub false_positive_rate: f64,
+    /// Blocks the pairs are drawn from.
+    pub selection: BlockSelection,
+}
+
+impl Default for PatchworkConfig {
+    fn default() -> Self {
+        PatchworkConfig {
+            pairs: 10_000,
+            delta: 2,
+            false_positive_rate: 1e-6,
+            selection: BlockSelection::default(),
+        }
+    }
+}
+
+/// Outcome of [`detect_patchwork`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatchworkDetection {
+    /// Mean luma difference between the raised and lowered pixels.
+    pub mean_difference: f64,
+    /// The mean difference in standard errors; around 0 without the mark.
+    pub z_score: f64,
+    /// Probability of a z-score at least this high in an unmarked image.
+    pub p_value: f64,
+    /// Whether `p_value` is below the configured false positive rate.
+    pub present: bool,
+}
+
+/// Marks `image` with the patchwork pattern of `key`.
+pub fn embed_patchwork(
+    image: &DynamicImage,
+    key: &[u8],
+    config: &PatchworkConfig,
+) -> Result<RgbImage> {
+    let mut image = image.to_rgb8();
+    let pairs = select_pairs(&image, key, config)?;
+    apply(&mut image, &pairs, config);
+
+    Ok(image)
+}
+
+/// [`embed_patchwork`] with the blocks of `image` classified beforehand, so repeated marking of
+/// the same master skips the classification.
+pub fn embed_patchwork_with_analysis(
+    image: &DynamicImage,
+    key: &[u8],
+    config: &PatchworkConfig,
+    analysis: &Analysis,
+) -> Result<RgbImage> {
+    let mut image = image.to_rgb8();
+    if !analysis.is_for(&image) {
+        return Err("The analysis was computed from another image".into());
+    }
+    let selected = analysis.mask(config.selection);
+    let pairs = select_pairs_in(&selected, image.width(), image.height(), key, config)?;
+    apply(&mut image, &pairs, config);
+
+    Ok(image)
+}
+
+fn apply(image: &mut RgbImage, pairs: &[(usize, usize)], config: &PatchworkConfig) {
+    let raw: &mut [u8] = image;
+    for &(raised, lowered) in pairs {
+        // The same change in all three channels moves the luma and leaves the chroma alone.
+        for c in 0..3 {
+            raw[raised * 3 + c] = raw[raised * 3 + c].saturating_add(config.delta);
+            raw[lowered * 3 + c] = raw[lowered * 3 + c].saturating_sub(config.delta);
+        }
+    }
+}
+
+/// Tests whether `image` carries the patchwork mark of `key`.
+pub fn detect_patchwork(
+    image: &DynamicImage,
+    key: &[u8],
+    config: &PatchworkConfig,
+) -> Result<PatchworkDetection> {
+    let image = image.to_rgb8();
+    let luma = |index: usize| {
+        let p = &image.as_raw()[index * 3..index * 3 + 3];
+        0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64
+    };
+
+    let differences: Vec<f64> = select_pairs(&image, key, config)?
+        .into_iter()
+        .map(|(raised, lowered)| luma(raised) - luma(lowered))
+        .collect();
+    let n = differences.len() as f64;
+    let mean = differences.iter().sum::<f64>() / n;
+    let variance = differences.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / (n - 1.0);
+    let standard_error = (variance / n).sqrt();
+
+    let z_score = if standard_error > 0.0 {
+        mean / standard_error
+    } else if mean > 0.0 {
+        f64::INFINITY
+    } else {
+        0.0
+    };
+    let p_value = 0.5 * erfc(z_score / std::f64::consts::SQRT_2);
+
+    Ok(PatchworkDetection {
+        mean_difference: mean,
+        z_score,
+        p_value,
+        present: p_value < config.false_positive_rate,
+    })
+}
+
+/// Distinct pixel index pairs for `key`, drawn without replacement, with both pixels in the
+/// selected blocks.
+///
+/// The keyed order covers every pixel and pairs that leave the selection are skipped, so a block
+/// that the detector classifies differently only drops or adds its own pairs instead of
+/// reshuffling all of them.
+fn select_pairs(
+    image: &RgbImage,
+    key: &[u8],
+    config: &PatchworkConfig,
+) -> Result<Vec<(usize, usize)>> {
+    let selected = config.selection.mask(image);
+    select_pairs_in(&selected, image.width(), image.height(), key, config)
+}
+
+/// [`select_pairs`] with the selection mask at hand.
+fn select_pairs_in(
+    selected: &[bool],
+    width: u32,
+    height: u32,
+    key: &[u8],
+    config: &PatchworkConfig,
+) -> Result<Vec<(usize, usize)>> {
+    let len = selected.len();
+    let available = selected.iter().filter(|&&s| s).count();
+    if config.pairs < 2 || config.pairs > available / 2 {
+        return Err(format!(
+            "{} pairs requested, but the {} selected pixels of a {}x{} image allow 2 to {}",
+            config.pairs,
+            available,
+            width,
+            height,
+            available / 2
+        )
+        .into());
+    }
+
+    let mut prng = Prng::from_key(key);
+    let mut indices: Vec<usize> = (0..len).collect();
+    let mut pairs = Vec::with_capacity(config.pairs);
+    // Partial Fisher-Yates shuffle, two slots at a time: each prefix is a uniform sample.
+    for i in (0..len - 1).step_by(2) {
+        for slot in i..i + 2 {
+            let j = slot + prng.next_below((len - slot) as u64) as usize;
+            indices.swap(slot, j);
+        }
+        let (a, b) = (indices[i], indices[i + 1]);
+        if selected[a] && selected[b] {
+            pairs.push((a, b));
+            if pairs.len() == config.pairs {
+                return Ok(pairs);
+            }
+        }
+    }
+
+    Err(format!(
+        "Only {} of the {} requested pairs fall inside the selected blocks",
+        pairs.len(),
+        config.pairs
+    )
+    .into())
+}
+
+/// Complementary error function, from Numerical Recipes' Chebyshev fit (relative error below
+/// 1.2e-7), plenty for a significance test.
+fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1.0 / (1.0 + 0.5 * z);
+    let poly = -z * z - 1.26551223
+        + t * (1.00002368
+            + t * (0.37409196
+                + t * (0.09678418
+                    + t * (-0.18628806
+                        + t * (0.27886807
+                            + t * (-1.13520398
+                                + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+    let value = t * poly.exp();
+
+    if x >= 0.0 {
+        value
+    } else {
+        2.0 - value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, Rgb};
+
+    use super::*;
+    use crate::metrics::psnr;
+
+    /// Hashed, direction-free noise in `0..modulus`.
+    fn noise(x: u32, y: u32, modulus: u32) -> u8 {
+        let mut h = x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663);
+        h ^= h >> 13;
+        h = h.wrapping_mul(0x5bd1_e995);
+        h ^= h >> 15;
+        (h % modulus) as u8
+    }
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(256, 200, |x, y| {
+            let noise = noise(x, y, 61);
+            Rgb([(x / 2) as u8 + noise, (y / 2) as u8 + noise, 90 + noise])
+        }))
+    }
+
+    #[test]
+    fn test_detect_patchwork() {
+        let image = image();
+        let config = PatchworkConfig::default();
+        let marked = DynamicImage::ImageRgb8(embed_patchwork(&image, b"key", &config).unwrap());
+        assert!(psnr(&image, &marked).unwrap() > 45.0);
+
+        let found = detect_patchwork(&marked, b"key", &config).unwrap();
+        assert!(found.present, "{:?}", found);
+        assert!((found.mean_difference - 4.0).abs() < 0.5, "{:?}", found);
+
+        for (candidate, key) in [(&marked, &b"other"[..]), (&image, &b"key"[..])] {
+            let detection = detect_patchwork(candidate, key, &config).unwrap();
+            assert!(!detection.present, "{:?}", detection);
+            assert!(detection.z_score.abs() < 5.0, "{:?}", detection);
+        }
+    }
+
+    #[test]
+    fn test_select_pairs() {
+        let image = image().to_rgb8();
+        let config = PatchworkConfig {
+            pairs: 50,
+            selection: BlockSelection::All,
+            ..Default::default()
+        };
+        let small = RgbImage::from_fn(10, 10, |x, y| image[(x, y)]);
+        let pairs = select_pairs(&small, b"key", &config).unwrap();
+        let mut seen: Vec<usize> = pairs.iter().flat_map(|&(a, b)| [a, b]).collect();
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen.len(), 100);
+        assert_eq!(pairs, select_pairs(&small, b"key", &config).unwrap());
+
+        let config = PatchworkConfig {
+            pairs: 51,
+            ..config
+        };
+        assert!(select_pairs(&small, b"key", &config).is_err());
+    }
+
+    #[test]
+    fn test_texture_selection() {
+        // Flat on the left, noisy on the right.
+        let image = RgbImage::from_fn(256, 200, |x, y| {
+            let noise = noise(x, y, 61);
+            Rgb([if x < 128 { 120 } else { 90 + noise }; 3])
+        });
+        let config = PatchworkConfig {
+            pairs: 4_000,
+            ..Default::default()
+        };
+        let pairs = select_pairs(&image, b"key", &config).unwrap();
+        assert!(pairs.iter().all(|&(a, b)| a % 256 >= 128 && b % 256 >= 128));
+
+        let image = DynamicImage::ImageRgb8(image);
+        let marked = DynamicImage::ImageRgb8(embed_patchwork(&image, b"key", &config).unwrap());
+        assert_eq!(
+            marked.crop_imm(0, 0, 128, 200),
+            image.crop_imm(0, 0, 128, 200)
+        );
+        assert!(detect_patchwork(&marked, b"key", &config).unwrap().present);
+
+        let config = PatchworkConfig {
+            pairs: 7_000,
+            ..config
+        };
+        assert!(select_pairs(image.as_rgb8().unwrap(), b"key", &config).is_err());
+    }
+
+    #[test]
+    fn test_embed_patchwork_with_analysis() {
+        let image = image();
+        let config = PatchworkConfig::default();
+        let analysis = Analysis::from_bytes(&Analysis::new(&image.to_rgb8()).to_bytes()).unwrap();
+        assert_eq!(
+            embed_patchwork_with_analysis(&image, b"key", &config, &analysis).unwrap(),
+            embed_patchwork(&image, b"key", &config).unwrap()
+        );
+
+        let other = DynamicImage::ImageRgb8(image.to_rgb8().view(0, 0, 128, 100).to_image());
+        assert!(embed_patchwork_with_analysis(&other, b"key", &config, &analysis).is_err());
+    }
+
+    #[test]
+    fn test_erfc() {
+        assert!((erfc(0.0) - 1.0).abs() < 1e-6);
+        assert!((erfc(1.0) - 0.157299207).abs() < 1e-6);
+        assert!((erfc(-1.0) - 1.842700793).abs() < 1e-6);
+    }
+}
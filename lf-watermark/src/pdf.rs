@@ -0,0 +1,571 @@
+//! Marking the images embedded in PDF documents.
+//!
+//! Photos in a PDF are usually stored as JPEG streams (`/DCTDecode`). [`mark_pdf`] decodes each
+//! of them, applies the invisible mark and optionally a visible text stamp, and writes the new
+//! image streams as an incremental update: the original bytes are kept and the replaced objects,
+//! a cross-reference section and a trailer are appended, so fonts, text and page structure are
+//! never rewritten. Progressive images stay progressive. Images in other encodings or color
+//! spaces, lossless JPEG among them, are counted as skipped.
+//! Encrypted documents are rejected.
+
+use std::collections::BTreeMap;
+
+use image::{DynamicImage, GrayImage};
+
+use crate::codec::{encode, ChromaSubsampling, JpegInfo, JpegMode};
+use crate::visible::{stamp_text, TextStamp};
+use crate::{embed_watermark_with_config, Result, WatermarkConfig};
+
+/// Deepest nesting of dictionaries and arrays accepted, which bounds the parser's recursion on
+/// hostile input.
+const MAX_NESTING: usize = 64;
+
+/// How [`mark_pdf`] treats the document's images.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdfOptions {
+    pub config: WatermarkConfig,
+    /// JPEG quality of the re-encoded images, 1 to 100.
+    pub quality: u8,
+    /// Visible text stamped into every marked image.
+    pub visible_text: Option<String>,
+    pub stamp: TextStamp,
+}
+
+impl Default for PdfOptions {
+    fn default() -> Self {
+        PdfOptions {
+            config: WatermarkConfig::default(),
+            quality: 90,
+            visible_text: None,
+            stamp: TextStamp::default(),
+        }
+    }
+}
+
+/// Result of [`mark_pdf`].
+#[derive(Debug, Clone)]
+pub struct MarkedPdf {
+    pub bytes: Vec<u8>,
+    /// Number of images that were marked.
+    pub marked: usize,
+    /// Number of images left as they were because of their encoding or color space.
+    pub skipped: usize,
+}
+
+/// Marks every JPEG image of `pdf` with `watermark`.
+pub fn mark_pdf(pdf: &[u8], watermark: &str, options: &PdfOptions) -> Result<MarkedPdf> {
+    if !pdf.starts_with(b"%PDF-") {
+        return Err("Not a PDF file".into());
+    }
+    let trailer = trailer(pdf)?;
+    if trailer.contains_key("Encrypt") {
+        return Err("Encrypted PDFs are not supported".into());
+    }
+    let root = trailer.get("Root").ok_or("PDF trailer has no /Root")?;
+    let size: u32 = trailer
+        .get("Size")
+        .and_then(|size| size.parse().ok())
+        .ok_or("PDF trailer has no valid /Size")?;
+
+    // A later definition of an object replaces an earlier one, as in an incremental update.
+    let mut images = BTreeMap::new();
+    for object in objects(pdf)? {
+        if let Some(stream) = object.stream.clone() {
+            if object.dict.get("Subtype").map(String::as_str) == Some("/Image") {
+                images.insert((object.number, object.generation), (object.dict, stream));
+            }
+        }
+    }
+
+    let mut output = pdf.to_vec();
+    if !output.ends_with(b"\n") {
+        output.push(b'\n');
+    }
+    let (mut marked, mut skipped) = (0, 0);
+    let mut offsets = BTreeMap::new();
+    for ((number, generation), (dict, stream)) in images {
+        let Some(jpeg) = mark_image(&dict, &pdf[stream], watermark, options)? else {
+            skipped += 1;
+            continue;
+        };
+
+        offsets.insert(number, (output.len(), generation));
+        output.extend_from_slice(format!("{} {} obj\n<<", number, generation).as_bytes());
+        for (key, value) in &dict {
+            if !matches!(key.as_str(), "Length" | "DecodeParms") {
+                output.extend_from_slice(format!("/{} {}", key, value).as_bytes());
+            }
+        }
+        output.extend_from_slice(format!("/Length {}>>\nstream\n", jpeg.len()).as_bytes());
+        output.extend_from_slice(&jpeg);
+        output.extend_from_slice(b"\nendstream\nendobj\n");
+        marked += 1;
+    }
+
+    if !offsets.is_empty() {
+        let xref = output.len();
+        output.extend_from_slice(b"xref\n");
+        let numbers: Vec<u32> = offsets.keys().copied().collect();
+        for run in numbers.chunk_by(|a, b| b - a == 1) {
+            output.extend_from_slice(format!("{} {}\n", run[0], run.len()).as_bytes());
+            for number in run {
+                let (offset, generation) = offsets[number];
+                output
+                    .extend_from_slice(format!("{:010} {:05} n \n", offset, generation).as_bytes());
+            }
+        }
+
+        let mut new_trailer = format!("trailer\n<</Size {}/Root {}", size, root);
+        for key in ["Info", "ID"] {
+            if let Some(value) = trailer.get(key) {
+                new_trailer.push_str(&format!("/{} {}", key, value));
+            }
+        }
+        new_trailer.push_str(&format!(
+            "/Prev {}>>\nstartxref\n{}\n%%EOF\n",
+            start_xref(pdf)?,
+            xref
+        ));
+        output.extend_from_slice(new_trailer.as_bytes());
+    } else {
+        output = pdf.to_vec();
+    }
+
+    Ok(MarkedPdf {
+        bytes: output,
+        marked,
+        skipped,
+    })
+}
+
+/// The re-encoded stream of one image, or `None` if it cannot be marked.
+fn mark_image(
+    dict: &BTreeMap<String, String>,
+    data: &[u8],
+    watermark: &str,
+    options: &PdfOptions,
+) -> Result<Option<Vec<u8>>> {
+    let filter = dict.get("Filter").map(|f| f.trim_matches(['[', ']', ' ']));
+    let gray = match dict.get("ColorSpace").map(String::as_str) {
+        Some("/DeviceRGB") => false,
+        Some("/DeviceGray") => true,
+        _ => return Ok(None),
+    };
+    let mode = JpegInfo::read(data).map(|info| info.mode);
+    if filter != Some("/DCTDecode") || mode == Some(JpegMode::Lossless) {
+        return Ok(None);
+    }
+    let progressive = mode == Some(JpegMode::Progressive);
+
+    let mut image = image::load_from_memory_with_format(data, image::ImageFormat::Jpeg)?;
+    if let Some(text) = &options.visible_text {
+        image = DynamicImage::ImageRgb8(stamp_text(&image, text, &options.stamp)?);
+    }
+    let marked = embed_watermark_with_config(&image, watermark, &options.config)?;
+
+    if gray {
+        let luma = DynamicImage::ImageRgb8(marked).to_luma8();
+        return Ok(Some(encode_gray(&luma, options.quality, progressive)?));
+    }
+    // The chroma layout is not kept, only the coding process.
+    let info = JpegInfo {
+        mode: mode.unwrap_or(JpegMode::Baseline),
+        subsampling: ChromaSubsampling::Yuv420,
+    };
+
+    Ok(Some(encode(&marked, info.output_format(options.quality)?)?))
+}
+
+fn encode_gray(image: &GrayImage, quality: u8, progressive: bool) -> Result<Vec<u8>> {
+    let width = u16::try_from(image.width()).map_err(|_| "Image too wide for JPEG")?;
+    let height = u16::try_from(image.height()).map_err(|_| "Image too tall for JPEG")?;
+    let mut output = Vec::new();
+    let mut encoder = jpeg_encoder::Encoder::new(&mut output, quality.clamp(1, 100));
+    encoder.set_progressive(progressive);
+    encoder.encode(image.as_raw(), width, height, jpeg_encoder::ColorType::Luma)?;
+
+    Ok(output)
+}
+
+/// An indirect object of the file.
+struct Object {
+    number: u32,
+    generation: u16,
+    dict: BTreeMap<String, String>,
+    /// Byte range of the stream data, if the object is a stream.
+    stream: Option<std::ops::Range<usize>>,
+}
+
+/// Objects with a dictionary, in file order. Stream data is skipped, so object headers inside
+/// binary streams are never mistaken for objects.
+fn objects(pdf: &[u8]) -> Result<Vec<Object>> {
+    let mut objects = Vec::new();
+    let mut i = 0;
+    while let Some(found) = find(pdf, b" obj", i) {
+        let Some((number, generation)) = object_header(pdf, found) else {
+            i = found + 4;
+            continue;
+        };
+        let body = skip_whitespace(pdf, found + 4);
+        if !pdf[body..].starts_with(b"<<") {
+            i = body;
+            continue;
+        }
+        let (dict, end) = parse_dict(pdf, body, 0)?;
+        i = end;
+
+        let mut stream = None;
+        let after = skip_whitespace(pdf, end);
+        if pdf[after..].starts_with(b"stream") {
+            let mut data = after + 6;
+            if pdf[data..].starts_with(b"\r\n") {
+                data += 2;
+            } else if pdf[data..].starts_with(b"\n") {
+                data += 1;
+            }
+            let direct = dict.get("Length").and_then(|len| len.parse::<usize>().ok());
+            let data_end = match direct
+                .and_then(|len| data.checked_add(len))
+                .filter(|&end| end <= pdf.len())
+            {
+                Some(end) => end,
+                // Indirect or wrong lengths: the data ends at the keyword.
+                None => find(pdf, b"endstream", data).ok_or("Unterminated PDF stream")?,
+            };
+            stream = Some(data..data_end);
+            i = data_end;
+        }
+
+        objects.push(Object {
+            number,
+            generation,
+            dict,
+            stream,
+        });
+    }
+
+    Ok(objects)
+}
+
+/// Object and generation numbers before the ` obj` at `at`.
+fn object_header(pdf: &[u8], at: usize) -> Option<(u32, u16)> {
+    let digits_back = |end: usize| {
+        let start = pdf[..end]
+            .iter()
+            .rposition(|b| !b.is_ascii_digit())
+            .map_or(0, |p| p + 1);
+        (start < end).then_some(start)
+    };
+    let generation_start = digits_back(at)?;
+    if generation_start == 0 || !pdf[generation_start - 1].is_ascii_whitespace() {
+        return None;
+    }
+    let number_end = pdf[..generation_start]
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())?
+        + 1;
+    let number_start = digits_back(number_end)?;
+    if number_start > 0 && !pdf[number_start - 1].is_ascii_whitespace() {
+        return None;
+    }
+
+    let number = std::str::from_utf8(&pdf[number_start..number_end]).ok()?;
+    let generation = std::str::from_utf8(&pdf[generation_start..at]).ok()?;
+    Some((number.parse().ok()?, generation.parse().ok()?))
+}
+
+/// Offset of the last cross-reference section.
+fn start_xref(pdf: &[u8]) -> Result<usize> {
+    let at = rfind(pdf, b"startxref").ok_or("PDF has no startxref")?;
+    let start = skip_whitespace(pdf, at + 9);
+    let end = pdf[start..]
+        .iter()
+        .position(|b| !b.is_ascii_digit())
+        .map_or(pdf.len(), |p| start + p);
+
+    Ok(std::str::from_utf8(&pdf[start..end])?.parse()?)
+}
+
+/// Dictionary of the last trailer, or of the last cross-reference stream.
+fn trailer(pdf: &[u8]) -> Result<BTreeMap<String, String>> {
+    let xref = start_xref(pdf)?;
+    if xref >= pdf.len() {
+        return Err("PDF startxref points past the end".into());
+    }
+    let dict = if pdf[xref..].starts_with(b"xref") {
+        find(pdf, b"trailer", xref).ok_or("PDF has no trailer")? + 7
+    } else {
+        find(pdf, b"obj", xref).ok_or("PDF has no trailer")? + 3
+    };
+
+    Ok(parse_dict(pdf, skip_whitespace(pdf, dict), 0)?.0)
+}
+
+/// Parses the dictionary at `start` into its keys and values, kept as source text. `depth` counts
+/// the enclosing dictionaries and arrays.
+fn parse_dict(pdf: &[u8], start: usize, depth: usize) -> Result<(BTreeMap<String, String>, usize)> {
+    if depth > MAX_NESTING {
+        return Err("PDF objects nested too deeply".into());
+    }
+    if !pdf[start..].starts_with(b"<<") {
+        return Err("Expected a PDF dictionary".into());
+    }
+    let mut entries = BTreeMap::new();
+    let mut i = skip_whitespace(pdf, start + 2);
+    while !pdf[i..].starts_with(b">>") {
+        if pdf.get(i) != Some(&b'/') {
+            return Err(format!("Expected a name at byte {} of the PDF", i).into());
+        }
+        let key_end = name_end(pdf, i + 1);
+        let key = String::from_utf8_lossy(&pdf[i + 1..key_end]).into_owned();
+        let value_start = skip_whitespace(pdf, key_end);
+        let value_end = value_end(pdf, value_start, depth + 1)?;
+        let value = String::from_utf8_lossy(&pdf[value_start..value_end]).into_owned();
+        entries.insert(key, value);
+        i = skip_whitespace(pdf, value_end);
+        if i >= pdf.len() {
+            return Err("Unterminated PDF dictionary".into());
+        }
+    }
+
+    Ok((entries, i + 2))
+}
+
+/// End of the value starting at `start`.
+fn value_end(pdf: &[u8], start: usize, depth: usize) -> Result<usize> {
+    if depth > MAX_NESTING {
+        return Err("PDF objects nested too deeply".into());
+    }
+    let unterminated = || "Unterminated PDF value";
+    match pdf.get(start).ok_or_else(unterminated)? {
+        b'/' => Ok(name_end(pdf, start + 1)),
+        b'<' if pdf[start..].starts_with(b"<<") => Ok(parse_dict(pdf, start, depth)?.1),
+        b'<' => Ok(find(pdf, b">", start).ok_or_else(unterminated)? + 1),
+        b'[' => {
+            let mut i = skip_whitespace(pdf, start + 1);
+            while pdf.get(i) != Some(&b']') {
+                i = skip_whitespace(pdf, value_end(pdf, i, depth + 1)?);
+                if i >= pdf.len() {
+                    return Err(unterminated().into());
+                }
+            }
+            Ok(i + 1)
+        }
+        b'(' => {
+            let (mut depth, mut i) = (0, start);
+            loop {
+                match pdf.get(i).ok_or_else(unterminated)? {
+                    b'\\' => i += 1,
+                    b'(' => depth += 1,
+                    b')' if depth == 1 => return Ok(i + 1),
+                    b')' => depth -= 1,
+                    _ => {}
+                }
+                i += 1;
+            }
+        }
+        _ => {
+            // A number, keyword or reference such as `12 0 R`.
+            let token = |at: usize| name_end(pdf, at);
+            let end = token(start);
+            let second = skip_whitespace(pdf, end);
+            let third = skip_whitespace(pdf, token(second));
+            let is_number = |range: std::ops::Range<usize>| {
+                !range.is_empty() && pdf[range].iter().all(u8::is_ascii_digit)
+            };
+            if is_number(start..end)
+                && is_number(second..token(second))
+                && pdf.get(third) == Some(&b'R')
+                && name_end(pdf, third) == third + 1
+            {
+                Ok(third + 1)
+            } else if end == start {
+                Err(format!("Unexpected byte at {} of the PDF", start).into())
+            } else {
+                Ok(end)
+            }
+        }
+    }
+}
+
+/// End of a name or bare token: the next whitespace or delimiter.
+fn name_end(pdf: &[u8], start: usize) -> usize {
+    pdf[start..]
+        .iter()
+        .position(|&b| b.is_ascii_whitespace() || b"/<>[]()%".contains(&b))
+        .map_or(pdf.len(), |p| start + p)
+}
+
+fn skip_whitespace(pdf: &[u8], start: usize) -> usize {
+    pdf[start.min(pdf.len())..]
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .map_or(pdf.len(), |p| start + p)
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|w| w == needle)
+        .map(|p| from + p)
+}
+
+fn rfind(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).rposition(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{GenericImageView, ImageFormat, Rgb, RgbImage};
+
+    use super::*;
+
+    fn jpeg() -> Vec<u8> {
+        let image = RgbImage::from_fn(64, 48, |x, y| Rgb([(x * 3) as u8, (y * 4) as u8, 90]));
+        let mut jpeg = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        jpeg
+    }
+
+    /// One page showing a JPEG image, plus an image in an unsupported color space.
+    fn pdf() -> Vec<u8> {
+        let jpeg = jpeg();
+        let content = b"q 64 0 0 48 0 0 cm /Im1 Do Q";
+        let mut pdf = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::new();
+        let mut object = |pdf: &mut Vec<u8>, body: &[u8]| {
+            offsets.push(pdf.len());
+            pdf.extend_from_slice(format!("{} 0 obj\n", offsets.len()).as_bytes());
+            pdf.extend_from_slice(body);
+            pdf.extend_from_slice(b"\nendobj\n");
+        };
+        object(&mut pdf, b"<< /Type /Catalog /Pages 2 0 R >>");
+        object(&mut pdf, b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+        object(
+            &mut pdf,
+            b"<< /Type /Page /Parent 2 0 R /MediaBox [0 0 64 48] /Contents 4 0 R \
+              /Resources << /XObject << /Im1 5 0 R /Im2 6 0 R >> >> >>",
+        );
+        let mut stream = format!("<< /Length {} >>\nstream\n", content.len()).into_bytes();
+        stream.extend_from_slice(content);
+        stream.extend_from_slice(b"\nendstream");
+        object(&mut pdf, &stream);
+        let mut image = format!(
+            "<< /Type /XObject /Subtype /Image /Width 64 /Height 48 /ColorSpace /DeviceRGB \
+             /BitsPerComponent 8 /Filter /DCTDecode /Length {} >>\nstream\n",
+            jpeg.len()
+        )
+        .into_bytes();
+        image.extend_from_slice(&jpeg);
+        image.extend_from_slice(b"\nendstream");
+        object(&mut pdf, &image);
+        object(
+            &mut pdf,
+            b"<< /Subtype /Image /Width 1 /Height 1 /ColorSpace /DeviceCMYK \
+              /BitsPerComponent 8 /Length 4 >>\nstream\n\x00\x00\x00\x00\nendstream",
+        );
+
+        let xref = pdf.len();
+        pdf.extend_from_slice(
+            format!("xref\n0 {}\n0000000000 65535 f \n", offsets.len() + 1).as_bytes(),
+        );
+        for offset in &offsets {
+            pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        pdf.extend_from_slice(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                offsets.len() + 1,
+                xref
+            )
+            .as_bytes(),
+        );
+        pdf
+    }
+
+    #[test]
+    fn test_mark_pdf() {
+        let pdf = pdf();
+        let options = PdfOptions {
+            config: WatermarkConfig {
+                strength: 0.01,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let marked = mark_pdf(&pdf, "Hello, World!", &options).unwrap();
+        assert_eq!((marked.marked, marked.skipped), (1, 1));
+        assert!(marked.bytes.starts_with(&pdf));
+
+        // The appended object replaces the image, and the trailer chains to the original.
+        let trailer = trailer(&marked.bytes).unwrap();
+        assert_eq!(trailer["Root"], "1 0 R");
+        assert_eq!(trailer["Prev"], start_xref(&pdf).unwrap().to_string());
+        let objects = objects(&marked.bytes).unwrap();
+        let image = objects.iter().rev().find(|o| o.number == 5).unwrap();
+        let data = &marked.bytes[image.stream.clone().unwrap()];
+        assert_eq!(image.dict["Length"], data.len().to_string());
+
+        let original = image::load_from_memory(&jpeg()).unwrap();
+        let replaced = image::load_from_memory(data).unwrap();
+        assert_eq!(replaced.dimensions(), (64, 48));
+        let mean = |image: &DynamicImage| {
+            image.to_luma8().iter().map(|&v| v as f64).sum::<f64>() / (64.0 * 48.0)
+        };
+        let shift = mean(&replaced) - mean(&original);
+        assert!((shift - 5.35).abs() < 1.0, "{}", shift);
+
+        // The new xref entry points at the new object.
+        let xref = start_xref(&marked.bytes).unwrap();
+        let entry = find(&marked.bytes, b"5 1\n", xref).unwrap() + 4;
+        let offset: usize = std::str::from_utf8(&marked.bytes[entry..entry + 10])
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(marked.bytes[offset..].starts_with(b"5 0 obj"));
+    }
+
+    #[test]
+    fn test_mark_pdf_rejects() {
+        assert!(mark_pdf(b"GIF89a", "Hello", &PdfOptions::default()).is_err());
+
+        let mut encrypted = pdf();
+        let at = rfind(&encrypted, b" >>").unwrap();
+        encrypted.splice(at..at, b" /Encrypt 7 0 R".iter().copied());
+        assert!(mark_pdf(&encrypted, "Hello", &PdfOptions::default()).is_err());
+    }
+
+    #[test]
+    fn test_objects_oversized_length() {
+        // Both would wrap past the end of the file if added to the data offset.
+        for length in [usize::MAX, usize::MAX - 20] {
+            let pdf = format!(
+                "1 0 obj\n<< /Length {} >>\nstream\nabcd\nendstream\nendobj\n",
+                length
+            );
+            let objects = objects(pdf.as_bytes()).unwrap();
+            let stream = objects[0].stream.clone().unwrap();
+            assert_eq!(&pdf.as_bytes()[stream], b"abcd\n");
+        }
+    }
+
+    #[test]
+    fn test_parse_dict() {
+        let text = b"<< /A 1 0 R /B [1 (a(b)c) <41>] /C << /D /E >> /F (x\\)y) /G 12 >>";
+        let (dict, end) = parse_dict(text, 0, 0).unwrap();
+        assert_eq!(end, text.len());
+        assert_eq!(dict["A"], "1 0 R");
+        assert_eq!(dict["B"], "[1 (a(b)c) <41>]");
+        assert_eq!(dict["C"], "<< /D /E >>");
+        assert_eq!(dict["F"], "(x\\)y)");
+        assert_eq!(dict["G"], "12");
+
+        let nested = format!("<< /A {} >>", "[".repeat(10_000));
+        assert!(parse_dict(nested.as_bytes(), 0, 0).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Strength expressed in perceptual units.
+//!
+//! `WatermarkConfig::strength` multiplies the value derived from the payload, so the same number
+//! gives a different visible change for every payload, band and curve. [`strength_for`] converts a
+//! target a reviewer can reason about into that multiplier, and [`expected_psnr`] goes the other
+//! way.
+
+use image::{DynamicImage, GenericImageView};
+
+use crate::metrics::{perceptual_distance, BASE_THRESHOLDS};
+use crate::{
+    embed_watermark_with_config, payload_value, pixel_count, ChannelStrength, Result,
+    WatermarkConfig,
+};
+
+/// RMS luma change, in 8-bit levels, of the probe embed [`PerceptualStrength::Jnd`] measures.
+/// Large enough that rounding to 8 bits does not dominate the measurement.
+const PROBE_RMS: f64 = 4.0;
+/// Most embeds [`PerceptualStrength::Jnd`] measures after the probe.
+const TUNING_STEPS: usize = 6;
+/// Relative error of the measured distance at which [`PerceptualStrength::Jnd`] stops tuning.
+const TUNING_TOLERANCE: f64 = 0.01;
+
+/// Target visibility of the mark.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PerceptualStrength {
+    /// PSNR in dB of the marked image against the original.
+    Psnr(f64),
+    /// Multiple of the just-noticeable difference, as measured by
+    /// [`perceptual_distance`] on the image the mark is for.
+    Jnd(f64),
+    /// RMS luma change as a percentage of the JPEG luma DC quantization step at `quality`.
+    /// Below 50% a re-encode at that quality tends to round a flat mark away.
+    QuantizationStep { percent: f64, quality: u8 },
+}
+
+/// The `strength` that makes embedding `watermark` into `image` with `config` hit `target`.
+///
+/// PSNR and quantization-step targets follow from the mark's energy, which depends only on the
+/// payload, the band, the curve, the image size and, for PSNR, the multipliers of
+/// `config.channels`; clipping at black and white makes the real change smaller than predicted.
+/// Quantization-step and JND targets are about the luma and need a config that marks it.
+///
+/// JND targets depend on the content as well, so the strength is tuned on
+/// [`perceptual_distance`] itself: a probe embed gives a first estimate, the distance being
+/// linear in the strength, and further embeds rescale it by the measured distance until it is
+/// within 1% of the target. Clipping and 8-bit rounding bend the line, and are accounted for
+/// that way. Each step is one embed and one measurement.
+pub fn strength_for(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+    target: PerceptualStrength,
+) -> Result<f32> {
+    let per_strength = rms_per_strength(image, watermark, config)?;
+    if per_strength == 0.0 {
+        return Err(format!("Payload {:?} carries no mark", watermark).into());
+    }
+    let rgb = per_strength * channel_gain(config);
+    if rgb == 0.0 {
+        return Err("The config marks no plane".into());
+    }
+    let luma = per_strength * config.channels.y.abs() as f64;
+
+    let strength = match target {
+        PerceptualStrength::Psnr(db) => 255.0 / 10f64.powf(db / 20.0) / rgb,
+        PerceptualStrength::QuantizationStep { percent, quality } => {
+            // A uniform offset of `d` moves the DC of an 8x8 JPEG block by `8 * d`.
+            percent / 100.0 * jpeg_dc_step(quality)? / 8.0 / nonzero(luma)?
+        }
+        PerceptualStrength::Jnd(multiple) => {
+            let distance_at = |strength: f64| {
+                let config = WatermarkConfig {
+                    strength: strength as f32,
+                    ..config.clone()
+                };
+                let marked = embed_watermark_with_config(image, watermark, &config)?;
+                perceptual_distance(image, &marked.into())
+            };
+
+            let probe = PROBE_RMS / nonzero(luma)?;
+            let distance = distance_at(probe)?;
+            if distance == 0.0 {
+                return Err("Probe embed left the luma unchanged".into());
+            }
+            let mut strength = probe * multiple / distance;
+            for _ in 0..TUNING_STEPS {
+                let distance = distance_at(strength)?;
+                if distance == 0.0 || (distance - multiple).abs() <= TUNING_TOLERANCE * multiple {
+                    break;
+                }
+                strength *= multiple / distance;
+            }
+
+            strength
+        }
+    };
+
+    Ok(strength as f32)
+}
+
+/// PSNR in dB that embedding `watermark` into `image` with `config` is expected to give, before
+/// rounding and clipping.
+pub fn expected_psnr(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<f64> {
+    let rms =
+        rms_per_strength(image, watermark, config)? * channel_gain(config) * config.strength as f64;
+
+    Ok(20.0 * (255.0 / rms).log10())
+}
+
+/// RMS change per unit of strength of the mark pattern, before `config.channels` scales it for
+/// each plane.
+///
+/// [`crate::mark_coefficients`] adds `d * w * sqrt(2N / M)` to each of the `M` coefficients of
+/// the band, with `d` the payload value; the inverse transform turns an AC coefficient change into
+/// the same energy in pixels and a DC change into half of it.
+fn rms_per_strength(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<f64> {
+    let (width, height) = image.dimensions();
+    let band = config.band.indices(pixel_count(width, height)?)?;
+    let band_len = band.len();
+
+    let energy: f64 = band
+        .enumerate()
+        .map(|(i, k)| {
+            let weight = config.curve.weight(i, band_len) as f64;
+            let gain = if k == 0 { 0.5 } else { 1.0 };
+            weight * weight * gain
+        })
+        .sum();
+
+    Ok(payload_value(watermark)?.abs() as f64 * (2.0 * energy / band_len as f64).sqrt())
+}
+
+/// RMS RGB change per unit of the mark pattern.
+///
+/// Every marked plane receives the same pattern scaled by its multiplier in `config.channels`, so
+/// each of R, G and B moves by the pattern times a fixed mix of the multipliers, given by the
+/// YCbCr to RGB conversion. With the default luma-only channels all three move with the luma and
+/// the gain is 1.
+fn channel_gain(config: &WatermarkConfig) -> f64 {
+    let ChannelStrength { y, cb, cr } = config.channels;
+    let (y, cb, cr) = (y as f64, cb as f64, cr as f64);
+    let r = y + 1.402 * cr;
+    let g = y - 0.34414 * cb - 0.71414 * cr;
+    let b = y + 1.772 * cb;
+
+    ((r * r + g * g + b * b) / 3.0).sqrt()
+}
+
+/// `luma`, the RMS luma change per unit of strength, unless the config leaves the luma unmarked.
+fn nonzero(luma: f64) -> Result<f64> {
+    if luma == 0.0 {
+        return Err("The config does not mark the luma".into());
+    }
+    Ok(luma)
+}
+
+/// Luma DC quantization step of a libjpeg-scaled Annex K table.
+fn jpeg_dc_step(quality: u8) -> Result<f64> {
+    if !(1..=100).contains(&quality) {
+        return Err(format!("JPEG quality {} is outside 1..=100", quality).into());
+    }
+
+    let quality = quality as f64;
+    let scale = if quality < 50.0 {
+        5000.0 / quality
+    } else {
+        200.0 - 2.0 * quality
+    };
+
+    Ok(((BASE_THRESHOLDS[0] as f64 * scale + 50.0) / 100.0)
+        .floor()
+        .clamp(1.0, 255.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgb, RgbImage};
+
+    use super::*;
+    use crate::metrics::psnr;
+    use crate::Band;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([(96 + x) as u8, (80 + y) as u8, 120])
+        }))
+    }
+
+    #[test]
+    fn test_psnr_target() {
+        let image = image();
+        let watermark = "Hello, World!";
+        for band in [Band::Dc, Band::Low] {
+            let config = WatermarkConfig {
+                band,
+                ..Default::default()
+            };
+            let strength =
+                strength_for(&image, watermark, &config, PerceptualStrength::Psnr(40.0)).unwrap();
+            let config = WatermarkConfig { strength, ..config };
+            assert!((expected_psnr(&image, watermark, &config).unwrap() - 40.0).abs() < 1e-3);
+
+            let marked = embed_watermark_with_config(&image, watermark, &config).unwrap();
+            let measured = psnr(&image, &marked.into()).unwrap();
+            assert!(
+                (measured - 40.0).abs() < 1.0,
+                "{:?}: {}",
+                config.band,
+                measured
+            );
+        }
+    }
+
+    #[test]
+    fn test_psnr_target_with_channels() {
+        let image = image();
+        let watermark = "Hello, World!";
+        for channels in [
+            ChannelStrength {
+                y: 0.5,
+                ..Default::default()
+            },
+            ChannelStrength::HVS,
+        ] {
+            let config = WatermarkConfig {
+                channels,
+                ..Default::default()
+            };
+            let strength =
+                strength_for(&image, watermark, &config, PerceptualStrength::Psnr(40.0)).unwrap();
+            let config = WatermarkConfig { strength, ..config };
+            assert!((expected_psnr(&image, watermark, &config).unwrap() - 40.0).abs() < 1e-3);
+
+            let marked = embed_watermark_with_config(&image, watermark, &config).unwrap();
+            let measured = psnr(&image, &marked.into()).unwrap();
+            assert!(
+                (measured - 40.0).abs() < 1.0,
+                "{:?}: {}",
+                channels,
+                measured
+            );
+        }
+    }
+
+    #[test]
+    fn test_quantization_step_target() {
+        assert_eq!(jpeg_dc_step(50).unwrap(), 16.0);
+        assert_eq!(jpeg_dc_step(90).unwrap(), 3.0);
+        assert!(jpeg_dc_step(0).is_err());
+
+        let config = WatermarkConfig::default();
+        let target = PerceptualStrength::QuantizationStep {
+            percent: 50.0,
+            quality: 50,
+        };
+        let strength = strength_for(&image(), "Hello", &config, target).unwrap();
+        let offset = payload_value("Hello").unwrap() * strength;
+        assert!((offset - 1.0).abs() < 1e-4, "{}", offset);
+    }
+
+    #[test]
+    fn test_jnd_target() {
+        let image = image();
+        let config = WatermarkConfig::default();
+        let strength =
+            strength_for(&image, "Hello", &config, PerceptualStrength::Jnd(1.0)).unwrap();
+
+        let config = WatermarkConfig { strength, ..config };
+        let marked = embed_watermark_with_config(&image, "Hello", &config).unwrap();
+        let distance = perceptual_distance(&image, &marked.into()).unwrap();
+        assert!((distance - 1.0).abs() < 0.25, "{}", distance);
+
+        assert!(strength_for(&image, "", &config, PerceptualStrength::Jnd(1.0)).is_err());
+    }
+
+    #[test]
+    fn test_jnd_target_with_clipping() {
+        // Near white, so a brightening mark clips and a linear estimate falls short.
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, y| {
+            Rgb([
+                (200 + (x * 3 + y) % 56) as u8,
+                (210 + y % 40) as u8,
+                (190 + x % 60) as u8,
+            ])
+        }));
+        let config = WatermarkConfig::default();
+        for multiple in [0.5, 1.0] {
+            let target = PerceptualStrength::Jnd(multiple);
+            let strength = strength_for(&image, "Hello", &config, target).unwrap();
+
+            let config = WatermarkConfig {
+                strength,
+                ..config.clone()
+            };
+            let marked = embed_watermark_with_config(&image, "Hello", &config).unwrap();
+            let distance = perceptual_distance(&image, &marked.into()).unwrap();
+            assert!(
+                (distance - multiple).abs() < 0.02 * multiple,
+                "{} for {}",
+                distance,
+                multiple
+            );
+        }
+    }
+}
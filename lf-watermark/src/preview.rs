@@ -0,0 +1,115 @@
+//! The stock-photo workflow: a public preview that is useless as a replacement, and a full
+//! resolution original marked for each purchaser.
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+
+use crate::visible::{tile_text, Placement, TextStamp};
+use crate::{embed_watermark_with_config, Result, WatermarkConfig};
+
+/// How a preview is degraded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviewOptions {
+    /// Longest side of the preview in pixels; smaller images keep their size.
+    pub max_side: u32,
+    /// Text repeated over the whole preview.
+    pub text: String,
+    /// Size, color and opacity of the text; the margin is the gap between copies.
+    pub stamp: TextStamp,
+}
+
+impl Default for PreviewOptions {
+    fn default() -> Self {
+        PreviewOptions {
+            max_side: 800,
+            text: "PREVIEW".to_string(),
+            stamp: TextStamp {
+                placement: Placement {
+                    margin: 24,
+                    opacity: 0.4,
+                    ..Default::default()
+                },
+                scale: 3,
+                color: Rgb([255, 255, 255]),
+            },
+        }
+    }
+}
+
+/// Downscales `image` to `options.max_side` and covers it with visible text.
+pub fn protect_for_preview(image: &DynamicImage, options: &PreviewOptions) -> Result<RgbImage> {
+    if options.max_side == 0 {
+        return Err("Preview size must be positive".into());
+    }
+
+    let (width, height) = image.dimensions();
+    let mut preview = if width.max(height) > options.max_side {
+        image
+            .resize(options.max_side, options.max_side, FilterType::Lanczos3)
+            .to_rgb8()
+    } else {
+        image.to_rgb8()
+    };
+    tile_text(&mut preview, &options.text, &options.stamp)?;
+
+    Ok(preview)
+}
+
+/// The full resolution `image` with `purchaser_id` embedded invisibly, to be delivered once the
+/// licence is paid for.
+pub fn release_original(
+    image: &DynamicImage,
+    purchaser_id: &str,
+    config: &WatermarkConfig,
+) -> Result<RgbImage> {
+    if purchaser_id.is_empty() {
+        return Err("Purchaser ID must not be empty".into());
+    }
+
+    embed_watermark_with_config(image, purchaser_id, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::psnr;
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(600, 300, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, 96])
+        }))
+    }
+
+    #[test]
+    fn test_protect_for_preview() {
+        let image = image();
+        let options = PreviewOptions {
+            max_side: 400,
+            ..Default::default()
+        };
+        let preview = protect_for_preview(&image, &options).unwrap();
+        assert_eq!(preview.dimensions(), (400, 200));
+
+        let plain = image.resize(400, 400, FilterType::Lanczos3);
+        assert!(psnr(&plain, &preview.into()).unwrap() < 25.0);
+
+        let small = DynamicImage::ImageRgb8(RgbImage::new(200, 100));
+        let preview = protect_for_preview(&small, &options).unwrap();
+        assert_eq!(preview.dimensions(), (200, 100));
+    }
+
+    #[test]
+    fn test_release_original() {
+        let image = image();
+        let config = WatermarkConfig::default();
+        let original = release_original(&image, "CUSTOMER42", &config).unwrap();
+        assert_eq!(original.dimensions(), image.dimensions());
+        assert_eq!(
+            original,
+            embed_watermark_with_config(&image, "CUSTOMER42", &config).unwrap()
+        );
+
+        assert!(release_original(&image, "", &config).is_err());
+        assert!(release_original(&image, "ä", &config).is_err());
+    }
+}
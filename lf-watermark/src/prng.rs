@@ -0,0 +1,60 @@
+use sha2::{Digest, Sha256};
+
+/// Small SplitMix64 generator.
+///
+/// Everything that needs reproducible randomness (keyed selections, attack noise) goes through
+/// this so results only depend on the seed, never on the platform or an external crate version.
+pub(crate) struct Prng(u64);
+
+impl Prng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Prng(seed)
+    }
+
+    /// Generator seeded from the SHA-256 of a secret key, for keyed selections.
+    pub(crate) fn from_key(key: &[u8]) -> Self {
+        let digest = Sha256::digest(key);
+        let mut seed = [0; 8];
+        seed.copy_from_slice(&digest[..8]);
+        Prng::new(u64::from_le_bytes(seed))
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `[0, 1)`.
+    pub(crate) fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform value in `0..bound`. `bound` must not be zero.
+    pub(crate) fn next_below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prng_is_reproducible() {
+        let mut a = Prng::new(42);
+        let mut b = Prng::new(42);
+        for _ in 0..16 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+
+        let f = Prng::new(7).next_f32();
+        assert!((0.0..1.0).contains(&f), "{}", f);
+        assert_eq!(
+            Prng::from_key(b"key").next_u64(),
+            Prng::from_key(b"key").next_u64()
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! Lattice marks at several scales at once, fused on detection.
+//!
+//! A thumbnail mark survives downscaling but not a crop, and a tiled mark the opposite.
+//! [`embed_pyramid`] embeds the same bits at every level of a pyramid of proxies, coarse to fine,
+//! and optionally into tiles at full resolution, so one call protects against both. Each level is
+//! a [`thumbnail`](crate::thumbnail) grid, which makes the pyramid Gaussian-like: every grid pixel
+//! averages the area it covers.
+//!
+//! [`detect_pyramid`] tries every level the image under test is large enough for, keeps those
+//! whose carrier agreement shows the mark, and sums their log-likelihood ratios, so levels that
+//! are each unsure of a bit can still decide it together. A level that lost the mark, as the grids
+//! do to a crop, is left out rather than adding noise.
+
+use image::{DynamicImage, GenericImageView, RgbImage};
+
+use crate::lattice::{decode_scs_llr, ScsConfig};
+use crate::region::{embed_tiles, scan, Rect, ScanOptions};
+use crate::thumbnail::ThumbnailConfig;
+use crate::thumbnail::{decode_thumbnail_llr, embed_thumbnail, thumbnail_agreement};
+use crate::{Band, Result};
+
+/// Passes over all levels. Embedding a level moves the carriers of the others a little, and a
+/// second pass puts them back.
+const PASSES: usize = 2;
+
+/// Settings shared by [`embed_pyramid`] and [`detect_pyramid`], which must match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PyramidConfig {
+    /// Proxy levels, each embedded like a thumbnail mark.
+    pub levels: Vec<ThumbnailConfig>,
+    /// How the full-resolution level that survives crops is tiled and found, and its lattice,
+    /// see [`embed_tiles`] and [`scan`]. `None` leaves it out. The grids leave the tiles of the
+    /// default lattice readable at their exact alignment only, so it scans every column.
+    pub tiles: Option<(ScanOptions, ScsConfig)>,
+    /// Carrier agreement at which a grid level counts as carrying the mark. The tile level is
+    /// found with `ScanOptions::accept`, which is stricter as a scan tries many windows.
+    pub accept: f64,
+}
+
+impl Default for PyramidConfig {
+    fn default() -> Self {
+        let level = |size| ThumbnailConfig {
+            grid: (size, size),
+            ..Default::default()
+        };
+        PyramidConfig {
+            levels: vec![level(64), level(128)],
+            tiles: Some((
+                ScanOptions {
+                    stride: 1,
+                    ..Default::default()
+                },
+                ScsConfig {
+                    band: Band::Low,
+                    ..Default::default()
+                },
+            )),
+            accept: 0.75,
+        }
+    }
+}
+
+/// What [`detect_pyramid`] found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PyramidMatch {
+    /// The bits, decided on the summed log-likelihood ratios.
+    pub bits: Vec<bool>,
+    /// Per level of `config.levels`, its carrier agreement, or `None` where the image is smaller
+    /// than the grid.
+    pub levels: Vec<Option<f64>>,
+    /// The tile the tile level was read from, if it carried the mark.
+    pub tile: Option<Rect>,
+    /// Levels that carried the mark and were fused, the tile level included.
+    pub fused: usize,
+}
+
+/// Embeds `bits` under `key` at every level of `config`.
+pub fn embed_pyramid(
+    image: &DynamicImage,
+    bits: &[bool],
+    key: &[u8],
+    config: &PyramidConfig,
+) -> Result<RgbImage> {
+    if config.levels.is_empty() && config.tiles.is_none() {
+        return Err("The pyramid has no levels".into());
+    }
+
+    let mut marked = image.to_rgb8();
+    for _ in 0..PASSES {
+        for level in &config.levels {
+            marked = embed_thumbnail(&DynamicImage::ImageRgb8(marked), bits, key, level)?;
+        }
+        // The tiles come last, since their finer lattice tolerates the least disturbance.
+        if let Some((options, scs)) = &config.tiles {
+            let image = DynamicImage::ImageRgb8(marked);
+            marked = embed_tiles(&image, bits, key, options.tile, scs)?;
+        }
+    }
+
+    Ok(marked)
+}
+
+/// Decodes `len` bits embedded under `key` by [`embed_pyramid`] from `image`, which may be a
+/// thumbnail or a crop of the marked one.
+///
+/// `None` means no level carried the mark: the image was not marked under `key`, was both
+/// downscaled below the grids and cropped below a tile, or was processed too heavily.
+pub fn detect_pyramid(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &PyramidConfig,
+) -> Result<Option<PyramidMatch>> {
+    if len == 0 {
+        return Err("No bits to detect".into());
+    }
+
+    let (width, height) = image.dimensions();
+    let mut sum = vec![0.0; len];
+    let mut fused = 0;
+    let mut add = |llrs: Vec<f32>| {
+        for (total, llr) in sum.iter_mut().zip(llrs) {
+            *total += llr;
+        }
+        fused += 1;
+    };
+
+    let mut levels = Vec::with_capacity(config.levels.len());
+    for level in &config.levels {
+        let (grid_width, grid_height) = level.grid;
+        if grid_width > width || grid_height > height {
+            levels.push(None);
+            continue;
+        }
+        let agreement = thumbnail_agreement(image, len, key, level)?;
+        if agreement >= config.accept {
+            add(decode_thumbnail_llr(image, len, key, level)?);
+        }
+        levels.push(Some(agreement));
+    }
+
+    let mut tile = None;
+    if let Some((options, scs)) = &config.tiles {
+        if options.tile <= width && options.tile <= height {
+            if let Some(found) = scan(image, len, key, scs, options)? {
+                let rect = found.rect;
+                let region = image.crop_imm(rect.x, rect.y, rect.width, rect.height);
+                add(decode_scs_llr(&region, len, key, scs)?);
+                tile = Some(rect);
+            }
+        }
+    }
+
+    if fused == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(PyramidMatch {
+        bits: sum.into_iter().map(|llr| llr > 0.0).collect(),
+        levels,
+        tile,
+        fused,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use image::imageops::FilterType;
+
+    use super::*;
+    use crate::metrics::psnr;
+    use crate::test_utils::grainy;
+
+    fn bits(len: usize) -> Vec<bool> {
+        (0..len).map(|i| (i * 5 + i / 4) % 3 != 0).collect()
+    }
+
+    #[test]
+    fn test_pyramid() {
+        let image = DynamicImage::ImageRgb8(grainy(512, 384, 41));
+        let config = PyramidConfig::default();
+        let bits = bits(24);
+        let marked = embed_pyramid(&image, &bits, b"key", &config).unwrap();
+        let marked = DynamicImage::ImageRgb8(marked);
+        assert!(psnr(&image, &marked).unwrap() > 36.0);
+
+        // All levels at full resolution.
+        let found = detect_pyramid(&marked, 24, b"key", &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.bits, bits);
+        assert_eq!(found.fused, 3, "{:?}", found);
+
+        // Only the coarse grid survives heavy downscaling.
+        let small = marked.resize_exact(85, 64, FilterType::Lanczos3);
+        let found = detect_pyramid(&small, 24, b"key", &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.bits, bits);
+        assert_eq!(found.levels[1], None);
+        assert_eq!((found.fused, found.tile), (1, None));
+
+        // Only the tiles survive a crop.
+        let crop = marked.crop_imm(203, 150, 160, 120);
+        let found = detect_pyramid(&crop, 24, b"key", &config).unwrap().unwrap();
+        assert_eq!(found.bits, bits);
+        assert_eq!(found.fused, 1, "{:?}", found);
+        assert!(found.tile.is_some());
+
+        assert_eq!(detect_pyramid(&image, 24, b"key", &config).unwrap(), None);
+    }
+
+    #[test]
+    fn test_pyramid_errors() {
+        let image = DynamicImage::ImageRgb8(grainy(128, 96, 41));
+        let empty = PyramidConfig {
+            levels: Vec::new(),
+            tiles: None,
+            ..Default::default()
+        };
+        assert!(embed_pyramid(&image, &bits(8), b"key", &empty).is_err());
+        assert!(embed_pyramid(&image, &bits(8), b"key", &PyramidConfig::default()).is_err());
+        assert!(detect_pyramid(&image, 0, b"key", &PyramidConfig::default()).is_err());
+    }
+}
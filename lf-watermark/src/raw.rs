@@ -0,0 +1,490 @@
+//! Embedding on raw pixel buffers, for callers holding frames from V4L2, ffmpeg or GPU readback
+//! who should not have to build a [`DynamicImage`](image::DynamicImage) copy first.
+
+use image::Rgb;
+
+use crate::{add_to_band, payload_value, rgb_to_ycbcr, ycbcr_to_rgb, Result, WatermarkConfig};
+
+/// Nominal luma range of limited-range ("TV") video: black at 16, white at 235.
+const LIMITED_LUMA: (f32, f32) = (16.0, 235.0);
+/// Nominal chroma excursion of limited-range video, 16 to 240 around 128.
+const LIMITED_CHROMA: f32 = 224.0;
+
+/// Memory layout of a raw frame. All formats are 8 bits per sample and tightly packed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Interleaved `R, G, B`.
+    Rgb8,
+    /// Interleaved `R, G, B, A`. Alpha is left untouched.
+    Rgba8,
+    /// A full-resolution Y plane followed by an interleaved, half-resolution `U, V` plane.
+    /// Only the Y plane is modified.
+    Nv12,
+    /// Like [`PixelFormat::Nv12`] with the chroma order swapped to `V, U`.
+    Nv21,
+    /// Planar YUV 4:2:0: a Y plane followed by half-resolution U and V planes.
+    I420,
+}
+
+impl PixelFormat {
+    /// Number of bytes a `width` x `height` frame occupies.
+    pub fn buffer_len(&self, width: u32, height: u32) -> usize {
+        let pixels = width as usize * height as usize;
+        match self {
+            PixelFormat::Rgb8 => pixels * 3,
+            PixelFormat::Rgba8 => pixels * 4,
+            PixelFormat::Nv12 | PixelFormat::Nv21 | PixelFormat::I420 => {
+                pixels + 2 * (width as usize).div_ceil(2) * (height as usize).div_ceil(2)
+            }
+        }
+    }
+}
+
+/// Luma weights of a YCbCr matrix, from ITU-T H.273.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatrixCoefficients {
+    /// SD video and JPEG.
+    #[default]
+    Bt601,
+    /// HD video.
+    Bt709,
+    /// UHD video, non-constant luminance.
+    Bt2020,
+}
+
+impl MatrixCoefficients {
+    /// `(Kr, Kb)`; `Kg` is what remains of one.
+    fn weights(self) -> (f32, f32) {
+        match self {
+            MatrixCoefficients::Bt601 => (0.299, 0.114),
+            MatrixCoefficients::Bt709 => (0.2126, 0.0722),
+            MatrixCoefficients::Bt2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// How a frame's YCbCr samples relate to RGB.
+///
+/// The default is full-range BT.601, the JFIF convention every embedder of this crate uses, so
+/// [`embed_raw`] results stay the same as for images. Video frames are usually limited range and
+/// BT.709 from HD up; take the values from the stream's metadata with
+/// [`ColorMatrix::from_h273`], or [`ColorMatrix::guess`] them from the frame size where the
+/// stream does not say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ColorMatrix {
+    pub coefficients: MatrixCoefficients,
+    /// Samples use the whole 0 to 255 range rather than the 16 to 235 (luma) and 16 to 240
+    /// (chroma) of broadcast video.
+    pub full_range: bool,
+}
+
+impl Default for ColorMatrix {
+    fn default() -> Self {
+        ColorMatrix {
+            coefficients: MatrixCoefficients::Bt601,
+            full_range: true,
+        }
+    }
+}
+
+impl ColorMatrix {
+    /// The matrix signaled by an H.273 `matrix_coefficients` code point, as found in H.264/HEVC
+    /// VUI, AV1 sequence headers, MP4 `colr` boxes and ffmpeg's `colorspace`, together with the
+    /// stream's full-range flag.
+    pub fn from_h273(matrix_coefficients: u8, full_range: bool) -> Result<Self> {
+        let coefficients = match matrix_coefficients {
+            1 => MatrixCoefficients::Bt709,
+            5 | 6 => MatrixCoefficients::Bt601,
+            9 => MatrixCoefficients::Bt2020,
+            2 => return Err("Matrix coefficients unspecified; use ColorMatrix::guess".into()),
+            code => return Err(format!("Unsupported matrix coefficients {}", code).into()),
+        };
+
+        Ok(ColorMatrix {
+            coefficients,
+            full_range,
+        })
+    }
+
+    /// The usual matrix of an untagged limited-range frame of this size: BT.601 for standard
+    /// definition, BT.709 from 720 lines or 1280 columns up, as players assume.
+    pub fn guess(width: u32, height: u32) -> Self {
+        let coefficients = if width >= 1280 || height >= 720 {
+            MatrixCoefficients::Bt709
+        } else {
+            MatrixCoefficients::Bt601
+        };
+
+        ColorMatrix {
+            coefficients,
+            full_range: false,
+        }
+    }
+
+    /// Luma code values of black and white.
+    fn luma_range(self) -> (f32, f32) {
+        if self.full_range {
+            (0.0, 255.0)
+        } else {
+            LIMITED_LUMA
+        }
+    }
+
+    /// Chroma code values per unit of color difference.
+    fn chroma_scale(self) -> f32 {
+        if self.full_range {
+            255.0
+        } else {
+            LIMITED_CHROMA
+        }
+    }
+
+    fn to_ycbcr(self, pixel: [u8; 3]) -> (f32, f32, f32) {
+        let (kr, kb) = self.coefficients.weights();
+        let [r, g, b] = pixel.map(|c| c as f32 / 255.0);
+        let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+        let cb = (b - y) / (2.0 * (1.0 - kb));
+        let cr = (r - y) / (2.0 * (1.0 - kr));
+
+        let (black, white) = self.luma_range();
+        let scale = self.chroma_scale();
+        (
+            black + y * (white - black),
+            128.0 + cb * scale,
+            128.0 + cr * scale,
+        )
+    }
+
+    fn to_rgb(self, y: f32, cb: f32, cr: f32) -> Rgb<u8> {
+        let (kr, kb) = self.coefficients.weights();
+        let (black, white) = self.luma_range();
+        let scale = self.chroma_scale();
+        let y = (y - black) / (white - black);
+        let (cb, cr) = ((cb - 128.0) / scale, (cr - 128.0) / scale);
+
+        let r = y + 2.0 * (1.0 - kr) * cr;
+        let b = y + 2.0 * (1.0 - kb) * cb;
+        let g = (y - kr * r - kb * b) / (1.0 - kr - kb);
+        Rgb([r, g, b].map(|c| (c * 255.0).round().clamp(0.0, 255.0) as u8))
+    }
+}
+
+/// Embeds `watermark` in place into a raw frame.
+///
+/// The result is the same as [`embed_watermark_with_config`](crate::embed_watermark_with_config)
+/// on an equivalent image. For the YUV 4:2:0 formats the Y plane already is the luma, so it is
+/// marked directly and the chroma planes are never touched.
+pub fn embed_raw(
+    pixels: &mut [u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    embed_raw_with_matrix(
+        pixels,
+        width,
+        height,
+        format,
+        ColorMatrix::default(),
+        watermark,
+        config,
+    )
+}
+
+/// Same as [`embed_raw`] for a frame whose YCbCr samples follow `matrix`.
+///
+/// RGB frames are converted with `matrix` instead of the JFIF one. In limited-range YUV frames
+/// the mark is scaled to the narrower luma range, so it is as strong in the displayed picture as
+/// in a full-range frame, and the luma is kept between black and white.
+pub fn embed_raw_with_matrix(
+    pixels: &mut [u8],
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    matrix: ColorMatrix,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    let expected = format.buffer_len(width, height);
+    if pixels.len() != expected {
+        return Err(format!(
+            "Invalid buffer size; expected {}, got {}",
+            expected,
+            pixels.len()
+        )
+        .into());
+    }
+    if width == 0 || height == 0 {
+        return Err("Empty frame".into());
+    }
+
+    let watermark = payload_value(watermark)? * config.strength;
+
+    let channels = match format {
+        PixelFormat::Rgb8 => 3,
+        PixelFormat::Rgba8 => 4,
+        PixelFormat::Nv12 | PixelFormat::Nv21 | PixelFormat::I420 => {
+            let luma = &mut pixels[..width as usize * height as usize];
+            let (black, white) = matrix.luma_range();
+            let watermark = watermark * (white - black) / 255.0;
+            mark_luma_plane(luma, width, height, width as usize, watermark, config)?;
+            if !matrix.full_range {
+                luma.iter_mut()
+                    .for_each(|y| *y = (*y).clamp(black as u8, white as u8));
+            }
+            return Ok(());
+        }
+    };
+    // The JFIF path rounds chroma to 8 bits; it is kept for the default so outputs do not change.
+    if matrix == ColorMatrix::default() {
+        return embed_interleaved(pixels, channels, watermark, config);
+    }
+
+    let mut y_channel = Vec::with_capacity(pixels.len() / channels);
+    let mut cbcr_channel = Vec::with_capacity(pixels.len() / channels);
+    for pixel in pixels.chunks_exact(channels) {
+        let (y, cb, cr) = matrix.to_ycbcr([pixel[0], pixel[1], pixel[2]]);
+        y_channel.push(y);
+        cbcr_channel.push((cb, cr));
+    }
+
+    let (black, white) = matrix.luma_range();
+    add_to_band(&mut y_channel, watermark * (white - black) / 255.0, config)?;
+
+    for (idx, pixel) in pixels.chunks_exact_mut(channels).enumerate() {
+        let (cb, cr) = cbcr_channel[idx];
+        let rgb = matrix.to_rgb(y_channel[idx], cb, cr);
+        pixel[..3].copy_from_slice(&rgb.0);
+    }
+
+    Ok(())
+}
+
+/// Embeds `watermark` in place into a single 8-bit luma plane whose rows are `stride` bytes apart.
+///
+/// Video frames often come with padded rows or with the planes in separate allocations; this marks
+/// the Y plane of any planar or semi-planar YUV frame without an RGB round trip. Padding bytes are
+/// left as they are.
+pub fn embed_luma_plane(
+    plane: &mut [u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    if width == 0 || height == 0 {
+        return Err("Empty frame".into());
+    }
+    if stride < width as usize {
+        return Err(format!("Stride {} is smaller than width {}", stride, width).into());
+    }
+
+    let expected = stride * (height as usize - 1) + width as usize;
+    if plane.len() < expected {
+        return Err(format!(
+            "Invalid buffer size; expected at least {}, got {}",
+            expected,
+            plane.len()
+        )
+        .into());
+    }
+
+    let watermark = payload_value(watermark)? * config.strength;
+    mark_luma_plane(plane, width, height, stride, watermark, config)
+}
+
+fn mark_luma_plane(
+    plane: &mut [u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    watermark: f32,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    let rows = || (0..height as usize).map(|row| row * stride..row * stride + width as usize);
+
+    let mut y_channel = Vec::with_capacity(width as usize * height as usize);
+    for row in rows() {
+        y_channel.extend(plane[row].iter().map(|&y| y as f32));
+    }
+
+    add_to_band(&mut y_channel, watermark, config)?;
+
+    let mut y_channel = y_channel.into_iter();
+    for row in rows() {
+        for (dst, y) in plane[row].iter_mut().zip(y_channel.by_ref()) {
+            *dst = y.round() as u8;
+        }
+    }
+
+    Ok(())
+}
+
+pub(crate) fn embed_interleaved(
+    pixels: &mut [u8],
+    channels: usize,
+    watermark: f32,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    let mut y_channel = Vec::with_capacity(pixels.len() / channels);
+    let mut cbcr_channel = Vec::with_capacity(pixels.len() / channels);
+    for pixel in pixels.chunks_exact(channels) {
+        let (y, cb, cr) = rgb_to_ycbcr(&Rgb([pixel[0], pixel[1], pixel[2]]));
+        y_channel.push(y as f32);
+        cbcr_channel.push((cb, cr));
+    }
+
+    add_to_band(&mut y_channel, watermark, config)?;
+
+    for (idx, pixel) in pixels.chunks_exact_mut(channels).enumerate() {
+        let (cb, cr) = cbcr_channel[idx];
+        let rgb = ycbcr_to_rgb(y_channel[idx], cb as f32, cr as f32);
+        pixel[..3].copy_from_slice(&rgb.0);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, RgbImage};
+
+    use super::*;
+    use crate::embed_watermark_with_config;
+
+    #[test]
+    fn test_embed_raw_rgb_matches_image_api() {
+        let img = RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 16) as u8, (y * 16) as u8, 50]));
+        let config = WatermarkConfig::default();
+        let expected =
+            embed_watermark_with_config(&DynamicImage::ImageRgb8(img.clone()), "Hello", &config)
+                .unwrap();
+
+        let mut rgb = img.clone().into_raw();
+        embed_raw(&mut rgb, 16, 16, PixelFormat::Rgb8, "Hello", &config).unwrap();
+        assert_eq!(rgb, expected.clone().into_raw());
+
+        let mut rgba: Vec<u8> = img.pixels().flat_map(|p| [p[0], p[1], p[2], 7]).collect();
+        embed_raw(&mut rgba, 16, 16, PixelFormat::Rgba8, "Hello", &config).unwrap();
+        let alpha_stripped: Vec<u8> = rgba.chunks(4).flat_map(|p| [p[0], p[1], p[2]]).collect();
+        assert_eq!(alpha_stripped, expected.into_raw());
+        assert!(rgba.chunks(4).all(|p| p[3] == 7));
+    }
+
+    #[test]
+    fn test_embed_raw_nv12() {
+        let config = WatermarkConfig {
+            strength: 0.01,
+            ..Default::default()
+        };
+        let mut frame = vec![100u8; PixelFormat::Nv12.buffer_len(8, 4)];
+        assert_eq!(frame.len(), 48);
+
+        embed_raw(
+            &mut frame,
+            8,
+            4,
+            PixelFormat::Nv12,
+            "Hello, World!",
+            &config,
+        )
+        .unwrap();
+        assert!(frame[..32].iter().all(|&y| y == 105));
+        assert!(frame[32..].iter().all(|&uv| uv == 100));
+
+        assert!(embed_raw(&mut frame, 8, 8, PixelFormat::Nv12, "Hello", &config).is_err());
+    }
+
+    #[test]
+    fn test_embed_luma_plane_with_stride() {
+        let config = WatermarkConfig {
+            strength: 0.01,
+            ..Default::default()
+        };
+        let mut plane = vec![100u8; 12 * 3 + 8];
+        embed_luma_plane(&mut plane, 8, 4, 12, "Hello, World!", &config).unwrap();
+
+        for (i, &y) in plane.iter().enumerate() {
+            let expected = if i % 12 < 8 { 105 } else { 100 };
+            assert_eq!(y, expected, "byte {}", i);
+        }
+
+        assert!(embed_luma_plane(&mut plane, 8, 4, 4, "Hello", &config).is_err());
+        assert!(embed_luma_plane(&mut plane, 8, 5, 12, "Hello", &config).is_err());
+    }
+
+    #[test]
+    fn test_color_matrix() {
+        for coefficients in [
+            MatrixCoefficients::Bt601,
+            MatrixCoefficients::Bt709,
+            MatrixCoefficients::Bt2020,
+        ] {
+            for full_range in [true, false] {
+                let matrix = ColorMatrix {
+                    coefficients,
+                    full_range,
+                };
+                for pixel in [[0, 0, 0], [255, 255, 255], [200, 30, 90], [12, 240, 128]] {
+                    let (y, cb, cr) = matrix.to_ycbcr(pixel);
+                    assert_eq!(matrix.to_rgb(y, cb, cr).0, pixel);
+                }
+            }
+        }
+
+        let hd = ColorMatrix::from_h273(1, false).unwrap();
+        assert_eq!(hd, ColorMatrix::guess(1920, 1080));
+        assert_eq!(hd.to_ycbcr([255, 255, 255]).0, 235.0);
+        assert_eq!(
+            ColorMatrix::guess(720, 576).coefficients,
+            MatrixCoefficients::Bt601
+        );
+        assert!(ColorMatrix::from_h273(2, false).is_err());
+    }
+
+    #[test]
+    fn test_embed_raw_with_matrix() {
+        let config = WatermarkConfig {
+            strength: 0.01,
+            ..Default::default()
+        };
+        let hd = ColorMatrix::guess(1920, 1080);
+
+        // Limited range: 5.35 full-range levels are 5.35 * 219 / 255 = 4.6 luma codes, and white
+        // stays at 235.
+        let mut frame = vec![100u8; PixelFormat::Nv12.buffer_len(8, 4)];
+        frame[..16].fill(235);
+        embed_raw_with_matrix(
+            &mut frame,
+            8,
+            4,
+            PixelFormat::Nv12,
+            hd,
+            "Hello, World!",
+            &config,
+        )
+        .unwrap();
+        assert!(frame[..16].iter().all(|&y| y == 235));
+        assert!(frame[16..32].iter().all(|&y| y == 105));
+
+        // A luma mark is a neutral change in RGB whatever the matrix.
+        let img = RgbImage::from_fn(16, 16, |x, y| Rgb([(x * 12) as u8, (y * 12) as u8, 90]));
+        let mut rgb = img.clone().into_raw();
+        embed_raw_with_matrix(
+            &mut rgb,
+            16,
+            16,
+            PixelFormat::Rgb8,
+            hd,
+            "Hello, World!",
+            &config,
+        )
+        .unwrap();
+        for (before, after) in img.into_raw().iter().zip(&rgb) {
+            assert!((*after as i16 - *before as i16 - 5).abs() <= 1);
+        }
+    }
+}
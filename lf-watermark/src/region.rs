@@ -0,0 +1,240 @@
+//! Lattice marks that survive cropping.
+//!
+//! A whole-image mark is lost to a crop, since every DCT coefficient depends on every pixel.
+//! [`embed_tiles`] instead embeds the same bits into each tile of a grid, so any region that still
+//! contains one whole tile carries them. [`extract_from_region`] decodes a region known to be a
+//! tile. A screenshot or crop does not say where the grid lies, but the grid repeats every
+//! `tile` pixels, so [`scan`] only has to try the alignments within the first period to land on a
+//! whole tile if there is one.
+
+use image::{DynamicImage, GenericImage, GenericImageView, RgbImage};
+use rustdct::DctPlanner;
+
+use crate::lattice::{agreement, carriers, decode_scs, embed_scs, llrs, ScsConfig};
+use crate::{forward_dct, split_ycbcr, Result};
+
+/// A rectangle of pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub(crate) fn fits(&self, width: u32, height: u32) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self.x as u64 + self.width as u64 <= width as u64
+            && self.y as u64 + self.height as u64 <= height as u64
+    }
+}
+
+/// Settings of [`scan`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanOptions {
+    /// Tile size the image was marked with.
+    pub tile: u32,
+    /// Horizontal distance between tried alignments. The carriers are the lowest frequencies of
+    /// the luma in raster order, which a shift by a few pixels along a row barely changes but a
+    /// shift by one row does, so rows are always tried one by one.
+    pub stride: u32,
+    /// Carrier agreement at which a window counts as a marked tile and the scan stops, see
+    /// [`carrier_agreement`](crate::lattice::carrier_agreement).
+    pub accept: f64,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            tile: 64,
+            stride: 4,
+            accept: 0.9,
+        }
+    }
+}
+
+/// A tile found by [`scan`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegionMatch {
+    pub rect: Rect,
+    pub bits: Vec<bool>,
+    pub agreement: f64,
+}
+
+/// Embeds `bits` under `key` into every whole `tile` x `tile` tile of `image`, counted from the
+/// top left corner. The partial tiles at the right and bottom edges are left untouched.
+pub fn embed_tiles(
+    image: &DynamicImage,
+    bits: &[bool],
+    key: &[u8],
+    tile: u32,
+    config: &ScsConfig,
+) -> Result<RgbImage> {
+    let (width, height) = image.dimensions();
+    if tile == 0 || tile > width || tile > height {
+        return Err(format!("A {}x{} image holds no tile of {}", width, height, tile).into());
+    }
+
+    let mut marked = image.to_rgb8();
+    for y in (0..=height - tile).step_by(tile as usize) {
+        for x in (0..=width - tile).step_by(tile as usize) {
+            let region = DynamicImage::ImageRgb8(marked.view(x, y, tile, tile).to_image());
+            marked.copy_from(&embed_scs(&region, bits, key, config)?, x, y)?;
+        }
+    }
+
+    Ok(marked)
+}
+
+/// Decodes `len` bits embedded under `key` from the `rect` region of `image`, which has to be a
+/// whole tile of [`embed_tiles`] for the bits to mean anything.
+pub fn extract_from_region(
+    image: &DynamicImage,
+    rect: Rect,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+) -> Result<Vec<bool>> {
+    let (width, height) = image.dimensions();
+    if !rect.fits(width, height) {
+        return Err(format!("{:?} is not inside the {}x{} image", rect, width, height).into());
+    }
+
+    let region = image.crop_imm(rect.x, rect.y, rect.width, rect.height);
+    decode_scs(&region, len, key, config)
+}
+
+/// Looks for a tile of [`embed_tiles`] anywhere in `image`, which may be a crop of the marked
+/// one, and decodes `len` bits from the first window whose carrier agreement reaches
+/// `options.accept`, moved along its row to where the agreement is highest.
+///
+/// `None` means no window did: the image was not marked under `key`, has no whole tile left or
+/// was processed too heavily.
+pub fn scan(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+    options: &ScanOptions,
+) -> Result<Option<RegionMatch>> {
+    let (width, height) = image.dimensions();
+    let tile = options.tile;
+    if tile == 0 || tile > width || tile > height {
+        return Err(format!("A {}x{} image holds no tile of {}", width, height, tile).into());
+    }
+    if options.stride == 0 {
+        return Err("Stride must be positive".into());
+    }
+    let carriers = carriers(tile, tile, len, key, config)?;
+    if carriers.is_empty() {
+        return Err("No bits to scan for".into());
+    }
+
+    let (luma, _) = split_ycbcr(&image.to_rgb8());
+    let mut dct_planner: DctPlanner<f32> = DctPlanner::new();
+    let mut window = vec![0.0; tile as usize * tile as usize];
+    let mut measure = |x: u32, y: u32, window: &mut [f32]| {
+        for (row, target) in window.chunks_exact_mut(tile as usize).enumerate() {
+            let start = (y as usize + row) * width as usize + x as usize;
+            target.copy_from_slice(&luma[start..start + tile as usize]);
+        }
+        forward_dct(&mut dct_planner, window);
+        agreement(window, &carriers, config)
+    };
+
+    // Past one grid period every alignment repeats.
+    let (columns, rows) = ((width - tile + 1).min(tile), (height - tile + 1).min(tile));
+    for y in 0..rows {
+        for x in (0..columns).step_by(options.stride as usize) {
+            if measure(x, y, &mut window) < options.accept {
+                continue;
+            }
+
+            // A window a few pixels off still decodes; settle on the best one nearby.
+            let near = x.saturating_sub(options.stride - 1)..(x + options.stride).min(columns);
+            let (x, agreement) =
+                near.map(|x| (x, measure(x, y, &mut window)))
+                    .fold(
+                        (x, 0.0),
+                        |best, next| if next.1 > best.1 { next } else { best },
+                    );
+            measure(x, y, &mut window);
+            let bits = llrs(&window, &carriers, config)
+                .into_iter()
+                .map(|llr| llr > 0.0)
+                .collect();
+            return Ok(Some(RegionMatch {
+                rect: Rect {
+                    x,
+                    y,
+                    width: tile,
+                    height: tile,
+                },
+                bits,
+                agreement,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::grainy;
+    use crate::Band;
+
+    fn config() -> ScsConfig {
+        ScsConfig {
+            band: Band::Low,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_scan_finds_a_tile_in_a_crop() {
+        let image = DynamicImage::ImageRgb8(grainy(200, 150, 41));
+        let bits: Vec<bool> = (0..24).map(|i| i % 3 == 0).collect();
+        let key = b"region key";
+        let marked =
+            DynamicImage::ImageRgb8(embed_tiles(&image, &bits, key, 64, &config()).unwrap());
+
+        let tile = Rect {
+            x: 64,
+            y: 0,
+            width: 64,
+            height: 64,
+        };
+        assert_eq!(
+            extract_from_region(&marked, tile, bits.len(), key, &config()).unwrap(),
+            bits
+        );
+
+        // The crop keeps the tile at (64, 64) of the marked image, at (27, 43) of the crop.
+        let cropped = marked.crop_imm(37, 21, 150, 110);
+        let found = scan(
+            &cropped,
+            bits.len(),
+            key,
+            &config(),
+            &ScanOptions::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!((found.rect.x, found.rect.y), (27, 43));
+        assert_eq!(found.bits, bits);
+        assert!(found.agreement >= 0.9, "{:?}", found);
+
+        let options = ScanOptions::default();
+        assert_eq!(
+            scan(&image, bits.len(), key, &config(), &options).unwrap(),
+            None
+        );
+        assert!(scan(&marked.crop_imm(0, 0, 60, 60), 24, key, &config(), &options).is_err());
+        let outside = Rect { x: 160, ..tile };
+        assert!(extract_from_region(&marked, outside, 24, key, &config()).is_err());
+    }
+}
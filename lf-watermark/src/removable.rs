@@ -0,0 +1,271 @@
+//! Visible text marks that the key holder can remove again.
+//!
+//! Blending text into an image destroys the pixels underneath, so no blend parameters alone give
+//! them back exactly. [`stamp_removable`] therefore keeps the original values of the covered
+//! pixels together with the stamp parameters, seals them with ChaCha20-Poly1305 under the rights
+//! holder's key and stores them in a zTXt chunk of the PNG output. [`remove_mark`] restores the
+//! unmarked image bit for bit; without the key the chunk is opaque, and any change to it is
+//! detected.
+
+use std::io::Cursor;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use image::{DynamicImage, Rgb, RgbImage};
+use sha2::{Digest, Sha256};
+
+use crate::visible::{stamp_text, text_size, TextMask, TextStamp};
+use crate::Result;
+
+const CHUNK_KEYWORD: &str = "lf-watermark-removal";
+const FORMAT_VERSION: u8 = 1;
+const NONCE_LEN: usize = 12;
+
+/// Stamps `text` like [`stamp_text`] and returns a PNG that carries the sealed removal data.
+///
+/// The nonce is derived from the sealed data itself, so encrypting the same content twice gives
+/// the same chunk and, in practice, different contents never share a nonce.
+pub fn stamp_removable(
+    image: &DynamicImage,
+    text: &str,
+    stamp: &TextStamp,
+    key: &[u8; 32],
+) -> Result<Vec<u8>> {
+    let original = image.to_rgb8();
+    let (width, height) = original.dimensions();
+    let (text_width, text_height) = text_size(text, stamp.scale);
+    let (x0, y0) = stamp
+        .placement
+        .origin(width, height, text_width, text_height)?;
+    let marked = stamp_text(image, text, stamp)?;
+
+    let mut plain = vec![FORMAT_VERSION];
+    plain.extend_from_slice(&x0.to_le_bytes());
+    plain.extend_from_slice(&y0.to_le_bytes());
+    plain.extend_from_slice(&stamp.scale.to_le_bytes());
+    plain.extend_from_slice(&(text.len() as u32).to_le_bytes());
+    plain.extend_from_slice(text.as_bytes());
+    for (x, y) in covered(text, stamp.scale)? {
+        plain.extend_from_slice(&original.get_pixel(x0 + x, y0 + y).0);
+    }
+
+    let nonce: [u8; NONCE_LEN] = Sha256::digest(&plain)[..NONCE_LEN]
+        .try_into()
+        .map_err(|_| "Invalid nonce length")?;
+    let sealed = cipher(key)
+        .encrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: &plain,
+                aad: &dimensions(width, height),
+            },
+        )
+        .map_err(|_| "Sealing the removal data failed")?;
+
+    let mut chunk = nonce.to_vec();
+    chunk.extend_from_slice(&sealed);
+
+    let mut output = Vec::new();
+    let mut encoder = png::Encoder::new(&mut output, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_ztxt_chunk(CHUNK_KEYWORD.to_string(), to_hex(&chunk))?;
+    encoder.write_header()?.write_image_data(marked.as_raw())?;
+
+    Ok(output)
+}
+
+/// Removes the mark of a PNG produced by [`stamp_removable`] with the same `key`.
+pub fn remove_mark(png: &[u8], key: &[u8; 32]) -> Result<RgbImage> {
+    let decoder = png::Decoder::new(Cursor::new(png));
+    let reader = decoder.read_info()?;
+    let mut chunk = reader
+        .info()
+        .compressed_latin1_text
+        .iter()
+        .find(|chunk| chunk.keyword == CHUNK_KEYWORD)
+        .cloned()
+        .ok_or("No removal data in this PNG")?;
+    chunk.decompress_text()?;
+    let chunk = from_hex(&chunk.get_text()?)?;
+    if chunk.len() < NONCE_LEN {
+        return Err("Removal data truncated".into());
+    }
+
+    let mut image = image::load_from_memory(png)?.to_rgb8();
+    let (width, height) = image.dimensions();
+    let (nonce, sealed) = chunk.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| "Invalid nonce length")?;
+    let plain = cipher(key)
+        .decrypt(
+            &Nonce::from(nonce),
+            Payload {
+                msg: sealed,
+                aad: &dimensions(width, height),
+            },
+        )
+        .map_err(|_| "Wrong key, or the removal data or image size was altered")?;
+
+    let mut fields = Fields(&plain);
+    if fields.take(1)? != [FORMAT_VERSION] {
+        return Err("Unsupported removal data version".into());
+    }
+    let (x0, y0, scale) = (fields.u32()?, fields.u32()?, fields.u32()?);
+    let text_len = fields.u32()? as usize;
+    let text = std::str::from_utf8(fields.take(text_len)?)?;
+
+    // The sealed origin must put the text box inside the image, as it did when stamping.
+    let (text_width, text_height) = text_size(text, scale);
+    let inside = |origin: u32, size: u32, limit: u32| {
+        origin.checked_add(size).is_some_and(|end| end <= limit)
+    };
+    if !inside(x0, text_width, width) || !inside(y0, text_height, height) {
+        return Err("Removal data does not match the image".into());
+    }
+
+    for (x, y) in covered(text, scale)? {
+        let pixel = fields.take(3)?;
+        image.put_pixel(x0 + x, y0 + y, Rgb([pixel[0], pixel[1], pixel[2]]));
+    }
+
+    Ok(image)
+}
+
+/// Pixels of the text box that the stamp draws on, in raster order.
+fn covered(text: &str, scale: u32) -> Result<Vec<(u32, u32)>> {
+    let mask = TextMask::new(text, scale)?;
+    let (width, height) = text_size(text, scale);
+
+    Ok((0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .filter(|&(x, y)| mask.is_lit(x, y))
+        .collect())
+}
+
+fn cipher(key: &[u8; 32]) -> ChaCha20Poly1305 {
+    ChaCha20Poly1305::new(&(*key).into())
+}
+
+/// Associated data binding the sealed data to the image size.
+fn dimensions(width: u32, height: u32) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&width.to_le_bytes());
+    bytes[4..].copy_from_slice(&height.to_le_bytes());
+    bytes
+}
+
+/// Reader over the fields of the decrypted removal data.
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err("Removal data truncated".into());
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return Err("Removal data has an odd number of hex digits".into());
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            Ok(u8::from_str_radix(
+                text.get(i..i + 2).ok_or("Invalid hex")?,
+                16,
+            )?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::visible::Placement;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(200, 60, |x, y| {
+            Rgb([(x * 2) as u8, (y * 4) as u8, ((x * y) % 256) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_remove_mark() {
+        let image = image();
+        let stamp = TextStamp {
+            placement: Placement {
+                opacity: 0.8,
+                margin: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let png = stamp_removable(&image, "(c) Studio", &stamp, &KEY).unwrap();
+
+        let marked = image::load_from_memory(&png).unwrap().to_rgb8();
+        assert_eq!(marked, stamp_text(&image, "(c) Studio", &stamp).unwrap());
+        assert_ne!(marked, image.to_rgb8());
+
+        assert_eq!(remove_mark(&png, &KEY).unwrap(), image.to_rgb8());
+        assert!(remove_mark(&png, &[8; 32]).is_err());
+    }
+
+    #[test]
+    fn test_remove_mark_rejects_tampering() {
+        let png = stamp_removable(&image(), "MARK", &TextStamp::default(), &KEY).unwrap();
+
+        let mut chunk = png::Decoder::new(Cursor::new(&png))
+            .read_info()
+            .unwrap()
+            .info()
+            .compressed_latin1_text[0]
+            .clone();
+        chunk.decompress_text().unwrap();
+        let mut hex = chunk.get_text().unwrap();
+        let flipped = if hex.ends_with('0') { "1" } else { "0" };
+        hex.replace_range(hex.len() - 1.., flipped);
+
+        let mut tampered = Vec::new();
+        let mut encoder = png::Encoder::new(&mut tampered, 200, 60);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder
+            .add_ztxt_chunk(CHUNK_KEYWORD.to_string(), hex)
+            .unwrap();
+        let pixels = image::load_from_memory(&png).unwrap().to_rgb8();
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(pixels.as_raw())
+            .unwrap();
+        assert!(remove_mark(&tampered, &KEY).is_err());
+
+        let plain = crate::codec::encode(&pixels, crate::OutputFormat::Png).unwrap();
+        assert!(remove_mark(&plain, &KEY).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0, 171, 255]), "00abff");
+        assert_eq!(from_hex("00abff").unwrap(), vec![0, 171, 255]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//! Search for the scale and rotation a lattice-marked image went through.
+//!
+//! The lattice decoder needs the marked image's own geometry: its carriers are chosen among the
+//! coefficients of an image of exactly that size, and a rotation by a degree already moves every
+//! row. Without a synchronization template the transform has to be found by trying: [`search`]
+//! undoes each transform of a grid and keeps the one under which the carriers agree best with
+//! their bits, see [`carrier_agreement`]. Rotations are searched coarse to fine. An undone scale
+//! has to reproduce the marked dimensions exactly, so for the best scale the sizes one pixel off
+//! its rounding are tried too.
+//!
+//! The mark only survives being rotated back with a coarse lattice, a step of 32 or more.
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, Rgb, RgbImage};
+
+use crate::lattice::{carrier_agreement, decode_scs, ScsConfig};
+use crate::Result;
+
+/// Transforms [`search`] tries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchGrid {
+    /// Factors the image may have been resized by.
+    pub scales: Vec<f32>,
+    /// Coarse rotations in degrees clockwise, evenly spaced.
+    pub rotations: Vec<f32>,
+    /// Rounds of halving the rotation spacing around the best rotation so far.
+    pub refinements: u32,
+}
+
+impl Default for SearchGrid {
+    /// Common resizes from half to double size, rotations up to 5 degrees either way in steps
+    /// of half a degree, refined to a sixteenth of a degree.
+    fn default() -> Self {
+        SearchGrid {
+            scales: vec![1.0, 0.9, 0.75, 2.0 / 3.0, 0.5, 1.25, 1.5, 2.0],
+            rotations: (-10..=10).map(|i| i as f32 * 0.5).collect(),
+            refinements: 3,
+        }
+    }
+}
+
+/// A transform the image went through, and the size it is undone to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform {
+    pub scale: f32,
+    /// Degrees clockwise.
+    pub rotation: f32,
+    /// Size of the image before the transform.
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Best transform [`search`] found, with the bits decoded under it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchMatch {
+    pub transform: Transform,
+    pub bits: Vec<bool>,
+    /// Carrier agreement after undoing the transform; near one half means no mark was found.
+    pub agreement: f64,
+}
+
+/// Finds the transform of `grid` under which `len` bits embedded under `key` are clearest in
+/// `image`, and decodes them.
+///
+/// It always returns the best transform tried; judge the result by its agreement, or better by
+/// a check carried in the bits.
+pub fn search(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ScsConfig,
+    grid: &SearchGrid,
+) -> Result<SearchMatch> {
+    if grid.scales.is_empty() || grid.rotations.is_empty() {
+        return Err("The search grid is empty".into());
+    }
+    if grid
+        .scales
+        .iter()
+        .any(|&scale| scale.is_nan() || scale <= 0.0)
+    {
+        return Err("Scales must be positive".into());
+    }
+
+    let (width, height) = image.dimensions();
+    let image = image.to_rgb8();
+    let consider = |best: &mut Option<(Transform, f64)>, transform: Transform| {
+        let undone = DynamicImage::ImageRgb8(undo(&image, &transform));
+        // Sizes too small for the bits are not candidates.
+        if let Ok(agreement) = carrier_agreement(&undone, len, key, config) {
+            if best.is_none_or(|(_, best)| agreement > best) {
+                *best = Some((transform, agreement));
+            }
+        }
+    };
+
+    let mut best = None;
+
+    for &scale in &grid.scales {
+        let (w, h) = original_size(width, height, scale);
+        for &rotation in &grid.rotations {
+            consider(
+                &mut best,
+                Transform {
+                    scale,
+                    rotation,
+                    width: w,
+                    height: h,
+                },
+            );
+        }
+    }
+
+    let mut spacing = rotation_spacing(&grid.rotations);
+    for _ in 0..grid.refinements {
+        spacing /= 2.0;
+        let center = best
+            .ok_or("No transform of the grid leaves room for the bits")?
+            .0;
+        for rotation in [center.rotation - spacing, center.rotation + spacing] {
+            consider(&mut best, Transform { rotation, ..center });
+        }
+    }
+
+    let center = best
+        .ok_or("No transform of the grid leaves room for the bits")?
+        .0;
+    for (dw, dh) in [(-1, 0), (1, 0), (0, -1), (0, 1), (-1, -1), (1, 1)] {
+        let (w, h) = (center.width as i64 + dw, center.height as i64 + dh);
+        if w > 0 && h > 0 {
+            consider(
+                &mut best,
+                Transform {
+                    width: w as u32,
+                    height: h as u32,
+                    ..center
+                },
+            );
+        }
+    }
+
+    let (transform, agreement) = best.expect("the center was a candidate");
+    let bits = decode_scs(
+        &DynamicImage::ImageRgb8(undo(&image, &transform)),
+        len,
+        key,
+        config,
+    )?;
+
+    Ok(SearchMatch {
+        transform,
+        bits,
+        agreement,
+    })
+}
+
+/// Undoes `transform` on `image`: rotates it back about its center and resizes it to the size
+/// before the transform.
+pub fn undo(image: &RgbImage, transform: &Transform) -> RgbImage {
+    let rotated = if transform.rotation == 0.0 {
+        image.clone()
+    } else {
+        rotate(image, -transform.rotation)
+    };
+    if rotated.dimensions() == (transform.width, transform.height) {
+        return rotated;
+    }
+
+    image::imageops::resize(
+        &rotated,
+        transform.width,
+        transform.height,
+        FilterType::Triangle,
+    )
+}
+
+/// Rotates `image` by `degrees` clockwise about its center, keeping its size. Corners rotated in
+/// from outside repeat the nearest edge pixel.
+pub fn rotate(image: &RgbImage, degrees: f32) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (cx, cy) = ((width as f32 - 1.0) / 2.0, (height as f32 - 1.0) / 2.0);
+
+    RgbImage::from_fn(width, height, |x, y| {
+        let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+        let sx = (cx + dx * cos + dy * sin).clamp(0.0, width as f32 - 1.0);
+        let sy = (cy - dx * sin + dy * cos).clamp(0.0, height as f32 - 1.0);
+        let (x0, y0) = (sx.floor() as u32, sy.floor() as u32);
+        let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+        let (fx, fy) = (sx - x0 as f32, sy - y0 as f32);
+
+        Rgb(std::array::from_fn(|c| {
+            let at = |x, y| image.get_pixel(x, y)[c] as f32;
+            let top = at(x0, y0) * (1.0 - fx) + at(x1, y0) * fx;
+            let bottom = at(x0, y1) * (1.0 - fx) + at(x1, y1) * fx;
+            (top * (1.0 - fy) + bottom * fy).round() as u8
+        }))
+    })
+}
+
+fn original_size(width: u32, height: u32, scale: f32) -> (u32, u32) {
+    let undo = |v: u32| ((v as f32 / scale).round() as u32).max(1);
+    (undo(width), undo(height))
+}
+
+/// Smallest gap between the coarse rotations, or a degree for a single one.
+fn rotation_spacing(rotations: &[f32]) -> f32 {
+    let mut sorted = rotations.to_vec();
+    sorted.sort_by(f32::total_cmp);
+    sorted
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .filter(|&gap| gap > 0.0)
+        .reduce(f32::min)
+        .unwrap_or(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lattice::embed_scs;
+    use crate::test_utils::grainy;
+    use crate::Band;
+
+    fn config() -> ScsConfig {
+        ScsConfig {
+            step: 64.0,
+            repetition: 32,
+            band: Band::Low,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_search_undoes_rotation_and_scale() {
+        let image = DynamicImage::ImageRgb8(grainy(320, 240, 41));
+        let bits: Vec<bool> = (0..64).map(|i| i % 3 == 0).collect();
+        let key = b"search key";
+        let marked = embed_scs(&image, &bits, key, &config()).unwrap();
+
+        let attacked = DynamicImage::ImageRgb8(rotate(&marked, 2.3)).resize_exact(
+            240,
+            180,
+            FilterType::Triangle,
+        );
+        let grid = SearchGrid {
+            scales: vec![1.0, 0.75, 0.5],
+            rotations: (-6..=6).map(|i| i as f32 * 0.5).collect(),
+            refinements: 3,
+        };
+        let found = search(&attacked, bits.len(), key, &config(), &grid).unwrap();
+        assert_eq!(found.transform.scale, 0.75);
+        assert_eq!((found.transform.width, found.transform.height), (320, 240));
+        assert!(
+            (found.transform.rotation - 2.3).abs() <= 0.1,
+            "{:?}",
+            found.transform
+        );
+        // Resampling twice each way costs a few bits even under the exact inverse; a check or
+        // error correction in the payload takes care of them.
+        let errors = found.bits.iter().zip(&bits).filter(|(a, b)| a != b).count();
+        assert!(errors <= 3, "{} bit errors", errors);
+
+        let unmarked = search(&image, bits.len(), key, &config(), &grid).unwrap();
+        assert!(
+            unmarked.agreement < found.agreement - 0.05,
+            "{:?}",
+            unmarked
+        );
+
+        let empty = SearchGrid {
+            scales: vec![],
+            ..Default::default()
+        };
+        assert!(search(&attacked, bits.len(), key, &config(), &empty).is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let image = RgbImage::from_fn(5, 5, |x, y| Rgb([(x * 50) as u8, (y * 50) as u8, 0]));
+        assert_eq!(rotate(&image, 0.0), image);
+        // A quarter turn clockwise moves the left column to the top row.
+        let turned = rotate(&image, 90.0);
+        assert_eq!(turned.get_pixel(4, 0), image.get_pixel(0, 0));
+        assert_eq!(turned.get_pixel(0, 0), image.get_pixel(0, 4));
+    }
+}
@@ -0,0 +1,522 @@
+//! Per-viewer marking of live streams.
+//!
+//! A relay that sends every viewer a uniquely marked stream keeps one [`SessionMarker`] per
+//! viewer and passes each decoded frame through it before re-encoding. The payload value, the DCT
+//! plans and all buffers depend only on the session and the frame size, so they are prepared when
+//! the session starts; marking a frame is then a fixed amount of work without allocation or
+//! planning, and its latency does not spike.
+//!
+//! The session ID of a [`SessionMarker`] is the same in every frame, so a leak shows who but not
+//! when. A [`FrameStamper`] marks each frame with the session ID and a stamp that changes from
+//! frame to frame, the frame counter or a timestamp, as lattice-coded bits with a keyed check;
+//! [`read_stamp`] recovers both from any single frame of a leaked clip. It runs a whole-image
+//! transform per frame and allocates, so it is meant for relays with time to spare per frame.
+//!
+//! Since the stamp changes every frame, so does the mark, which shimmers under overlays composited
+//! later, such as a lower third or a score bug. [`StampConfig::exclusions`] keeps the mark out of
+//! their rectangles: those pixels are restored after embedding, and the embedding is repeated so
+//! the carriers make up for them elsewhere. The reader does not need to know the rectangles.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, Rgb, RgbImage};
+use rustdct::{Dct2, Dct3, DctPlanner};
+use sha2::{Digest, Sha256};
+
+use crate::lattice::{decode_scs, embed_scs, ScsConfig};
+use crate::raw::PixelFormat;
+use crate::region::Rect;
+use crate::{
+    dct_normalization, mark_coefficients, payload_value, rgb_to_ycbcr, telemetry, ycbcr_to_rgb,
+    Determinism, Result, WatermarkConfig,
+};
+
+/// Marks the frames of one viewer's stream with that viewer's session ID.
+pub struct SessionMarker {
+    session_id: String,
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    config: WatermarkConfig,
+    watermark: f32,
+    dct2: Arc<dyn Dct2<f32>>,
+    dct3: Arc<dyn Dct3<f32>>,
+    normalization_factor: f32,
+    y_channel: Vec<f32>,
+    cbcr_channel: Vec<(u8, u8)>,
+    scratch: Vec<f32>,
+    frames: u64,
+    max_frame_time: Duration,
+}
+
+impl SessionMarker {
+    /// Prepares marking `width` x `height` frames of `format` with `session_id`.
+    ///
+    /// Fails here, not on the first frame, if the session ID contains unsupported characters or
+    /// the config cannot be embedded at this size.
+    pub fn new(
+        session_id: &str,
+        width: u32,
+        height: u32,
+        format: PixelFormat,
+        config: WatermarkConfig,
+    ) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err("Empty frame".into());
+        }
+        if config.determinism == Determinism::Strict || config.channels.has_chroma() {
+            return Err("Session marking supports fast, luma-only configs".into());
+        }
+        let len = width as usize * height as usize;
+        config.band.indices(len)?;
+        let watermark = payload_value(session_id)? * config.strength;
+
+        let mut planner = DctPlanner::new();
+        let dct2 = planner.plan_dct2(len);
+        let dct3 = planner.plan_dct3(len);
+        let scratch_len = dct2.get_scratch_len().max(dct3.get_scratch_len());
+        let interleaved = matches!(format, PixelFormat::Rgb8 | PixelFormat::Rgba8);
+
+        Ok(SessionMarker {
+            session_id: session_id.to_string(),
+            width,
+            height,
+            format,
+            config,
+            watermark,
+            dct2,
+            dct3,
+            normalization_factor: dct_normalization(len),
+            y_channel: Vec::with_capacity(len),
+            cbcr_channel: Vec::with_capacity(if interleaved { len } else { 0 }),
+            scratch: vec![0.0; scratch_len],
+            frames: 0,
+            max_frame_time: Duration::ZERO,
+        })
+    }
+
+    pub fn session_id(&self) -> &str {
+        &self.session_id
+    }
+
+    /// Number of frames marked so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Longest time a single [`SessionMarker::mark`] took, for checking the latency budget.
+    pub fn max_frame_time(&self) -> Duration {
+        self.max_frame_time
+    }
+
+    /// Marks `frame` in place. The result is the same as [`embed_raw`](crate::embed_raw) with the
+    /// session ID as the watermark.
+    pub fn mark(&mut self, frame: &mut [u8]) -> Result<()> {
+        let expected = self.format.buffer_len(self.width, self.height);
+        if frame.len() != expected {
+            return Err(format!(
+                "Invalid buffer size; expected {}, got {}",
+                expected,
+                frame.len()
+            )
+            .into());
+        }
+
+        let start = Instant::now();
+        let result = self.mark_frame(frame);
+        let elapsed = start.elapsed();
+        let pixels = self.width as usize * self.height as usize;
+        telemetry::record_pixels(pixels, result.is_ok(), elapsed);
+        if result.is_ok() {
+            self.frames += 1;
+            self.max_frame_time = self.max_frame_time.max(elapsed);
+        }
+
+        result
+    }
+
+    fn mark_frame(&mut self, frame: &mut [u8]) -> Result<()> {
+        let len = self.width as usize * self.height as usize;
+        let channels = match self.format {
+            PixelFormat::Rgb8 => Some(3),
+            PixelFormat::Rgba8 => Some(4),
+            PixelFormat::Nv12 | PixelFormat::Nv21 | PixelFormat::I420 => None,
+        };
+
+        self.y_channel.clear();
+        self.cbcr_channel.clear();
+        match channels {
+            Some(channels) => {
+                for pixel in frame.chunks_exact(channels) {
+                    let (y, cb, cr) = rgb_to_ycbcr(&Rgb([pixel[0], pixel[1], pixel[2]]));
+                    self.y_channel.push(y as f32);
+                    self.cbcr_channel.push((cb, cr));
+                }
+            }
+            // The Y plane comes first and already is the luma.
+            None => self
+                .y_channel
+                .extend(frame[..len].iter().map(|&y| y as f32)),
+        }
+
+        self.dct2
+            .process_dct2_with_scratch(&mut self.y_channel, &mut self.scratch);
+        let factor = self.normalization_factor;
+        self.y_channel.iter_mut().for_each(|s| *s *= factor);
+        mark_coefficients(&mut self.y_channel, self.watermark, &self.config)?;
+        self.dct3
+            .process_dct3_with_scratch(&mut self.y_channel, &mut self.scratch);
+        self.y_channel.iter_mut().for_each(|s| *s *= factor);
+
+        match channels {
+            Some(channels) => {
+                for (idx, pixel) in frame.chunks_exact_mut(channels).enumerate() {
+                    let (cb, cr) = self.cbcr_channel[idx];
+                    let rgb = ycbcr_to_rgb(self.y_channel[idx], cb as f32, cr as f32);
+                    pixel[..3].copy_from_slice(&rgb.0);
+                }
+            }
+            None => {
+                for (dst, &y) in frame[..len].iter_mut().zip(&self.y_channel) {
+                    *dst = y.round() as u8;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Bytes of the check value of a stamp.
+const CHECK_LEN: usize = 2;
+/// Rounds of restoring the excluded rectangles and embedding again.
+const EXCLUSION_PASSES: usize = 4;
+
+/// Layout of the marks of a [`FrameStamper`], which the reader must share.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StampConfig {
+    /// Longest session ID in bytes; shorter IDs are padded.
+    pub id_len: usize,
+    /// Bytes of the stamp, 1 to 8. Stamps wrap around at `256^stamp_len`: four bytes of frame
+    /// counter last over two years at 60 frames per second.
+    pub stamp_len: usize,
+    /// Lattice the bits are embedded with.
+    pub scs: ScsConfig,
+    /// Rectangles of the frame the mark must leave unchanged, where overlays will be composited.
+    /// The rest of the frame carries the bits, so large exclusions need a stronger lattice.
+    pub exclusions: Vec<Rect>,
+}
+
+impl Default for StampConfig {
+    fn default() -> Self {
+        StampConfig {
+            id_len: 8,
+            stamp_len: 4,
+            scs: ScsConfig::default(),
+            exclusions: Vec::new(),
+        }
+    }
+}
+
+impl StampConfig {
+    fn bits(&self) -> Result<usize> {
+        if self.id_len == 0 || !(1..=8).contains(&self.stamp_len) {
+            return Err("Stamps need a session ID of at least one byte and 1 to 8 bytes".into());
+        }
+        Ok(8 * (self.id_len + self.stamp_len + CHECK_LEN))
+    }
+}
+
+/// Marks the frames of one viewer's stream with the session ID and a per-frame stamp.
+#[derive(Debug, Clone)]
+pub struct FrameStamper {
+    id: Vec<u8>,
+    key: Vec<u8>,
+    config: StampConfig,
+    frames: u64,
+}
+
+impl FrameStamper {
+    /// Prepares stamping frames with `session_id` under `key`.
+    pub fn new(session_id: &str, key: &[u8], config: StampConfig) -> Result<Self> {
+        config.bits()?;
+        if session_id.is_empty() || session_id.len() > config.id_len || session_id.contains('\0') {
+            return Err(format!(
+                "Session IDs must be 1 to {} bytes without NUL, got {:?}",
+                config.id_len, session_id
+            )
+            .into());
+        }
+        let mut id = session_id.as_bytes().to_vec();
+        id.resize(config.id_len, 0);
+
+        Ok(FrameStamper {
+            id,
+            key: key.to_vec(),
+            config,
+            frames: 0,
+        })
+    }
+
+    /// Number of frames marked so far with [`FrameStamper::mark`].
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Marks `frame` with its index in the stream as the stamp.
+    pub fn mark(&mut self, frame: &DynamicImage) -> Result<RgbImage> {
+        let marked = self.mark_with_stamp(frame, self.frames)?;
+        self.frames += 1;
+        Ok(marked)
+    }
+
+    /// Marks `frame` with `stamp`, such as the seconds since the session started. Only the low
+    /// `config.stamp_len` bytes are kept.
+    ///
+    /// Fails if an exclusion rectangle is not inside the frame.
+    pub fn mark_with_stamp(&self, frame: &DynamicImage, stamp: u64) -> Result<RgbImage> {
+        let (width, height) = (frame.width(), frame.height());
+        if let Some(rect) = self
+            .config
+            .exclusions
+            .iter()
+            .find(|r| !r.fits(width, height))
+        {
+            return Err(format!("{:?} is not inside the {}x{} frame", rect, width, height).into());
+        }
+
+        let stamp = &stamp.to_be_bytes()[8 - self.config.stamp_len..];
+        let mut bytes = self.id.clone();
+        bytes.extend_from_slice(stamp);
+        bytes.extend_from_slice(&stamp_check(&self.key, &self.id, stamp));
+        let bits: Vec<bool> = bytes
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |i| byte >> i & 1 == 1))
+            .collect();
+
+        let mut marked = embed_scs(frame, &bits, &self.key, &self.config.scs)?;
+        if self.config.exclusions.is_empty() {
+            return Ok(marked);
+        }
+
+        let original = frame.to_rgb8();
+        for pass in 0..EXCLUSION_PASSES {
+            if pass > 0 {
+                let image = DynamicImage::ImageRgb8(marked);
+                marked = embed_scs(&image, &bits, &self.key, &self.config.scs)?;
+            }
+            for rect in &self.config.exclusions {
+                for y in rect.y..rect.y + rect.height {
+                    for x in rect.x..rect.x + rect.width {
+                        marked.put_pixel(x, y, *original.get_pixel(x, y));
+                    }
+                }
+            }
+        }
+
+        Ok(marked)
+    }
+}
+
+/// What [`read_stamp`] found in a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameStamp {
+    pub session_id: String,
+    pub stamp: u64,
+}
+
+/// Reads the session ID and stamp of a frame marked by a [`FrameStamper`] under `key`, or `None`
+/// if the check does not match: the frame is unmarked, marked under another key or too damaged.
+pub fn read_stamp(
+    frame: &DynamicImage,
+    key: &[u8],
+    config: &StampConfig,
+) -> Result<Option<FrameStamp>> {
+    let bytes: Vec<u8> = decode_scs(frame, config.bits()?, key, &config.scs)?
+        .chunks_exact(8)
+        .map(|byte| byte.iter().fold(0, |acc, &bit| acc << 1 | bit as u8))
+        .collect();
+    let (id, rest) = bytes.split_at(config.id_len);
+    let (stamp, stored) = rest.split_at(config.stamp_len);
+    if stored != stamp_check(key, id, stamp) {
+        return Ok(None);
+    }
+
+    let id = id.iter().position(|&b| b == 0).map_or(id, |end| &id[..end]);
+    Ok(Some(FrameStamp {
+        session_id: String::from_utf8_lossy(id).into_owned(),
+        stamp: stamp.iter().fold(0, |acc, &byte| acc << 8 | byte as u64),
+    }))
+}
+
+/// Keyed check value over the padded session ID and the stamp.
+fn stamp_check(key: &[u8], id: &[u8], stamp: &[u8]) -> [u8; CHECK_LEN] {
+    let digest = Sha256::new()
+        .chain_update(key)
+        .chain_update(id)
+        .chain_update(stamp)
+        .finalize();
+
+    [digest[0], digest[1]]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::grainy;
+    use crate::{embed_raw, Band};
+
+    fn frame(format: PixelFormat, seed: usize) -> Vec<u8> {
+        (0..format.buffer_len(24, 16))
+            .map(|i| ((i * 7 + seed * 13) % 251) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn test_session_marker_matches_embed_raw() {
+        let config = WatermarkConfig {
+            band: Band::Low,
+            ..Default::default()
+        };
+        for format in [PixelFormat::Rgb8, PixelFormat::Rgba8, PixelFormat::Nv12] {
+            let mut marker =
+                SessionMarker::new("VIEWER 42", 24, 16, format, config.clone()).unwrap();
+            for seed in 0..3 {
+                let mut expected = frame(format, seed);
+                embed_raw(&mut expected, 24, 16, format, "VIEWER 42", &config).unwrap();
+
+                let mut marked = frame(format, seed);
+                marker.mark(&mut marked).unwrap();
+                assert_eq!(marked, expected, "{:?}", format);
+            }
+            assert_eq!(marker.frames(), 3);
+            assert!(marker.max_frame_time() > Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn test_session_marker_errors() {
+        let config = WatermarkConfig::default();
+        assert!(
+            SessionMarker::new("viewer\u{1F600}", 24, 16, PixelFormat::Rgb8, config.clone())
+                .is_err()
+        );
+        assert!(SessionMarker::new("viewer", 0, 16, PixelFormat::Rgb8, config.clone()).is_err());
+
+        let mut marker = SessionMarker::new("viewer", 24, 16, PixelFormat::Rgb8, config).unwrap();
+        assert!(marker.mark(&mut [0; 10]).is_err());
+        assert_eq!(marker.frames(), 0);
+    }
+
+    #[test]
+    fn test_frame_stamper() {
+        let config = StampConfig::default();
+        let mut stamper = FrameStamper::new("VIEWER42", b"key", config.clone()).unwrap();
+        for (index, amplitude) in [41, 37, 43].into_iter().enumerate() {
+            let frame = DynamicImage::ImageRgb8(grainy(128, 96, amplitude));
+            let marked = DynamicImage::ImageRgb8(stamper.mark(&frame).unwrap());
+            let found = read_stamp(&marked, b"key", &config).unwrap().unwrap();
+            assert_eq!(found.session_id, "VIEWER42");
+            assert_eq!(found.stamp, index as u64);
+            assert_eq!(read_stamp(&marked, b"other", &config).unwrap(), None);
+        }
+        assert_eq!(stamper.frames(), 3);
+
+        let frame = DynamicImage::ImageRgb8(grainy(128, 96, 41));
+        let timestamp = 1_700_000_000;
+        let marked = stamper.mark_with_stamp(&frame, timestamp).unwrap();
+        let found = read_stamp(&marked.into(), b"key", &config)
+            .unwrap()
+            .unwrap();
+        assert_eq!(found.stamp, timestamp);
+        assert_eq!(read_stamp(&frame, b"key", &config).unwrap(), None);
+
+        let short = StampConfig {
+            stamp_len: 1,
+            ..config.clone()
+        };
+        let stamper = FrameStamper::new("V", b"key", short.clone()).unwrap();
+        let marked = stamper.mark_with_stamp(&frame, 300).unwrap();
+        let found = read_stamp(&marked.into(), b"key", &short).unwrap().unwrap();
+        assert_eq!((found.session_id.as_str(), found.stamp), ("V", 300 % 256));
+    }
+
+    #[test]
+    fn test_frame_stamper_exclusions() {
+        // A lower third and a score bug.
+        let exclusions = vec![
+            Rect {
+                x: 0,
+                y: 64,
+                width: 128,
+                height: 32,
+            },
+            Rect {
+                x: 96,
+                y: 0,
+                width: 32,
+                height: 16,
+            },
+        ];
+        let config = StampConfig {
+            exclusions: exclusions.clone(),
+            ..Default::default()
+        };
+        let stamper = FrameStamper::new("VIEWER42", b"key", config.clone()).unwrap();
+        let frame = DynamicImage::ImageRgb8(grainy(128, 96, 41));
+        let original = frame.to_rgb8();
+        for stamp in [7, 1_700_000_000] {
+            let marked = stamper.mark_with_stamp(&frame, stamp).unwrap();
+            for rect in &exclusions {
+                let inside = |x: u32, y: u32| {
+                    (rect.x..rect.x + rect.width).contains(&x)
+                        && (rect.y..rect.y + rect.height).contains(&y)
+                };
+                for (x, y, pixel) in marked.enumerate_pixels().filter(|(x, y, _)| inside(*x, *y)) {
+                    assert_eq!(pixel, original.get_pixel(x, y));
+                }
+            }
+            assert_ne!(marked, original);
+
+            let found = read_stamp(&marked.into(), b"key", &config)
+                .unwrap()
+                .unwrap();
+            assert_eq!(
+                (found.session_id.as_str(), found.stamp),
+                ("VIEWER42", stamp)
+            );
+        }
+
+        let outside = StampConfig {
+            exclusions: vec![Rect {
+                x: 100,
+                y: 0,
+                width: 32,
+                height: 16,
+            }],
+            ..Default::default()
+        };
+        let stamper = FrameStamper::new("V", b"key", outside).unwrap();
+        assert!(stamper.mark_with_stamp(&frame, 0).is_err());
+    }
+
+    #[test]
+    fn test_frame_stamper_errors() {
+        let config = StampConfig::default();
+        assert!(FrameStamper::new("", b"key", config.clone()).is_err());
+        assert!(FrameStamper::new("VIEWER 42", b"key", config.clone()).is_err());
+        for stamp_len in [0, 9] {
+            let config = StampConfig {
+                stamp_len,
+                ..config.clone()
+            };
+            assert!(FrameStamper::new("V", b"key", config).is_err());
+        }
+
+        let mut stamper = FrameStamper::new("V", b"key", config).unwrap();
+        let tiny = DynamicImage::ImageRgb8(grainy(8, 8, 41));
+        assert!(stamper.mark(&tiny).is_err());
+        assert_eq!(stamper.frames(), 0);
+    }
+}
@@ -0,0 +1,244 @@
+//! Marking of SVG vector assets.
+//!
+//! A vector file has no pixels to mark, and any rendering of it is made at a size chosen by the
+//! viewer. Two marks are made instead. The SVG source gets a metadata element carrying the payload
+//! and a digest of the drawing, which survives every tool that keeps unknown metadata and tells
+//! whether the drawing was edited since. Rasters are rendered at the resolution they are delivered
+//! at and marked like any photo, which survives conversion to a bitmap and screenshots.
+//!
+//! SVG rendering is left to a [`Rasterizer`] of the caller's choosing, such as resvg, so this crate
+//! does not pull in a renderer and its fonts.
+
+use image::{DynamicImage, RgbImage};
+use sha2::{Digest, Sha256};
+
+use crate::{embed_watermark_with_config, payload_value, Result, WatermarkConfig};
+
+const MARK_ID: &str = "lf-watermark";
+
+/// Renders SVG sources to bitmaps.
+pub trait Rasterizer {
+    /// Renders `svg` scaled to `width` x `height` pixels.
+    fn rasterize(&self, svg: &str, width: u32, height: u32) -> Result<DynamicImage>;
+}
+
+/// Both marks of an SVG asset.
+#[derive(Debug, Clone)]
+pub struct MarkedSvg {
+    /// The SVG source with the metadata mark.
+    pub source: String,
+    /// The marked raster at the requested resolution.
+    pub raster: RgbImage,
+}
+
+/// Mark read back from an SVG source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SvgMark {
+    pub payload: String,
+    /// Whether the drawing is unchanged since it was marked.
+    pub intact: bool,
+}
+
+/// Marks the source of `svg` and renders it with `rasterizer` at `width` x `height` to mark the
+/// raster as well.
+pub fn mark_svg(
+    svg: &str,
+    watermark: &str,
+    width: u32,
+    height: u32,
+    rasterizer: &dyn Rasterizer,
+    config: &WatermarkConfig,
+) -> Result<MarkedSvg> {
+    let source = mark_svg_source(svg, watermark)?;
+    let rendered = rasterizer.rasterize(&source, width, height)?;
+    if rendered.width() != width || rendered.height() != height {
+        return Err(format!(
+            "The rasterizer rendered {}x{}, expected {}x{}",
+            rendered.width(),
+            rendered.height(),
+            width,
+            height
+        )
+        .into());
+    }
+    let raster = embed_watermark_with_config(&rendered, watermark, config)?;
+
+    Ok(MarkedSvg { source, raster })
+}
+
+/// Adds the metadata mark to the source of `svg`, as the first child of the root element.
+pub fn mark_svg_source(svg: &str, watermark: &str) -> Result<String> {
+    payload_value(watermark)?;
+    if find_mark(svg).is_some() {
+        return Err("The SVG is already marked".into());
+    }
+
+    let at = root_content_start(svg)?;
+    let element = format!(
+        "<metadata id=\"{}\" data-payload=\"{}\" data-digest=\"{}\"/>",
+        MARK_ID,
+        escape(watermark),
+        digest(svg, watermark)
+    );
+
+    Ok([&svg[..at], &element, &svg[at..]].concat())
+}
+
+/// Reads the metadata mark of an SVG source marked by [`mark_svg_source`].
+pub fn read_svg_mark(svg: &str) -> Result<SvgMark> {
+    let range = find_mark(svg).ok_or("The SVG carries no mark")?;
+    let element = &svg[range.clone()];
+    let payload = unescape(&attribute(element, "data-payload")?);
+    let stored = attribute(element, "data-digest")?;
+    let original = [&svg[..range.start], &svg[range.end..]].concat();
+
+    Ok(SvgMark {
+        intact: digest(&original, &payload) == stored,
+        payload,
+    })
+}
+
+/// Byte range of the mark element, if any.
+fn find_mark(svg: &str) -> Option<std::ops::Range<usize>> {
+    let start = svg.find(&format!("<metadata id=\"{}\"", MARK_ID))?;
+    let end = start + svg[start..].find("/>")? + 2;
+
+    Some(start..end)
+}
+
+/// Offset just past the start tag of the root `svg` element.
+fn root_content_start(svg: &str) -> Result<usize> {
+    let mut offset = 0;
+    loop {
+        let rest = &svg[offset..];
+        let tag = rest.find('<').ok_or("No svg root element")?;
+        let rest = &rest[tag..];
+        // The prolog may hold a declaration, processing instructions, comments and a doctype.
+        let skip = if rest.starts_with("<?") {
+            rest.find("?>").map(|end| end + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 3)
+        } else if rest.starts_with("<!") {
+            rest.find('>').map(|end| end + 1)
+        } else {
+            break;
+        };
+        offset += tag + skip.ok_or("Unterminated SVG prolog")?;
+    }
+
+    let start = offset + svg[offset..].find('<').ok_or("No svg root element")?;
+    let name = &svg[start + 1..];
+    let name_len = name
+        .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+        .unwrap_or(name.len());
+    if !matches!(&name[..name_len], "svg" | "svg:svg") {
+        return Err("The root element is not svg".into());
+    }
+
+    // The start tag ends at the first '>' outside an attribute value.
+    let mut quote = None;
+    for (i, c) in svg[start..].char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => {
+                if svg[..start + i].ends_with('/') {
+                    return Err("The svg root element is empty".into());
+                }
+                return Ok(start + i + 1);
+            }
+            _ => {}
+        }
+    }
+
+    Err("Unterminated svg start tag".into())
+}
+
+fn attribute(element: &str, name: &str) -> Result<String> {
+    let prefix = format!("{}=\"", name);
+    let start = element.find(&prefix).ok_or("Malformed SVG mark")? + prefix.len();
+    let len = element[start..].find('"').ok_or("Malformed SVG mark")?;
+
+    Ok(element[start..start + len].to_string())
+}
+
+/// Hex SHA-256 over the unmarked source and the payload, truncated to 128 bits.
+fn digest(svg: &str, watermark: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(svg.as_bytes());
+    hasher.update([0]);
+    hasher.update(watermark.as_bytes());
+
+    hasher.finalize()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    const SVG: &str = "<?xml version=\"1.0\"?>\n<!-- logo -->\n<!DOCTYPE svg>\n\
+        <svg xmlns=\"http://www.w3.org/2000/svg\" title=\"a > b\" viewBox=\"0 0 10 10\">\
+        <rect width=\"10\" height=\"5\" fill=\"#c33\"/></svg>";
+
+    /// Renders a gradient of the requested size, standing in for a real renderer.
+    struct Gradient;
+
+    impl Rasterizer for Gradient {
+        fn rasterize(&self, svg: &str, width: u32, height: u32) -> Result<DynamicImage> {
+            assert!(svg.contains("<rect"));
+            Ok(DynamicImage::ImageRgb8(RgbImage::from_fn(
+                width,
+                height,
+                |x, y| Rgb([(x * 3) as u8, (y * 5) as u8, 128]),
+            )))
+        }
+    }
+
+    #[test]
+    fn test_svg_source_mark() {
+        let marked = mark_svg_source(SVG, "Studio <b> & co").unwrap();
+        assert!(marked.starts_with(&SVG[..SVG.find("<rect").unwrap()]));
+        assert_eq!(
+            read_svg_mark(&marked).unwrap(),
+            SvgMark {
+                payload: "Studio <b> & co".into(),
+                intact: true,
+            }
+        );
+
+        let edited = marked.replace("#c33", "#33c");
+        assert!(!read_svg_mark(&edited).unwrap().intact);
+        assert!(mark_svg_source(&marked, "again").is_err());
+        assert!(read_svg_mark(SVG).is_err());
+        assert!(mark_svg_source("<html></html>", "x").is_err());
+        assert!(mark_svg_source("<svg/>", "x").is_err());
+    }
+
+    #[test]
+    fn test_mark_svg() {
+        let config = WatermarkConfig::default();
+        let marked = mark_svg(SVG, "Studio", 64, 48, &Gradient, &config).unwrap();
+        assert_eq!(read_svg_mark(&marked.source).unwrap().payload, "Studio");
+
+        let rendered = Gradient.rasterize(SVG, 64, 48).unwrap();
+        let expected = embed_watermark_with_config(&rendered, "Studio", &config).unwrap();
+        assert_eq!(marked.raster, expected);
+    }
+}
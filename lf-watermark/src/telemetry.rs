@@ -0,0 +1,53 @@
+//! Operational metrics, reported through the `metrics` facade with the `metrics` feature and
+//! compiled to nothing without it.
+//!
+//! Metric names:
+//! - `lf_watermark_images_total`, counter labeled with `format`: images embedded.
+//! - `lf_watermark_failures_total`, counter labeled with `format`: embeds that returned an error.
+//! - `lf_watermark_input_bytes_total` and `lf_watermark_output_bytes_total`, counters: encoded
+//!   bytes read and written.
+//! - `lf_watermark_pixels_total`, counter: pixels embedded into.
+//! - `lf_watermark_embed_seconds`, histogram: wall time of one embed, decoding and encoding
+//!   included where the call does them.
+
+use std::time::Duration;
+
+/// An embed of an encoded image. `output_bytes` is `None` if it failed.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_encoded(
+    format: &'static str,
+    input_bytes: usize,
+    output_bytes: Option<usize>,
+    duration: Duration,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        ::metrics::counter!("lf_watermark_input_bytes_total").increment(input_bytes as u64);
+        match output_bytes {
+            Some(output_bytes) => {
+                ::metrics::counter!("lf_watermark_images_total", "format" => format).increment(1);
+                ::metrics::counter!("lf_watermark_output_bytes_total")
+                    .increment(output_bytes as u64);
+            }
+            None => {
+                ::metrics::counter!("lf_watermark_failures_total", "format" => format).increment(1);
+            }
+        }
+        ::metrics::histogram!("lf_watermark_embed_seconds").record(duration.as_secs_f64());
+    }
+}
+
+/// An embed of decoded pixels, as done by [`Embedder`](crate::Embedder).
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_pixels(pixels: usize, succeeded: bool, duration: Duration) {
+    #[cfg(feature = "metrics")]
+    {
+        if succeeded {
+            ::metrics::counter!("lf_watermark_images_total", "format" => "raw").increment(1);
+            ::metrics::counter!("lf_watermark_pixels_total").increment(pixels as u64);
+        } else {
+            ::metrics::counter!("lf_watermark_failures_total", "format" => "raw").increment(1);
+        }
+        ::metrics::histogram!("lf_watermark_embed_seconds").record(duration.as_secs_f64());
+    }
+}
@@ -0,0 +1,180 @@
+//! Generators and assertions for property-testing code built on this crate.
+//!
+//! Everything here is seeded, so a failing case can be reproduced from the seed a property-test
+//! framework reports.
+
+use std::io::Cursor;
+
+use image::{DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+
+use crate::codec::embed_watermark_encoded;
+use crate::metrics::psnr;
+use crate::prng::Prng;
+use crate::{embed_watermark_with_config, WatermarkConfig};
+
+/// Smooth diagonal ramp in all three channels.
+pub fn gradient(width: u32, height: u32) -> RgbImage {
+    let scale = |v: u32, max: u32| (v as u64 * 255 / max.saturating_sub(1).max(1) as u64) as u8;
+    RgbImage::from_fn(width, height, |x, y| {
+        Rgb([
+            scale(x, width),
+            scale(y, height),
+            scale(x + y, width + height - 1),
+        ])
+    })
+}
+
+/// Independent uniform noise in every sample.
+pub fn noise(width: u32, height: u32, seed: u64) -> RgbImage {
+    let mut rng = Prng::new(seed);
+    RgbImage::from_fn(width, height, |_, _| {
+        Rgb([0; 3].map(|_: u8| rng.next_below(256) as u8))
+    })
+}
+
+/// Grain of `0..amplitude` levels at `x`, `y`, hashed from the position.
+pub fn grain(x: u32, y: u32, amplitude: u32) -> u32 {
+    (x * 7919 + y * 104729) % amplitude
+}
+
+/// A ramp in each channel with [`grain`] added.
+///
+/// Every block has texture and the DCT coefficients are far from zero, as in photos, and no seed
+/// is needed, so it is the usual fixture of the lattice mark tests. Red and green wrap around
+/// past 255.
+pub fn grainy(width: u32, height: u32, amplitude: u32) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        let grain = grain(x, y, amplitude);
+        Rgb([(x + grain) as u8, (y + grain) as u8, (96 + grain) as u8])
+    })
+}
+
+/// Photo-like content: a few octaves of smooth value noise tinted per channel, plus fine grain.
+///
+/// It has the large smooth areas, edges of varying contrast and sensor noise of real pictures,
+/// which pure gradients and pure noise each miss.
+pub fn texture(width: u32, height: u32, seed: u64) -> RgbImage {
+    let mut rng = Prng::new(seed);
+    let octaves: Vec<(u32, Vec<f32>)> = [4u32, 8, 16, 32]
+        .into_iter()
+        .map(|cells| {
+            let grid = (0..(cells + 1) * (cells + 1))
+                .map(|_| rng.next_f32())
+                .collect();
+            (cells, grid)
+        })
+        .collect();
+    let tint = [0; 3].map(|_: u8| 0.6 + 0.4 * rng.next_f32());
+
+    let sample = |cells: u32, grid: &[f32], u: f32, v: f32| {
+        let (gx, gy) = (u * cells as f32, v * cells as f32);
+        let (x0, y0) = ((gx as u32).min(cells - 1), (gy as u32).min(cells - 1));
+        let (fx, fy) = (gx - x0 as f32, gy - y0 as f32);
+        let at = |x: u32, y: u32| grid[(y * (cells + 1) + x) as usize];
+        let top = at(x0, y0) + (at(x0 + 1, y0) - at(x0, y0)) * fx;
+        let bottom = at(x0, y0 + 1) + (at(x0 + 1, y0 + 1) - at(x0, y0 + 1)) * fx;
+        top + (bottom - top) * fy
+    };
+
+    RgbImage::from_fn(width, height, |x, y| {
+        let (u, v) = (x as f32 / width as f32, y as f32 / height as f32);
+        let mut value = 0.0;
+        let mut amplitude = 0.5;
+        for (cells, grid) in &octaves {
+            value += amplitude * sample(*cells, grid, u, v);
+            amplitude *= 0.5;
+        }
+        let grain = (rng.next_f32() - 0.5) * 0.04;
+
+        Rgb(tint.map(|t| ((value * t + grain) * 255.0).round().clamp(0.0, 255.0) as u8))
+    })
+}
+
+/// A random payload of `len` characters from [`CHAR_MAP`](lf_watermark_core::CHAR_MAP).
+pub fn payload(len: usize, seed: u64) -> String {
+    let alphabet: Vec<char> = lf_watermark_core::CHAR_MAP.chars().collect();
+    let mut rng = Prng::new(seed);
+    (0..len)
+        .map(|_| alphabet[rng.next_below(alphabet.len() as u64) as usize])
+        .collect()
+}
+
+/// Asserts the properties every embed must have, panicking with a description otherwise:
+/// - embedding succeeds and keeps the dimensions,
+/// - it is deterministic,
+/// - a non-empty payload changes the image, but with a PSNR of at least `min_psnr` dB,
+/// - going through a lossless encoded round trip gives the same pixels as embedding in memory.
+pub fn assert_roundtrip(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+    min_psnr: f64,
+) {
+    let marked = embed_watermark_with_config(image, watermark, config)
+        .unwrap_or_else(|err| panic!("Embedding {:?} failed; {}", watermark, err));
+    assert_eq!(
+        marked.dimensions(),
+        image.dimensions(),
+        "Dimensions changed"
+    );
+
+    let again = embed_watermark_with_config(image, watermark, config).unwrap();
+    assert_eq!(marked, again, "Embedding is not deterministic");
+
+    let marked = DynamicImage::ImageRgb8(marked);
+    let quality = psnr(&image.to_rgb8().into(), &marked).unwrap();
+    assert!(
+        quality >= min_psnr,
+        "PSNR {} dB below {} dB",
+        quality,
+        min_psnr
+    );
+    if lf_watermark_core::payload_value(watermark).unwrap() > 0.0 && config.strength > 0.0 {
+        assert!(quality.is_finite(), "Embedding left the image unchanged");
+    }
+
+    let mut png = Vec::new();
+    image
+        .to_rgb8()
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+    let encoded = embed_watermark_encoded(&png, watermark, config).unwrap();
+    let decoded = image::load_from_memory(&encoded).unwrap();
+    assert_eq!(
+        decoded.to_rgb8(),
+        marked.to_rgb8(),
+        "Encoded round trip differs"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generators_are_seeded() {
+        assert_eq!(noise(8, 8, 1), noise(8, 8, 1));
+        assert_ne!(noise(8, 8, 1), noise(8, 8, 2));
+        assert_eq!(texture(16, 16, 3), texture(16, 16, 3));
+        assert_eq!(payload(12, 5), payload(12, 5));
+        assert!(lf_watermark_core::payload_value(&payload(64, 9)).is_ok());
+
+        let grain = grainy(300, 8, 41);
+        assert_eq!(grain, grainy(300, 8, 41));
+        assert!(grain.pixels().all(|pixel| (96..137).contains(&pixel[2])));
+
+        let ramp = gradient(16, 4);
+        assert_eq!(ramp.get_pixel(0, 0), &Rgb([0, 0, 0]));
+        assert_eq!(ramp.get_pixel(15, 3), &Rgb([255, 255, 255]));
+    }
+
+    #[test]
+    fn test_assert_roundtrip() {
+        let config = WatermarkConfig::default();
+        for seed in 0..4 {
+            let image = DynamicImage::ImageRgb8(texture(32, 24, seed));
+            assert_roundtrip(&image, &payload(8, seed), &config, 25.0);
+        }
+        assert_roundtrip(&gradient(16, 16).into(), "", &config, 25.0);
+    }
+}
@@ -0,0 +1,215 @@
+//! Protection of text in document images.
+//!
+//! Screenshots and scans of documents are mostly paper with a little ink, and the mark changes
+//! both. Around glyphs that change softens the ink edges OCR relies on. [`text_mask`] finds
+//! blocks that look like printed text, high-contrast two-level content with several strokes
+//! across it, and [`embed_watermark_document`] keeps the mark out of them or weakens it there,
+//! so the rest of the page carries the mark and the text stays as crisp as in the original.
+
+use image::{DynamicImage, RgbImage};
+
+use crate::blocks::BLOCK;
+use crate::{embed_watermark_with_config, Result, WatermarkConfig};
+
+/// Luma range, in 8-bit levels, below which a block cannot be ink on paper.
+const MIN_CONTRAST: f64 = 96.0;
+/// Share of a block's pixels within a quarter of the range of its darkest or lightest level.
+const MIN_TWO_LEVEL: f64 = 0.75;
+/// Crossings of the middle level along the rows and columns of a block. A single edge crosses
+/// once per row; glyph strokes cross twice each, and a block of text has several.
+const MIN_CROSSINGS: usize = (BLOCK + BLOCK / 2) as usize;
+
+/// How the mark treats text regions of a document.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TextProtection {
+    /// Leave text regions exactly as they are.
+    #[default]
+    Avoid,
+    /// Scale the mark by this factor in `[0, 1]` inside text regions.
+    Reduce(f32),
+}
+
+/// Whether each block of `image` holds text, row by row; blocks next to a text block count as
+/// text too, so partly covered glyphs and their anti-aliasing are included.
+pub fn text_blocks(image: &RgbImage) -> Vec<bool> {
+    let (width, height) = image.dimensions();
+    let luma: Vec<f64> = image
+        .pixels()
+        .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+        .collect();
+    let at = |x: u32, y: u32| luma[y as usize * width as usize + x as usize];
+
+    let (columns, rows) = (width.div_ceil(BLOCK), height.div_ceil(BLOCK));
+    let mut text = Vec::with_capacity(columns as usize * rows as usize);
+    for by in (0..height).step_by(BLOCK as usize) {
+        for bx in (0..width).step_by(BLOCK as usize) {
+            let (x1, y1) = ((bx + BLOCK).min(width), (by + BLOCK).min(height));
+            let samples: Vec<f64> = (by..y1)
+                .flat_map(|y| (bx..x1).map(move |x| (x, y)))
+                .map(|(x, y)| at(x, y))
+                .collect();
+            let min = samples.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = samples.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let range = max - min;
+            if range < MIN_CONTRAST {
+                text.push(false);
+                continue;
+            }
+
+            let two_level = samples
+                .iter()
+                .filter(|&&s| s - min < range / 4.0 || max - s < range / 4.0)
+                .count();
+            let middle = (min + max) / 2.0;
+            let mut crossings = 0;
+            for y in by..y1 {
+                for x in bx..x1 {
+                    if x + 1 < x1 && (at(x, y) < middle) != (at(x + 1, y) < middle) {
+                        crossings += 1;
+                    }
+                    if y + 1 < y1 && (at(x, y) < middle) != (at(x, y + 1) < middle) {
+                        crossings += 1;
+                    }
+                }
+            }
+            text.push(
+                two_level as f64 >= MIN_TWO_LEVEL * samples.len() as f64
+                    && crossings >= MIN_CROSSINGS,
+            );
+        }
+    }
+
+    let (columns, rows) = (columns as usize, rows as usize);
+    (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .map(|(column, row)| {
+            (row.saturating_sub(1)..(row + 2).min(rows)).any(|r| {
+                (column.saturating_sub(1)..(column + 2).min(columns)).any(|c| text[r * columns + c])
+            })
+        })
+        .collect()
+}
+
+/// Whether each pixel of `image`, in raster order, lies in a text block of [`text_blocks`].
+pub fn text_mask(image: &RgbImage) -> Vec<bool> {
+    let (width, height) = image.dimensions();
+    let blocks = text_blocks(image);
+    let columns = width.div_ceil(BLOCK) as usize;
+
+    (0..height)
+        .flat_map(|y| (0..width).map(move |x| (x, y)))
+        .map(|(x, y)| blocks[(y / BLOCK) as usize * columns + (x / BLOCK) as usize])
+        .collect()
+}
+
+/// [`embed_watermark_with_config`] for document images, with the mark kept out of or weakened in
+/// the text regions of `image` as `protection` says.
+pub fn embed_watermark_document(
+    image: &DynamicImage,
+    watermark: &str,
+    config: &WatermarkConfig,
+    protection: TextProtection,
+) -> Result<RgbImage> {
+    let weight = match protection {
+        TextProtection::Avoid => 0.0,
+        TextProtection::Reduce(factor) if (0.0..=1.0).contains(&factor) => factor,
+        TextProtection::Reduce(factor) => {
+            return Err(format!("Text strength factor {} is outside 0 to 1", factor).into())
+        }
+    };
+
+    let original = image.to_rgb8();
+    let mut marked = embed_watermark_with_config(image, watermark, config)?;
+    let mask = text_mask(&original);
+
+    for ((pixel, source), &text) in marked.pixels_mut().zip(original.pixels()).zip(&mask) {
+        if text {
+            for c in 0..3 {
+                let change = pixel[c] as f32 - source[c] as f32;
+                pixel[c] = (source[c] as f32 + change * weight)
+                    .round()
+                    .clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    Ok(marked)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    /// Hashed, direction-free noise in `0..modulus`.
+    fn noise(x: u32, y: u32, modulus: u32) -> u8 {
+        let mut h = x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663);
+        h ^= h >> 13;
+        h = h.wrapping_mul(0x5bd1_e995);
+        h ^= h >> 15;
+        (h % modulus) as u8
+    }
+
+    /// A page with vertical glyph strokes in its top left, a photo in its right half and a hard
+    /// edge at the bottom left.
+    fn page() -> RgbImage {
+        RgbImage::from_fn(128, 64, |x, y| {
+            let level = if x >= 64 {
+                (x / 2) as u8 + noise(x, y, 61)
+            } else if (8..32).contains(&y) && (8..40).contains(&x) && x % 5 == 1 {
+                20
+            } else if y >= 44 && x >= 28 {
+                60
+            } else {
+                235
+            };
+            Rgb([level; 3])
+        })
+    }
+
+    #[test]
+    fn test_text_blocks() {
+        let blocks = text_blocks(&page());
+        let columns = 16;
+        let text: Vec<(usize, usize)> = (0..blocks.len())
+            .filter(|&i| blocks[i])
+            .map(|i| (i % columns, i / columns))
+            .collect();
+
+        // Three rows and four columns of text blocks, grown by one block on each side.
+        assert_eq!(text.len(), 6 * 5);
+        assert!(text.iter().all(|&(c, r)| c <= 5 && r <= 4));
+        assert!(text_blocks(&RgbImage::from_pixel(9, 9, Rgb([255; 3])))
+            .iter()
+            .all(|&t| !t));
+    }
+
+    #[test]
+    fn test_embed_watermark_document() {
+        let image = DynamicImage::ImageRgb8(page());
+        let config = WatermarkConfig::default();
+        let mask = text_mask(image.as_rgb8().unwrap());
+        let full = embed_watermark_with_config(&image, "Hello", &config).unwrap();
+
+        let avoided =
+            embed_watermark_document(&image, "Hello", &config, TextProtection::Avoid).unwrap();
+        for (i, ((marked, source), plain)) in avoided
+            .pixels()
+            .zip(image.as_rgb8().unwrap().pixels())
+            .zip(full.pixels())
+            .enumerate()
+        {
+            assert_eq!(marked, if mask[i] { source } else { plain });
+        }
+        assert_ne!(&avoided, image.as_rgb8().unwrap());
+
+        let kept = embed_watermark_document(&image, "Hello", &config, TextProtection::Reduce(1.0))
+            .unwrap();
+        assert_eq!(kept, full);
+        assert!(
+            embed_watermark_document(&image, "Hello", &config, TextProtection::Reduce(1.5))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,230 @@
+//! Lattice marks that survive downscaling to thumbnails.
+//!
+//! The lattice decoder needs the geometry of the marked image, and a thumbnail has lost all but
+//! its coarsest structure. [`embed_thumbnail`] therefore embeds the bits into a fixed, small
+//! proxy of the image, the `grid`, and spreads the change back over the full resolution, so the
+//! payload only lives in detail that survives downscaling. [`decode_thumbnail`] resizes whatever
+//! it gets, the original or any thumbnail at least as large as the grid, to the grid and decodes
+//! there; it needs neither the original nor the size it was marked at.
+//!
+//! Resampling blurs the change a little on the way down, so embedding repeats the round trip a
+//! few times and corrects for what it measures.
+
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, Rgb, Rgb32FImage, RgbImage};
+
+use crate::lattice::{carrier_agreement, decode_scs_llr, embed_scs, ScsConfig};
+use crate::{Band, Result};
+
+/// Embedding round trips that correct the change for the blur of resampling.
+const REFINEMENTS: usize = 4;
+
+/// Settings shared by [`embed_thumbnail`] and [`decode_thumbnail`], which must match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThumbnailConfig {
+    /// Width and height of the proxy the bits are embedded in. A thumbnail smaller than it in
+    /// either dimension has lost some of the mark.
+    pub grid: (u32, u32),
+    /// Lattice settings on the grid. Resampling leaves more noise than 8-bit rounding, so the
+    /// default lattice is coarser than [`ScsConfig::default`].
+    pub scs: ScsConfig,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            grid: (64, 64),
+            scs: ScsConfig {
+                step: 24.0,
+                band: Band::Low,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl ThumbnailConfig {
+    /// Number of bits the grid carries.
+    pub fn capacity(&self) -> Result<usize> {
+        self.scs.capacity(self.grid.0, self.grid.1)
+    }
+}
+
+/// Embeds `bits` under `key` into the detail of `image` that survives downscaling to
+/// `config.grid`.
+pub fn embed_thumbnail(
+    image: &DynamicImage,
+    bits: &[bool],
+    key: &[u8],
+    config: &ThumbnailConfig,
+) -> Result<RgbImage> {
+    let (width, height) = image.dimensions();
+    let (grid_width, grid_height) = config.grid;
+    if grid_width > width || grid_height > height {
+        return Err(format!(
+            "A {}x{} image is smaller than the {}x{} grid",
+            width, height, grid_width, grid_height
+        )
+        .into());
+    }
+
+    let mut marked = image.to_rgb8();
+    for _ in 0..REFINEMENTS {
+        let proxy = DynamicImage::ImageRgb8(shrink(&marked, config));
+        let target = embed_scs(&proxy, bits, key, &config.scs)?;
+        let proxy = proxy.to_rgb8();
+        let change = Rgb32FImage::from_fn(grid_width, grid_height, |x, y| {
+            let (p, t) = (proxy.get_pixel(x, y), target.get_pixel(x, y));
+            Rgb([0, 1, 2].map(|c| t[c] as f32 - p[c] as f32))
+        });
+        let change = imageops::resize(&change, width, height, FilterType::Triangle);
+        for (pixel, delta) in marked.pixels_mut().zip(change.pixels()) {
+            for c in 0..3 {
+                pixel[c] = (pixel[c] as f32 + delta[c]).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+
+    Ok(marked)
+}
+
+/// Decodes `len` bits embedded under `key` by [`embed_thumbnail`] from `image`, the marked image
+/// or a thumbnail of it.
+pub fn decode_thumbnail(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ThumbnailConfig,
+) -> Result<Vec<bool>> {
+    Ok(decode_thumbnail_llr(image, len, key, config)?
+        .into_iter()
+        .map(|llr| llr > 0.0)
+        .collect())
+}
+
+/// Log-likelihood ratios of the bits [`decode_thumbnail`] decides on, see
+/// [`decode_scs_llr`].
+pub fn decode_thumbnail_llr(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ThumbnailConfig,
+) -> Result<Vec<f32>> {
+    let proxy = DynamicImage::ImageRgb8(shrink(&image.to_rgb8(), config));
+    decode_scs_llr(&proxy, len, key, &config.scs)
+}
+
+/// [`carrier_agreement`] on the grid, a presence measure for a thumbnail mark.
+pub fn thumbnail_agreement(
+    image: &DynamicImage,
+    len: usize,
+    key: &[u8],
+    config: &ThumbnailConfig,
+) -> Result<f64> {
+    let proxy = DynamicImage::ImageRgb8(shrink(&image.to_rgb8(), config));
+    carrier_agreement(&proxy, len, key, &config.scs)
+}
+
+/// `image` resized to the grid. The triangle filter widens with the scale factor, so it averages
+/// over the area of each grid pixel whatever the size of `image`.
+fn shrink(image: &RgbImage, config: &ThumbnailConfig) -> RgbImage {
+    let (width, height) = config.grid;
+    imageops::resize(image, width, height, FilterType::Triangle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::codecs::jpeg::JpegEncoder;
+
+    use super::*;
+    use crate::lattice::decode_scs;
+    use crate::metrics::psnr;
+    use crate::test_utils::grainy;
+
+    fn bits(len: usize) -> Vec<bool> {
+        (0..len).map(|i| (i * 7 + i / 3) % 3 == 0).collect()
+    }
+
+    fn errors(decoded: &[bool], bits: &[bool]) -> usize {
+        decoded.iter().zip(bits).filter(|(a, b)| a != b).count()
+    }
+
+    #[test]
+    fn test_thumbnail_resize_matrix() {
+        let image = DynamicImage::ImageRgb8(grainy(768, 512, 41));
+        let config = ThumbnailConfig::default();
+        let bits = bits(config.capacity().unwrap());
+        assert_eq!(bits.len(), 31);
+        let marked = embed_thumbnail(&image, &bits, b"key", &config).unwrap();
+        let marked = DynamicImage::ImageRgb8(marked);
+        assert!(psnr(&image, &marked).unwrap() > 40.0);
+
+        let filters = [
+            FilterType::Triangle,
+            FilterType::CatmullRom,
+            FilterType::Gaussian,
+            FilterType::Lanczos3,
+        ];
+        for factor in [1, 2, 4, 6, 8] {
+            for filter in filters {
+                let small = marked.resize_exact(768 / factor, 512 / factor, filter);
+                let decoded = decode_thumbnail(&small, bits.len(), b"key", &config).unwrap();
+                assert_eq!(errors(&decoded, &bits), 0, "{}x with {:?}", factor, filter);
+                let agreement = thumbnail_agreement(&small, bits.len(), b"key", &config).unwrap();
+                assert!(
+                    agreement > 0.8,
+                    "{} at {}x with {:?}",
+                    agreement,
+                    factor,
+                    filter
+                );
+            }
+        }
+
+        // A thumbnail that is JPEG compressed as well, at a size that is no whole fraction.
+        let small = marked
+            .resize_exact(150, 100, FilterType::Lanczos3)
+            .to_rgb8();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut Cursor::new(&mut jpeg), 75)
+            .encode_image(&small)
+            .unwrap();
+        let small = image::load_from_memory(&jpeg).unwrap();
+        let decoded = decode_thumbnail(&small, bits.len(), b"key", &config).unwrap();
+        assert_eq!(errors(&decoded, &bits), 0);
+
+        let absent = thumbnail_agreement(&image, bits.len(), b"key", &config).unwrap();
+        assert!(absent < 0.75, "{}", absent);
+    }
+
+    #[test]
+    fn test_thumbnail_beats_full_resolution() {
+        // A whole-image lattice mark of the same bits does not survive the same thumbnail.
+        let image = DynamicImage::ImageRgb8(grainy(512, 384, 41));
+        let config = ThumbnailConfig::default();
+        let bits = bits(config.capacity().unwrap());
+        let plain = DynamicImage::ImageRgb8(embed_scs(&image, &bits, b"key", &config.scs).unwrap());
+        let thumbnail = plain
+            .resize_exact(128, 96, FilterType::Triangle)
+            .resize_exact(512, 384, FilterType::Triangle);
+        let decoded = decode_scs(&thumbnail, bits.len(), b"key", &config.scs).unwrap();
+        assert!(errors(&decoded, &bits) > 4);
+
+        let marked = embed_thumbnail(&image, &bits, b"key", &config).unwrap();
+        let thumbnail = DynamicImage::ImageRgb8(marked).resize_exact(128, 96, FilterType::Triangle);
+        let decoded = decode_thumbnail(&thumbnail, bits.len(), b"key", &config).unwrap();
+        assert_eq!(errors(&decoded, &bits), 0);
+    }
+
+    #[test]
+    fn test_thumbnail_limits() {
+        let image = DynamicImage::ImageRgb8(grainy(48, 96, 41));
+        let config = ThumbnailConfig::default();
+        assert!(embed_thumbnail(&image, &bits(8), b"key", &config).is_err());
+
+        let image = DynamicImage::ImageRgb8(grainy(128, 96, 41));
+        assert!(embed_thumbnail(&image, &bits(32), b"key", &config).is_err());
+    }
+}
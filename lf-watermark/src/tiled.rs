@@ -0,0 +1,137 @@
+//! Embedding into TIFF scans too large to decode in one piece.
+//!
+//! Stitched scans easily go past a gigapixel, where a flat `f32` luma plane alone is several
+//! gigabytes. Here the input is read one row of strips or tiles at a time and each such band is
+//! marked and written out before the next one is decoded, so memory use is bounded by the band
+//! size rather than the image size.
+
+use std::io::{Read, Seek, Write};
+
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+use tiff::encoder::{colortype, TiffEncoder, TiffKind};
+use tiff::ColorType;
+
+use crate::raw::embed_interleaved;
+use crate::{payload_value, Result, WatermarkConfig};
+
+/// Past this many bytes of pixel data the output is written as BigTIFF, whose 64-bit offsets
+/// lift the 4 GiB limit of classic TIFF. Some headroom is left for the directory.
+const BIG_TIFF_THRESHOLD: u64 = u32::MAX as u64 - (1 << 20);
+
+/// Embeds `watermark` into an 8-bit RGB TIFF, streaming from `reader` to `writer`.
+///
+/// Strip and tile layouts are both accepted; the output is always stripped, with one strip per
+/// band of the input. Each band is marked on its own, so with [`Band::Dc`](crate::Band::Dc) the
+/// result matches [`embed_watermark_with_config`](crate::embed_watermark_with_config) on the
+/// whole image up to rounding, while the other bands place the mark per band of rows.
+pub fn embed_tiff_tiled<R: Read + Seek, W: Write + Seek>(
+    reader: R,
+    writer: W,
+    watermark: &str,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    // Only one band is ever decoded, but the strip and tile offset tables of a gigapixel image
+    // are larger than the default limits allow.
+    let mut decoder = Decoder::new(reader)?.with_limits(Limits::unlimited());
+    let color_type = decoder.colortype()?;
+    if color_type != ColorType::RGB(8) {
+        return Err(format!("Unsupported TIFF color type; {:?}", color_type).into());
+    }
+
+    let watermark = payload_value(watermark)? * config.strength;
+    let (width, height) = decoder.dimensions()?;
+    if width as u64 * height as u64 * 3 > BIG_TIFF_THRESHOLD {
+        write_bands(
+            &mut decoder,
+            TiffEncoder::new_big(writer)?,
+            watermark,
+            config,
+        )
+    } else {
+        write_bands(&mut decoder, TiffEncoder::new(writer)?, watermark, config)
+    }
+}
+
+fn write_bands<R: Read + Seek, W: Write + Seek, K: TiffKind>(
+    decoder: &mut Decoder<R>,
+    mut encoder: TiffEncoder<W, K>,
+    watermark: f32,
+    config: &WatermarkConfig,
+) -> Result<()> {
+    let (width, height) = decoder.dimensions()?;
+    let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+    let across = width.div_ceil(chunk_width);
+    let row_len = width as usize * 3;
+
+    let mut image = encoder.new_image::<colortype::RGB8>(width, height)?;
+    image.rows_per_strip(chunk_height)?;
+
+    let mut band = Vec::new();
+    for band_index in 0..height.div_ceil(chunk_height) {
+        let rows = chunk_height.min(height - band_index * chunk_height) as usize;
+        band.clear();
+        band.resize(row_len * rows, 0);
+
+        for column in 0..across {
+            let chunk = band_index * across + column;
+            let chunk_row_len = decoder.chunk_data_dimensions(chunk).0 as usize * 3;
+            let data = match decoder.read_chunk(chunk)? {
+                DecodingResult::U8(data) => data,
+                _ => return Err("Unsupported TIFF sample format".into()),
+            };
+
+            let x = column as usize * chunk_width as usize * 3;
+            for (row, src) in data.chunks_exact(chunk_row_len).enumerate() {
+                band[row * row_len + x..][..chunk_row_len].copy_from_slice(src);
+            }
+        }
+
+        embed_interleaved(&mut band, 3, watermark, config)?;
+        image.write_strip(&band)?;
+    }
+    image.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use super::*;
+    use crate::embed_watermark_with_config;
+
+    #[test]
+    fn test_embed_tiff_tiled() {
+        let img = RgbImage::from_fn(16, 23, |x, y| Rgb([(x * 16) as u8, (y * 10) as u8, 50]));
+
+        let mut input = Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut input).unwrap();
+        let mut image = encoder
+            .new_image::<colortype::RGB8>(img.width(), img.height())
+            .unwrap();
+        image.rows_per_strip(5).unwrap();
+        image.write_data(img.as_raw()).unwrap();
+        input.set_position(0);
+
+        let mut output = Cursor::new(Vec::new());
+        let config = WatermarkConfig::default();
+        embed_tiff_tiled(input, &mut output, "Hello, World!", &config).unwrap();
+
+        let marked = image::load_from_memory(output.get_ref()).unwrap().to_rgb8();
+        let expected =
+            embed_watermark_with_config(&DynamicImage::ImageRgb8(img), "Hello, World!", &config)
+                .unwrap();
+        for (a, b) in marked.pixels().zip(expected.pixels()) {
+            for c in 0..3 {
+                assert!(a[c].abs_diff(b[c]) <= 1, "{:?} {:?}", a, b);
+            }
+        }
+
+        let mut output = Cursor::new(Vec::new());
+        let gray = Cursor::new(vec![0u8; 16]);
+        assert!(embed_tiff_tiled(gray, &mut output, "Hello", &config).is_err());
+    }
+}
@@ -0,0 +1,422 @@
+//! Visible marks stamped on top of the image.
+//!
+//! The invisible mark needs a detector to be of any use. Where a visible mark is acceptable and
+//! no detection infrastructure exists, the payload can be stamped so that anyone can read it back,
+//! as text with [`stamp_text`] or as a QR code with `stamp_qr` (feature `qr`).
+
+use font8x8::{UnicodeFonts, BASIC_FONTS};
+use image::{DynamicImage, Rgb, RgbImage};
+
+use crate::Result;
+
+/// Corner of the image a stamp is placed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+/// Where and how strongly a stamp is drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Placement {
+    pub corner: Corner,
+    /// Distance in pixels from the two image edges of `corner`.
+    pub margin: u32,
+    /// 1.0 draws the stamp opaque, lower values blend it with the image underneath.
+    pub opacity: f32,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement {
+            corner: Corner::default(),
+            margin: 16,
+            opacity: 1.0,
+        }
+    }
+}
+
+impl Placement {
+    /// Top-left pixel of a `width` x `height` stamp in an image of `image_width` x `image_height`.
+    pub fn origin(
+        &self,
+        image_width: u32,
+        image_height: u32,
+        width: u32,
+        height: u32,
+    ) -> Result<(u32, u32)> {
+        let fits = |stamp: u32, image: u32| {
+            stamp
+                .checked_add(self.margin.saturating_mul(2))
+                .is_some_and(|needed| needed <= image)
+        };
+        if !fits(width, image_width) || !fits(height, image_height) {
+            return Err(format!(
+                "Stamp of {}x{} with a margin of {} does not fit in {}x{}",
+                width, height, self.margin, image_width, image_height
+            )
+            .into());
+        }
+
+        let left = self.margin;
+        let top = self.margin;
+        let right = image_width - self.margin - width;
+        let bottom = image_height - self.margin - height;
+
+        Ok(match self.corner {
+            Corner::TopLeft => (left, top),
+            Corner::TopRight => (right, top),
+            Corner::BottomLeft => (left, bottom),
+            Corner::BottomRight => (right, bottom),
+        })
+    }
+}
+
+/// Pixels per glyph side of the built-in font at scale 1.
+const GLYPH: u32 = 8;
+
+/// Settings of [`stamp_text`] and [`tile_text`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextStamp {
+    pub placement: Placement,
+    /// Pixels per font pixel; glyphs are `8 * scale` pixels square.
+    pub scale: u32,
+    pub color: Rgb<u8>,
+}
+
+impl Default for TextStamp {
+    fn default() -> Self {
+        TextStamp {
+            placement: Placement::default(),
+            scale: 2,
+            color: Rgb([255, 255, 255]),
+        }
+    }
+}
+
+/// Width and height in pixels of `text` on one line at `scale`.
+pub fn text_size(text: &str, scale: u32) -> (u32, u32) {
+    let columns = text.chars().count() as u32;
+    (
+        columns.saturating_mul(GLYPH * scale),
+        if columns == 0 { 0 } else { GLYPH * scale },
+    )
+}
+
+/// Draws `text` on one line in a corner of `image`, with the built-in 8x8 Basic Latin font.
+/// Characters the font lacks are drawn as `?`.
+pub fn stamp_text(image: &DynamicImage, text: &str, stamp: &TextStamp) -> Result<RgbImage> {
+    if stamp.scale == 0 {
+        return Err("Text scale must be positive".into());
+    }
+
+    let mut image = image.to_rgb8();
+    let (width, height) = text_size(text, stamp.scale);
+    let (x0, y0) = stamp
+        .placement
+        .origin(image.width(), image.height(), width, height)?;
+    draw_text(&mut image, x0, y0, text, stamp)?;
+
+    Ok(image)
+}
+
+/// Repeats `text` over the whole of `image`, each row shifted by half a copy, so that no
+/// region large enough to be useful is left unmarked. `stamp.placement.margin` is the gap between
+/// copies; the corner is ignored.
+pub fn tile_text(image: &mut RgbImage, text: &str, stamp: &TextStamp) -> Result<()> {
+    if stamp.scale == 0 {
+        return Err("Text scale must be positive".into());
+    }
+    let (width, height) = text_size(text, stamp.scale);
+    if width == 0 {
+        return Ok(());
+    }
+
+    let step_x = width + stamp.placement.margin;
+    let step_y = height + stamp.placement.margin;
+    let mut row = 0;
+    let mut y = 0;
+    while y < image.height() {
+        let shift = if row % 2 == 1 { step_x / 2 } else { 0 };
+        let mut x = -(shift as i64);
+        while x < image.width() as i64 {
+            draw_text(image, x, y as i64, text, stamp)?;
+            x += step_x as i64;
+        }
+        y += step_y;
+        row += 1;
+    }
+
+    Ok(())
+}
+
+/// Draws `text` with its top-left corner at `(x0, y0)`, clipping whatever falls outside `image`.
+fn draw_text(
+    image: &mut RgbImage,
+    x0: impl Into<i64>,
+    y0: impl Into<i64>,
+    text: &str,
+    stamp: &TextStamp,
+) -> Result<()> {
+    let (x0, y0) = (x0.into(), y0.into());
+    let mask = TextMask::new(text, stamp.scale)?;
+    let (width, height) = text_size(text, stamp.scale);
+
+    // Clip the text box to the image.
+    let left = x0.max(0);
+    let top = y0.max(0);
+    let right = (x0 + width as i64).min(image.width() as i64);
+    let bottom = (y0 + height as i64).min(image.height() as i64);
+    if left >= right || top >= bottom {
+        return Ok(());
+    }
+
+    blend(
+        image,
+        left as u32,
+        top as u32,
+        (right - left) as u32,
+        (bottom - top) as u32,
+        stamp.placement.opacity,
+        |x, y| {
+            let lit = mask.is_lit((left - x0) as u32 + x, (top - y0) as u32 + y);
+            lit.then_some(stamp.color)
+        },
+    )
+}
+
+/// Which pixels of a line of text are drawn.
+pub(crate) struct TextMask {
+    glyphs: Vec<[u8; 8]>,
+    scale: u32,
+}
+
+impl TextMask {
+    pub(crate) fn new(text: &str, scale: u32) -> Result<Self> {
+        let glyphs = text
+            .chars()
+            .map(|c| BASIC_FONTS.get(c).or_else(|| BASIC_FONTS.get('?')))
+            .collect::<Option<_>>()
+            .ok_or("Built-in font has no fallback glyph")?;
+
+        Ok(TextMask { glyphs, scale })
+    }
+
+    /// Whether `(x, y)`, relative to the top-left of the text box, is part of a glyph.
+    pub(crate) fn is_lit(&self, x: u32, y: u32) -> bool {
+        let side = GLYPH * self.scale;
+        let Some(glyph) = self.glyphs.get((x / side) as usize) else {
+            return false;
+        };
+        let row = glyph[((y % side) / self.scale) as usize];
+
+        row >> ((x % side) / self.scale) & 1 == 1
+    }
+}
+
+/// Settings of [`stamp_qr`].
+#[cfg(feature = "qr")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QrStamp {
+    pub placement: Placement,
+    /// Side of one QR module in pixels.
+    pub module_size: u32,
+}
+
+#[cfg(feature = "qr")]
+impl Default for QrStamp {
+    fn default() -> Self {
+        QrStamp {
+            placement: Placement::default(),
+            module_size: 4,
+        }
+    }
+}
+
+/// Light modules around the code that scanners need to find it.
+#[cfg(feature = "qr")]
+const QUIET_ZONE: u32 = 4;
+
+/// Stamps `payload` as a QR code, quiet zone included, in a corner of `image`.
+///
+/// The code uses medium error correction, which keeps it readable through moderate JPEG
+/// compression and a semi-transparent blend.
+#[cfg(feature = "qr")]
+pub fn stamp_qr(image: &DynamicImage, payload: &str, stamp: &QrStamp) -> Result<RgbImage> {
+    use qrcode::{Color, EcLevel, QrCode};
+
+    if stamp.module_size == 0 {
+        return Err("Module size must be positive".into());
+    }
+
+    let code = QrCode::with_error_correction_level(payload, EcLevel::M)
+        .map_err(|err| format!("Cannot encode {:?} as a QR code; {}", payload, err))?;
+    let modules = code.width() as u32;
+    let colors = code.to_colors();
+
+    let side = (modules + 2 * QUIET_ZONE)
+        .checked_mul(stamp.module_size)
+        .ok_or("QR code too large")?;
+    let mut image = image.to_rgb8();
+    let (x0, y0) = stamp
+        .placement
+        .origin(image.width(), image.height(), side, side)?;
+
+    blend(
+        &mut image,
+        x0,
+        y0,
+        side,
+        side,
+        stamp.placement.opacity,
+        |x, y| {
+            let mx = (x / stamp.module_size).checked_sub(QUIET_ZONE);
+            let my = (y / stamp.module_size).checked_sub(QUIET_ZONE);
+            let dark = match (mx, my) {
+                (Some(mx), Some(my)) if mx < modules && my < modules => {
+                    colors[(my * modules + mx) as usize] == Color::Dark
+                }
+                _ => false,
+            };
+
+            Some(if dark {
+                Rgb([0, 0, 0])
+            } else {
+                Rgb([255, 255, 255])
+            })
+        },
+    )?;
+
+    Ok(image)
+}
+
+/// Blends the `width` x `height` patch produced by `color` (in patch coordinates) at `(x0, y0)`.
+/// Pixels for which `color` returns `None` are left alone.
+fn blend(
+    image: &mut RgbImage,
+    x0: u32,
+    y0: u32,
+    width: u32,
+    height: u32,
+    opacity: f32,
+    mut color: impl FnMut(u32, u32) -> Option<Rgb<u8>>,
+) -> Result<()> {
+    if !(0.0..=1.0).contains(&opacity) {
+        return Err(format!("Opacity {} is outside 0.0..=1.0", opacity).into());
+    }
+
+    for y in 0..height {
+        for x in 0..width {
+            let Some(stamp) = color(x, y) else {
+                continue;
+            };
+            let pixel = image.get_pixel_mut(x0 + x, y0 + y);
+            for c in 0..3 {
+                let under = pixel[c] as f32;
+                pixel[c] = (under + (stamp[c] as f32 - under) * opacity).round() as u8;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_placement_origin() {
+        let placement = Placement {
+            corner: Corner::TopRight,
+            margin: 10,
+            opacity: 1.0,
+        };
+        assert_eq!(placement.origin(200, 100, 50, 30).unwrap(), (140, 10));
+
+        let placement = Placement {
+            corner: Corner::BottomLeft,
+            ..placement
+        };
+        assert_eq!(placement.origin(200, 100, 50, 30).unwrap(), (10, 60));
+        assert!(placement.origin(200, 100, 50, 90).is_err());
+    }
+
+    #[test]
+    fn test_stamp_text() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(64, 32, Rgb([0, 0, 0])));
+        let stamp = TextStamp {
+            placement: Placement {
+                corner: Corner::TopLeft,
+                margin: 0,
+                opacity: 1.0,
+            },
+            scale: 1,
+            ..Default::default()
+        };
+        assert_eq!(text_size("AB", 1), (16, 8));
+
+        let stamped = stamp_text(&image, "I", &stamp).unwrap();
+        let lit: Vec<(u32, u32)> = stamped
+            .enumerate_pixels()
+            .filter(|(_, _, p)| p[0] == 255)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        // The glyph sits in its 8x8 cell and nothing is drawn outside it.
+        assert!(!lit.is_empty());
+        assert!(lit.iter().all(|&(x, y)| x < 8 && y < 8));
+
+        // Unknown characters fall back to '?'.
+        assert_eq!(
+            stamp_text(&image, "\u{2603}", &stamp).unwrap(),
+            stamp_text(&image, "?", &stamp).unwrap()
+        );
+        assert!(stamp_text(&image, "too long for this", &stamp).is_err());
+
+        let mut tiled = RgbImage::from_pixel(100, 50, Rgb([0, 0, 0]));
+        tile_text(&mut tiled, "AB", &stamp).unwrap();
+        for (x0, y0) in [(0, 0), (48, 0), (84, 40)] {
+            let region = (x0..x0 + 16).flat_map(|x| (y0..y0 + 10).map(move |y| (x, y)));
+            assert!(region
+                .into_iter()
+                .any(|(x, y)| tiled.get_pixel(x, y)[0] > 0));
+        }
+    }
+
+    #[cfg(feature = "qr")]
+    #[test]
+    fn test_stamp_qr() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(320, 240, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }));
+        let payload = "https://example.com/licence/1234";
+
+        for opacity in [1.0, 0.7] {
+            let stamp = QrStamp {
+                placement: Placement {
+                    opacity,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let stamped = stamp_qr(&image, payload, &stamp).unwrap();
+            assert_eq!(stamped.get_pixel(0, 0), &Rgb([0, 0, 128]));
+
+            let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(320, 240, |x, y| {
+                stamped.get_pixel(x as u32, y as u32)[1]
+            });
+            let grids = prepared.detect_grids();
+            assert_eq!(grids.len(), 1, "opacity {}", opacity);
+            assert_eq!(grids[0].decode().unwrap().1, payload);
+        }
+
+        let stamp = QrStamp {
+            module_size: 40,
+            ..Default::default()
+        };
+        assert!(stamp_qr(&image, payload, &stamp).is_err());
+    }
+}